@@ -0,0 +1,81 @@
+//! Reading generated csv input through serde, and with `CsvSource::fast`,
+//! and parsing its amounts with and without `Money::from_ascii`
+
+use std::{fmt::Write, hint::black_box};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use transaction_engine::{io::CsvSource, testing::ActionGenerator, ActionKind, Money};
+
+const RECORDS: usize = 100_000;
+
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for action in ActionGenerator::new(42).take(RECORDS) {
+        let kind = match action.kind {
+            ActionKind::Deposit => "deposit",
+            ActionKind::Withdrawal => "withdrawal",
+            ActionKind::Dispute => "dispute",
+            ActionKind::Resolve => "resolve",
+            ActionKind::Chargeback => "chargeback",
+            ActionKind::Reversal => "reversal",
+        };
+        let amount = action.amount.map(|a| a.to_string()).unwrap_or_default();
+        let tx = action
+            .transaction_id
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        writeln!(input, "{},{},{},{}", kind, action.client_id, tx, amount).unwrap();
+    }
+    input
+}
+
+fn read(c: &mut Criterion) {
+    let input = input();
+
+    let mut group = c.benchmark_group("csv");
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    group.bench_function("serde", |b| {
+        b.iter(|| {
+            let source = CsvSource::from_reader(input.as_bytes());
+            black_box(source.filter(Result::is_ok).count())
+        })
+    });
+    group.bench_function("fast", |b| {
+        b.iter(|| {
+            let source = CsvSource::from_reader(input.as_bytes()).fast();
+            black_box(source.filter(Result::is_ok).count())
+        })
+    });
+    group.finish();
+}
+
+fn amounts(c: &mut Criterion) {
+    let amounts: Vec<String> = ActionGenerator::new(42)
+        .filter_map(|action| action.amount)
+        .take(RECORDS)
+        .map(|amount| amount.to_string())
+        .collect();
+
+    let mut group = c.benchmark_group("amount");
+    group.throughput(Throughput::Elements(amounts.len() as u64));
+    group.bench_function("from_str", |b| {
+        b.iter(|| {
+            amounts
+                .iter()
+                .filter(|amount| amount.parse::<Money>().is_ok())
+                .count()
+        })
+    });
+    group.bench_function("from_ascii", |b| {
+        b.iter(|| {
+            amounts
+                .iter()
+                .filter(|amount| Money::from_ascii(amount.as_bytes()).is_ok())
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read, amounts);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+//! Replaying generated history into a fresh state, one action at a time with
+//! `update`, with `bulk_load`, and with `ingest_unchecked`
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use transaction_engine::{testing::ActionGenerator, Action, ActionKind, State, TrustedBatch};
+
+const ACTIONS: usize = 100_000;
+
+fn history() -> Vec<Action> {
+    // `ingest_unchecked` doesn't take reversals
+    ActionGenerator::new(42)
+        .with_clients(1_000)
+        .with_weight(ActionKind::Reversal, 0)
+        .take(ACTIONS)
+        .collect()
+}
+
+fn replay(c: &mut Criterion) {
+    let history = history();
+    let batch = TrustedBatch::new(history.clone()).expect("generated history is trusted");
+
+    let mut group = c.benchmark_group("replay");
+    group.throughput(Throughput::Elements(ACTIONS as u64));
+    group.sample_size(20);
+
+    group.bench_function("update", |b| {
+        b.iter_batched(
+            || history.clone(),
+            |history| {
+                let mut state = State::new();
+                for action in history {
+                    // Generated history has withdrawals that fail
+                    let _ = state.update(action);
+                }
+                black_box(state)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("bulk_load", |b| {
+        b.iter_batched(
+            || history.clone(),
+            |history| {
+                let mut state = State::new();
+                let _ = state.bulk_load(history);
+                black_box(state)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("ingest_unchecked", |b| {
+        b.iter_batched(
+            || batch.clone(),
+            |batch| {
+                let mut state = State::new();
+                state.ingest_unchecked(batch).expect("state is empty");
+                black_box(state)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, replay);
+criterion_main!(benches);
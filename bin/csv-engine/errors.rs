@@ -0,0 +1,182 @@
+//! What to do with records that can't be applied, and the report of them
+//! written with `--errors-out`
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use csv::{ByteRecord, Writer};
+use serde::Serialize;
+use transaction_engine::{Action, ActionKind, ClientFormat, TransactionId, UpdateError};
+
+use crate::Format;
+
+/// The code for records that couldn't be read as an action at all
+const INVALID_RECORD: &str = "invalid_record";
+
+/// Behaviour on records that don't deserialize, or actions the engine rejects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ErrorPolicy {
+    /// Count them in the manifest, but otherwise carry on
+    #[default]
+    Ignore,
+    /// Also print each one to stderr
+    Log,
+    /// Stop the run with a fatal error at the first one
+    Abort,
+}
+
+/// A csv file (or JSON array) listing every record that couldn't be applied
+pub enum ErrorReport {
+    Csv(Box<Writer<File>>),
+    Json { writer: BufWriter<File>, rows: u64 },
+}
+
+/// A row of the report. The action's fields are empty for records that
+/// didn't deserialize.
+#[derive(Serialize)]
+struct Row<'a> {
+    input: &'a Path,
+    /// The 1-based record number in the input, not counting the header
+    record: u64,
+    #[serde(rename = "type")]
+    kind: Option<ActionKind>,
+    client: Option<String>,
+    tx: Option<TransactionId>,
+    amount: Option<String>,
+    #[serde(rename = "ref")]
+    reference: Option<&'a str>,
+    currency: Option<&'a str>,
+    /// The ledger's currency, for actions rejected for being in another
+    expected_currency: Option<&'a str>,
+    error: String,
+    /// What went wrong, as an `UpdateError::code` for actions the engine
+    /// rejected, or `invalid_record`
+    code: &'static str,
+    /// The record as it was in the input (with its fields trimmed)
+    raw: Option<String>,
+}
+
+impl ErrorReport {
+    pub fn create(path: &Path, format: Format) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            Format::Csv => Self::Csv(Box::new(Writer::from_writer(file))),
+            Format::Json => {
+                let mut writer = BufWriter::new(file);
+                writer.write_all(b"[")?;
+                Self::Json { writer, rows: 0 }
+            }
+        })
+    }
+
+    /// Add a record, with the action it held if it deserialized
+    pub fn record(
+        &mut self,
+        input: &Path,
+        record: u64,
+        raw: &ByteRecord,
+        action: Option<&Action>,
+        error: &(dyn std::error::Error + 'static),
+    ) -> io::Result<()> {
+        let update_error = error.downcast_ref::<UpdateError>();
+        let expected_currency = match update_error {
+            Some(UpdateError::CurrencyMismatch { expected, .. }) => Some(expected.as_str()),
+            _ => None,
+        };
+        let format = ClientFormat::registered();
+        let row = Row {
+            input,
+            record,
+            kind: action.map(|a| a.kind),
+            client: action.map(|a| format.format(a.client_id)),
+            tx: action.and_then(|a| a.transaction_id),
+            amount: action.and_then(|a| a.amount).map(|a| a.to_string()),
+            reference: action.and_then(|a| a.reference.as_deref()),
+            currency: action.and_then(|a| a.currency.as_deref()),
+            expected_currency,
+            error: error.to_string(),
+            code: update_error.map_or(INVALID_RECORD, UpdateError::code),
+            raw: raw_line(raw),
+        };
+        match self {
+            Self::Csv(writer) => writer.serialize(row)?,
+            Self::Json { writer, rows } => {
+                writer.write_all(if *rows == 0 { b"\n  " } else { b",\n  " })?;
+                serde_json::to_writer(&mut *writer, &row)?;
+                *rows += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let file = match self {
+            Self::Csv(mut writer) => {
+                writer.flush()?;
+                writer.into_inner().map_err(|e| e.into_error())?
+            }
+            Self::Json { mut writer, .. } => {
+                writer.write_all(b"\n]\n")?;
+                writer.into_inner().map_err(|e| e.into_error())?
+            }
+        };
+        file.sync_data()
+    }
+}
+
+/// A record written back out as a line of csv, if there was one
+fn raw_line(record: &ByteRecord) -> Option<String> {
+    if record.is_empty() {
+        return None;
+    }
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(Vec::new());
+    writer.write_byte_record(record).ok()?;
+    let mut line = writer.into_inner().ok()?;
+    line.pop();
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use transaction_engine::AccountError;
+
+    use super::*;
+
+    #[test]
+    fn test_json_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("errors.json");
+        let input = Path::new("in.csv");
+        let mut report = ErrorReport::create(&path, Format::Json).unwrap();
+
+        let raw = ByteRecord::from(vec!["withdrawal", "1", "2", "9.5"]);
+        let action: Action = "withdrawal,1,2,9.5".parse().unwrap();
+        let error = UpdateError::TransactionFailed {
+            transaction: action.transaction_id.unwrap(),
+            error: AccountError::InsufficientFunds,
+        };
+        report
+            .record(input, 2, &raw, Some(&action), &error)
+            .unwrap();
+        let raw = ByteRecord::from(vec!["bogus", "1", "a, b"]);
+        let error = io::Error::other("unknown variant");
+        report.record(input, 3, &raw, None, &error).unwrap();
+        report.finish().unwrap();
+
+        let rows: Vec<serde_json::Value> =
+            serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["code"], "insufficient_funds");
+        assert_eq!(rows[0]["raw"], "withdrawal,1,2,9.5");
+        assert_eq!(rows[0]["tx"], 2);
+        assert_eq!(rows[1]["code"], "invalid_record");
+        assert_eq!(rows[1]["raw"], "bogus,1,\"a, b\"");
+        assert_eq!(rows[1]["type"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,144 @@
+//! `inspect`: a read-only prompt over a saved state, for answering questions
+//! from a checkpoint without running anything
+
+use std::{
+    error::Error,
+    io::{BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+use transaction_engine::{persist, ClientId, State, TransactionId};
+
+const HELP: &str = "\
+commands:
+  account <client>   the client's balances and status
+  tx <id>            a transaction and its history
+  ref <reference>    the transaction with an external reference
+  holds <client>     the funds held in the client's account, by hold
+  summary            totals over every account
+  help               this list
+  quit               leave (as does end of input)";
+
+/// Load the snapshot (or state directory) at `path` and answer commands from
+/// stdin until it ends
+pub fn run(path: &Path) -> Result<(), Box<dyn Error>> {
+    let state = persist::read_state(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let stdin = std::io::stdin();
+    let prompt = stdin.is_terminal();
+    if prompt {
+        eprintln!(
+            "{} accounts, {} actions applied. Type `help` for commands.",
+            state.summary().clients,
+            state.sequence()
+        );
+    }
+    session(&state, stdin.lock(), std::io::stdout().lock(), prompt)
+}
+
+fn session<R: BufRead, W: Write>(
+    state: &State,
+    input: R,
+    mut output: W,
+    prompt: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let (Some(command), argument) = (words.next(), words.next()) else {
+            continue;
+        };
+        if matches!(command, "quit" | "exit") {
+            return Ok(());
+        }
+        match answer(state, command, argument) {
+            Ok(answer) => writeln!(output, "{}", answer)?,
+            Err(e) => writeln!(output, "error: {}", e)?,
+        }
+    }
+}
+
+fn answer(state: &State, command: &str, argument: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let client = || -> Result<ClientId, Box<dyn Error>> {
+        let client = argument.ok_or("which client?")?;
+        Ok(client
+            .parse()
+            .map_err(|_| format!("invalid client id '{}'", client))?)
+    };
+    match command {
+        "account" => {
+            let client = client()?;
+            let account = state
+                .try_account(client)?
+                .ok_or_else(|| format!("client {} has no account", client))?;
+            json(&account)
+        }
+        "tx" => {
+            let id = argument.ok_or("which transaction?")?;
+            let id: TransactionId = id
+                .parse()
+                .map_err(|_| format!("invalid transaction id '{}'", id))?;
+            let transaction = state
+                .transaction(id)?
+                .ok_or_else(|| format!("transaction {} isn't recorded", id))?;
+            json(&transaction)
+        }
+        "ref" => {
+            let reference = argument.ok_or("which reference?")?;
+            let id = state
+                .transaction_for_reference(reference)
+                .ok_or_else(|| format!("no transaction has reference {}", reference))?;
+            answer(state, "tx", Some(&id.to_string()))
+        }
+        "holds" => json(&state.holds(client()?)?),
+        "summary" => json(&state.summary()),
+        "help" => Ok(HELP.into()),
+        other => Err(format!("unknown command '{}', try `help`", other).into()),
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Result<String, Box<dyn Error>> {
+    Ok(serde_json::to_string_pretty(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use transaction_engine::{io::CsvSource, SingleThreadedEngine, SyncEngineExt};
+
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let input = "type,client,tx,amount,ref\n\
+            deposit,7,1,5.0,\n\
+            deposit,7,2,2.5,upstream-2\n\
+            dispute,7,2,,\n";
+        let mut engine = SingleThreadedEngine::new();
+        engine
+            .process_all(CsvSource::from_reader(input.as_bytes()).map(Result::unwrap))
+            .unwrap();
+
+        let commands = "account 7\n\nholds 7\nref upstream-2\ntx 9\nbogus\nquit\nsummary\n";
+        let mut output = Vec::new();
+        session(engine.state(), commands.as_bytes(), &mut output, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("{\n  \"client\": 7,"), "{}", output);
+        // Only the disputed deposit is held
+        assert!(output.contains("{\n  \"2\": "), "{}", output);
+        assert!(output.contains("\"ref\": \"upstream-2\""), "{}", output);
+        assert!(output.contains("error: transaction 9 isn't recorded"));
+        assert!(output.contains("error: unknown command 'bogus'"));
+        // Nothing after `quit`
+        assert!(!output.contains("\"clients\""));
+    }
+}
@@ -0,0 +1,1047 @@
+//! Transaction engine binary, for applying csv files of actions and writing
+//! out the resulting accounts
+//!
+//! Inputs are processed in the order they're given, as if they were one long
+//! file. With `-` as an input, or no inputs at all, actions are streamed from
+//! stdin. The accounts are written to stdout (or `--output <path>`) as csv, or
+//! as a JSON array with `--format json`.
+//!
+//! On SIGUSR1, the accounts as they stand are written to the output, followed
+//! by a `# SNAPSHOT: after N records` line, and processing carries on. This
+//! happens once the record being read is in, so waits for the next record
+//! when the input is idle.
+//!
+//! The process exit code reports how the run went, so it can be used from
+//! scripts and orchestrators without parsing any output:
+//!
+//! - `0`: every record was applied
+//! - `2`: completed, but the engine rejected some actions
+//! - `3`: completed, but some records could not be deserialized
+//! - `4`: fatal error (including invalid arguments), the output is missing or
+//!   incomplete
+//! - `5`: interrupted by SIGINT/SIGTERM, the output only covers the records
+//!   read before the signal
+//!
+//! Pass `--manifest <path>` to also write a JSON summary of the run.
+//!
+//! Records that don't deserialize and actions the engine rejects are handled
+//! according to `--error-policy`: `ignore` (the default) only counts them,
+//! `log` also prints them to stderr and `abort` stops the run at the first
+//! one. `--errors-out <path>` writes every one of them to a csv report (or a
+//! JSON array, with `--errors-format json`), with an error code and the raw
+//! record alongside the error.
+//!
+//! `inspect <snapshot>` loads a snapshot file (or a state directory) and
+//! answers questions about it at a prompt (`account 7`, `tx 4521`, `holds 7`,
+//! `summary`, see `help`), without changing anything.
+//!
+//! `--state-dir <dir> reconcile <corrected.csv>...` replays a corrected
+//! history from scratch and compares the state in `dir` with it, for cleaning
+//! up after an upstream data bug. It writes the deposits and withdrawals that
+//! would bring each account's total in line as a csv input to review (or
+//! JSON with `--format json`), and applies them to the state too with
+//! `--apply`. Differences they can't make up, like funds held by a dispute
+//! that shouldn't be open, are printed as warnings for someone to look into,
+//! and the exit code is `2` if there are any.
+//!
+//! With `--state-dir <dir> run <input.csv>...`, the state left by the previous
+//! run in `dir` is loaded first and the updated state is saved back
+//! afterwards, so a series of files (e.g. daily settlements) can be applied
+//! one at a time.
+//!
+//! With `--checkpoint-every <n>` as well, the state is checkpointed every `n`
+//! records, along with how far through which input the run is. If the run is
+//! interrupted (by a signal, or by dying), running it again with the same
+//! inputs carries on from the last checkpoint rather than reading everything
+//! again. Inputs before the one it stopped in are taken as done, and the one
+//! it stopped in must not have changed since (going by a hash of its header
+//! row and its length). Records between the checkpoint and where the run
+//! stopped are read again, so any errors in them are reported again, but
+//! actions from them that were already applied are skipped.
+//!
+//! With `--rollback-corrupt-inputs`, each input is applied under a savepoint,
+//! and an input with any records that don't deserialize (e.g. a truncated or
+//! corrupted file) is rolled back as a whole rather than left half applied.
+//! The rest of the inputs still go ahead. With a state directory, an input's
+//! actions are only journalled once it's been kept.
+//!
+//! Inputs are read as v1 (exactly the `type`, `client`, `tx` and `amount`
+//! columns) if that's all their header row has, and as v2 (which adds
+//! optional `ts`, `currency`, `memo` and `idempotency` columns, among others)
+//! otherwise. `--schema v1|v2` reads them as one or the other, and a header
+//! row that doesn't fit `v1` is counted as a schema error with nothing else
+//! read from that input.
+//!
+//! `--currency <code>` rejects actions whose `currency` column names another
+//! currency. The errors report lists both the action's currency and the
+//! expected one for them.
+//!
+//! `--dispute-window-days <n>` rejects disputes made more than `n` days after
+//! the transaction they dispute. This needs a `timestamp` column (seconds since
+//! the Unix epoch) in the input, records without one aren't limited.
+//!
+//! Accounts are written in no particular order. `--sort-by client|total|held`
+//! sorts them (largest balance first for `total` and `held`), and
+//! `--only-locked` and `--only-nonzero` leave out unlocked accounts and
+//! accounts with nothing in them.
+//!
+//! Balances are written rounded to 4 decimal places, with midpoints away from
+//! zero. `--decimals <n>` and `--rounding <mode>` change that for systems
+//! that expect something else (e.g. `--decimals 2 --rounding even` for
+//! banker's rounding to cents).
+//!
+//! Each account is written with the original `client`, `available`, `held`,
+//! `total` and `locked` columns. `--output-columns extended` adds its
+//! `status`, `freeze_reason`, `quarantined` and `version`.
+//!
+//! Client ids are written as plain numbers. `--client-width <n>` zero pads
+//! them to `n` digits and `--client-prefix <prefix>` puts something in front
+//! (e.g. `--client-prefix CUST- --client-width 6` for `CUST-000123`), while
+//! `--mask-clients <n>` masks all but their last `n` digits. This applies to
+//! the accounts output and the errors report, not to saved state.
+//!
+//! With the `sled` feature, `--store <dir>` keeps accounts and transactions in
+//! a sled database in `dir` as they're processed, instead of in memory. The
+//! database is left behind for querying, and later runs with the same
+//! directory carry on from it.
+//!
+//! With `--crash-file <path>`, a panic in the engine writes the action it
+//! was processing, the 32 before it and the account totals to `path` as
+//! JSON, to attach to a bug report.
+//!
+//! On SIGINT or SIGTERM, processing stops at the next record boundary and the
+//! run finishes normally (including the state checkpoint) with the records read
+//! so far. The output then ends with a `# TRUNCATED` comment line, so it can't
+//! be mistaken for a complete summary. A second signal exits immediately.
+
+mod errors;
+mod inspect;
+mod manifest;
+mod reconcile;
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use csv::ByteRecord;
+#[cfg(unix)]
+use signal_hook::consts::SIGUSR1;
+use signal_hook::consts::TERM_SIGNALS;
+use transaction_engine::{
+    crash::FlightRecorder,
+    io::{Compression, CsvSchema, CsvSource, InputFingerprint},
+    persist::{Journal, ResumePoint, StateDir},
+    progress::{Progress, ProgressTracker},
+    AccountData, AccountFilter, AccountOrder, Action, ActionKind, ActionMeta, ChargebackPolicy,
+    ClientFormat, DisputeWindow, OutputColumns, OutputConfig, Rounding, SingleThreadedEngine,
+    State, Timestamp, TransactionState, UpdateError,
+};
+
+use crate::{
+    errors::{ErrorPolicy, ErrorReport},
+    manifest::{ContentDigest, Hashed, LockedRejections, Manifest, RunStats, Status},
+};
+
+/// The input path for reading from stdin
+const STDIN: &str = "-";
+
+/// Actions kept for the `--crash-file` report
+const CRASH_ACTIONS: usize = 32;
+
+#[derive(Debug, Parser)]
+#[command(version, about, subcommand_negates_reqs = true)]
+struct Args {
+    /// Csv files of actions, processed in order. `-` (or no inputs at all)
+    /// reads from stdin.
+    inputs: Vec<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Write the accounts here instead of to stdout
+    #[arg(long, short, global = true)]
+    output: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t, global = true)]
+    format: Format,
+
+    /// Which columns the inputs have (going by each one's header row if
+    /// not given)
+    #[arg(long, value_enum, global = true)]
+    schema: Option<Schema>,
+
+    /// Write the accounts in this order (in no particular order if not
+    /// given)
+    #[arg(long, value_enum, global = true)]
+    sort_by: Option<SortBy>,
+
+    /// Only write frozen or closed accounts
+    #[arg(long, global = true)]
+    only_locked: bool,
+
+    /// Only write accounts with a balance that isn't zero
+    #[arg(long, global = true)]
+    only_nonzero: bool,
+
+    /// What to do with records that can't be applied
+    #[arg(long, value_enum, default_value_t, global = true)]
+    error_policy: ErrorPolicy,
+
+    /// Keep each action's input file, record number and the time of the run
+    /// with its transaction (and in the audit trail), see `ActionMeta`
+    #[arg(long, global = true)]
+    provenance: bool,
+
+    /// An id for the run, kept with `--provenance`
+    #[arg(long, global = true, requires = "provenance")]
+    ingest_id: Option<String>,
+
+    /// Write every record that couldn't be applied to a csv report
+    #[arg(long, global = true)]
+    errors_out: Option<PathBuf>,
+
+    /// Write the `--errors-out` report as csv or as a JSON array
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        global = true,
+        requires = "errors_out"
+    )]
+    errors_format: Format,
+
+    /// Also write a JSON summary of the run
+    #[arg(long, global = true)]
+    manifest: Option<PathBuf>,
+
+    /// Carry state over from the previous run in this directory, with the
+    /// `run` command
+    #[arg(long, global = true, conflicts_with = "store")]
+    state_dir: Option<PathBuf>,
+
+    /// Keep accounts and transactions in a sled database in this directory
+    #[arg(long, global = true)]
+    store: Option<PathBuf>,
+
+    /// Print progress to stderr every so many seconds (5 if not given)
+    #[arg(long, global = true, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "5")]
+    progress: Option<u64>,
+
+    /// Undo everything from an input that has records that don't deserialize
+    #[arg(long, global = true)]
+    rollback_corrupt_inputs: bool,
+
+    /// Checkpoint the state every this many records, so an interrupted run
+    /// can carry on from there
+    #[arg(
+        long,
+        global = true,
+        value_name = "RECORDS",
+        requires = "state_dir",
+        conflicts_with = "rollback_corrupt_inputs",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    checkpoint_every: Option<u64>,
+
+    /// Reject disputes made more than this many days after their transaction
+    #[arg(long, global = true, value_name = "DAYS")]
+    dispute_window_days: Option<u64>,
+
+    /// How to handle disputes of deposits that have already been withdrawn
+    /// (recorded as failed if not given)
+    #[arg(long, value_enum, global = true)]
+    chargeback_policy: Option<Chargebacks>,
+
+    /// Reject disputes from clients that already have this many open
+    #[arg(long, global = true, value_name = "N")]
+    max_open_disputes: Option<usize>,
+
+    /// Reject actions with a `currency` other than this one (e.g. USD)
+    #[arg(long, global = true, value_name = "CODE")]
+    currency: Option<String>,
+
+    /// Round balances in the output to this many decimal places (4 if not
+    /// given)
+    #[arg(long, global = true, value_name = "PLACES")]
+    decimals: Option<u32>,
+
+    /// How to round balances in the output
+    #[arg(long, value_enum, default_value_t, global = true)]
+    rounding: RoundingMode,
+
+    /// Which columns to write for each account
+    #[arg(long, value_enum, default_value_t, global = true)]
+    output_columns: Columns,
+
+    /// Write client ids zero padded to this many digits
+    #[arg(long, global = true, value_name = "DIGITS")]
+    client_width: Option<usize>,
+
+    /// Write client ids after this prefix (e.g. CUST-)
+    #[arg(long, global = true, value_name = "PREFIX")]
+    client_prefix: Option<String>,
+
+    /// Write client ids with all but this many of their last digits masked
+    #[arg(
+        long,
+        global = true,
+        value_name = "VISIBLE",
+        conflicts_with_all = ["client_width", "client_prefix"]
+    )]
+    mask_clients: Option<usize>,
+
+    /// If the engine panics, write the actions it was processing to this
+    /// JSON file
+    #[arg(long, global = true, value_name = "PATH")]
+    crash_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Apply the inputs on top of the state in `--state-dir`
+    Run { inputs: Vec<PathBuf> },
+    /// Answer questions about a saved state at a prompt, without changing it
+    Inspect {
+        /// A snapshot file, or a state directory
+        snapshot: PathBuf,
+    },
+    /// Work out the adjustments that bring the state in `--state-dir` in line
+    /// with a corrected history, and write them out as csv
+    Reconcile {
+        /// The corrected history, from the beginning, in order
+        #[arg(required = true)]
+        corrected: Vec<PathBuf>,
+        /// Apply the adjustments to the state as well
+        #[arg(long)]
+        apply: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Schema {
+    /// Exactly type, client, tx and amount
+    V1,
+    /// The v1 columns, plus optional ts, currency, memo, idempotency and
+    /// the rest
+    V2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortBy {
+    /// Client id, lowest first
+    Client,
+    /// Total balance, largest first
+    Total,
+    /// Held balance, largest first
+    Held,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Chargebacks {
+    /// Reject the dispute
+    Strict,
+    /// Hold the funds anyway, taking the account negative
+    AllowNegative,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum RoundingMode {
+    /// Midpoints away from zero
+    #[default]
+    AwayFromZero,
+    /// Midpoints to the even neighbour (banker's rounding)
+    Even,
+    /// Drop the extra places
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Columns {
+    /// client, available, held, total and locked
+    #[default]
+    V1,
+    /// The v1 columns, then status, freeze_reason, quarantined and version
+    Extended,
+}
+
+impl Args {
+    fn parse() -> Result<Self, clap::Error> {
+        // Parse through the command, so its usage in errors has the binary's
+        // name
+        let mut command = Self::command();
+        let matches = command.try_get_matches_from_mut(std::env::args_os())?;
+        Self::from_arg_matches(&matches)?.validate(&mut command)
+    }
+
+    /// The stateful mode is spelled out with the `run` command
+    fn validate(mut self, command: &mut clap::Command) -> Result<Self, clap::Error> {
+        match self.command.take() {
+            Some(Command::Run { inputs }) if self.state_dir.is_some() => self.inputs = inputs,
+            // Reads a snapshot rather than inputs
+            Some(inspect @ Command::Inspect { .. }) => {
+                self.command = Some(inspect);
+                return Ok(self);
+            }
+            Some(reconcile @ Command::Reconcile { .. }) if self.state_dir.is_some() => {
+                self.command = Some(reconcile);
+                return Ok(self);
+            }
+            Some(Command::Reconcile { .. }) => {
+                return Err(command.error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the `reconcile` command needs --state-dir",
+                ))
+            }
+            Some(Command::Run { .. }) => {
+                return Err(command.error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the `run` command needs --state-dir",
+                ))
+            }
+            None if self.state_dir.is_some() => {
+                return Err(command.error(
+                    ErrorKind::MissingSubcommand,
+                    "expected the `run` command with --state-dir",
+                ))
+            }
+            None => {}
+        }
+        if self.inputs.is_empty() {
+            self.inputs.push(PathBuf::from(STDIN));
+        }
+        Ok(self)
+    }
+
+    /// Set up a state the way the arguments ask
+    fn configure(&self, state: &mut State) {
+        state.set_dispute_window(self.dispute_window());
+        state.set_chargeback_policy(self.chargeback_policy());
+        state.set_max_open_disputes(self.max_open_disputes);
+        state.set_currency(self.currency.clone());
+        state.set_output(self.output_config());
+    }
+
+    fn csv_schema(&self) -> CsvSchema {
+        match self.schema {
+            None => CsvSchema::Detect,
+            Some(Schema::V1) => CsvSchema::V1,
+            Some(Schema::V2) => CsvSchema::V2,
+        }
+    }
+
+    fn dispute_window(&self) -> Option<DisputeWindow> {
+        self.dispute_window_days.map(DisputeWindow::days)
+    }
+
+    fn output_config(&self) -> OutputConfig {
+        OutputConfig {
+            precision: self.decimals.or(OutputConfig::default().precision),
+            rounding: match self.rounding {
+                RoundingMode::AwayFromZero => Rounding::MidpointAwayFromZero,
+                RoundingMode::Even => Rounding::MidpointNearestEven,
+                RoundingMode::Truncate => Rounding::ToZero,
+            },
+            columns: match self.output_columns {
+                Columns::V1 => OutputColumns::V1,
+                Columns::Extended => OutputColumns::Extended,
+            },
+        }
+    }
+
+    fn client_format(&self) -> ClientFormat {
+        if let Some(visible) = self.mask_clients {
+            return ClientFormat::Masked { visible };
+        }
+        if self.client_width.is_none() && self.client_prefix.is_none() {
+            return ClientFormat::Plain;
+        }
+        ClientFormat::Padded {
+            prefix: self.client_prefix.clone().unwrap_or_default(),
+            width: self.client_width.unwrap_or(0),
+        }
+    }
+
+    /// The accounts to write out, matching every `--only-*` flag given
+    fn account_filter(&self) -> AccountFilter {
+        let mut filters = Vec::new();
+        if self.only_locked {
+            filters.push(AccountFilter::Locked);
+        }
+        if self.only_nonzero {
+            filters.push(AccountFilter::NonZero);
+        }
+        AccountFilter::All(filters)
+    }
+
+    fn chargeback_policy(&self) -> Option<ChargebackPolicy> {
+        self.chargeback_policy.map(|policy| match policy {
+            Chargebacks::Strict => ChargebackPolicy::Strict,
+            Chargebacks::AllowNegative => ChargebackPolicy::AllowNegative,
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            // Usage errors are fatal, but asking for help isn't
+            return match e.kind() {
+                ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => ExitCode::SUCCESS,
+                _ => ExitCode::from(Status::Fatal.exit_code()),
+            };
+        }
+    };
+
+    args.client_format().register();
+
+    if let Some(Command::Inspect { snapshot }) = &args.command {
+        return match inspect::run(snapshot) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(Status::Fatal.exit_code())
+            }
+        };
+    }
+
+    if let Some(Command::Reconcile { corrected, apply }) = &args.command {
+        return match reconcile::run(&args, corrected, *apply) {
+            Ok(status) => ExitCode::from(status.exit_code()),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(Status::Fatal.exit_code())
+            }
+        };
+    }
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let snapshot = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(e) = signal_hook::flag::register(SIGUSR1, Arc::clone(&snapshot)) {
+        eprintln!("error: failed to install signal handler: {}", e);
+        return ExitCode::from(Status::Fatal.exit_code());
+    }
+    for signal in TERM_SIGNALS {
+        // The first signal just sets the flag, a second one (with the flag
+        // already set) kills the process
+        let registered = signal_hook::flag::register_conditional_shutdown(
+            *signal,
+            Status::Interrupted.exit_code() as i32,
+            Arc::clone(&interrupted),
+        )
+        .and_then(|_| signal_hook::flag::register(*signal, Arc::clone(&interrupted)));
+        if let Err(e) = registered {
+            eprintln!("error: failed to install signal handler: {}", e);
+            return ExitCode::from(Status::Fatal.exit_code());
+        }
+    }
+
+    let started = Instant::now();
+    let mut stats = RunStats::default();
+    let result = run(&args, &mut stats, &interrupted, &snapshot);
+
+    let status = match &result {
+        Ok(_) => Status::from_stats(&stats),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            Status::Fatal
+        }
+    };
+
+    if let Some(path) = &args.manifest {
+        let mut manifest = Manifest::new(
+            &args.inputs,
+            args.output.as_deref(),
+            status,
+            stats,
+            started.elapsed(),
+        );
+        match result {
+            Ok((inputs, output)) => {
+                manifest.inputs = Some(inputs);
+                manifest.output = Some(output);
+            }
+            Err(e) => manifest.error = Some(e.to_string()),
+        }
+        if let Err(e) = manifest.write_to(path) {
+            eprintln!("error: failed to write manifest: {}", e);
+            return ExitCode::from(Status::Fatal.exit_code());
+        }
+    }
+
+    ExitCode::from(status.exit_code())
+}
+
+type Digests = (Vec<ContentDigest>, ContentDigest);
+
+fn run(
+    args: &Args,
+    stats: &mut RunStats,
+    interrupted: &AtomicBool,
+    snapshot: &AtomicBool,
+) -> Result<Digests, Box<dyn Error>> {
+    let state_dir = args.state_dir.as_ref().map(StateDir::open).transpose()?;
+    let (mut state, mut journal) = match (&state_dir, &args.store) {
+        (Some(dir), _) => {
+            let (state, journal) = dir.restore()?;
+            (state, Some(journal))
+        }
+        (None, Some(path)) => (open_store(path)?, None),
+        (None, None) => (State::new(), None),
+    };
+    args.configure(&mut state);
+    let mut engine = SingleThreadedEngine::from_state(state);
+    if let Some(path) = &args.crash_file {
+        let recorder = FlightRecorder::new(CRASH_ACTIONS);
+        recorder.install_panic_hook(path);
+        engine = engine.with_flight_recorder(recorder);
+    }
+    let failed_before = engine.state().failed_transactions().count();
+    let locked_before = LockedRejections::of(engine.state());
+
+    // Open the output before doing any work, so a bad path fails fast
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| {
+                format!("failed to create {}: {}", path.display(), e)
+            })?))
+        }
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut output = Hashed::new(output);
+    let mut report = args
+        .errors_out
+        .as_deref()
+        .map(|path| ErrorReport::create(path, args.errors_format))
+        .transpose()?;
+
+    let mut progress = args.progress.map(|secs| {
+        let tracker = ProgressTracker::new(print_progress)
+            .every(Duration::from_secs(secs))
+            .every_records(u64::MAX);
+        // Stdin has no size to estimate the time left from
+        let sizes: Option<u64> = args
+            .inputs
+            .iter()
+            .map(|path| std::fs::metadata(path).ok().map(|m| m.len()))
+            .sum();
+        match sizes {
+            Some(total) if !args.inputs.iter().any(|path| path.as_os_str() == STDIN) => {
+                tracker.with_total_bytes(total)
+            }
+            _ => tracker,
+        }
+    });
+    let mut earlier_bytes = 0;
+
+    // Where the last run stopped, if it was interrupted part way through an
+    // input. The inputs before that one were already done.
+    let resume = match &state_dir {
+        Some(dir) => dir.resume_point()?,
+        None => None,
+    };
+    let resume_at = match &resume {
+        Some(point) => {
+            let index = args
+                .inputs
+                .iter()
+                .position(|path| *path == point.input)
+                .ok_or_else(|| {
+                    format!(
+                        "the last run stopped part way through {}, which isn't one of the inputs",
+                        point.input.display()
+                    )
+                })?;
+            Some((index, point))
+        }
+        None => None,
+    };
+    let restored_seq = journal.as_ref().map_or(0, |journal| journal.sequence());
+    let mut stopped_at = None;
+    let received_at = Timestamp::now();
+
+    let mut inputs = Vec::new();
+    for (index, path) in args.inputs.iter().enumerate() {
+        let mut start = 0;
+        let mut skip_actions = 0;
+        let mut fingerprint = None;
+        match resume_at {
+            Some((resume_index, _)) if index < resume_index => continue,
+            Some((resume_index, point)) if index == resume_index => {
+                let found = InputFingerprint::of_path(path)
+                    .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+                if found != point.fingerprint {
+                    return Err(format!(
+                        "{} has changed since the last run stopped part way through it",
+                        path.display()
+                    )
+                    .into());
+                }
+                start = point.records;
+                skip_actions = point.applied_since(restored_seq);
+                fingerprint = Some(found);
+            }
+            _ if path.as_os_str() == STDIN && args.checkpoint_every.is_some() => {
+                return Err("--checkpoint-every can't carry on from stdin, pass files".into());
+            }
+            _ if path.as_os_str() == STDIN => {}
+            // A resumed run can be interrupted again
+            _ if args.checkpoint_every.is_some() || resume.is_some() => {
+                fingerprint = Some(
+                    InputFingerprint::of_path(path)
+                        .map_err(|e| format!("failed to open {}: {}", path.display(), e))?,
+                );
+            }
+            _ => {}
+        }
+        let input: Box<dyn Read> = if path.as_os_str() == STDIN {
+            Box::new(std::io::stdin().lock())
+        } else {
+            Box::new(
+                File::open(path)
+                    .map_err(|e| format!("failed to open {}: {}", path.display(), e))?,
+            )
+        };
+        // The digest and progress go by the bytes of the file as stored
+        let compression = if path.as_os_str() == STDIN {
+            Compression::None
+        } else {
+            Compression::from_path(path)
+        };
+        let input = compression
+            .decoder(Hashed::new(input))
+            .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+        let mut reader = CsvSource::from_reader(input).with_schema(args.csv_schema());
+        if args.provenance {
+            reader = reader.with_meta(ActionMeta {
+                source: Some(path.display().to_string()),
+                record: None,
+                received_at: Some(received_at),
+                ingest: args.ingest_id.clone(),
+            });
+        }
+        if start > 0 {
+            reader.seek_to_record(start)?;
+        }
+        let mut record = start;
+        // Rolled back if the input turns out to be corrupt, with its actions
+        // only journalled once it's kept
+        let savepoint = args.rollback_corrupt_inputs.then(|| engine.savepoint());
+        let mut unjournalled = Vec::new();
+        let schema_errors_before = stats.schema_errors;
+        while let Some(item) = reader.next() {
+            record += 1;
+            stats.records_read += 1;
+            let rejected_before = stats.actions_rejected + stats.schema_errors;
+            match item {
+                Ok(_) if skip_actions > 0 => {
+                    // Applied (and journalled) before the last run stopped
+                    skip_actions -= 1;
+                }
+                Ok(action) => {
+                    // Journal before applying, so the action isn't lost if we
+                    // die part way
+                    match journal.as_mut() {
+                        Some(_) if savepoint.is_some() => unjournalled.push(action.clone()),
+                        Some(journal) => {
+                            journal.append(&action)?;
+                        }
+                        None => {}
+                    }
+                    if let Err(e) = engine.try_process(action.clone()) {
+                        stats.actions_rejected += 1;
+                        let policy = args.error_policy;
+                        let raw = reader.last_record();
+                        handle_error(
+                            policy,
+                            report.as_mut(),
+                            path,
+                            record,
+                            raw,
+                            Some(&action),
+                            &e,
+                        )?;
+                    } else if let Some(report) = report.as_mut() {
+                        // Not an error for the policy, but one for the report
+                        if let Err(e) = failed_withdrawal(engine.state(), &action) {
+                            report.record(path, record, reader.last_record(), Some(&action), &e)?;
+                        }
+                    }
+                }
+                Err(e) => {
+                    stats.schema_errors += 1;
+                    let raw = reader.last_record();
+                    handle_error(
+                        args.error_policy,
+                        report.as_mut(),
+                        path,
+                        record,
+                        raw,
+                        None,
+                        &e,
+                    )?;
+                }
+            }
+
+            if let Some(progress) = progress.as_mut() {
+                progress.set_bytes_read(earlier_bytes + reader.get_ref().get_ref().bytes());
+                progress.record(stats.actions_rejected + stats.schema_errors > rejected_before);
+            }
+            if snapshot.swap(false, Ordering::Relaxed) {
+                write_accounts(engine.state(), args, &mut output)?;
+                writeln!(output, "# SNAPSHOT: after {} records", stats.records_read)?;
+                output.flush()?;
+            }
+            let resume_point = |journal: &Journal| {
+                fingerprint.as_ref().map(|fingerprint| ResumePoint {
+                    input: path.clone(),
+                    fingerprint: fingerprint.clone(),
+                    records: record,
+                    // Actions still to be skipped are already journalled
+                    seq: journal.sequence() - skip_actions,
+                })
+            };
+            if let (Some(every), Some(dir), Some(journal)) =
+                (args.checkpoint_every, &state_dir, journal.as_mut())
+            {
+                if record % every == 0 {
+                    dir.checkpoint(engine.state(), journal)?;
+                    if let Some(point) = resume_point(journal) {
+                        dir.set_resume_point(&point)?;
+                    }
+                }
+            }
+            if interrupted.load(Ordering::Relaxed) {
+                stats.interrupted = true;
+                stopped_at = journal.as_ref().and_then(resume_point);
+                break;
+            }
+        }
+        if let Some(savepoint) = savepoint {
+            if stats.schema_errors > schema_errors_before {
+                engine.rollback_to(savepoint)?;
+                stats.inputs_rolled_back += 1;
+                if args.error_policy == ErrorPolicy::Log {
+                    eprintln!("rolled back {}: it has corrupt records", path.display());
+                }
+            } else {
+                engine.release(savepoint);
+                if let Some(journal) = journal.as_mut() {
+                    for action in &unjournalled {
+                        journal.append(action)?;
+                    }
+                }
+            }
+        }
+        let (_, digest) = reader.into_inner().into_inner().finish();
+        earlier_bytes += digest.bytes;
+        inputs.push(digest);
+        if stats.interrupted {
+            break;
+        }
+    }
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    let failed = engine.state().failed_transactions().count();
+    stats.transactions_failed = failed.saturating_sub(failed_before) as u64;
+    stats.locked_rejections = LockedRejections::of(engine.state()).since(locked_before);
+
+    if let (Some(dir), Some(journal)) = (&state_dir, journal.as_mut()) {
+        dir.checkpoint(engine.state(), journal)?;
+        match &stopped_at {
+            Some(point) => dir.set_resume_point(point)?,
+            None => dir.clear_resume_point()?,
+        }
+    }
+    let mut state = engine.into_state();
+    state.flush_stores()?;
+    if let Some(report) = report {
+        report.finish()?;
+    }
+
+    stats.accounts_written = write_accounts(&state, args, &mut output)?;
+    if stats.interrupted {
+        writeln!(
+            output,
+            "# TRUNCATED: interrupted after {} records",
+            stats.records_read
+        )?;
+    }
+    output.flush()?;
+    let (_, output) = output.finish();
+    Ok((inputs, output))
+}
+
+fn print_progress(progress: &Progress) {
+    let mut line = format!(
+        "progress: {} records ({} rejected), {:.0} records/s",
+        progress.records,
+        progress.rejected,
+        progress.throughput()
+    );
+    if let (Some(done), Some(remaining)) = (progress.fraction_done, progress.remaining()) {
+        line += &format!(
+            ", {:.1}% done, about {}s left",
+            done * 100.0,
+            remaining.as_secs()
+        );
+    }
+    eprintln!("{}", line);
+}
+
+/// Apply the error policy to a record that couldn't be applied
+fn handle_error(
+    policy: ErrorPolicy,
+    report: Option<&mut ErrorReport>,
+    input: &Path,
+    record: u64,
+    raw: &ByteRecord,
+    action: Option<&Action>,
+    error: &(dyn Error + 'static),
+) -> Result<(), Box<dyn Error>> {
+    if let Some(report) = report {
+        report.record(input, record, raw, action, error)?;
+    }
+    match policy {
+        ErrorPolicy::Ignore => Ok(()),
+        ErrorPolicy::Log => {
+            eprintln!("warning: {} record {}: {}", input.display(), record, error);
+            Ok(())
+        }
+        ErrorPolicy::Abort => {
+            Err(format!("{} record {}: {}", input.display(), record, error).into())
+        }
+    }
+}
+
+/// The failure of a withdrawal that was applied, but recorded as failed
+/// (e.g. for insufficient funds) rather than rejected
+fn failed_withdrawal(state: &State, action: &Action) -> Result<(), UpdateError> {
+    if action.kind != ActionKind::Withdrawal {
+        return Ok(());
+    }
+    let id = match (action.transaction_id, action.reference.as_deref()) {
+        (Some(id), _) => id,
+        (None, Some(reference)) => match state.transaction_for_reference(reference) {
+            Some(id) => id,
+            None => return Ok(()),
+        },
+        (None, None) => return Ok(()),
+    };
+    match state.transaction(id)?.map(|transaction| transaction.state) {
+        Some(TransactionState::Failed(error)) => Err(UpdateError::TransactionFailed {
+            transaction: id,
+            error,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Write out the accounts picked by the `--only-*` flags, in the
+/// `--sort-by` order, returning how many there were
+fn write_accounts<W: Write>(
+    state: &State,
+    args: &Args,
+    mut writer: W,
+) -> Result<u64, Box<dyn Error>> {
+    let filter = args.account_filter();
+    let accounts: Box<dyn Iterator<Item = AccountData>> = match args.sort_by {
+        Some(order) => {
+            let order = match order {
+                SortBy::Client => AccountOrder::Client,
+                SortBy::Total => AccountOrder::Total,
+                SortBy::Held => AccountOrder::Held,
+            };
+            Box::new(state.accounts_sorted(filter, order).into_iter())
+        }
+        None => Box::new(state.accounts_where(filter)),
+    };
+    let mut written = 0;
+    match args.format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for data in accounts {
+                writer.serialize(data)?;
+                written += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Json => {
+            let accounts: Vec<_> = accounts.collect();
+            serde_json::to_writer_pretty(&mut writer, &accounts)?;
+            writeln!(writer)?;
+            written = accounts.len() as u64;
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(feature = "sled")]
+fn open_store(path: &Path) -> Result<State, Box<dyn Error>> {
+    let state = transaction_engine::store::sled::open_state(path, &Default::default())?;
+    Ok(state)
+}
+
+#[cfg(not(feature = "sled"))]
+fn open_store(_path: &Path) -> Result<State, Box<dyn Error>> {
+    Err("--store needs the binary to be built with the sled feature".into())
+}
+
+// TODO: fix tests with static output though hashmap will produce random client orders
+// #[cfg(test)]
+// mod tests {
+//     use super::*;
+
+//     const EXPECT: &str = include_str!("../../test_data/output.csv");
+
+//     const DENSE: &str = include_str!("../../test_data/dense.csv");
+//     const PRETTY: &str = include_str!("../../test_data/pretty.csv");
+
+//     #[test]
+//     fn test_dense() {
+//         let reader = CsvSource::from_reader(DENSE.as_bytes());
+
+//         let mut writer = Writer::from_writer(Vec::new());
+//         process(reader, &mut writer, &mut RunStats::default(), None, None, None, &AtomicBool::default()).unwrap();
+
+//         let result =
+//             String::from_utf8(writer.into_inner().expect("Failed to get result bytes")).unwrap();
+
+//         assert_eq!(result.as_str(), EXPECT);
+//     }
+
+//     #[test]
+//     fn test_pretty() {
+//         let reader = CsvSource::from_reader(PRETTY.as_bytes());
+
+//         let mut writer = Writer::from_writer(Vec::new());
+//         process(reader, &mut writer, &mut RunStats::default(), None, None, None, &AtomicBool::default()).unwrap();
+
+//         let result =
+//             String::from_utf8(writer.into_inner().expect("Failed to get result bytes")).unwrap();
+
+//         assert_eq!(result.as_str(), EXPECT);
+//     }
+// }
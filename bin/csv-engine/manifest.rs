@@ -0,0 +1,227 @@
+//! Machine-readable summary of a run, for orchestration tools that need more
+//! than the exit code
+
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use transaction_engine::{AccountError, LockedOperation, State};
+
+/// Counts of what happened to the input records
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RunStats {
+    /// Every record in the input, including those that failed to deserialize
+    pub records_read: u64,
+    /// Records that could not be deserialized into an action
+    pub schema_errors: u64,
+    /// Actions the engine refused to apply (e.g. a reused transaction id)
+    pub actions_rejected: u64,
+    /// Deposits, withdrawals or disputes that were recorded but failed (e.g.
+    /// insufficient funds)
+    pub transactions_failed: u64,
+    /// The failed transactions that a locked account refused, by what it
+    /// refused
+    pub locked_rejections: LockedRejections,
+    /// Inputs undone by `--rollback-corrupt-inputs`
+    pub inputs_rolled_back: u64,
+    /// Account rows written to the output
+    pub accounts_written: u64,
+    /// Processing was stopped early by a signal, so the output only reflects
+    /// the records read up to that point
+    pub interrupted: bool,
+}
+
+/// Counts of failed transactions refused by a locked account, by the
+/// operation refused. Deposits still arriving at a frozen account and payouts
+/// it's blocking need different follow-ups.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LockedRejections {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    /// Disputes that couldn't hold their funds
+    pub holds: u64,
+    /// Resolves that couldn't release their funds
+    pub releases: u64,
+    pub chargebacks: u64,
+    pub reversals: u64,
+}
+
+impl LockedRejections {
+    /// Count the refusals recorded in a state
+    pub fn of(state: &State) -> Self {
+        let mut counts = Self::default();
+        let failures = state
+            .failed_transactions()
+            .flat_map(|transaction| transaction.failures().collect::<Vec<_>>());
+        for failure in failures {
+            let AccountError::Locked(operation) = failure else {
+                continue;
+            };
+            let count = match operation {
+                LockedOperation::Deposit => &mut counts.deposits,
+                LockedOperation::Withdrawal => &mut counts.withdrawals,
+                LockedOperation::Hold => &mut counts.holds,
+                LockedOperation::Release => &mut counts.releases,
+                LockedOperation::Chargeback => &mut counts.chargebacks,
+                LockedOperation::Reversal => &mut counts.reversals,
+                // Restatements don't fail transactions, and unrecorded
+                // operations are from before this run
+                LockedOperation::Restatement | LockedOperation::Unrecorded => continue,
+            };
+            *count += 1;
+        }
+        counts
+    }
+
+    /// The rejections added since `before` was counted
+    pub fn since(self, before: Self) -> Self {
+        Self {
+            deposits: self.deposits.saturating_sub(before.deposits),
+            withdrawals: self.withdrawals.saturating_sub(before.withdrawals),
+            holds: self.holds.saturating_sub(before.holds),
+            releases: self.releases.saturating_sub(before.releases),
+            chargebacks: self.chargebacks.saturating_sub(before.chargebacks),
+            reversals: self.reversals.saturating_sub(before.reversals),
+        }
+    }
+}
+
+/// How the run ended, in order of precedence. The discriminant is the
+/// process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Clean = 0,
+    CompletedWithRejections = 2,
+    SchemaErrors = 3,
+    Fatal = 4,
+    Interrupted = 5,
+}
+
+impl Status {
+    pub fn from_stats(stats: &RunStats) -> Self {
+        if stats.interrupted {
+            Self::Interrupted
+        } else if stats.schema_errors > 0 {
+            Self::SchemaErrors
+        } else if stats.actions_rejected > 0 || stats.transactions_failed > 0 {
+            Self::CompletedWithRejections
+        } else {
+            Self::Clean
+        }
+    }
+
+    pub fn exit_code(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Size and hash of everything that passed through a reader or writer
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentDigest {
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Manifest<'a> {
+    pub status: Status,
+    pub exit_code: u8,
+    pub input_paths: &'a [PathBuf],
+    /// The size and hash of each input, in the same order as the paths
+    pub inputs: Option<Vec<ContentDigest>>,
+    /// Missing when the output went to stdout
+    pub output_path: Option<&'a Path>,
+    pub output: Option<ContentDigest>,
+    #[serde(flatten)]
+    pub stats: RunStats,
+    pub duration_ms: u128,
+    /// The reason for a fatal exit
+    pub error: Option<String>,
+}
+
+impl<'a> Manifest<'a> {
+    pub fn new(
+        input_paths: &'a [PathBuf],
+        output_path: Option<&'a Path>,
+        status: Status,
+        stats: RunStats,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            status,
+            exit_code: status.exit_code(),
+            input_paths,
+            inputs: None,
+            output_path,
+            output: None,
+            stats,
+            duration_ms: duration.as_millis(),
+            error: None,
+        }
+    }
+
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// Wraps a reader or writer, hashing all the bytes that pass through it
+pub struct Hashed<T> {
+    inner: T,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<T> Hashed<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// How many bytes have passed through so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn finish(self) -> (T, ContentDigest) {
+        let hash = self.hasher.finalize();
+        let sha256 = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        let digest = ContentDigest {
+            bytes: self.bytes,
+            sha256,
+        };
+        (self.inner, digest)
+    }
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
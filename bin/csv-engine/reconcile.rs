@@ -0,0 +1,104 @@
+//! `reconcile`: bringing a saved state in line with a corrected history,
+//! after an upstream data bug
+//!
+//! The corrected inputs are replayed from scratch, with the same
+//! configuration, and the saved state is compared with the result. The
+//! adjustments that would bring it in line are written out as a v2 csv input
+//! to review (or as JSON, with the discrepancies too), and applied straight
+//! away with `--apply`.
+
+use std::{error::Error, fs::File, io::Write, path::PathBuf};
+
+use transaction_engine::{
+    io::CsvSource,
+    persist::StateDir,
+    report::{Discrepancy, DiscrepancyReason},
+    State,
+};
+
+use crate::{manifest::Status, Args, Format};
+
+/// Reconcile the state in `--state-dir` with `corrected`, returning whether
+/// anything was left for a person to look into
+pub fn run(args: &Args, corrected: &[PathBuf], apply: bool) -> Result<Status, Box<dyn Error>> {
+    let dir = args
+        .state_dir
+        .as_ref()
+        .ok_or("reconcile needs --state-dir")?;
+    let dir = StateDir::open(dir)?;
+    let (mut state, mut journal) = dir.restore()?;
+    args.configure(&mut state);
+
+    let mut rebuilt = State::new();
+    args.configure(&mut rebuilt);
+    for path in corrected {
+        let source = CsvSource::from_path(path)
+            .map_err(|e| format!("failed to open {}: {}", path.display(), e))?
+            .with_schema(args.csv_schema());
+        for (record, action) in source.enumerate() {
+            // The corrected history has to be whole for the comparison to
+            // mean anything, but the engine rejecting some of it is normal
+            let action =
+                action.map_err(|e| format!("{} record {}: {}", path.display(), record + 1, e))?;
+            let _ = rebuilt.update(action);
+        }
+    }
+
+    // Named after the state they apply to, so they can only be applied once
+    let prefix = format!("backfill-{}-", state.sequence());
+    let reconciliation = state.reconcile(&rebuilt, &prefix)?;
+    for discrepancy in &reconciliation.discrepancies {
+        eprintln!("warning: {}", describe(discrepancy));
+    }
+    if apply {
+        for action in reconciliation.actions() {
+            journal.append(&action)?;
+            let reference = action.reference.clone().unwrap_or_default();
+            if let Err(e) = state.update(action) {
+                eprintln!("warning: adjustment {} wasn't applied: {}", reference, e);
+            }
+        }
+        dir.checkpoint(&state, &mut journal)?;
+    }
+
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(
+            File::create(path)
+                .map_err(|e| format!("failed to create {}: {}", path.display(), e))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match args.format {
+        Format::Csv => reconciliation.write_csv(&mut output)?,
+        Format::Json => {
+            reconciliation.write_json(&mut output)?;
+            writeln!(output)?;
+        }
+    }
+    output.flush()?;
+
+    Ok(if reconciliation.discrepancies.is_empty() {
+        Status::Clean
+    } else {
+        Status::CompletedWithRejections
+    })
+}
+
+fn describe(discrepancy: &Discrepancy) -> String {
+    let (current, corrected) = (&discrepancy.current, &discrepancy.corrected);
+    let problem = match discrepancy.reason {
+        DiscrepancyReason::Held => format!(
+            "{} is held, where the corrected history holds {}",
+            current.held, corrected.held
+        ),
+        DiscrepancyReason::Locked if current.locked => {
+            "the account is locked, but not in the corrected history".to_string()
+        }
+        DiscrepancyReason::Locked => "the account is locked in the corrected history".to_string(),
+        DiscrepancyReason::Frozen => format!(
+            "the account is locked, so its total can't be adjusted from {} to {}",
+            current.total, corrected.total
+        ),
+    };
+    format!("client {}: {}", discrepancy.client, problem)
+}
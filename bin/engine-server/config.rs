@@ -0,0 +1,122 @@
+//! The server's config file
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+use transaction_engine::{persist::GroupCommit, ChargebackPolicy, DisputeWindow, State};
+
+/// Everything the server is configured with, read from a TOML file:
+///
+/// ```toml
+/// listen = "0.0.0.0:8080"
+/// state_dir = "/var/lib/engine-server"
+/// checkpoint_interval_secs = 300
+///
+/// [engine]
+/// currency = "USD"
+/// dispute_window_days = 60
+///
+/// [group_commit]
+/// max_actions = 256
+/// max_delay_ms = 2
+/// ```
+///
+/// Only `listen` is needed. Without a `state_dir`, accounts are kept in
+/// memory and lost when the server stops.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+
+    /// Where the snapshot and journal are kept, if anywhere
+    pub state_dir: Option<PathBuf>,
+
+    /// How often to checkpoint the state dir while running (it's always
+    /// checkpointed on shutdown)
+    pub checkpoint_interval_secs: Option<u64>,
+
+    /// How many account updates `/events` subscribers can fall behind by
+    #[serde(default = "default_feed_capacity")]
+    pub feed_capacity: usize,
+
+    #[serde(default)]
+    pub engine: EngineConfig,
+
+    /// Sync the journal for batches of actions, rather than one at a time.
+    /// Without it, actions are only synced at checkpoints and shutdown.
+    pub group_commit: Option<GroupCommitConfig>,
+}
+
+/// The engine's policies, as the csv engine's flags of the same names
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineConfig {
+    pub currency: Option<String>,
+    pub dispute_window_days: Option<u64>,
+    pub max_open_disputes: Option<usize>,
+    pub chargeback_policy: Option<Chargebacks>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Chargebacks {
+    Strict,
+    AllowNegative,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupCommitConfig {
+    pub max_actions: usize,
+    pub max_delay_ms: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("couldn't read the config file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid config: {0}")]
+    Invalid(#[from] toml::de::Error),
+}
+
+impl Config {
+    pub fn read(path: &std::path::Path) -> Result<Self, ConfigError> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn checkpoint_interval(&self) -> Option<Duration> {
+        self.checkpoint_interval_secs.map(Duration::from_secs)
+    }
+
+    pub fn group_commit(&self) -> Option<GroupCommit> {
+        self.group_commit.as_ref().map(|config| GroupCommit {
+            max_actions: config.max_actions,
+            max_delay: Duration::from_millis(config.max_delay_ms),
+        })
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = toml::de::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s)
+    }
+}
+
+impl EngineConfig {
+    pub fn configure(&self, state: &mut State) {
+        state.set_dispute_window(self.dispute_window_days.map(DisputeWindow::days));
+        state.set_chargeback_policy(self.chargeback_policy.map(|policy| match policy {
+            Chargebacks::Strict => ChargebackPolicy::Strict,
+            Chargebacks::AllowNegative => ChargebackPolicy::AllowNegative,
+        }));
+        state.set_max_open_disputes(self.max_open_disputes);
+        state.set_currency(self.currency.clone());
+    }
+}
+
+fn default_feed_capacity() -> usize {
+    1024
+}
@@ -0,0 +1,499 @@
+//! An HTTP server over a persistent engine, as a reference for deploying the
+//! `server` features together
+//!
+//! Everything is set in a TOML config file, passed as the only argument (see
+//! `config::Config`). The server restores its state directory on start, and
+//! serves:
+//!
+//! - `POST /actions`: apply one action, as JSON (the journal's format) or as
+//!   an `io::protobuf::Action` with `Content-Type: application/x-protobuf`.
+//!   Answers `200` with the client's account once it's been applied, `422`
+//!   if the engine rejected it (or it didn't decode), and `503` if the
+//!   engine's storage failed, in which case it's safe to retry.
+//! - `GET /accounts` and `GET /accounts/{client}`: accounts as JSON
+//! - `GET /events`, or `GET /events?client=<id>` for one client: account
+//!   changes and dispute events as Server-Sent Events, from a
+//!   `push::AccountFeed`
+//! - `GET /metrics`: counters in the Prometheus text format
+//! - `GET /health`
+//!
+//! On SIGINT or SIGTERM, the server stops taking connections, ends event
+//! streams, lets requests in flight finish, and then checkpoints the state
+//! directory before exiting.
+
+mod config;
+
+use std::{
+    future::Future,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State as Extract},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use prost::Message;
+use serde::Deserialize;
+use tokio::{net::TcpListener, sync::watch};
+use transaction_engine::{
+    io::protobuf, persist::PersistError, persist::StateDir, push::AccountFeed, Action, ClientId,
+    MultiThreadedEngine, State, UpdateError,
+};
+
+use crate::config::{Config, ConfigError};
+
+#[derive(Debug, Parser)]
+#[command(about = "Serve a transaction engine over HTTP")]
+struct Args {
+    /// The TOML config file
+    config: PathBuf,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ServerError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Persist(#[from] PersistError),
+
+    #[error("server failed: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// What every request handler shares
+struct Server {
+    engine: MultiThreadedEngine,
+    feed: AccountFeed,
+    dir: Option<StateDir>,
+    metrics: Metrics,
+    /// Set once the server is shutting down, to end event streams (which
+    /// would otherwise hold the shutdown up forever)
+    stopping: watch::Sender<bool>,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    applied: AtomicU64,
+    rejected: AtomicU64,
+    failed: AtomicU64,
+    checkpoints: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = async {
+        let config = Config::read(&args.config)?;
+        let server = Arc::new(Server::start(&config)?);
+        let listener = TcpListener::bind(config.listen).await?;
+        eprintln!("listening on {}", listener.local_addr()?);
+        server
+            .run(listener, config.checkpoint_interval(), shutdown_signal())
+            .await
+    }
+    .await;
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+impl Server {
+    /// Restore the state directory (if there is one) and set the engine up
+    /// as configured
+    fn start(config: &Config) -> Result<Self, ServerError> {
+        let dir = config.state_dir.as_ref().map(StateDir::open).transpose()?;
+        let (mut state, journal) = match &dir {
+            Some(dir) => {
+                let (state, journal) = dir.restore()?;
+                (state, Some(journal))
+            }
+            None => (State::new(), None),
+        };
+        config.engine.configure(&mut state);
+        let feed = AccountFeed::new(config.feed_capacity);
+        state.set_observer(feed.clone());
+
+        let mut engine = MultiThreadedEngine::from_state(state);
+        if let Some(journal) = journal {
+            engine = engine.with_journal(journal);
+        }
+        if let Some(group_commit) = config.group_commit() {
+            engine = engine.with_group_commit(group_commit);
+        }
+        Ok(Self {
+            engine,
+            feed,
+            dir,
+            metrics: Metrics::default(),
+            stopping: watch::Sender::new(false),
+        })
+    }
+
+    /// Serve requests until `shutdown` completes, then checkpoint
+    async fn run(
+        self: Arc<Self>,
+        listener: TcpListener,
+        checkpoint_interval: Option<Duration>,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(), ServerError> {
+        if let Some(interval) = checkpoint_interval {
+            tokio::spawn(self.clone().checkpoint_every(interval));
+        }
+        let server = self.clone();
+        axum::serve(listener, router(self.clone()))
+            .with_graceful_shutdown(async move {
+                shutdown.await;
+                server.stopping.send_replace(true);
+            })
+            .await?;
+
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || server.checkpoint())
+            .await
+            .expect("checkpoint panicked")?;
+        Ok(())
+    }
+
+    async fn checkpoint_every(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        // The first tick is immediate, and there's nothing to checkpoint yet
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let server = self.clone();
+            let result = tokio::task::spawn_blocking(move || server.checkpoint()).await;
+            if let Ok(Err(e)) = result {
+                // The journal still has everything, so the next one can
+                // catch up
+                eprintln!("checkpoint failed: {e}");
+            }
+        }
+    }
+
+    /// Flush the journal, and snapshot the state if there's a directory
+    fn checkpoint(&self) -> Result<(), PersistError> {
+        self.engine.flush_journal()?;
+        if let Some(dir) = &self.dir {
+            self.engine.checkpoint(dir)?;
+            self.metrics.checkpoints.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+fn router(server: Arc<Server>) -> Router {
+    Router::new()
+        .route("/actions", post(submit))
+        .route("/accounts", get(accounts))
+        .route("/accounts/{client}", get(account))
+        .route("/events", get(events))
+        .route("/metrics", get(metrics))
+        .route("/health", get(|| async { "ok" }))
+        .with_state(server)
+}
+
+async fn submit(
+    Extract(server): Extract<Arc<Server>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let action = match decode_action(&headers, &body) {
+        Ok(action) => action,
+        Err(error) => {
+            server.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return rejection(StatusCode::UNPROCESSABLE_ENTITY, error);
+        }
+    };
+    let client = action.client_id;
+    // The engine blocks on its lock, and on the journal with group commit
+    let engine = server.engine.clone();
+    let result = tokio::task::spawn_blocking(move || engine.try_process(action))
+        .await
+        .expect("processing panicked");
+    match result {
+        Ok(()) => {
+            server.metrics.applied.fetch_add(1, Ordering::Relaxed);
+            let account = server
+                .engine
+                .state()
+                .read()
+                .expect("poisoned!")
+                .account(client);
+            Json(account).into_response()
+        }
+        Err(e @ (UpdateError::Store(_) | UpdateError::Journal(_))) => {
+            server.metrics.failed.fetch_add(1, Ordering::Relaxed);
+            rejection(StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+        }
+        Err(e) => {
+            server.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            rejection(StatusCode::UNPROCESSABLE_ENTITY, e.to_string())
+        }
+    }
+}
+
+fn decode_action(headers: &HeaderMap, body: &[u8]) -> Result<Action, String> {
+    let protobuf = headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/x-protobuf");
+    if protobuf {
+        let action = protobuf::Action::decode(body).map_err(|e| e.to_string())?;
+        Action::try_from(action).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    }
+}
+
+fn rejection(status: StatusCode, error: String) -> Response {
+    (status, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+async fn accounts(Extract(server): Extract<Arc<Server>>) -> Response {
+    let state = server.engine.state();
+    let accounts: Vec<_> = state.read().expect("poisoned!").accounts().collect();
+    Json(accounts).into_response()
+}
+
+async fn account(Extract(server): Extract<Arc<Server>>, Path(client): Path<ClientId>) -> Response {
+    match server
+        .engine
+        .state()
+        .read()
+        .expect("poisoned!")
+        .account(client)
+    {
+        Some(account) => Json(account).into_response(),
+        None => rejection(
+            StatusCode::NOT_FOUND,
+            format!("no account for client {client}"),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    client: Option<ClientId>,
+}
+
+async fn events(
+    Extract(server): Extract<Arc<Server>>,
+    Query(query): Query<EventsQuery>,
+) -> Response {
+    let mut subscription = server.feed.subscribe();
+    if let Some(client) = query.client {
+        subscription = subscription.only(client);
+    }
+    let stopping = server.stopping.subscribe();
+    let stream = futures_util::stream::unfold(
+        (subscription, stopping),
+        |(mut subscription, mut stopping)| async move {
+            let update = tokio::select! {
+                update = subscription.next() => update?,
+                _ = stopping.wait_for(|stopping| *stopping) => return None,
+            };
+            let frame = Ok::<_, std::convert::Infallible>(update.to_sse());
+            Some((frame, (subscription, stopping)))
+        },
+    );
+    (
+        [
+            (header::CONTENT_TYPE, "text/event-stream"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+async fn metrics(Extract(server): Extract<Arc<Server>>) -> Response {
+    let metrics = &server.metrics;
+    let accounts = server
+        .engine
+        .state()
+        .read()
+        .expect("poisoned!")
+        .accounts()
+        .len();
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+        for (labels, value) in samples {
+            out.push_str(&format!("{name}{labels} {value}\n"));
+        }
+    };
+    metric(
+        "engine_actions_total",
+        "counter",
+        "Actions submitted, by what became of them",
+        &[
+            (
+                r#"{result="applied"}"#,
+                metrics.applied.load(Ordering::Relaxed),
+            ),
+            (
+                r#"{result="rejected"}"#,
+                metrics.rejected.load(Ordering::Relaxed),
+            ),
+            (
+                r#"{result="failed"}"#,
+                metrics.failed.load(Ordering::Relaxed),
+            ),
+        ],
+    );
+    metric(
+        "engine_accounts",
+        "gauge",
+        "Accounts opened",
+        &[("", accounts as u64)],
+    );
+    metric(
+        "engine_event_subscribers",
+        "gauge",
+        "Open event streams",
+        &[("", server.feed.subscribers() as u64)],
+    );
+    metric(
+        "engine_checkpoints_total",
+        "counter",
+        "Checkpoints of the state directory",
+        &[("", metrics.checkpoints.load(Ordering::Relaxed))],
+    );
+    if let Some(syncs) = server.engine.group_commit_syncs() {
+        metric(
+            "engine_journal_syncs_total",
+            "counter",
+            "Journal syncs made by group commit",
+            &[("", syncs)],
+        );
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
+
+/// Wait for SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("couldn't watch for SIGTERM");
+        tokio::select! {
+            _ = interrupt => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = interrupt.await;
+    eprintln!("shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    /// Send one request and read the whole response
+    fn request(addr: std::net::SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    async fn serve(
+        config: &Config,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::sync::oneshot::Sender<()>,
+        tokio::task::JoinHandle<Result<(), ServerError>>,
+    ) {
+        let server = Arc::new(Server::start(config).unwrap());
+        let listener = TcpListener::bind(config.listen).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        let running = tokio::spawn(server.run(listener, None, async {
+            let _ = stopped.await;
+        }));
+        (addr, stop, running)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serves_and_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = format!(
+            "listen = \"127.0.0.1:0\"\nstate_dir = {:?}\n\n[group_commit]\nmax_actions = 8\nmax_delay_ms = 1\n",
+            dir.path()
+        )
+        .parse()
+        .unwrap();
+
+        let (addr, stop, running) = serve(&config).await;
+        let responses = tokio::task::spawn_blocking(move || {
+            let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+            let overdraw = r#"{"type":"withdrawal","client":1,"tx":2,"amount":"10"}"#;
+            [
+                request(addr, "POST", "/actions", deposit),
+                request(addr, "POST", "/actions", overdraw),
+                request(addr, "POST", "/actions", "not json"),
+                request(addr, "GET", "/accounts/1", ""),
+                request(addr, "GET", "/accounts/2", ""),
+                request(addr, "GET", "/metrics", ""),
+            ]
+        })
+        .await
+        .unwrap();
+        assert!(responses[0].starts_with("HTTP/1.1 200"), "{}", responses[0]);
+        // Amounts are written as strings with the crypto feature
+        let amount = if cfg!(feature = "crypto") {
+            r#""2.5""#
+        } else {
+            "2.5"
+        };
+        assert!(
+            responses[0].contains(&format!(r#""available":{amount}"#)),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[1].starts_with("HTTP/1.1 422"));
+        assert!(responses[2].starts_with("HTTP/1.1 422"));
+        assert!(responses[3].contains(&format!(r#""total":{amount}"#)));
+        assert!(responses[4].starts_with("HTTP/1.1 404"));
+        assert!(responses[5].contains(r#"engine_actions_total{result="applied"} 1"#));
+        assert!(responses[5].contains(r#"engine_actions_total{result="rejected"} 2"#));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+
+        // Shutting down checkpointed everything, so a new server carries on
+        let (addr, stop, running) = serve(&config).await;
+        let account = tokio::task::spawn_blocking(move || request(addr, "GET", "/accounts/1", ""))
+            .await
+            .unwrap();
+        assert!(account.contains(&format!(r#""total":{amount}"#)));
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+#ifndef TRANSACTION_ENGINE_H
+#define TRANSACTION_ENGINE_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The size of each amount in a `TeAccount`, including its NUL
+ */
+#define TE_AMOUNT_LEN 64
+
+typedef enum TeStatus {
+  TE_STATUS_OK = 0,
+  /**
+   * The engine refused the action, see `te_engine_last_error`
+   */
+  TE_STATUS_REJECTED = 1,
+  /**
+   * The client has no account
+   */
+  TE_STATUS_NOT_FOUND = 2,
+  /**
+   * A pointer was null, or the action couldn't be read (e.g. an id out of
+   * range or an invalid amount)
+   */
+  TE_STATUS_INVALID_ARGUMENT = -1,
+  /**
+   * The engine's storage failed
+   */
+  TE_STATUS_STORE_ERROR = -2,
+} TeStatus;
+
+typedef enum TeActionKind {
+  TE_ACTION_KIND_DEPOSIT = 0,
+  TE_ACTION_KIND_WITHDRAWAL = 1,
+  TE_ACTION_KIND_DISPUTE = 2,
+  TE_ACTION_KIND_RESOLVE = 3,
+  TE_ACTION_KIND_CHARGEBACK = 4,
+  TE_ACTION_KIND_REVERSAL = 5,
+} TeActionKind;
+
+/**
+ * An engine, from `te_engine_new`
+ */
+typedef struct TeEngine TeEngine;
+
+/**
+ * An action to submit, as a row of the csv input
+ */
+typedef struct TeAction {
+  /**
+   * One of the `TeActionKind` values (anything else is undefined
+   * behaviour, as for any Rust enum)
+   */
+  enum TeActionKind kind;
+  uint32_t client;
+  uint64_t tx;
+  /**
+   * A NUL terminated decimal string, or null for actions without an
+   * amount
+   */
+  const char *amount;
+  /**
+   * The transaction a reversal undoes, ignored for other actions
+   */
+  uint64_t reverses;
+} TeAction;
+
+/**
+ * A client's account, as `te_engine_account` fills it in
+ */
+typedef struct TeAccount {
+  uint32_t client;
+  /**
+   * NUL terminated decimal strings, rounded as in the csv output
+   */
+  char available[TE_AMOUNT_LEN];
+  char held[TE_AMOUNT_LEN];
+  char total[TE_AMOUNT_LEN];
+  bool locked;
+  /**
+   * See `Account::version`
+   */
+  uint64_t version;
+} TeAccount;
+
+
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create an engine, to be freed with `te_engine_free`
+ */
+struct TeEngine *te_engine_new(void);
+
+/**
+ * Free an engine from `te_engine_new`. Does nothing if it's null.
+ *
+ * # Safety
+ *
+ * `engine` is null or from `te_engine_new`, and isn't used again
+ */
+void te_engine_free(struct TeEngine *engine);
+
+/**
+ * Apply an action to the engine
+ *
+ * # Safety
+ *
+ * `engine` is from `te_engine_new`, and `action` points to a `TeAction`
+ * whose `amount` is null or NUL terminated
+ */
+enum TeStatus te_engine_submit(struct TeEngine *engine, const struct TeAction *action);
+
+/**
+ * Fill in `out` with a client's account, or give `TE_STATUS_NOT_FOUND` if
+ * it doesn't have one
+ *
+ * # Safety
+ *
+ * `engine` is from `te_engine_new`, and `out` points to a `TeAccount`
+ */
+enum TeStatus te_engine_account(struct TeEngine *engine, uint32_t client, struct TeAccount *out);
+
+/**
+ * The message of the last call's error, or null if it succeeded. Valid
+ * until the next call on the engine.
+ *
+ * # Safety
+ *
+ * `engine` is from `te_engine_new`
+ */
+const char *te_engine_last_error(const struct TeEngine *engine);
+
+/**
+ * The code of the last call's error (e.g. `insufficient_funds`), or null
+ * if it succeeded. Valid until the next call on the engine.
+ *
+ * # Safety
+ *
+ * `engine` is from `te_engine_new`
+ */
+const char *te_engine_last_error_code(const struct TeEngine *engine);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TRANSACTION_ENGINE_H */
@@ -1,16 +1,247 @@
-use serde::Serialize;
+use std::{cmp::Ordering, collections::BTreeMap, fmt};
 
-use crate::{Amount, ClientId};
+use serde::{Deserialize, Serialize, Serializer};
 
-#[derive(Debug, Default)]
+use crate::{
+    persist::{exact_amount, exact_amounts},
+    Amount, ClientId, Timestamp, TransactionId,
+};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredAccount")]
 pub struct Account {
+    #[serde(with = "exact_amount")]
+    available: Amount,
+    #[serde(with = "exact_amount")]
+    held: Amount,
+
+    /// The funds held for each open dispute and prepared withdrawal. These
+    /// add up to `held`, apart from anything held before holds were tracked.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        with = "exact_amounts"
+    )]
+    holds: BTreeMap<HoldId, Amount>,
+
+    status: AccountStatus,
+
+    /// Under investigation. Money can come in, but not go out.
+    #[serde(default)]
+    quarantined: bool,
+
+    /// Spending caps for withdrawals tagged with a category, by category
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    envelopes: BTreeMap<String, Envelope>,
+
+    /// When a temporary freeze lifts by itself, if the account has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock_expiry: Option<LockExpiry>,
+
+    /// Bumped every time the account is written, see `Account::version`
+    version: u64,
+}
+
+/// A hold on some of an account's funds, identified by the disputed deposit
+/// or prepared withdrawal it was placed for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HoldId(pub TransactionId);
+
+impl From<TransactionId> for HoldId {
+    fn from(transaction: TransactionId) -> Self {
+        Self(transaction)
+    }
+}
+
+impl fmt::Display for HoldId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::str::FromStr for HoldId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        id.parse().map(Self)
+    }
+}
+
+/// When a temporary freeze (from `Account::freeze_until`) runs out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockExpiry {
+    /// With the first action on the account at or after this time (going by
+    /// its timestamp, or the system clock if it has none)
+    At(Timestamp),
+
+    /// Once this many more actions on the account have been refused
+    AfterActions(u64),
+}
+
+/// A cap on how much can be withdrawn under one category, and how much has
+/// been so far
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(with = "exact_amount")]
+    pub cap: Amount,
+    #[serde(with = "exact_amount")]
+    pub spent: Amount,
+}
+
+impl Envelope {
+    /// How much more can be withdrawn under the envelope
+    pub fn remaining(&self) -> Amount {
+        self.cap - self.spent
+    }
+}
+
+/// Where an account is in its lifecycle
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    /// Open for all transactions
+    #[default]
+    Active,
+
+    /// Nothing can be done with the account until it's unfrozen
+    Frozen { reason: FreezeReason },
+
+    /// Unused for a while. Withdrawals are refused, but a deposit makes the
+    /// account active again.
+    Dormant,
+
+    /// Permanently closed, nothing can be done with the account
+    Closed,
+}
+
+impl AccountStatus {
+    /// The name of the status, without any details
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Frozen { .. } => "frozen",
+            Self::Dormant => "dormant",
+            Self::Closed => "closed",
+        }
+    }
+
+    /// Rebuild a status from its name and (for frozen accounts) the freeze
+    /// reason, as they're written out in `AccountData`
+    pub fn from_parts(name: &str, freeze_reason: Option<&str>) -> Option<Self> {
+        Some(match name {
+            "active" => Self::Active,
+            "frozen" => Self::Frozen {
+                reason: FreezeReason::parse(freeze_reason.unwrap_or("")),
+            },
+            "dormant" => Self::Dormant,
+            "closed" => Self::Closed,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Why an account was frozen
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FreezeReason {
+    /// Frozen automatically when one of its transactions was charged back
+    Chargeback,
+
+    /// Frozen by an operator, with a note explaining why
+    Manual(String),
+}
+
+impl FreezeReason {
+    /// The inverse of `Display`. Anything that isn't a chargeback is taken as
+    /// a manual note.
+    pub fn parse(reason: &str) -> Self {
+        match reason {
+            "chargeback" => Self::Chargeback,
+            _ => Self::Manual(reason.strip_prefix("manual: ").unwrap_or(reason).into()),
+        }
+    }
+}
+
+impl fmt::Display for FreezeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Chargeback => f.write_str("chargeback"),
+            Self::Manual(note) => write!(f, "manual: {}", note),
+        }
+    }
+}
+
+/// The persisted form of an account. Snapshots from before account statuses
+/// existed only have a `locked` flag, which was only ever set by chargebacks.
+#[derive(Deserialize)]
+struct StoredAccount {
+    #[serde(with = "exact_amount")]
     available: Amount,
+    #[serde(with = "exact_amount")]
     held: Amount,
+    #[serde(default, with = "exact_amounts")]
+    holds: BTreeMap<HoldId, Amount>,
 
+    #[serde(default)]
+    status: Option<AccountStatus>,
+    #[serde(default)]
     locked: bool,
+    #[serde(default)]
+    quarantined: bool,
+    #[serde(default)]
+    envelopes: BTreeMap<String, Envelope>,
+    #[serde(default)]
+    lock_expiry: Option<LockExpiry>,
+    #[serde(default)]
+    version: u64,
+}
+
+impl From<StoredAccount> for Account {
+    fn from(stored: StoredAccount) -> Self {
+        let status = match stored.status {
+            Some(status) => status,
+            None if stored.locked => AccountStatus::Frozen {
+                reason: FreezeReason::Chargeback,
+            },
+            None => AccountStatus::Active,
+        };
+        Self {
+            available: stored.available,
+            held: stored.held,
+            holds: stored.holds,
+            status,
+            quarantined: stored.quarantined,
+            envelopes: stored.envelopes,
+            lock_expiry: stored.lock_expiry,
+            version: stored.version,
+        }
+    }
 }
 
 impl Account {
+    /// An account with the given balances and status, without going through
+    /// the transitions that would normally get it there
+    pub(crate) fn with_balances(available: Amount, held: Amount, status: AccountStatus) -> Self {
+        Self {
+            available,
+            held,
+            holds: BTreeMap::new(),
+            status,
+            quarantined: false,
+            envelopes: BTreeMap::new(),
+            lock_expiry: None,
+            version: 0,
+        }
+    }
+
     /// Get the amount of available funds in the account
     pub fn available_funds(&self) -> Amount {
         self.available
@@ -26,23 +257,59 @@ impl Account {
         self.available + self.held
     }
 
-    /// Check if the account is locked or frozen
+    pub fn status(&self) -> &AccountStatus {
+        &self.status
+    }
+
+    /// How many times the account has been written, starting from 1 when
+    /// it's opened. Any change to it (an action, a status change, a rollback)
+    /// bumps it, so an external tool can tell whether the account is still
+    /// as it last read it (see `State::change_status_at`). Versions only
+    /// go up, but say nothing about how many actions there were: a
+    /// `bulk_load` writes each account once per batch.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    /// Check if the account is frozen or closed
     pub fn is_locked(&self) -> bool {
-        self.locked
+        matches!(
+            self.status,
+            AccountStatus::Frozen { .. } | AccountStatus::Closed
+        )
     }
 
-    /// Deposit an amount into the account, if it isn't locked
+    /// Check if withdrawals are blocked while the account is investigated
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined
+    }
+
+    /// Fail if the account is frozen or closed, saying which operation a
+    /// frozen one refused
+    fn check_open(&self, operation: LockedOperation) -> Result<(), AccountError> {
+        match self.status {
+            AccountStatus::Frozen { .. } => Err(AccountError::Locked(operation)),
+            AccountStatus::Closed => Err(AccountError::Closed),
+            AccountStatus::Active | AccountStatus::Dormant => Ok(()),
+        }
+    }
+
+    /// Deposit an amount into the account, if it isn't locked. Depositing
+    /// into a dormant account reactivates it.
     ///
     /// Deposit amounts must be positive
     pub fn deposit(&mut self, amount: Amount) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::Locked);
-        }
+        self.check_open(LockedOperation::Deposit)?;
 
         if amount.is_sign_negative() {
             return Err(AccountError::NegativeAmount);
         }
         self.available += amount;
+        self.status = AccountStatus::Active;
         Ok(())
     }
 
@@ -51,8 +318,12 @@ impl Account {
     ///
     /// Withdrawal amounts must be positive
     pub fn withdraw(&mut self, amount: Amount) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::Locked);
+        self.check_open(LockedOperation::Withdrawal)?;
+        if self.status == AccountStatus::Dormant {
+            return Err(AccountError::Dormant);
+        }
+        if self.quarantined {
+            return Err(AccountError::Quarantined);
         }
         if amount.is_sign_negative() {
             return Err(AccountError::NegativeAmount);
@@ -64,129 +335,779 @@ impl Account {
         Ok(())
     }
 
-    /// Add a hold on some funds from the account, if the funds are available
-    /// and the account isn't locked.
-    ///
-    /// Held amounts must be positive
-    pub fn hold(&mut self, amount: Amount) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::Locked);
+    /// Withdraw an amount tagged with a spending category. If the account
+    /// has an envelope for the category, the withdrawal has to fit in what's
+    /// left of it, and is counted against it. Categories without an envelope
+    /// aren't capped.
+    pub fn withdraw_from(&mut self, category: &str, amount: Amount) -> Result<(), AccountError> {
+        self.check_envelope(category, amount)?;
+        self.withdraw(amount)?;
+        self.spend_envelope(category, amount);
+        Ok(())
+    }
+
+    /// Fail if a withdrawal wouldn't fit in what's left of its envelope
+    pub(crate) fn check_envelope(
+        &self,
+        category: &str,
+        amount: Amount,
+    ) -> Result<(), AccountError> {
+        match self.envelopes.get(category) {
+            Some(envelope) if amount > envelope.remaining() => Err(AccountError::EnvelopeExceeded),
+            _ => Ok(()),
         }
-        if amount.is_sign_negative() {
-            return Err(AccountError::NegativeAmount);
+    }
+
+    /// Count a withdrawal against its envelope, if the category has one
+    pub(crate) fn spend_envelope(&mut self, category: &str, amount: Amount) {
+        if let Some(envelope) = self.envelopes.get_mut(category) {
+            envelope.spent += amount;
+        }
+    }
+
+    /// Take a withdrawal back off its envelope (e.g. for an aborted
+    /// prepared withdrawal)
+    pub(crate) fn refund_envelope(&mut self, category: &str, amount: Amount) {
+        if let Some(envelope) = self.envelopes.get_mut(category) {
+            envelope.spent -= amount;
+        }
+    }
+
+    pub fn envelopes(&self) -> &BTreeMap<String, Envelope> {
+        &self.envelopes
+    }
+
+    /// Cap withdrawals in a category at `cap`, keeping what's been spent so
+    /// far if the envelope already exists, or remove the envelope with `None`
+    pub fn set_envelope(&mut self, category: &str, cap: Option<Amount>) {
+        match cap {
+            Some(cap) => self.envelopes.entry(category.into()).or_default().cap = cap,
+            None => {
+                self.envelopes.remove(category);
+            }
         }
+    }
+
+    /// Start every envelope afresh (e.g. at the start of a budgeting period)
+    pub fn reset_envelopes(&mut self) {
+        for envelope in self.envelopes.values_mut() {
+            envelope.spent = Amount::default();
+        }
+    }
+
+    /// Take back the (signed) amount of an earlier transaction: a deposit's
+    /// funds are removed, a withdrawal's are returned.
+    ///
+    /// This is a correction rather than the client moving money, so it works
+    /// on dormant or quarantined accounts, but not on locked ones. A deposit
+    /// can only be reversed if its funds are still available.
+    pub fn reverse(&mut self, amount: Amount) -> Result<(), AccountError> {
+        self.check_open(LockedOperation::Reversal)?;
         if amount > self.available {
             return Err(AccountError::InsufficientFunds);
         }
         self.available -= amount;
-        self.held += amount;
         Ok(())
     }
 
-    /// Release held funds in the account, if the funds are available and the
-    /// account isn't locked.
+    /// Move the difference made by correcting a settled transaction's amount
+    /// into or out of the available balance. As with a reversal, this works
+    /// on dormant or quarantined accounts but not on locked ones, and can't
+    /// take away more than is available.
+    pub(crate) fn restate(&mut self, difference: Amount) -> Result<(), AccountError> {
+        self.check_open(LockedOperation::Restatement)?;
+        if difference.is_sign_negative() && -difference > self.available {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.available += difference;
+        Ok(())
+    }
+
+    /// Hold some funds from the account under `id`, if the funds are
+    /// available and the account isn't locked.
     ///
-    /// Release amounts must be positive
-    pub fn release(&mut self, amount: Amount) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::Locked);
+    /// Held amounts must be positive
+    pub fn hold(&mut self, id: HoldId, amount: Amount) -> Result<(), AccountError> {
+        if amount > self.available {
+            self.check_hold(id, amount)?;
+            return Err(AccountError::InsufficientFunds);
         }
+        self.hold_allowing_negative(id, amount)
+    }
+
+    /// Hold funds even if that takes the available balance negative, for
+    /// `ChargebackPolicy::AllowNegative`. The account still has to be open.
+    pub(crate) fn hold_allowing_negative(
+        &mut self,
+        id: HoldId,
+        amount: Amount,
+    ) -> Result<(), AccountError> {
+        self.check_hold(id, amount)?;
+        self.available -= amount;
+        self.held += amount;
+        self.holds.insert(id, amount);
+        Ok(())
+    }
+
+    fn check_hold(&self, id: HoldId, amount: Amount) -> Result<(), AccountError> {
+        self.check_open(LockedOperation::Hold)?;
         if amount.is_sign_negative() {
             return Err(AccountError::NegativeAmount);
         }
-        if amount > self.held {
-            return Err(AccountError::InsufficientFunds);
+        if self.holds.contains_key(&id) {
+            return Err(AccountError::HoldExists(id));
         }
-        self.held -= amount;
+        Ok(())
+    }
+
+    /// Return the funds under a hold to the available balance, if the
+    /// account isn't locked, returning how much was held
+    pub fn release(&mut self, id: HoldId) -> Result<Amount, AccountError> {
+        self.check_open(LockedOperation::Release)?;
+        let amount = self.take_hold(id)?;
         self.available += amount;
+        Ok(amount)
+    }
+
+    /// Clear the funds under a hold from the account, but do not return them
+    /// to the account's available funds. Returns how much was held.
+    pub fn chargeback(&mut self, id: HoldId) -> Result<Amount, AccountError> {
+        self.check_open(LockedOperation::Chargeback)?;
+        self.take_hold(id)
+    }
+
+    /// Take the funds reserved for a prepared withdrawal out of the held
+    /// balance. The checks were made when they were held, so this can't fail.
+    pub(crate) fn settle_hold(&mut self, id: HoldId) {
+        let _ = self.take_hold(id);
+    }
+
+    /// Return funds reserved for an aborted withdrawal to the available
+    /// balance
+    pub(crate) fn return_hold(&mut self, id: HoldId) {
+        if let Ok(amount) = self.take_hold(id) {
+            self.available += amount;
+        }
+    }
+
+    fn take_hold(&mut self, id: HoldId) -> Result<Amount, AccountError> {
+        let amount = self
+            .holds
+            .remove(&id)
+            .ok_or(AccountError::HoldMissing(id))?;
+        self.held -= amount;
+        Ok(amount)
+    }
+
+    /// The funds held for each open dispute and prepared withdrawal
+    pub fn holds(&self) -> &BTreeMap<HoldId, Amount> {
+        &self.holds
+    }
+
+    /// Put `amount` of the held funds that aren't under any hold (held
+    /// before holds were tracked, e.g. in an older snapshot) under `id`.
+    /// Does nothing if the hold already exists, or there isn't enough.
+    pub(crate) fn adopt_hold(&mut self, id: HoldId, amount: Amount) {
+        let attributed: Amount = self.holds.values().copied().sum();
+        if !self.holds.contains_key(&id) && amount <= self.held - attributed {
+            self.holds.insert(id, amount);
+        }
+    }
+
+    /// Freeze an active or dormant account
+    pub fn freeze(&mut self, reason: FreezeReason) -> Result<(), StatusError> {
+        match self.status {
+            AccountStatus::Active | AccountStatus::Dormant => {
+                self.status = AccountStatus::Frozen { reason };
+                Ok(())
+            }
+            _ => Err(self.invalid_transition("frozen")),
+        }
+    }
+
+    /// Freeze an active or dormant account until `expiry`, after which the
+    /// engine unfreezes it without anyone having to
+    pub fn freeze_until(
+        &mut self,
+        reason: FreezeReason,
+        expiry: LockExpiry,
+    ) -> Result<(), StatusError> {
+        self.freeze(reason)?;
+        self.lock_expiry = Some(expiry);
         Ok(())
     }
 
-    /// Clear held funds from the account, but do not return them to the
-    /// account's available funds.
-    pub fn chargeback(&mut self, amount: Amount) -> Result<(), AccountError> {
-        if self.locked {
-            return Err(AccountError::Locked);
+    /// When the account's freeze lifts by itself, if it's temporary
+    pub fn lock_expiry(&self) -> Option<LockExpiry> {
+        self.lock_expiry
+    }
+
+    /// Count an action against a temporary freeze, unfreezing the account
+    /// (and returning true) if it has run out
+    pub(crate) fn tick_lock(&mut self, at: Timestamp) -> bool {
+        match self.lock_expiry {
+            Some(LockExpiry::At(until)) if at >= until => {}
+            Some(LockExpiry::AfterActions(0)) => {}
+            Some(LockExpiry::AfterActions(left)) => {
+                self.lock_expiry = Some(LockExpiry::AfterActions(left - 1));
+                return false;
+            }
+            _ => return false,
         }
-        if amount.is_sign_negative() {
-            return Err(AccountError::NegativeAmount);
+        self.lock_expiry = None;
+        self.unfreeze().is_ok()
+    }
+
+    /// Make a frozen account active again
+    pub fn unfreeze(&mut self) -> Result<(), StatusError> {
+        match self.status {
+            AccountStatus::Frozen { .. } => {
+                self.status = AccountStatus::Active;
+                self.lock_expiry = None;
+                Ok(())
+            }
+            _ => Err(self.invalid_transition("active")),
         }
-        if amount > self.held {
-            return Err(AccountError::InsufficientFunds);
+    }
+
+    /// Mark an active account as dormant
+    pub fn mark_dormant(&mut self) -> Result<(), StatusError> {
+        match self.status {
+            AccountStatus::Active => {
+                self.status = AccountStatus::Dormant;
+                Ok(())
+            }
+            _ => Err(self.invalid_transition("dormant")),
         }
-        self.held -= amount;
+    }
+
+    /// Close an active or dormant account. Its funds must have been paid out
+    /// first.
+    pub fn close(&mut self) -> Result<(), StatusError> {
+        match self.status {
+            AccountStatus::Active | AccountStatus::Dormant => {
+                if self.available != Amount::default() || self.held != Amount::default() {
+                    return Err(StatusError::NonZeroBalance);
+                }
+                self.status = AccountStatus::Closed;
+                Ok(())
+            }
+            _ => Err(self.invalid_transition("closed")),
+        }
+    }
+
+    /// Block withdrawals while the account is investigated. Deposits and
+    /// disputes still go through, and the account's status is unaffected
+    /// (e.g. it can still be frozen if the investigation finds something).
+    ///
+    /// Quarantining an already quarantined account does nothing.
+    pub fn quarantine(&mut self) -> Result<(), StatusError> {
+        if self.status == AccountStatus::Closed {
+            return Err(self.invalid_transition("quarantined"));
+        }
+        self.quarantined = true;
         Ok(())
     }
 
-    /// Lock an account
-    pub fn lock(&mut self) {
-        self.locked = true;
+    /// Allow withdrawals again after a quarantine
+    pub fn lift_quarantine(&mut self) {
+        self.quarantined = false;
     }
 
-    /// Unlock an account
-    pub fn unlock(&mut self) {
-        self.locked = false;
+    fn invalid_transition(&self, to: &'static str) -> StatusError {
+        StatusError::InvalidTransition {
+            from: self.status.name(),
+            to,
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[serde(from = "StoredAccountError")]
 pub enum AccountError {
-    #[error("the account is locked")]
-    Locked,
+    /// The account is frozen, and refused the operation
+    #[error("the account is locked, so the {0} was refused")]
+    Locked(LockedOperation),
+
+    #[error("the account is closed")]
+    Closed,
+
+    #[error("the account is dormant")]
+    Dormant,
+
+    #[error("the account is quarantined")]
+    Quarantined,
 
     #[error("there are not enough funds to withdraw")]
     InsufficientFunds,
 
     #[error("cannot deposit or withdraw a negative amount")]
     NegativeAmount,
+
+    #[error("the withdrawal is more than is left in its envelope")]
+    EnvelopeExceeded,
+
+    #[error("there is already a hold {0} on the account")]
+    HoldExists(HoldId),
+
+    #[error("there is no hold {0} on the account")]
+    HoldMissing(HoldId),
+}
+
+impl AccountError {
+    /// A short name for the error that won't change, for machine-readable
+    /// reports
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Locked(_) => "account_locked",
+            Self::Closed => "account_closed",
+            Self::Dormant => "account_dormant",
+            Self::Quarantined => "account_quarantined",
+            Self::InsufficientFunds => "insufficient_funds",
+            Self::NegativeAmount => "negative_amount",
+            Self::EnvelopeExceeded => "envelope_exceeded",
+            Self::HoldExists(_) => "hold_exists",
+            Self::HoldMissing(_) => "hold_missing",
+        }
+    }
+}
+
+/// What a locked account refused to do, so a frozen account still being sent
+/// deposits can be told apart from one blocking a payout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedOperation {
+    Deposit,
+    Withdrawal,
+    /// Holding funds for a dispute or a prepared withdrawal
+    Hold,
+    /// Releasing held funds when a dispute is resolved
+    Release,
+    Chargeback,
+    Reversal,
+    Restatement,
+    /// Refused before the operation was recorded (in an older snapshot)
+    Unrecorded,
+}
+
+impl fmt::Display for LockedOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Hold => "hold",
+            Self::Release => "release",
+            Self::Chargeback => "chargeback",
+            Self::Reversal => "reversal",
+            Self::Restatement => "restatement",
+            Self::Unrecorded => "operation",
+        })
+    }
+}
+
+/// The persisted form of an `AccountError`. Snapshots from before locked
+/// accounts said what they refused have a bare `Locked`.
+#[derive(Deserialize)]
+enum StoredAccountError {
+    Locked(LockedOperation),
+    Closed,
+    Dormant,
+    Quarantined,
+    InsufficientFunds,
+    NegativeAmount,
+    EnvelopeExceeded,
+    HoldExists(HoldId),
+    HoldMissing(HoldId),
+    #[serde(untagged)]
+    Legacy(LegacyAccountError),
+}
+
+#[derive(Deserialize)]
+enum LegacyAccountError {
+    Locked,
+}
+
+impl From<StoredAccountError> for AccountError {
+    fn from(stored: StoredAccountError) -> Self {
+        match stored {
+            StoredAccountError::Locked(operation) => Self::Locked(operation),
+            StoredAccountError::Closed => Self::Closed,
+            StoredAccountError::Dormant => Self::Dormant,
+            StoredAccountError::Quarantined => Self::Quarantined,
+            StoredAccountError::InsufficientFunds => Self::InsufficientFunds,
+            StoredAccountError::NegativeAmount => Self::NegativeAmount,
+            StoredAccountError::EnvelopeExceeded => Self::EnvelopeExceeded,
+            StoredAccountError::HoldExists(id) => Self::HoldExists(id),
+            StoredAccountError::HoldMissing(id) => Self::HoldMissing(id),
+            StoredAccountError::Legacy(LegacyAccountError::Locked) => {
+                Self::Locked(LockedOperation::Unrecorded)
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatusError {
+    #[error("an account can't go from {from} to {to}")]
+    InvalidTransition {
+        from: &'static str,
+        to: &'static str,
+    },
+
+    #[error("an account can't be closed while it still holds funds")]
+    NonZeroBalance,
+
+    #[error("account {0} does not exist")]
+    AccountMissing(ClientId),
+
+    /// The account was written since the version the change was based on
+    #[error("account {client} is at version {actual}, not {expected}")]
+    VersionMismatch {
+        client: ClientId,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error(transparent)]
+    Store(#[from] crate::store::StoreError),
 }
 
 /// Serializable account data
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AccountData {
     pub client: ClientId,
     pub available: Amount,
     pub held: Amount,
     pub total: Amount,
     pub locked: bool,
+    pub status: AccountStatus,
+    /// Why the account is frozen, if it is
+    pub freeze_reason: Option<String>,
+    pub quarantined: bool,
+    /// See `Account::version`
+    pub version: u64,
+    /// Which of the fields it's serialized with
+    pub columns: OutputColumns,
 }
 
-#[cfg(feature = "decimal")]
-impl From<(&ClientId, &Account)> for AccountData {
-    fn from((id, account): (&ClientId, &Account)) -> Self {
-        use rust_decimal::prelude::*;
-        let strategy = RoundingStrategy::MidpointAwayFromZero;
-        Self {
-            client: *id,
-            available: account
-                .available_funds()
-                .round_dp_with_strategy(4, strategy)
-                .normalize(),
-
-            held: account
-                .held_funds()
-                .round_dp_with_strategy(4, strategy)
-                .normalize(),
-
-            total: account
-                .total_funds()
-                .round_dp_with_strategy(4, strategy)
-                .normalize(),
+impl Serialize for AccountData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let extended = self.columns == OutputColumns::Extended;
+        AccountRow {
+            client: self.client,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            status: extended.then(|| self.status.name()),
+            freeze_reason: extended.then_some(self.freeze_reason.as_deref()),
+            quarantined: extended.then_some(self.quarantined),
+            version: extended.then_some(self.version),
+        }
+        .serialize(serializer)
+    }
+}
 
-            locked: account.is_locked(),
+/// The serialized form of an `AccountData`, without the extended columns
+/// unless they were asked for
+#[derive(Serialize)]
+#[serde(rename = "AccountData")]
+struct AccountRow<'a> {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    client: ClientId,
+    #[cfg_attr(feature = "crypto", serde(with = "crate::persist::exact_amount"))]
+    available: Amount,
+    #[cfg_attr(feature = "crypto", serde(with = "crate::persist::exact_amount"))]
+    held: Amount,
+    #[cfg_attr(feature = "crypto", serde(with = "crate::persist::exact_amount"))]
+    total: Amount,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freeze_reason: Option<Option<&'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quarantined: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+}
+
+/// Totals over every account, kept up to date as accounts change, from
+/// `State::summary`
+///
+/// Without the `decimal` feature the amounts are running float sums, so they
+/// can drift slightly from a fresh sum over the accounts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct AccountsSummary {
+    /// The number of accounts
+    pub clients: u64,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    /// Frozen or closed accounts
+    pub locked: u64,
+}
+
+impl AccountsSummary {
+    pub(crate) fn add(&mut self, account: &Account) {
+        self.clients += 1;
+        self.available += account.available_funds();
+        self.held += account.held_funds();
+        self.total += account.total_funds();
+        self.locked += u64::from(account.is_locked());
+    }
+
+    pub(crate) fn remove(&mut self, account: &Account) {
+        self.clients -= 1;
+        self.available -= account.available_funds();
+        self.held -= account.held_funds();
+        self.total -= account.total_funds();
+        self.locked -= u64::from(account.is_locked());
+    }
+}
+
+/// Picks out accounts for `State::accounts_where`. Filters can be combined
+/// with `and`, `or` and `not`, e.g.
+/// `AccountFilter::Locked.and(AccountFilter::HeldAbove(limit))` for locked
+/// accounts holding more than `limit`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountFilter {
+    /// Frozen or closed accounts
+    Locked,
+    /// Accounts with more than this held
+    HeldAbove(Amount),
+    /// Accounts with less than this available
+    AvailableBelow(Amount),
+    /// Accounts with a negative available, held or total balance (e.g. after
+    /// a chargeback under `ChargebackPolicy::AllowNegative`)
+    NegativeBalance,
+    /// Accounts with anything in them: a balance that isn't zero
+    NonZero,
+
+    /// Accounts matching every one of the filters
+    All(Vec<AccountFilter>),
+    /// Accounts matching any of the filters
+    Any(Vec<AccountFilter>),
+    Not(Box<AccountFilter>),
+}
+
+impl AccountFilter {
+    pub fn and(self, other: AccountFilter) -> Self {
+        match self {
+            Self::All(mut filters) => {
+                filters.push(other);
+                Self::All(filters)
+            }
+            filter => Self::All(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: AccountFilter) -> Self {
+        match self {
+            Self::Any(mut filters) => {
+                filters.push(other);
+                Self::Any(filters)
+            }
+            filter => Self::Any(vec![filter, other]),
         }
     }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+
+    pub fn matches(&self, account: &AccountData) -> bool {
+        match self {
+            Self::Locked => account.locked,
+            Self::HeldAbove(amount) => account.held > *amount,
+            Self::AvailableBelow(amount) => account.available < *amount,
+            Self::NegativeBalance => {
+                let zero = Amount::default();
+                account.available < zero || account.held < zero || account.total < zero
+            }
+            Self::NonZero => {
+                let zero = Amount::default();
+                account.available != zero || account.held != zero || account.total != zero
+            }
+            Self::All(filters) => filters.iter().all(|filter| filter.matches(account)),
+            Self::Any(filters) => filters.iter().any(|filter| filter.matches(account)),
+            Self::Not(filter) => !filter.matches(account),
+        }
+    }
+}
+
+/// The order `State::accounts_sorted` gives accounts in. Accounts with the
+/// same balance are ordered by client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccountOrder {
+    /// By client id, lowest first
+    #[default]
+    Client,
+    /// By total balance, largest first
+    Total,
+    /// By held balance, largest first
+    Held,
 }
 
+impl AccountOrder {
+    pub fn sort(self, accounts: &mut [AccountData]) {
+        let balance = |account: &AccountData| match self {
+            Self::Client => Amount::default(),
+            Self::Total => account.total,
+            Self::Held => account.held,
+        };
+        accounts.sort_by(|a, b| {
+            balance(b)
+                .partial_cmp(&balance(a))
+                .unwrap_or(Ordering::Equal)
+                .then(a.client.cmp(&b.client))
+        });
+    }
+}
+
+// The csv output can't hold nested values, so the reason gets its own column
+fn freeze_reason(account: &Account) -> Option<String> {
+    match account.status() {
+        AccountStatus::Frozen { reason } => Some(reason.to_string()),
+        _ => None,
+    }
+}
+
+/// Decimal places balances are rounded to in `AccountData` by default
+#[cfg(all(feature = "decimal", not(feature = "crypto")))]
+const OUTPUT_DECIMALS: Option<u32> = Some(4);
+#[cfg(feature = "crypto")]
+const OUTPUT_DECIMALS: Option<u32> = Some(crate::crypto::DECIMALS);
+/// Floats can't hold most rounded decimals exactly anyway
 #[cfg(not(feature = "decimal"))]
-impl From<(&ClientId, &Account)> for AccountData {
-    fn from((id, account): (&ClientId, &Account)) -> Self {
+const OUTPUT_DECIMALS: Option<u32> = None;
+
+/// How balances are rounded in `AccountData`, and which of its columns are
+/// written, set with `State::set_output`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputConfig {
+    /// Decimal places to round to, or `None` to leave balances as they are
+    pub precision: Option<u32>,
+    pub rounding: Rounding,
+    pub columns: OutputColumns,
+}
+
+impl OutputConfig {
+    /// Round balances to `precision` decimal places
+    pub fn new(precision: u32, rounding: Rounding) -> Self {
         Self {
-            client: *id,
-            available: account.available_funds(),
-            held: account.held_funds(),
-            total: account.total_funds(),
+            precision: Some(precision),
+            rounding,
+            columns: OutputColumns::default(),
+        }
+    }
+
+    /// Write `columns` instead of the v1 columns
+    pub fn with_columns(self, columns: OutputColumns) -> Self {
+        Self { columns, ..self }
+    }
+
+    fn round(&self, amount: Amount) -> Amount {
+        match self.precision {
+            Some(decimals) => self.rounding.round(amount, decimals),
+            None => amount,
+        }
+    }
+}
+
+impl Default for OutputConfig {
+    /// 4 decimal places (18 with the `crypto` feature, or unrounded without
+    /// the `decimal` feature), with midpoints away from zero
+    fn default() -> Self {
+        Self {
+            precision: OUTPUT_DECIMALS,
+            rounding: Rounding::default(),
+            columns: OutputColumns::default(),
+        }
+    }
+}
+
+/// The columns an `AccountData` is written with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColumns {
+    /// `client`, `available`, `held`, `total` and `locked`, as the output
+    /// has always had
+    #[default]
+    V1,
+    /// The v1 columns, then `status`, `freeze_reason`, `quarantined` and
+    /// `version`
+    Extended,
+}
+
+/// How amounts are rounded to a number of decimal places
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// To the nearest, with midpoints away from zero (0.125 to 0.13)
+    #[default]
+    MidpointAwayFromZero,
+    /// To the nearest, with midpoints to the even neighbour (0.125 to 0.12),
+    /// also known as banker's rounding
+    MidpointNearestEven,
+    /// Drop the extra places
+    ToZero,
+}
+
+impl Rounding {
+    /// Round an amount to `decimals` decimal places
+    #[cfg(feature = "decimal")]
+    pub fn round(self, amount: Amount, decimals: u32) -> Amount {
+        use rust_decimal::RoundingStrategy;
+        let strategy = match self {
+            Self::MidpointAwayFromZero => RoundingStrategy::MidpointAwayFromZero,
+            Self::MidpointNearestEven => RoundingStrategy::MidpointNearestEven,
+            Self::ToZero => RoundingStrategy::ToZero,
+        };
+        amount
+            .round_dp_with_strategy(decimals, strategy)
+            .normalize()
+    }
+
+    /// Round an amount to `decimals` decimal places
+    #[cfg(not(feature = "decimal"))]
+    pub fn round(self, amount: Amount, decimals: u32) -> Amount {
+        let factor = 10f64.powi(decimals as i32);
+        let scaled = amount * factor;
+        // Floats can't hold most decimals exactly, so an amount that's only a
+        // rounding error away from the last place is taken as being on it
+        let nearest = scaled.round();
+        if (scaled - nearest).abs() <= scaled.abs().max(1.0) * f64::EPSILON {
+            return nearest / factor;
+        }
+        let rounded = match self {
+            Self::MidpointAwayFromZero => nearest,
+            Self::MidpointNearestEven => scaled.round_ties_even(),
+            Self::ToZero => scaled.trunc(),
+        };
+        rounded / factor
+    }
+}
+
+impl AccountData {
+    /// An account's data, with its balances rounded as `output` says
+    pub fn from_with(client: ClientId, account: &Account, output: &OutputConfig) -> Self {
+        Self {
+            client,
+            available: output.round(account.available_funds()),
+            held: output.round(account.held_funds()),
+            total: output.round(account.total_funds()),
             locked: account.is_locked(),
+            status: account.status().clone(),
+            freeze_reason: freeze_reason(account),
+            quarantined: account.is_quarantined(),
+            version: account.version(),
+            columns: output.columns,
         }
     }
 }
+
+impl From<(&ClientId, &Account)> for AccountData {
+    fn from((id, account): (&ClientId, &Account)) -> Self {
+        Self::from_with(*id, account, &OutputConfig::default())
+    }
+}
@@ -1,12 +1,15 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{Amount, ClientId, TransactionId};
+use crate::{Amount, ClientId, Timestamp, TransactionId};
 
 /// An individual input item, representing an action on a transaction
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Action {
-    #[serde(rename = "tx")]
-    pub transaction_id: TransactionId,
+    /// `None` if the `tx` column is empty or missing, which is only allowed
+    /// for actions with a `reference`. The state assigns or looks up the id
+    /// of those.
+    #[serde(rename = "tx", default)]
+    pub transaction_id: Option<TransactionId>,
 
     #[serde(rename = "client")]
     pub client_id: ClientId,
@@ -16,10 +19,95 @@ pub struct Action {
     #[serde(rename = "type")]
     pub kind: ActionKind,
 
+    /// Read through `Money`, so negative, non-finite and over-precise amounts
+    /// fail to deserialize. Read and written as an exact decimal string with
+    /// the `crypto` feature, rather than through a float.
+    #[serde(default, deserialize_with = "crate::money::deserialize_optional")]
+    #[cfg_attr(
+        feature = "crypto",
+        serde(serialize_with = "crate::persist::optional_amount::serialize")
+    )]
     pub amount: Option<Amount>,
+
+    /// When the action was made, if the input has a `timestamp` column. Only
+    /// needed for time based policies like `DisputeWindow`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+
+    /// For a reversal, the transaction being reversed (`tx` is the id of the
+    /// new reversal transaction)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<TransactionId>,
+
+    /// The transaction's id in an upstream system (e.g. a UUID), if the input
+    /// has a `ref` column. Deposits, withdrawals and reversals record it
+    /// against their transaction, and the other actions can use it in place
+    /// of `tx`.
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+
+    /// A reference to evidence held elsewhere (e.g. a document URL or a case
+    /// id) for a dispute, resolve or chargeback, if the input has an
+    /// `evidence` column. It's kept with the transaction's dispute history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evidence: Option<String>,
+
+    /// The spending category of a withdrawal (e.g. `groceries`), if the input
+    /// has a `category` column. A withdrawal has to fit in what's left of
+    /// its account's envelope for the category, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+
+    /// The currency of the amount (e.g. `USD`), if the input has a
+    /// `currency` column. An engine with a ledger currency set rejects
+    /// actions in any other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+
+    /// Where the action came from, if its source says (see
+    /// `CsvSource::with_meta`). It's kept with the transaction it creates and
+    /// in the audit trail, so a transaction (or a rejected action) can be
+    /// traced back to its input long after.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Box<ActionMeta>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+/// Where an action came from. Every field is optional, since sources know
+/// different things about their actions.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActionMeta {
+    /// The input it was read from (e.g. a file path, or an API client)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// Its record number in `source`, counting from 1 after any header row
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<u64>,
+
+    /// When it was received (e.g. when its file arrived)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<Timestamp>,
+
+    /// The run, batch or request it came in with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ingest: Option<String>,
+}
+
+impl Action {
+    /// The transaction id of an action that's had it filled in. `State`
+    /// fills in the ids of reference-only actions before anything else
+    /// looks at them, and the other engines reject those they can't fill in.
+    ///
+    /// # Panics
+    ///
+    /// If the action has no transaction id
+    pub(crate) fn id(&self) -> TransactionId {
+        self.transaction_id
+            .expect("transaction ids are filled in before actions are applied")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ActionKind {
     /// Add funds to an account, creating it if it doesn't exist
@@ -32,4 +120,16 @@ pub enum ActionKind {
     Dispute,
     Resolve,
     Chargeback,
+
+    /// Undo a settled deposit or withdrawal outright (e.g. one entered by
+    /// mistake), without going through a dispute
+    Reversal,
+}
+
+impl ActionKind {
+    /// Whether the action creates a new transaction, rather than acting on an
+    /// existing one
+    pub fn creates_transaction(&self) -> bool {
+        matches!(self, Self::Deposit | Self::Withdrawal | Self::Reversal)
+    }
 }
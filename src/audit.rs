@@ -0,0 +1,273 @@
+//! A per-client record of everything that happened to each account, for
+//! answering data subject access requests and regulator inquiries (see
+//! `State::export_client`)
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::Balances, AccountData, AccountError, ActionKind, ActionMeta, Amount, ClientId,
+    Envelope, Timestamp, Transaction, TransactionId, TransactionState,
+};
+
+/// One entry in a client's audit trail
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The action's timestamp, or when it was applied if it didn't have one
+    pub at: Timestamp,
+    /// The state's sequence number when the entry was recorded, see
+    /// `State::sequence`
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// The account's balances just after the entry, if the client had an
+    /// account by then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balances: Option<Balances>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// An action was received for the client
+    Action {
+        kind: ActionKind,
+        /// `None` for an action that only had an external reference, which
+        /// couldn't be resolved
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transaction: Option<TransactionId>,
+        #[serde(
+            default,
+            with = "crate::persist::optional_amount",
+            skip_serializing_if = "Option::is_none"
+        )]
+        amount: Option<Amount>,
+        #[serde(flatten)]
+        outcome: Outcome,
+        /// Where the action came from, if it said
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        meta: Option<Box<ActionMeta>>,
+    },
+
+    /// The account moved to a new status, or in or out of quarantine
+    StatusChanged {
+        from: String,
+        to: String,
+        quarantined: bool,
+    },
+
+    /// A transaction's recorded amount was corrected with `State::restate`,
+    /// and the difference moved into or out of the available balance
+    Restated {
+        transaction: TransactionId,
+        #[serde(with = "crate::persist::exact_amount")]
+        from: Amount,
+        #[serde(with = "crate::persist::exact_amount")]
+        to: Amount,
+    },
+}
+
+/// What came of an action
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    /// Recorded, but the account refused it (e.g. insufficient funds)
+    Failed(AccountError),
+    /// Not recorded at all (e.g. a reused transaction id)
+    Rejected(String),
+}
+
+/// Every client's audit entries, oldest first
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct AuditTrail(HashMap<ClientId, Vec<AuditEntry>>);
+
+impl AuditTrail {
+    pub fn record(&mut self, client: ClientId, entry: AuditEntry) {
+        self.0.entry(client).or_default().push(entry);
+    }
+
+    pub fn entries(&self, client: ClientId) -> &[AuditEntry] {
+        self.0.get(&client).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Every client's entries, in no particular order of clients
+    #[cfg(feature = "sqlite")]
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, &[AuditEntry])> + '_ {
+        self.0
+            .iter()
+            .map(|(client, entries)| (*client, entries.as_slice()))
+    }
+
+    /// The number of entries for `client`, to truncate back to on rollback
+    pub fn len(&self, client: ClientId) -> usize {
+        self.entries(client).len()
+    }
+
+    pub fn truncate(&mut self, client: ClientId, len: usize) {
+        if let Some(entries) = self.0.get_mut(&client) {
+            entries.truncate(len);
+        }
+    }
+
+    pub fn forget(&mut self, client: ClientId) {
+        self.0.remove(&client);
+    }
+
+    pub fn extend(&mut self, other: AuditTrail) {
+        for (client, entries) in other.0 {
+            self.0.entry(client).or_default().extend(entries);
+        }
+    }
+}
+
+/// A reference to evidence held elsewhere (e.g. a document URL or a case
+/// management id), attached to a disputed transaction by one of its actions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Evidence {
+    /// The action's timestamp, or when it was applied if it didn't have one
+    pub at: Timestamp,
+    /// The dispute, resolve or chargeback it came with
+    pub kind: ActionKind,
+    pub reference: String,
+}
+
+/// Every disputed transaction's evidence, oldest first. Unlike the audit
+/// trail, this is always kept.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct EvidenceLog(HashMap<TransactionId, Vec<Evidence>>);
+
+impl EvidenceLog {
+    pub fn record(&mut self, transaction: TransactionId, evidence: Evidence) {
+        self.0.entry(transaction).or_default().push(evidence);
+    }
+
+    pub fn entries(&self, transaction: TransactionId) -> &[Evidence] {
+        self.0
+            .get(&transaction)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// The amount of evidence for `transaction`, to truncate back to on
+    /// rollback
+    pub fn len(&self, transaction: TransactionId) -> usize {
+        self.entries(transaction).len()
+    }
+
+    pub fn truncate(&mut self, transaction: TransactionId, len: usize) {
+        if let Some(evidence) = self.0.get_mut(&transaction) {
+            evidence.truncate(len);
+        }
+    }
+
+    pub fn forget(&mut self, transaction: TransactionId) {
+        self.0.remove(&transaction);
+    }
+
+    pub fn extend(&mut self, other: EvidenceLog) {
+        for (transaction, evidence) in other.0 {
+            self.0.entry(transaction).or_default().extend(evidence);
+        }
+    }
+}
+
+/// Everything the engine holds about one client, from `State::export_client`
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientExport {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    pub client: ClientId,
+    pub exported_at: Timestamp,
+
+    /// The account as it stands, if the client has one
+    pub account: Option<AccountData>,
+
+    /// The account's spending envelopes, by category
+    pub envelopes: BTreeMap<String, Envelope>,
+
+    /// Every recorded transaction, by id. Transactions dropped with
+    /// `State::forget_transactions` aren't included.
+    pub transactions: Vec<Transaction>,
+
+    /// Transactions that have been disputed, with how each dispute went
+    pub disputes: Vec<DisputeHistory>,
+
+    /// Empty unless the audit trail was enabled with `State::set_audit_trail`
+    pub audit_trail: Vec<AuditEntry>,
+}
+
+impl ClientExport {
+    /// Write the export as pretty printed JSON
+    pub fn write_json<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+}
+
+/// The disputes made on a single transaction
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DisputeHistory {
+    pub transaction: TransactionId,
+
+    /// Where the dispute stands: `disputed` while it's open, `succeeded` once
+    /// resolved or `cancelled` after a chargeback. Missing if the transaction
+    /// isn't recorded (e.g. it was forgotten, or never existed).
+    pub current_state: Option<TransactionState>,
+
+    /// The dispute, resolve and chargeback actions on the transaction, from
+    /// the audit trail
+    pub events: Vec<AuditEntry>,
+
+    /// Evidence attached by those actions (recorded even without the audit
+    /// trail)
+    pub evidence: Vec<Evidence>,
+}
+
+impl DisputeHistory {
+    /// Group the dispute related audit entries by transaction, also including
+    /// disputed transactions that predate the audit trail. `transactions` must
+    /// be sorted by id.
+    pub(crate) fn collect(
+        transactions: &[Transaction],
+        trail: &[AuditEntry],
+        evidence: &EvidenceLog,
+    ) -> Vec<Self> {
+        let mut disputes: BTreeMap<TransactionId, Vec<AuditEntry>> = BTreeMap::new();
+        for entry in trail {
+            if let AuditEvent::Action {
+                kind: ActionKind::Dispute | ActionKind::Resolve | ActionKind::Chargeback,
+                transaction: Some(transaction),
+                ..
+            } = &entry.event
+            {
+                disputes
+                    .entry(*transaction)
+                    .or_default()
+                    .push(entry.clone());
+            }
+        }
+        for transaction in transactions {
+            if matches!(transaction.state, TransactionState::Disputed)
+                || evidence.len(transaction.id) > 0
+            {
+                disputes.entry(transaction.id).or_default();
+            }
+        }
+
+        disputes
+            .into_iter()
+            .map(|(transaction, events)| Self {
+                transaction,
+                current_state: transactions
+                    .binary_search_by_key(&transaction, |t| t.id)
+                    .ok()
+                    .map(|i| transactions[i].state),
+                events,
+                evidence: evidence.entries(transaction).to_vec(),
+            })
+            .collect()
+    }
+}
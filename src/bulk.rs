@@ -0,0 +1,59 @@
+//! Operations over many clients at once, for incidents that affect
+//! thousands of accounts
+
+use serde::Serialize;
+
+use crate::{audit::ClientExport, Amount, ClientId, FreezeReason};
+
+/// An operation for `State::bulk`, applied to each client's account on its
+/// own
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkOperation {
+    /// Freeze the account, as `Account::freeze`
+    Freeze(FreezeReason),
+
+    /// Withdraw `amount` from the account, as a withdrawal with the external
+    /// reference `"{reference}:{client}"`. Running the same fee again only
+    /// charges the clients it didn't charge the first time, since the others'
+    /// references are already used.
+    Fee { amount: Amount, reference: String },
+}
+
+/// How a bulk operation went, client by client
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BulkReport {
+    /// Clients the operation was applied to, in the order they were given
+    #[serde(serialize_with = "crate::client_format::serialize_all")]
+    pub succeeded: Vec<ClientId>,
+
+    /// Clients it couldn't be applied to, whose accounts were left as they
+    /// were
+    pub failed: Vec<BulkFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BulkFailure {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    pub client: ClientId,
+    pub error: String,
+}
+
+/// Many clients' exports, from `State::export_clients`
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkExport {
+    /// An export for each client in `report.succeeded`, in the same order
+    pub exports: Vec<ClientExport>,
+    pub report: BulkReport,
+}
+
+impl BulkReport {
+    pub(crate) fn record<E: ToString>(&mut self, client: ClientId, result: Result<(), E>) {
+        match result {
+            Ok(()) => self.succeeded.push(client),
+            Err(e) => self.failed.push(BulkFailure {
+                client,
+                error: e.to_string(),
+            }),
+        }
+    }
+}
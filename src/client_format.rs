@@ -0,0 +1,144 @@
+//! How client ids are written in exports, so output can use the id
+//! conventions of the systems it goes to
+
+use std::{
+    fmt,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use serde::{Serialize, Serializer};
+
+use crate::ClientId;
+
+/// The format registered with `ClientFormat::register`, if it isn't `Plain`
+static REGISTERED: RwLock<Option<ClientFormat>> = RwLock::new(None);
+
+/// How client ids are written in account output and reports (statements,
+/// open holds, client exports and reconciliation discrepancies).
+///
+/// Ids are only formatted on the way out: snapshots, journals, stores and
+/// anything meant to be read back in as input (like reconciliation
+/// adjustments) keep the plain number, and so do error messages.
+#[derive(Clone, Default)]
+pub enum ClientFormat {
+    /// The id as a plain number
+    #[default]
+    Plain,
+    /// The id zero padded to `width` digits after `prefix`, e.g.
+    /// `CUST-000123`
+    Padded {
+        prefix: String,
+        width: usize,
+    },
+    /// All but the last `visible` digits of the id replaced with `*`, e.g.
+    /// `***23`. Ids with no more than `visible` digits are left as they are.
+    Masked {
+        visible: usize,
+    },
+    Custom(Arc<dyn Fn(ClientId) -> String + Send + Sync>),
+}
+
+impl ClientFormat {
+    /// A format from a closure
+    pub fn custom<F: Fn(ClientId) -> String + Send + Sync + 'static>(format: F) -> Self {
+        Self::Custom(Arc::new(format))
+    }
+
+    /// Write client ids this way in every export from now on, for the whole
+    /// process, in place of whatever was registered before
+    pub fn register(self) {
+        let format = match self {
+            Self::Plain => None,
+            format => Some(format),
+        };
+        *REGISTERED.write().unwrap_or_else(PoisonError::into_inner) = format;
+    }
+
+    /// The format client ids are being written in
+    pub fn registered() -> Self {
+        let registered = REGISTERED.read().unwrap_or_else(PoisonError::into_inner);
+        registered.clone().unwrap_or_default()
+    }
+
+    pub fn format(&self, client: ClientId) -> String {
+        match self {
+            Self::Plain => client.to_string(),
+            Self::Padded { prefix, width } => format!("{}{:0width$}", prefix, client.0),
+            Self::Masked { visible } => {
+                let digits = client.to_string();
+                let hidden = digits.len().saturating_sub(*visible);
+                format!("{}{}", "*".repeat(hidden), &digits[hidden..])
+            }
+            Self::Custom(format) => format(client),
+        }
+    }
+}
+
+impl fmt::Debug for ClientFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain => f.write_str("Plain"),
+            Self::Padded { prefix, width } => f
+                .debug_struct("Padded")
+                .field("prefix", prefix)
+                .field("width", width)
+                .finish(),
+            Self::Masked { visible } => f.debug_struct("Masked").field("visible", visible).finish(),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Serialize a client id in an export in the registered format, or as a
+/// number if there isn't one
+pub(crate) fn serialize<S: Serializer>(
+    client: &ClientId,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match &*REGISTERED.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(format) => serializer.collect_str(&format.format(*client)),
+        None => client.serialize(serializer),
+    }
+}
+
+/// `serialize` for every id in a list
+pub(crate) fn serialize_all<S: Serializer>(
+    clients: &[ClientId],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    struct Formatted<'a>(&'a ClientId);
+
+    impl Serialize for Formatted<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self.0, serializer)
+        }
+    }
+
+    serializer.collect_seq(clients.iter().map(Formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let client = ClientId(123);
+        assert_eq!(ClientFormat::Plain.format(client), "123");
+        let padded = ClientFormat::Padded {
+            prefix: "CUST-".into(),
+            width: 6,
+        };
+        assert_eq!(padded.format(client), "CUST-000123");
+        assert_eq!(ClientFormat::Masked { visible: 2 }.format(client), "*23");
+        assert_eq!(ClientFormat::Masked { visible: 5 }.format(client), "123");
+        let custom = ClientFormat::custom(|client| format!("c{}", client));
+        assert_eq!(custom.format(client), "c123");
+
+        // Nothing is registered in tests, so exports keep their numbers
+        assert!(matches!(ClientFormat::registered(), ClientFormat::Plain));
+        let mut serialized = Vec::new();
+        serialize(&client, &mut serde_json::Serializer::new(&mut serialized)).unwrap();
+        assert_eq!(serialized, b"123");
+    }
+}
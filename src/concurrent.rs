@@ -0,0 +1,440 @@
+//! An engine that locks per shard of clients rather than the whole state
+
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+};
+
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+
+use crate::{
+    engine::withdrawal_outcome,
+    persist::{sharded, PersistError},
+    store::StoreError,
+    sync::{Arc, Mutex},
+    AccountData, Action, ActionKind, ClientId, RawTransactionId, State, SyncEngine, TransactionId,
+    UpdateError,
+};
+
+/// Shards per available thread by default, so two busy clients rarely share
+/// one
+const SHARDS_PER_THREAD: usize = 16;
+
+/// A thread safe engine for write heavy workloads.
+///
+/// `MultiThreadedEngine` puts the whole state behind one lock, so every
+/// action waits on every other. Here clients are split between shards, each
+/// a `State` behind its own lock: actions on the same client always go to
+/// the same shard and are applied one at a time, while actions on clients in
+/// other shards go ahead in parallel. An account only depends on its own
+/// actions, so this gives the same result as applying the actions on one
+/// thread in the order each shard saw them.
+///
+/// Transaction ids and references are unique across every client, though,
+/// which no one shard can check. They're claimed in concurrent sets (which
+/// only contend on the same id or reference) before the action goes to its
+/// shard, and released again if the shard rejects it without using them.
+/// Ids for actions with only a reference are handed out in increasing order,
+/// skipping any already claimed.
+///
+/// Clones share the same shards, so the engine can be handed to each thread.
+#[derive(Debug, Clone)]
+pub struct ConcurrentEngine {
+    shards: Arc<Vec<Mutex<State>>>,
+    claims: Arc<Claims>,
+}
+
+/// The transaction ids and references claimed across all shards
+#[derive(Debug, Default)]
+struct Claims {
+    ids: DashSet<TransactionId>,
+    references: DashMap<String, TransactionId>,
+    /// The next id to try for actions with only a reference
+    next: AtomicU64,
+}
+
+impl ConcurrentEngine {
+    /// Create an engine with the given number of shards (at least one)
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: Arc::new((0..shards.max(1)).map(|_| Mutex::default()).collect()),
+            claims: Arc::default(),
+        }
+    }
+
+    /// Set up every shard's state the same way (e.g. with
+    /// `State::set_limits`), which has to be done before any actions are
+    /// processed
+    pub fn configure<F: Fn(&mut State)>(self, configure: F) -> Self {
+        for shard in self.shards.iter() {
+            configure(&mut shard.lock().expect("poisoned!"));
+        }
+        self
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Process an action, returning the error if it couldn't be applied
+    /// instead of ignoring it like `process` does. As with
+    /// `MultiThreadedEngine::try_process`, a withdrawal that was recorded as
+    /// failed is also an error.
+    pub fn try_process(&self, mut action: Action) -> Result<(), UpdateError> {
+        let claimed = self.claim(&action)?;
+        if let Some((id, _)) = claimed {
+            action.transaction_id = Some(id);
+        }
+        self.apply(&action, claimed)
+    }
+
+    /// Like `try_process`, but without taking the action. It's only cloned
+    /// if it has to be given an id for its reference.
+    pub fn try_process_ref(&self, action: &Action) -> Result<(), UpdateError> {
+        let claimed = self.claim(action)?;
+        match claimed {
+            Some((id, _)) if Some(id) != action.transaction_id => {
+                let action = Action {
+                    transaction_id: Some(id),
+                    ..action.clone()
+                };
+                self.apply(&action, claimed)
+            }
+            _ => self.apply(action, claimed),
+        }
+    }
+
+    /// Claim a new transaction's id and reference, see `Claims::claim`
+    fn claim(
+        &self,
+        action: &Action,
+    ) -> Result<Option<(TransactionId, Option<String>)>, UpdateError> {
+        if action.kind.creates_transaction() {
+            self.claims.claim(action)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Apply an action to its shard, releasing what it claimed if the shard
+    /// rejects it
+    fn apply(
+        &self,
+        action: &Action,
+        claimed: Option<(TransactionId, Option<String>)>,
+    ) -> Result<(), UpdateError> {
+        let mut state = self.shard(action.client_id).lock().expect("poisoned!");
+        let withdrawal = (action.kind == ActionKind::Withdrawal)
+            .then_some((action.transaction_id, action.reference.as_deref()));
+        let result = state.update_ref(action);
+        if let Some((id, reference)) = claimed {
+            // Rejected before its id was taken, so it can be used again
+            if !state.seen_transactions().contains(id) {
+                self.claims.release(id, reference);
+            }
+        }
+        match (result, withdrawal) {
+            (Ok(()), Some((id, reference))) => withdrawal_outcome(&state, id, reference),
+            (result, _) => result,
+        }
+    }
+
+    /// A client's account, waiting for any action being applied to its
+    /// shard
+    pub fn account(&self, client: ClientId) -> Option<AccountData> {
+        self.shard(client)
+            .lock()
+            .expect("poisoned!")
+            .account(client)
+    }
+
+    /// Merge every shard's accounts and transactions into one state, leaving
+    /// the shards (and so any clones of the engine) empty. Configuration set
+    /// with `configure` isn't carried over.
+    pub fn into_state(self) -> Result<State, StoreError> {
+        let mut merged = State::new();
+        for shard in self.shards.iter() {
+            merged.absorb(std::mem::take(&mut *shard.lock().expect("poisoned!")))?;
+        }
+        Ok(merged)
+    }
+
+    /// Snapshot every shard into `dir`, one file per shard plus a manifest,
+    /// writing them in parallel. Each snapshot replaces the last one in
+    /// `dir`, and a crash part way through leaves the last one intact.
+    ///
+    /// Every shard is locked until the snapshot is written, so it's of a
+    /// single moment across all of them. Unlike a `StateDir`, there's no
+    /// journal: actions applied since the last checkpoint are lost on a
+    /// crash.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<(), PersistError> {
+        let shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().expect("poisoned!"))
+            .collect();
+        let states: Vec<&State> = shards.iter().map(|shard| &**shard).collect();
+        sharded::write(dir.as_ref(), &states)
+    }
+
+    /// Continue from the last snapshot `checkpoint` wrote to `dir`, reading
+    /// the shards in parallel, with the same number of shards. Without one,
+    /// this is a new engine with the default number of shards.
+    ///
+    /// As with a `StateDir`, policies aren't part of the snapshot, so set
+    /// them again with `configure`.
+    // The conversion is a no-op with the `wide-transaction-ids` feature
+    #[allow(clippy::useless_conversion)]
+    pub fn restore<P: AsRef<Path>>(dir: P) -> Result<Self, PersistError> {
+        let Some(shards) = sharded::read(dir.as_ref())? else {
+            return Ok(Self::default());
+        };
+        let claims = Claims::default();
+        let mut next = 0;
+        for state in &shards {
+            for id in state.seen_transactions().iter() {
+                claims.ids.insert(id);
+            }
+            for (reference, id) in state.references() {
+                claims.references.insert(reference.clone(), *id);
+            }
+            if let Some(free) = state.seen_transactions().next_free() {
+                next = next.max(u64::from(free.0));
+            }
+        }
+        claims.next.store(next, Ordering::Relaxed);
+        Ok(Self {
+            shards: Arc::new(shards.into_iter().map(Mutex::new).collect()),
+            claims: Arc::new(claims),
+        })
+    }
+
+    fn shard(&self, client: ClientId) -> &Mutex<State> {
+        &self.shards[client.0 as usize % self.shards.len()]
+    }
+}
+
+impl Default for ConcurrentEngine {
+    /// An engine with a few shards for every thread the machine can run
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, usize::from);
+        Self::new(threads * SHARDS_PER_THREAD)
+    }
+}
+
+impl SyncEngine for ConcurrentEngine {
+    fn process(&mut self, action: Action) -> Result<(), UpdateError> {
+        // As with the other engines, rejected actions are ignored
+        let _ = self.try_process(action);
+        Ok(())
+    }
+
+    fn process_ref(&mut self, action: &Action) -> Result<(), UpdateError> {
+        let _ = self.try_process_ref(action);
+        Ok(())
+    }
+}
+
+impl Claims {
+    /// Claim a new transaction's id and reference (assigning it an id if it
+    /// only has a reference), returning them so they can be released
+    fn claim(
+        &self,
+        action: &Action,
+    ) -> Result<Option<(TransactionId, Option<String>)>, UpdateError> {
+        let Some(reference) = action.reference.clone() else {
+            // Without either, it's left for the shard to reject
+            let Some(id) = action.transaction_id else {
+                return Ok(None);
+            };
+            if !self.ids.insert(id) {
+                return Err(UpdateError::TransactionUsed(id));
+            }
+            return Ok(Some((id, None)));
+        };
+
+        let entry = match self.references.entry(reference.clone()) {
+            Entry::Occupied(_) => return Err(UpdateError::ReferenceUsed(reference)),
+            Entry::Vacant(entry) => entry,
+        };
+        let id = match action.transaction_id {
+            None => self.next_free()?,
+            Some(id) if self.ids.insert(id) => id,
+            Some(id) => return Err(UpdateError::TransactionUsed(id)),
+        };
+        entry.insert(id);
+        Ok(Some((id, Some(reference))))
+    }
+
+    /// Claim the next id that hasn't been claimed yet
+    fn next_free(&self) -> Result<TransactionId, UpdateError> {
+        loop {
+            let id = RawTransactionId::try_from(self.next.fetch_add(1, Ordering::Relaxed))
+                .ok()
+                .map(TransactionId)
+                .ok_or(UpdateError::NoFreeTransactionId)?;
+            if self.ids.insert(id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn release(&self, id: TransactionId, reference: Option<String>) {
+        self.ids.remove(&id);
+        if let Some(reference) = reference {
+            self.references.remove(&reference);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::CsvSource, SingleThreadedEngine, SyncEngineExt};
+
+    fn summarize(accounts: impl Iterator<Item = AccountData>) -> Vec<String> {
+        let mut accounts: Vec<_> = accounts.map(|a| format!("{:?}", a)).collect();
+        accounts.sort();
+        accounts
+    }
+
+    fn actions(input: &str) -> impl Iterator<Item = Action> + '_ {
+        CsvSource::from_reader(input.as_bytes()).map(Result::unwrap)
+    }
+
+    #[test]
+    fn test_matches_single_threaded() {
+        // Four clients per thread, each with its own deposits, withdrawals
+        // and disputes
+        let inputs: Vec<String> = (0..4u32)
+            .map(|thread| {
+                let mut input = String::from("type,client,tx,amount\n");
+                for client in thread * 4 + 1..=thread * 4 + 4 {
+                    let tx = client * 10;
+                    input += &format!(
+                        "deposit,{c},{},5.0\nwithdrawal,{c},{},2.5\ndeposit,{c},{},1.0\n\
+                         dispute,{c},{},\nwithdrawal,{c},{},9.0\nchargeback,{c},{},\n",
+                        tx,
+                        tx + 1,
+                        tx + 2,
+                        tx,
+                        tx + 3,
+                        tx,
+                        c = client
+                    );
+                }
+                input
+            })
+            .collect();
+
+        let engine = ConcurrentEngine::new(3);
+        thread::scope(|scope| {
+            for input in &inputs {
+                let mut engine = engine.clone();
+                scope.spawn(move || engine.process_all(actions(input)).unwrap());
+            }
+        });
+        let mut expected = SingleThreadedEngine::new();
+        for input in &inputs {
+            expected.process_all(actions(input)).unwrap();
+        }
+
+        let state = engine.into_state().unwrap();
+        assert_eq!(
+            summarize(state.accounts()),
+            summarize(expected.state().accounts())
+        );
+        assert_eq!(
+            state.failed_transactions().count(),
+            expected.state().failed_transactions().count()
+        );
+    }
+
+    #[test]
+    fn test_ids_and_references_are_claimed_across_shards() {
+        let engine = ConcurrentEngine::new(4);
+        let deposit = |client, tx, reference: Option<&str>| Action {
+            transaction_id: tx,
+            client_id: ClientId(client),
+            kind: ActionKind::Deposit,
+            amount: Some(crate::Amount::from(1u32)),
+            timestamp: None,
+            reverses: None,
+            reference: reference.map(String::from),
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        };
+
+        engine
+            .try_process(deposit(1, Some(TransactionId(7)), None))
+            .unwrap();
+        assert!(matches!(
+            engine.try_process(deposit(2, Some(TransactionId(7)), None)),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+
+        engine.try_process(deposit(3, None, Some("ref-a"))).unwrap();
+        assert!(matches!(
+            engine.try_process(deposit(4, None, Some("ref-a"))),
+            Err(UpdateError::ReferenceUsed(_))
+        ));
+        // A rejected action gives its id back
+        let mut no_amount = deposit(5, Some(TransactionId(8)), None);
+        no_amount.amount = None;
+        assert!(engine.try_process(no_amount).is_err());
+        engine
+            .try_process(deposit(6, Some(TransactionId(8)), None))
+            .unwrap();
+
+        let state = engine.into_state().unwrap();
+        assert_eq!(state.accounts().count(), 3);
+        // The reference was given the first free id
+        assert_eq!(
+            state.transaction_for_reference("ref-a"),
+            Some(TransactionId(0))
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            deposit,2,2,3.0\n\
+            withdrawal,3,3,1.0\n\
+            deposit,4,4,2.0\n\
+            dispute,4,4,\n";
+        let mut engine = ConcurrentEngine::new(3);
+        engine.process_all(actions(input)).unwrap();
+        engine.checkpoint(dir.path()).unwrap();
+        // Checkpointing again replaces the first generation's files
+        engine.checkpoint(dir.path()).unwrap();
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 4);
+
+        let restored = ConcurrentEngine::restore(dir.path()).unwrap();
+        assert_eq!(restored.shard_count(), 3);
+        for client in 1..=4 {
+            assert_eq!(
+                restored.account(ClientId(client)),
+                engine.account(ClientId(client))
+            );
+        }
+        // Ids used before the checkpoint are still claimed
+        let mut reused = actions("type,client,tx,amount\ndeposit,5,2,1.0\n");
+        assert!(matches!(
+            restored.try_process(reused.next().unwrap()),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+
+        let empty = tempfile::tempdir().unwrap();
+        let fresh = ConcurrentEngine::restore(empty.path()).unwrap();
+        assert_eq!(
+            fresh.shard_count(),
+            ConcurrentEngine::default().shard_count()
+        );
+    }
+}
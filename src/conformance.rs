@@ -0,0 +1,535 @@
+//! A published set of action sequences and the final accounts the reference
+//! engine ends up with for each, for checking that other engines (and the
+//! stores behind them) apply actions the same way
+//!
+//! Each `Case` covers one part of the semantics:
+//!
+//! - deposits and withdrawals, including ones the account can't cover
+//! - disputes, resolves and chargebacks, and the ones that don't apply (of
+//!   another client's transaction, of an unknown one, of a transaction that
+//!   isn't disputed, or disputed twice)
+//! - chargebacks locking the account, and a locked account refusing what
+//!   comes after
+//! - duplicate transaction ids, reversals, and amounts at full precision
+//!
+//! `run` applies every case to a new engine and compares the accounts it
+//! ends up with to the expected ones, so an engine only has to implement
+//! `SyncEngine` and give its accounts back once it's done:
+//!
+//! ```
+//! use transaction_engine::{conformance, SingleThreadedEngine};
+//!
+//! let report = conformance::run(SingleThreadedEngine::new, |engine| {
+//!     engine.into_state().accounts().collect()
+//! });
+//! assert!(report.is_ok(), "{report}");
+//! ```
+//!
+//! Only the final accounts are compared (their balances and whether they're
+//! locked), not what `process` returned along the way, so it doesn't matter
+//! whether an engine reports rejected actions or ignores them. An account the
+//! reference creates for an action it then rejects (e.g. a withdrawal from a
+//! client it hasn't seen) is expected too, with nothing in it. Amounts are
+//! compared to 4 decimal places, so engines without the `decimal` feature
+//! pass as well.
+
+use std::fmt;
+
+use crate::{
+    AccountData, Action, ActionKind, Amount, ClientId, RawClientId, RawTransactionId, SyncEngine,
+    TransactionId,
+};
+use ActionKind::{Chargeback, Dispute, Resolve};
+
+/// An action of a case
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    kind: ActionKind,
+    client: RawClientId,
+    transaction: RawTransactionId,
+    amount: Option<&'static str>,
+    reverses: Option<RawTransactionId>,
+}
+
+const fn deposit(client: RawClientId, transaction: RawTransactionId, amount: &'static str) -> Step {
+    Step {
+        kind: ActionKind::Deposit,
+        client,
+        transaction,
+        amount: Some(amount),
+        reverses: None,
+    }
+}
+
+const fn withdrawal(
+    client: RawClientId,
+    transaction: RawTransactionId,
+    amount: &'static str,
+) -> Step {
+    Step {
+        kind: ActionKind::Withdrawal,
+        ..deposit(client, transaction, amount)
+    }
+}
+
+/// A dispute, resolve or chargeback of `transaction`
+const fn claim(kind: ActionKind, client: RawClientId, transaction: RawTransactionId) -> Step {
+    Step {
+        kind,
+        client,
+        transaction,
+        amount: None,
+        reverses: None,
+    }
+}
+
+const fn reversal(
+    client: RawClientId,
+    transaction: RawTransactionId,
+    reverses: RawTransactionId,
+) -> Step {
+    Step {
+        reverses: Some(reverses),
+        ..claim(ActionKind::Reversal, client, transaction)
+    }
+}
+
+/// An account a case should end with, as (client, available, held, total,
+/// locked)
+type Row = (RawClientId, &'static str, &'static str, &'static str, bool);
+
+/// A sequence of actions and the accounts they should leave
+#[derive(Debug, Clone, Copy)]
+pub struct Case {
+    pub name: &'static str,
+    /// What the case checks
+    pub description: &'static str,
+    steps: &'static [Step],
+    expected: &'static [Row],
+}
+
+/// An account as a case expects it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectedAccount {
+    pub client: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl Case {
+    /// The case's actions, in the order they're applied
+    pub fn actions(&self) -> Vec<Action> {
+        self.steps
+            .iter()
+            .map(|step| Action {
+                reverses: step.reverses.map(TransactionId),
+                ..crate::testing::action(step.kind, step.client, step.transaction, step.amount)
+            })
+            .collect()
+    }
+
+    /// Every account the actions should leave, by client
+    pub fn expected(&self) -> Vec<ExpectedAccount> {
+        self.expected
+            .iter()
+            .map(
+                |&(client, available, held, total, locked)| ExpectedAccount {
+                    client: ClientId(client),
+                    available: parse(available),
+                    held: parse(held),
+                    total: parse(total),
+                    locked,
+                },
+            )
+            .collect()
+    }
+
+    /// Compare the accounts an engine ended up with to the expected ones,
+    /// giving every difference
+    pub fn compare(&self, accounts: &[AccountData]) -> Vec<Mismatch> {
+        let expected = self.expected();
+        let mut mismatches: Vec<_> = expected
+            .iter()
+            .filter_map(|expected| {
+                let found = accounts
+                    .iter()
+                    .find(|found| found.client == expected.client);
+                match found {
+                    Some(found) if expected.matches(found) => None,
+                    found => Some(Mismatch {
+                        case: self.name,
+                        client: expected.client,
+                        expected: Some(expected.clone()),
+                        found: found.cloned(),
+                    }),
+                }
+            })
+            .collect();
+        mismatches.extend(
+            accounts
+                .iter()
+                .filter(|found| !expected.iter().any(|e| e.client == found.client))
+                .map(|found| Mismatch {
+                    case: self.name,
+                    client: found.client,
+                    expected: None,
+                    found: Some(found.clone()),
+                }),
+        );
+        mismatches
+    }
+}
+
+impl ExpectedAccount {
+    fn matches(&self, found: &AccountData) -> bool {
+        let tolerance = parse("0.00005");
+        let close = |a: Amount, b: Amount| (a - b).abs() < tolerance;
+        close(self.available, found.available)
+            && close(self.held, found.held)
+            && close(self.total, found.total)
+            && self.locked == found.locked
+    }
+}
+
+fn parse(amount: &str) -> Amount {
+    amount.parse().expect("invalid amount")
+}
+
+/// An account that didn't end up as a case expected
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub case: &'static str,
+    pub client: ClientId,
+    /// `None` if the account shouldn't exist at all
+    pub expected: Option<ExpectedAccount>,
+    /// `None` if the engine has no account for the client
+    pub found: Option<AccountData>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: client {}: expected ", self.case, self.client)?;
+        match &self.expected {
+            Some(e) => write!(
+                f,
+                "available {}, held {}, total {}, locked {}",
+                e.available, e.held, e.total, e.locked
+            )?,
+            None => write!(f, "no account")?,
+        }
+        write!(f, ", found ")?;
+        match &self.found {
+            Some(a) => write!(
+                f,
+                "available {}, held {}, total {}, locked {}",
+                a.available, a.held, a.total, a.locked
+            ),
+            None => write!(f, "no account"),
+        }
+    }
+}
+
+/// How an engine did over every case, from `run`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConformanceReport {
+    /// The cases that matched, by name
+    pub passed: Vec<&'static str>,
+    /// Every difference in the cases that didn't
+    pub failed: Vec<Mismatch>,
+}
+
+impl ConformanceReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} cases passed", self.passed.len())?;
+        for mismatch in &self.failed {
+            write!(f, "\n{mismatch}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply every case to an engine from `new_engine`, then compare the
+/// accounts `finish` gives back for it (e.g. with `into_state`) to the
+/// expected ones
+pub fn run<E, N, F>(mut new_engine: N, mut finish: F) -> ConformanceReport
+where
+    E: SyncEngine,
+    N: FnMut() -> E,
+    F: FnMut(E) -> Vec<AccountData>,
+{
+    let mut report = ConformanceReport::default();
+    for case in CASES {
+        let mut engine = new_engine();
+        for action in case.actions() {
+            // Rejections are part of the cases, and show in the accounts
+            let _ = engine.process(action);
+        }
+        let mismatches = case.compare(&finish(engine));
+        if mismatches.is_empty() {
+            report.passed.push(case.name);
+        } else {
+            report.failed.extend(mismatches);
+        }
+    }
+    report
+}
+
+/// Every case, applied in this order by `run`
+pub const CASES: &[Case] = &[
+    Case {
+        name: "deposits_and_withdrawals",
+        description: "deposits and withdrawals move both available and total",
+        steps: &[
+            deposit(1, 1, "1.0"),
+            deposit(2, 2, "2.0"),
+            deposit(1, 3, "2.0"),
+            withdrawal(1, 4, "1.5"),
+            withdrawal(2, 5, "3.0"),
+        ],
+        expected: &[(1, "1.5", "0", "1.5", false), (2, "2", "0", "2", false)],
+    },
+    Case {
+        name: "insufficient_funds",
+        description: "a withdrawal of more than is available is rejected, one of all of it isn't",
+        steps: &[
+            deposit(1, 1, "5"),
+            withdrawal(1, 2, "5.0001"),
+            withdrawal(1, 3, "5"),
+        ],
+        expected: &[(1, "0", "0", "0", false)],
+    },
+    Case {
+        name: "withdrawal_from_new_client",
+        description: "a withdrawal from a client with no deposits leaves it an empty account",
+        steps: &[withdrawal(1, 1, "4")],
+        expected: &[(1, "0", "0", "0", false)],
+    },
+    Case {
+        name: "precision",
+        description: "amounts are kept to 4 decimal places",
+        steps: &[
+            deposit(1, 1, "0.0001"),
+            deposit(1, 2, "1.9999"),
+            withdrawal(1, 3, "0.5"),
+            withdrawal(1, 4, "1.4999"),
+        ],
+        expected: &[(1, "0.0001", "0", "0.0001", false)],
+    },
+    Case {
+        name: "duplicate_transaction",
+        description: "a transaction id can only be used once, even by another client",
+        steps: &[
+            deposit(1, 1, "10"),
+            deposit(1, 1, "5"),
+            deposit(2, 1, "5"),
+            withdrawal(1, 1, "2"),
+        ],
+        expected: &[(1, "10", "0", "10", false)],
+    },
+    Case {
+        name: "dispute",
+        description: "a dispute moves a deposit's amount from available to held",
+        steps: &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            claim(Dispute, 1, 1),
+        ],
+        expected: &[(1, "5", "10", "15", false)],
+    },
+    Case {
+        name: "resolve",
+        description: "a resolve releases the held amount again",
+        steps: &[
+            deposit(1, 1, "10"),
+            claim(Dispute, 1, 1),
+            claim(Resolve, 1, 1),
+        ],
+        expected: &[(1, "10", "0", "10", false)],
+    },
+    Case {
+        name: "chargeback",
+        description: "a chargeback takes the held amount out of the account and locks it",
+        steps: &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            claim(Dispute, 1, 1),
+            claim(Chargeback, 1, 1),
+        ],
+        expected: &[(1, "5", "0", "5", true)],
+    },
+    Case {
+        name: "locked_account",
+        description: "a locked account refuses deposits, withdrawals and disputes",
+        steps: &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            claim(Dispute, 1, 1),
+            claim(Chargeback, 1, 1),
+            deposit(1, 3, "1"),
+            withdrawal(1, 4, "1"),
+            claim(Dispute, 1, 2),
+        ],
+        expected: &[(1, "5", "0", "5", true)],
+    },
+    Case {
+        name: "dispute_twice",
+        description: "disputing a disputed transaction again holds nothing more",
+        steps: &[
+            deposit(1, 1, "10"),
+            claim(Dispute, 1, 1),
+            claim(Dispute, 1, 1),
+        ],
+        expected: &[(1, "0", "10", "10", false)],
+    },
+    Case {
+        name: "dispute_after_resolve",
+        description: "a resolved transaction can be disputed again",
+        steps: &[
+            deposit(1, 1, "10"),
+            claim(Dispute, 1, 1),
+            claim(Resolve, 1, 1),
+            claim(Dispute, 1, 1),
+        ],
+        expected: &[(1, "0", "10", "10", false)],
+    },
+    Case {
+        name: "undisputed",
+        description: "resolves and chargebacks of a transaction that isn't disputed are ignored",
+        steps: &[
+            deposit(1, 1, "10"),
+            claim(Resolve, 1, 1),
+            claim(Chargeback, 1, 1),
+            claim(Dispute, 1, 1),
+            claim(Resolve, 1, 1),
+            claim(Chargeback, 1, 1),
+        ],
+        expected: &[(1, "10", "0", "10", false)],
+    },
+    Case {
+        name: "unknown_transaction",
+        description: "disputes, resolves and chargebacks of an unknown transaction are ignored",
+        steps: &[
+            deposit(1, 1, "10"),
+            claim(Dispute, 1, 99),
+            claim(Resolve, 1, 99),
+            claim(Chargeback, 1, 99),
+            claim(Dispute, 2, 98),
+        ],
+        expected: &[(1, "10", "0", "10", false)],
+    },
+    Case {
+        name: "other_clients_transaction",
+        description: "a client can't dispute another client's transaction",
+        steps: &[
+            deposit(1, 1, "10"),
+            deposit(2, 2, "5"),
+            claim(Dispute, 2, 1),
+            claim(Chargeback, 2, 1),
+        ],
+        expected: &[(1, "10", "0", "10", false), (2, "5", "0", "5", false)],
+    },
+    Case {
+        name: "withdrawal_dispute",
+        description: "withdrawals can't be disputed",
+        steps: &[
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "4"),
+            claim(Dispute, 1, 2),
+        ],
+        expected: &[(1, "6", "0", "6", false)],
+    },
+    Case {
+        name: "dispute_exceeding_available",
+        description: "a deposit that has since been withdrawn can't be disputed",
+        steps: &[
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "8"),
+            claim(Dispute, 1, 1),
+            claim(Chargeback, 1, 1),
+        ],
+        expected: &[(1, "2", "0", "2", false)],
+    },
+    Case {
+        name: "reversal",
+        description: "a reversal takes a deposit back out, unless it's been withdrawn",
+        steps: &[
+            deposit(1, 1, "10"),
+            reversal(1, 2, 1),
+            deposit(2, 3, "10"),
+            withdrawal(2, 4, "8"),
+            reversal(2, 5, 3),
+        ],
+        expected: &[(1, "0", "0", "0", false), (2, "2", "0", "2", false)],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MultiThreadedEngine, SingleThreadedEngine};
+
+    #[test]
+    fn test_reference_engines() {
+        let report = run(SingleThreadedEngine::new, |engine| {
+            engine.into_state().accounts().collect()
+        });
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.passed.len(), CASES.len());
+
+        let report = run(MultiThreadedEngine::new, |engine| {
+            engine.state().read().unwrap().accounts().collect()
+        });
+        assert!(report.is_ok(), "{report}");
+
+        #[cfg(feature = "concurrent-engine")]
+        {
+            let report = run(
+                || crate::ConcurrentEngine::new(4),
+                |engine| engine.into_state().unwrap().accounts().collect(),
+            );
+            assert!(report.is_ok(), "{report}");
+        }
+    }
+
+    #[test]
+    fn test_mismatch() {
+        /// Ignores every chargeback
+        struct Forgiving(SingleThreadedEngine);
+        impl SyncEngine for Forgiving {
+            fn process(&mut self, action: Action) -> Result<(), crate::UpdateError> {
+                match action.kind {
+                    Chargeback => Ok(()),
+                    _ => self.0.process(action),
+                }
+            }
+        }
+
+        let report = run(
+            || Forgiving(SingleThreadedEngine::new()),
+            |engine| engine.0.into_state().accounts().collect(),
+        );
+        assert!(!report.is_ok());
+        assert!(report
+            .failed
+            .iter()
+            .all(|m| m.case == "chargeback" || m.case == "locked_account"));
+        let mismatch = report
+            .failed
+            .iter()
+            .find(|m| m.case == "chargeback")
+            .unwrap();
+        assert_eq!(mismatch.client, ClientId(1));
+        assert_eq!(mismatch.found.as_ref().unwrap().held, parse("10"));
+        assert!(mismatch
+            .to_string()
+            .starts_with("chargeback: client 1: expected"));
+    }
+}
@@ -0,0 +1,212 @@
+//! Crash reports, for filing bug reports when the engine dies mid-batch
+//!
+//! A panic unwinds out of the engine with nothing to say what it was doing.
+//! A `FlightRecorder` given to `SingleThreadedEngine::with_flight_recorder`
+//! keeps the last few actions it processed, the one it's processing now and
+//! the account totals as of the last one to finish. Its panic hook (opt-in,
+//! with `install_panic_hook`) writes those to a JSON crash file before the
+//! usual panic message is printed.
+//!
+//! The state itself isn't read from the hook, since the panic may have left
+//! it part way through an update (or still borrowed).
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    sync::{Arc, Mutex, TryLockError},
+};
+
+use serde::Serialize;
+
+use crate::{AccountsSummary, Action, State};
+
+/// The last actions an engine processed, shared with a panic hook
+#[derive(Debug, Clone)]
+pub struct FlightRecorder {
+    recording: Arc<Mutex<Recording>>,
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    capacity: usize,
+    /// The latest finished actions, oldest first
+    recent: VecDeque<Action>,
+    /// The action being processed, if one is
+    current: Option<Action>,
+    summary: AccountsSummary,
+    sequence: u64,
+}
+
+/// What the engine was doing when it panicked, as written to the crash file
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    /// The panic message (empty if it isn't a string)
+    pub message: String,
+    /// Where in the code the panic happened, as `file:line:column`
+    pub location: Option<String>,
+    /// The state's sequence number after the last finished action
+    pub sequence: u64,
+    /// The account totals after the last finished action
+    pub summary: AccountsSummary,
+    /// The action that was being processed
+    pub current: Option<Action>,
+    /// The actions before it, oldest first
+    pub recent: Vec<Action>,
+}
+
+impl FlightRecorder {
+    /// Keep the last `capacity` actions
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            recording: Arc::new(Mutex::new(Recording {
+                capacity,
+                ..Recording::default()
+            })),
+        }
+    }
+
+    /// Note that `action` is about to be processed
+    pub fn begin(&self, action: &Action) {
+        self.lock().current = Some(action.clone());
+    }
+
+    /// Note that the action from `begin` is done (whether or not it was
+    /// applied), leaving `state` as it is now
+    pub fn finish(&self, state: &State) {
+        let mut recording = self.lock();
+        if let Some(action) = recording.current.take() {
+            if recording.recent.len() >= recording.capacity {
+                recording.recent.pop_front();
+            }
+            if recording.capacity > 0 {
+                recording.recent.push_back(action);
+            }
+        }
+        recording.summary = state.summary();
+        recording.sequence = state.sequence();
+    }
+
+    /// Everything recorded so far, with no panic message
+    pub fn report(&self) -> CrashReport {
+        self.lock().report()
+    }
+
+    /// Write a crash report to `path` whenever any thread panics, before
+    /// carrying on to the panic hook that was already installed (which
+    /// prints the message by default). Only the latest report is kept.
+    pub fn install_panic_hook<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        let recording = Arc::clone(&self.recording);
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // A panic while the recording is locked on this thread would
+            // deadlock waiting for it
+            let mut report = match recording.try_lock() {
+                Ok(recording) => recording.report(),
+                Err(TryLockError::Poisoned(e)) => e.into_inner().report(),
+                Err(TryLockError::WouldBlock) => Recording::default().report(),
+            };
+            let payload = info.payload();
+            report.message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            report.location = info.location().map(|location| location.to_string());
+            let written = File::create(&path)
+                .map_err(serde_json::Error::io)
+                .and_then(|file| serde_json::to_writer_pretty(BufWriter::new(file), &report));
+            match written {
+                Ok(()) => eprintln!("crash report written to {}", path.display()),
+                Err(e) => eprintln!("failed to write crash report to {}: {}", path.display(), e),
+            }
+            previous(info);
+        }));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Recording> {
+        // Only a copy of what was processed, so still worth reporting after
+        // a panic
+        self.recording.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Recording {
+    fn report(&self) -> CrashReport {
+        CrashReport {
+            message: String::new(),
+            location: None,
+            sequence: self.sequence,
+            summary: self.summary,
+            current: self.current.clone(),
+            recent: self.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActionKind, ClientId, SingleThreadedEngine, SyncEngine, TransactionId};
+
+    fn deposit(transaction: crate::RawTransactionId, amount: crate::Amount) -> Action {
+        Action {
+            transaction_id: Some(TransactionId(transaction)),
+            client_id: ClientId(1),
+            kind: ActionKind::Deposit,
+            amount: Some(amount),
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn test_recorder_keeps_the_last_actions() {
+        let recorder = FlightRecorder::new(2);
+        let mut engine = SingleThreadedEngine::new().with_flight_recorder(recorder.clone());
+        for id in 1..=3 {
+            engine
+                .process(deposit(id, crate::Amount::from(1u32)))
+                .unwrap();
+        }
+
+        let report = recorder.report();
+        assert!(report.current.is_none());
+        let recent: Vec<_> = report
+            .recent
+            .iter()
+            .filter_map(|a| a.transaction_id)
+            .collect();
+        assert_eq!(recent, [TransactionId(2), TransactionId(3)]);
+        assert_eq!(report.sequence, 3);
+        assert_eq!(report.summary, engine.state().summary());
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_recorder_keeps_the_action_that_panicked() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let recorder = FlightRecorder::new(8);
+        let mut engine = SingleThreadedEngine::new().with_flight_recorder(recorder.clone());
+        engine.process(deposit(1, crate::Amount::MAX)).unwrap();
+        // Overflows the balance
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            engine.process(deposit(2, crate::Amount::MAX))
+        }));
+        assert!(panicked.is_err());
+
+        let report = recorder.report();
+        let current = report.current.expect("the panicking action is kept");
+        assert_eq!(current.transaction_id, Some(TransactionId(2)));
+        assert_eq!(report.recent.len(), 1);
+        assert_eq!(report.summary.available, crate::Amount::MAX);
+    }
+}
@@ -0,0 +1,311 @@
+//! Crypto ledger mode: exact amounts to 18 decimal places, and clients
+//! identified by on-chain addresses
+//!
+//! With the `crypto` feature, amounts keep up to 18 decimal places (down to
+//! an ether's wei) from input to output. Action amounts and account balances
+//! are read and written as exact decimal strings rather than through floats,
+//! and balances are rounded to 18 places for output rather than 4.
+//!
+//! Amounts are still 96 bit decimals, which leaves room for about 79 billion
+//! whole units at full precision. Chains count in integer minor units (wei,
+//! satoshis, lamports), so `from_minor_units` and `to_minor_units` convert
+//! to and from them, and fail rather than lose precision past that.
+//!
+//! `AddressBook` maps addresses to clients, handing out a new client id the
+//! first time an address is seen, so deposits to and withdrawals from an
+//! exchange's internal accounts can be fed in straight from chain data.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Action, ActionKind, Amount, ClientId, RawClientId};
+
+/// Decimal places kept in crypto ledger mode
+pub const DECIMALS: u32 = 18;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("{units} minor units with {decimals} decimals is out of range")]
+    OutOfRange { units: i128, decimals: u32 },
+
+    #[error("{amount} has more than {decimals} decimal places")]
+    TooPrecise { amount: Amount, decimals: u32 },
+
+    #[error("addresses can't be empty")]
+    EmptyAddress,
+
+    #[error("address {address} is already mapped to client {client}")]
+    AddressTaken { address: String, client: ClientId },
+
+    #[error("client {client} is already mapped to address {address}")]
+    ClientTaken { client: ClientId, address: String },
+
+    #[error("there are no client ids left for address {0}")]
+    ClientsExhausted(String),
+}
+
+/// An amount of `units` minor units of an asset with `decimals` decimal
+/// places (e.g. wei, with 18)
+pub fn from_minor_units(units: i128, decimals: u32) -> Result<Amount, CryptoError> {
+    Amount::try_from_i128_with_scale(units, decimals)
+        .map_err(|_| CryptoError::OutOfRange { units, decimals })
+}
+
+/// An amount in minor units of an asset with `decimals` decimal places,
+/// failing if it has more precision than the asset
+pub fn to_minor_units(amount: Amount, decimals: u32) -> Result<i128, CryptoError> {
+    let amount = amount.normalize();
+    let scale = amount.scale();
+    if scale > decimals {
+        return Err(CryptoError::TooPrecise { amount, decimals });
+    }
+    10i128
+        .checked_pow(decimals - scale)
+        .and_then(|factor| amount.mantissa().checked_mul(factor))
+        .ok_or(CryptoError::OutOfRange {
+            units: amount.mantissa(),
+            decimals,
+        })
+}
+
+/// A two way mapping between addresses and clients.
+///
+/// EVM addresses (`0x` followed by hex) are case insensitive, so they're
+/// lowercased first. Other addresses are taken as they are.
+///
+/// Serializes as a map from address to client, to be saved alongside the
+/// state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "HashMap<String, ClientId>", into = "HashMap<String, ClientId>")]
+pub struct AddressBook {
+    clients: HashMap<String, ClientId>,
+    addresses: HashMap<ClientId, String>,
+    /// The lowest id that might not have been handed out
+    next: RawClientId,
+}
+
+impl AddressBook {
+    /// An empty book, which hands out client ids from 1
+    pub fn new() -> Self {
+        Self::from(HashMap::new())
+    }
+
+    /// The client for an address, if it has one
+    pub fn get(&self, address: &str) -> Option<ClientId> {
+        self.clients.get(normalize(address).as_ref()).copied()
+    }
+
+    /// The address a client was mapped to, if any
+    pub fn address(&self, client: ClientId) -> Option<&str> {
+        self.addresses.get(&client).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Map an address to a particular client, e.g. for accounts that
+    /// predate the book
+    pub fn insert(&mut self, address: &str, client: ClientId) -> Result<(), CryptoError> {
+        let address = checked(address)?;
+        if let Some(existing) = self.clients.get(&address) {
+            if *existing == client {
+                return Ok(());
+            }
+            return Err(CryptoError::AddressTaken {
+                address,
+                client: *existing,
+            });
+        }
+        if let Some(existing) = self.addresses.get(&client) {
+            return Err(CryptoError::ClientTaken {
+                client,
+                address: existing.clone(),
+            });
+        }
+        self.clients.insert(address.clone(), client);
+        self.addresses.insert(client, address);
+        Ok(())
+    }
+
+    /// The client for an address, giving it the next free client id if it
+    /// hasn't been seen before
+    pub fn client(&mut self, address: &str) -> Result<ClientId, CryptoError> {
+        let address = checked(address)?;
+        if let Some(client) = self.clients.get(&address) {
+            return Ok(*client);
+        }
+        // The highest id is kept for erased clients
+        while self.addresses.contains_key(&ClientId(self.next)) {
+            self.next += 1;
+        }
+        if self.next == ClientId::TOMBSTONE.0 {
+            return Err(CryptoError::ClientsExhausted(address));
+        }
+        let client = ClientId(self.next);
+        self.next += 1;
+        self.clients.insert(address.clone(), client);
+        self.addresses.insert(client, address);
+        Ok(client)
+    }
+
+    /// A deposit to or withdrawal from `address` of `units` minor units (of
+    /// an asset with `decimals` decimal places), with the on-chain
+    /// transaction (e.g. its hash and log index) as the external reference.
+    /// The engine assigns the transaction id.
+    pub fn action<S: Into<String>>(
+        &mut self,
+        kind: ActionKind,
+        address: &str,
+        units: i128,
+        decimals: u32,
+        reference: S,
+    ) -> Result<Action, CryptoError> {
+        let amount = from_minor_units(units, decimals)?;
+        Ok(Action {
+            transaction_id: None,
+            client_id: self.client(address)?,
+            kind,
+            amount: Some(amount),
+            timestamp: None,
+            reverses: None,
+            reference: Some(reference.into()),
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        })
+    }
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<HashMap<String, ClientId>> for AddressBook {
+    fn from(clients: HashMap<String, ClientId>) -> Self {
+        let addresses = clients
+            .iter()
+            .map(|(address, client)| (*client, address.clone()))
+            .collect();
+        Self {
+            clients,
+            addresses,
+            next: 1,
+        }
+    }
+}
+
+impl From<AddressBook> for HashMap<String, ClientId> {
+    fn from(book: AddressBook) -> Self {
+        book.clients
+    }
+}
+
+fn checked(address: &str) -> Result<String, CryptoError> {
+    let address = normalize(address);
+    if address.is_empty() {
+        return Err(CryptoError::EmptyAddress);
+    }
+    Ok(address.into_owned())
+}
+
+fn normalize(address: &str) -> std::borrow::Cow<'_, str> {
+    let address = address.trim();
+    let is_evm = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+        .is_some_and(|hex| !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()));
+    if is_evm && address.bytes().any(|b| b.is_ascii_uppercase()) {
+        address.to_ascii_lowercase().into()
+    } else {
+        address.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::CsvSource, State};
+
+    #[test]
+    fn test_minor_units() {
+        let wei = 1_234_567_890_123_456_789i128;
+        let amount = from_minor_units(wei, DECIMALS).unwrap();
+        assert_eq!(amount.to_string(), "1.234567890123456789");
+        assert_eq!(to_minor_units(amount, DECIMALS).unwrap(), wei);
+        // Satoshis
+        assert_eq!(to_minor_units(amount.round_dp(8), 8).unwrap(), 123_456_789);
+        assert!(matches!(
+            to_minor_units(amount, 8),
+            Err(CryptoError::TooPrecise { .. })
+        ));
+        assert!(matches!(
+            from_minor_units(i128::MAX, DECIMALS),
+            Err(CryptoError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_address_book() {
+        let mut book = AddressBook::new();
+        book.insert("bc1qexisting", ClientId(2)).unwrap();
+        let alice = book
+            .client("0xAbCdEf0000000000000000000000000000000001")
+            .unwrap();
+        assert_eq!(alice, ClientId(1));
+        assert_eq!(
+            book.get("0xabcdef0000000000000000000000000000000001"),
+            Some(alice)
+        );
+        // Skips the pinned id
+        assert_eq!(book.client("So1anaAddress").unwrap(), ClientId(3));
+        assert!(matches!(
+            book.insert("bc1qother", ClientId(2)),
+            Err(CryptoError::ClientTaken { .. })
+        ));
+        assert_eq!(book.client("  ").unwrap_err(), CryptoError::EmptyAddress);
+
+        let saved = serde_json::to_string(&book).unwrap();
+        let mut restored: AddressBook = serde_json::from_str(&saved).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert_eq!(restored.address(ClientId(3)), Some("So1anaAddress"));
+        assert_eq!(restored.client("new").unwrap(), ClientId(4));
+    }
+
+    #[test]
+    fn test_full_precision() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,12345.123456789012345678\n\
+            withdrawal,1,2,0.000000000000000001\n";
+        let mut state = State::new();
+        for action in CsvSource::from_reader(input.as_bytes()) {
+            state.update(action.unwrap()).unwrap();
+        }
+
+        let mut book = AddressBook::new();
+        let deposit = book
+            .action(ActionKind::Deposit, "0xbeef", 5, DECIMALS, "0xhash:0")
+            .unwrap();
+        assert_eq!(deposit.client_id, ClientId(1));
+        state.update(deposit).unwrap();
+
+        let mut output = csv::Writer::from_writer(Vec::new());
+        output
+            .serialize(state.account(ClientId(1)).unwrap())
+            .unwrap();
+        let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+        assert!(
+            output.contains(",12345.123456789012345682,0,12345.123456789012345682,"),
+            "{}",
+            output
+        );
+    }
+}
@@ -1,16 +1,39 @@
-use std::sync::{Arc, RwLock};
-
 #[cfg(feature = "async-engine")]
 use async_trait::async_trait;
 
 use crate::{
-    state::{State, UpdateError},
-    Action,
+    crash::FlightRecorder,
+    events::EventObserver,
+    persist::{Committer, GroupCommit, Journal, PersistError, Primary, StateDir},
+    progress::{ProgressReporter, ProgressTracker},
+    soak::{SoakMetrics, SoakMonitor},
+    state::{BulkLoadError, GroupError, PreparedAction, Savepoint, State, UpdateError},
+    store::{AccountStore, StoreError, TransactionStore},
+    sync::{Arc, Mutex, RwLock},
+    view::ClientView,
+    Account, Action, ActionKind, ChargebackPolicy, ClientId, DisputeWindow, LimitsPolicy,
+    OutputConfig, StatusError, Transaction, TransactionId, TransactionState,
 };
 
+/// An engine that applies actions as they're given to it.
+///
+/// The trait is object safe, so the engine can be picked at runtime and used
+/// as a `Box<dyn SyncEngine>`. Processing whole iterators is in
+/// `SyncEngineExt`, which every engine (boxed or not) gets.
 pub trait SyncEngine {
     fn process(&mut self, action: Action) -> Result<(), UpdateError>;
 
+    /// Process an action without taking it, for sources that keep their
+    /// actions (e.g. parsed batches reused between reads). Engines clone it
+    /// only if they have to.
+    fn process_ref(&mut self, action: &Action) -> Result<(), UpdateError> {
+        self.process(action.clone())
+    }
+}
+
+/// Methods for any `SyncEngine` that can't be on the trait itself without
+/// stopping it from being used as a trait object
+pub trait SyncEngineExt: SyncEngine {
     fn process_all<I: IntoIterator<Item = Action>>(
         &mut self,
         actions: I,
@@ -20,6 +43,57 @@ pub trait SyncEngine {
         }
         Ok(())
     }
+
+    /// Process borrowed actions, see `SyncEngine::process_ref`
+    fn process_all_ref<'a, I: IntoIterator<Item = &'a Action>>(
+        &mut self,
+        actions: I,
+    ) -> Result<(), UpdateError> {
+        for action in actions.into_iter() {
+            self.process_ref(action)?
+        }
+        Ok(())
+    }
+
+    /// `process_all`, giving the number of actions processed, which
+    /// includes any the engine ignored (under `EngineMode::Lenient`). When
+    /// an action is returned as rejected, the error says how many were
+    /// processed before it.
+    fn process_all_counted<I: IntoIterator<Item = Action>>(
+        &mut self,
+        actions: I,
+    ) -> Result<u64, Stopped> {
+        let mut processed = 0;
+        for action in actions.into_iter() {
+            self.process(action)
+                .map_err(|error| Stopped { processed, error })?;
+            processed += 1;
+        }
+        Ok(processed)
+    }
+}
+
+/// Where `SyncEngineExt::process_all_counted` stopped
+#[derive(Debug, thiserror::Error)]
+#[error("stopped after {processed} actions: {error}")]
+pub struct Stopped {
+    /// The actions processed before the rejected one. A strict engine stops
+    /// at the first one it rejects, so it applied all of these.
+    pub processed: u64,
+    #[source]
+    pub error: UpdateError,
+}
+
+impl<E: SyncEngine + ?Sized> SyncEngineExt for E {}
+
+impl<E: SyncEngine + ?Sized> SyncEngine for Box<E> {
+    fn process(&mut self, action: Action) -> Result<(), UpdateError> {
+        (**self).process(action)
+    }
+
+    fn process_ref(&mut self, action: &Action) -> Result<(), UpdateError> {
+        (**self).process_ref(action)
+    }
 }
 
 #[cfg(feature = "async-engine")]
@@ -29,56 +103,1090 @@ pub trait AsyncEngine {
     // async fn process_stream();
 }
 
+/// What `SyncEngine::process` does with actions the engine rejects: the
+/// lenient `Ignore` for replaying production inputs, or the strict `Return`
+/// for test and QA pipelines that should fail on the first one
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Leave the state as it was and carry on with the next action
+    #[default]
+    #[doc(alias = "lenient")]
+    Ignore,
+    /// Return the error (as `try_process` would), which stops `process_all`
+    /// at the first rejected action (see `SyncEngineExt::process_all_counted`
+    /// for how many came before it)
+    #[doc(alias = "strict")]
+    Return,
+}
+
+/// How strict an engine is with the actions it rejects, as a shorthand for
+/// its `ErrorPolicy`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EngineMode {
+    /// Fail on the first rejected action, for test and QA pipelines:
+    /// `process` returns the error, so `process_all` stops there (and
+    /// `process_all_counted` says how many actions were processed before it)
+    Strict,
+    /// Skip rejected actions, for replaying production inputs
+    #[default]
+    Lenient,
+}
+
+impl From<EngineMode> for ErrorPolicy {
+    fn from(mode: EngineMode) -> Self {
+        match mode {
+            EngineMode::Strict => Self::Return,
+            EngineMode::Lenient => Self::Ignore,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct SingleThreadedEngine {
     state: State,
+    errors: ErrorPolicy,
+    recorder: Option<FlightRecorder>,
 }
 
 impl SingleThreadedEngine {
     pub fn new() -> Self {
+        Self::default()
+    }
+    /// Create an engine that continues from an existing state (e.g. one
+    /// restored from disk)
+    pub fn from_state(state: State) -> Self {
         Self {
-            state: State::new(),
+            state,
+            errors: ErrorPolicy::Ignore,
+            recorder: None,
         }
     }
+
+    /// Choose what `process` does with rejected actions (ignoring them by
+    /// default)
+    pub fn with_error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.errors = policy;
+        self
+    }
+
+    /// Make the engine strict or lenient (the default), see `EngineMode`
+    pub fn with_mode(self, mode: EngineMode) -> Self {
+        self.with_error_policy(mode.into())
+    }
+
+    /// Keep the last actions processed (through `process` and `try_process`)
+    /// in `recorder`, for a crash report if one of them panics, see
+    /// `crash::FlightRecorder`
+    pub fn with_flight_recorder(mut self, recorder: FlightRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
     pub fn state(&self) -> &State {
         &self.state
     }
+
+    pub fn into_state(self) -> State {
+        self.state
+    }
+
+    /// A view of the engine scoped to `client`, which can only look at and
+    /// apply actions for that client, see `ClientView`
+    pub fn client_view(&mut self, client: ClientId) -> ClientView<'_> {
+        ClientView::new(self, client)
+    }
+
+    /// Change a client's status, if the account is still at `version`, see
+    /// `State::change_status_at`
+    pub fn change_status_at<F>(
+        &mut self,
+        client: ClientId,
+        version: u64,
+        transition: F,
+    ) -> Result<(), StatusError>
+    where
+        F: FnOnce(&mut Account) -> Result<(), StatusError>,
+    {
+        self.state.change_status_at(client, version, transition)
+    }
+
+    /// Hydrate a new engine from historical actions, see `State::bulk_load`
+    pub fn bulk_load<I: IntoIterator<Item = Action>>(
+        &mut self,
+        actions: I,
+    ) -> Result<u64, BulkLoadError> {
+        self.state.bulk_load(actions)
+    }
+
+    /// Process an action, returning the error if it couldn't be applied
+    /// instead of ignoring it like `process` does
+    pub fn try_process(&mut self, action: Action) -> Result<(), UpdateError> {
+        let Some(recorder) = &self.recorder else {
+            return self.state.update(action);
+        };
+        recorder.begin(&action);
+        let result = self.state.update(action);
+        recorder.finish(&self.state);
+        result
+    }
+
+    /// Process a group of actions all-or-nothing, see `State::update_atomic`
+    pub fn process_atomic(&mut self, actions: &[Action]) -> Result<(), GroupError> {
+        self.state.update_atomic(actions)
+    }
+
+    /// Mark the current state, so a batch that goes wrong part way (e.g. a
+    /// corrupted file) can be undone with `rollback_to`, see
+    /// `State::savepoint`
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.state.savepoint()
+    }
+
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StoreError> {
+        self.state.rollback_to(savepoint)
+    }
+
+    pub fn release(&mut self, savepoint: Savepoint) {
+        self.state.release(savepoint)
+    }
+
+    /// Validate a withdrawal and reserve its funds, to be finished with
+    /// `commit` or `abort`, see `State::prepare`
+    pub fn prepare(&mut self, action: Action) -> Result<PreparedAction, UpdateError> {
+        self.state.prepare(action)
+    }
+
+    pub fn commit(&mut self, prepared: PreparedAction) -> Result<(), UpdateError> {
+        self.state.commit(prepared)
+    }
+
+    pub fn abort(&mut self, prepared: PreparedAction) -> Result<(), UpdateError> {
+        self.state.abort(prepared)
+    }
+
+    /// Process a batch of actions like `process_all`, but spread over
+    /// rayon's thread pool, for replays and backfills that would otherwise
+    /// keep one core busy.
+    ///
+    /// Actions are grouped by client and each group is applied in order, so
+    /// the result is the same as `process_all`. Only an engine that hasn't
+    /// processed anything yet can do this (otherwise it fails with
+    /// `BulkLoadError::NotEmpty`), since the groups are applied to fresh
+    /// states that are merged in afterwards.
+    #[cfg(feature = "rayon")]
+    pub fn process_all_par<I>(&mut self, actions: I) -> Result<(), BulkLoadError>
+    where
+        I: IntoIterator<Item = Action>,
+    {
+        crate::parallel::process_par(&mut self.state, actions)
+    }
+
+    /// Process actions like `process_all`, counting each one (and whether it
+    /// was rejected) in `progress`
+    pub fn process_all_with_progress<I, R>(&mut self, actions: I, progress: &mut ProgressTracker<R>)
+    where
+        I: IntoIterator<Item = Action>,
+        R: ProgressReporter,
+    {
+        for action in actions {
+            let rejected = self.state.update(action).is_err();
+            progress.record(rejected);
+        }
+    }
 }
 impl SyncEngine for SingleThreadedEngine {
     fn process(&mut self, action: Action) -> Result<(), UpdateError> {
-        // Per the assignment, we'll ignore pretty much all errors here, leaving the
-        // account unchanged. A more sophisticated system would log the ignored actions
-        // on error
-        let _ = self.state.update(action);
-        Ok(())
+        // Per the assignment, we'll ignore pretty much all errors here by
+        // default, leaving the account unchanged. A more sophisticated system
+        // would log the ignored actions on error
+        self.errors.apply(self.try_process(action))
+    }
+
+    fn process_ref(&mut self, action: &Action) -> Result<(), UpdateError> {
+        let Some(recorder) = &self.recorder else {
+            return self.errors.apply(self.state.update_ref(action));
+        };
+        recorder.begin(action);
+        let result = self.state.update_ref(action);
+        recorder.finish(&self.state);
+        self.errors.apply(result)
     }
 }
 
-#[derive(Debug, Default)]
+/// An engine that can be cloned and shared between threads, with every clone
+/// applying actions to the same state
+#[derive(Debug, Default, Clone)]
 pub struct MultiThreadedEngine {
     // Realistically, if we were implementing this, we'd probably use the tokio
     // primitives
     state: Arc<RwLock<State>>,
+
+    /// Where the order actions were applied in is recorded, if anywhere
+    journal: Option<Arc<Mutex<Journal>>>,
+
+    /// Syncs the journal for batches of actions before they're acknowledged,
+    /// if enabled
+    group_commit: Option<Arc<Committer>>,
+
+    /// Where applied actions are sent on to replicas, if anywhere
+    primary: Option<Primary>,
+
+    /// Checking a sample of accounts as actions are applied, if enabled
+    soak: Option<Arc<Mutex<SoakMonitor>>>,
+
+    errors: ErrorPolicy,
 }
 
 impl MultiThreadedEngine {
     pub fn new() -> Self {
         Self {
             state: Arc::new(RwLock::new(State::new())),
+            journal: None,
+            group_commit: None,
+            primary: None,
+            soak: None,
+            errors: ErrorPolicy::Ignore,
+        }
+    }
+
+    /// Continue from an existing state (e.g. one restored from a `StateDir`, or
+    /// a promoted `Replica`'s)
+    pub fn from_state(state: State) -> Self {
+        Self {
+            state: Arc::new(RwLock::new(state)),
+            ..Self::new()
         }
     }
+
     pub fn state(&self) -> Arc<RwLock<State>> {
         self.state.clone()
     }
+
+    /// Record every processed action in `journal`, in the order they were
+    /// actually applied across all threads, so the run can be reproduced
+    /// exactly with `persist::replay_journal`.
+    ///
+    /// Only actions that go through `process` are recorded, not updates made
+    /// directly through `state`.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(Arc::new(Mutex::new(journal)));
+        self
+    }
+
+    /// Don't return from `process` (or `try_process` and `process_atomic`)
+    /// until the action is synced to the journal given to `with_journal`.
+    /// Rather than syncing once per action, threads wait up to
+    /// `config.max_delay` for others to join them, and then one sync covers
+    /// every action appended so far.
+    ///
+    /// This bounds how long each action waits, so it's worth it with many
+    /// threads processing at once. A single thread would wait out the delay
+    /// on every action, and is better off calling `flush_journal` itself.
+    pub fn with_group_commit(mut self, config: GroupCommit) -> Self {
+        self.group_commit = Some(Arc::new(Committer::new(config)));
+        self
+    }
+
+    /// Send every processed action on to the replicas of `primary`, in the
+    /// order they were applied. As with `with_journal`, only actions that go
+    /// through `process` (or `process_atomic`) are sent.
+    pub fn with_replication(mut self, primary: Primary) -> Self {
+        self.primary = Some(primary);
+        self
+    }
+
+    /// Check a sample of accounts with `monitor` as actions are processed
+    /// (see `soak`). Only actions that go through `process` (or
+    /// `process_atomic`) are seen, so the monitor should be started
+    /// `with_state` if the engine doesn't start empty.
+    pub fn with_soak(mut self, monitor: SoakMonitor) -> Self {
+        self.soak = Some(Arc::new(Mutex::new(monitor)));
+        self
+    }
+
+    /// Choose what `process` does with rejected actions (ignoring them by
+    /// default). Failures of the engine itself (e.g. of the journal) are
+    /// always returned.
+    pub fn with_error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.errors = policy;
+        self
+    }
+
+    /// Make the engine strict or lenient (the default), see `EngineMode`
+    pub fn with_mode(self, mode: EngineMode) -> Self {
+        self.with_error_policy(mode.into())
+    }
+
+    /// What the soak monitor has found so far, if there is one
+    pub fn soak_metrics(&self) -> Option<SoakMetrics> {
+        let soak = self.soak.as_ref()?;
+        Some(soak.lock().expect("poisoned!").metrics().clone())
+    }
+
+    /// Process an action, returning the error if it couldn't be applied
+    /// instead of ignoring it like `process` does. Unlike
+    /// `SingleThreadedEngine::try_process`, a withdrawal that was recorded as
+    /// failed is also an error (`UpdateError::TransactionFailed`), since the
+    /// thread that sent it can't otherwise tell whether it went through.
+    ///
+    /// Withdrawals from the same account on different threads are decided
+    /// in the order they're applied, never by how their threads interleave:
+    /// each one's balance check and debit happen together under the state's
+    /// write lock. So when two in-flight withdrawals can't both be covered,
+    /// exactly one succeeds and the other fails with
+    /// `AccountError::InsufficientFunds` (recorded as a failed transaction,
+    /// and in that order in the journal), in whichever order they arrive.
+    /// The same goes for `prepare`, since prepared withdrawals hold their
+    /// funds.
+    pub fn try_process(&self, action: Action) -> Result<(), UpdateError> {
+        self.apply(&action)?
+    }
+
+    /// Like `try_process`, but without taking the action
+    pub fn try_process_ref(&self, action: &Action) -> Result<(), UpdateError> {
+        self.apply(action)?
+    }
+
+    /// Apply an action, with the outer error for the engine failing (the
+    /// journal, replication or soak checks) and the inner one for the action
+    /// being rejected
+    fn apply(&self, action: &Action) -> Result<Result<(), UpdateError>, UpdateError> {
+        // TODO: add an error type for lock failures
+        let mut state = self.state.write().expect("poisoned!");
+        let seq = match &self.journal {
+            // Appended while holding the state lock, so the journal order is
+            // the order the actions are applied in
+            Some(journal) => Some(journal.lock().expect("poisoned!").append(action)?),
+            None => None,
+        };
+        if let Some(primary) = &self.primary {
+            primary.publish(action)?;
+        }
+        let withdrawal = (action.kind == ActionKind::Withdrawal)
+            .then_some((action.transaction_id, action.reference.as_deref()));
+        let result = match &self.soak {
+            Some(soak) => {
+                let mut soak = soak.lock().expect("poisoned!");
+                soak.before(action);
+                let result = state.update_ref(action);
+                soak.observe(&state)?;
+                result
+            }
+            None => state.update_ref(action),
+        };
+        let outcome = match (result, withdrawal) {
+            (Ok(()), Some((id, reference))) => withdrawal_outcome(&state, id, reference),
+            (result, _) => result,
+        };
+        // Other threads can go ahead while this one waits for the sync
+        drop(state);
+        self.wait_durable(seq)?;
+        Ok(outcome)
+    }
+
+    /// Wait for the journal entry `seq` to be synced, with group commit
+    fn wait_durable(&self, seq: Option<u64>) -> Result<(), PersistError> {
+        match (&self.group_commit, &self.journal, seq) {
+            (Some(committer), Some(journal), Some(seq)) => committer.wait(seq, journal),
+            _ => Ok(()),
+        }
+    }
+
+    /// Process a group of actions all-or-nothing, see `State::update_atomic`.
+    ///
+    /// No other thread can see the group part way through. With a journal,
+    /// the group is only recorded if it was applied.
+    pub fn process_atomic(&self, actions: &[Action]) -> Result<(), GroupError> {
+        let mut state = self.state.write().expect("poisoned!");
+        state.update_atomic(actions)?;
+        let mut seq = None;
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.lock().expect("poisoned!");
+            for (index, action) in actions.iter().enumerate() {
+                seq = Some(journal.append(action).map_err(|e| GroupError {
+                    index,
+                    source: e.into(),
+                })?);
+            }
+        }
+        if let Some(primary) = &self.primary {
+            for (index, action) in actions.iter().enumerate() {
+                primary.publish(action).map_err(|e| GroupError {
+                    index,
+                    source: e.into(),
+                })?;
+            }
+        }
+        if let Some(soak) = &self.soak {
+            let mut soak = soak.lock().expect("poisoned!");
+            for (index, action) in actions.iter().enumerate() {
+                soak.before(action);
+                soak.observe(&state).map_err(|e| GroupError {
+                    index,
+                    source: e.into(),
+                })?;
+            }
+        }
+        drop(state);
+        self.wait_durable(seq).map_err(|e| GroupError {
+            index: actions.len().saturating_sub(1),
+            source: e.into(),
+        })
+    }
+
+    /// Validate a withdrawal and reserve its funds, to be finished with
+    /// `commit` or `abort`, see `State::prepare`.
+    ///
+    /// The three steps aren't journalled or sent to replicas. Prepared
+    /// withdrawals are kept in checkpoints, but anything prepared or
+    /// committed since the last one is lost on a restore, so checkpoint
+    /// after each commit (or abort) that has to survive a restart.
+    pub fn prepare(&self, action: Action) -> Result<PreparedAction, UpdateError> {
+        self.state.write().expect("poisoned!").prepare(action)
+    }
+
+    pub fn commit(&self, prepared: PreparedAction) -> Result<(), UpdateError> {
+        self.state.write().expect("poisoned!").commit(prepared)
+    }
+
+    pub fn abort(&self, prepared: PreparedAction) -> Result<(), UpdateError> {
+        self.state.write().expect("poisoned!").abort(prepared)
+    }
+
+    /// How many syncs group commit has made so far (fewer syncs per action
+    /// means bigger batches), if it's enabled
+    pub fn group_commit_syncs(&self) -> Option<u64> {
+        self.group_commit
+            .as_ref()
+            .map(|committer| committer.syncs())
+    }
+
+    /// Make sure everything recorded so far is on disk
+    pub fn flush_journal(&self) -> Result<(), PersistError> {
+        match &self.journal {
+            Some(journal) => journal.lock().expect("poisoned!").flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Snapshot the state to `dir` and clear the journal, see
+    /// `StateDir::checkpoint`. The journal given to `with_journal` must be
+    /// the one `dir` was restored with. Without a journal, there's nothing
+    /// to checkpoint and nothing is written.
+    ///
+    /// Actions wait for the checkpoint to finish, so none fall between the
+    /// snapshot and the cleared journal.
+    pub fn checkpoint(&self, dir: &StateDir) -> Result<(), PersistError> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let state = self.state.write().expect("poisoned!");
+        let mut journal = journal.lock().expect("poisoned!");
+        dir.checkpoint(&state, &mut journal)
+    }
 }
 
 impl SyncEngine for MultiThreadedEngine {
     fn process(&mut self, action: Action) -> Result<(), UpdateError> {
-        // TODO: add an error type for lock failures
-        let mut state = self.state.write().expect("poisoned!");
-        let _ = state.update(action);
-        Ok(())
+        // As with the single threaded engine, rejected actions are ignored
+        // by default
+        self.errors.apply(self.apply(&action)?)
+    }
+
+    fn process_ref(&mut self, action: &Action) -> Result<(), UpdateError> {
+        self.errors.apply(self.apply(action)?)
+    }
+}
+
+impl ErrorPolicy {
+    fn apply(self, result: Result<(), UpdateError>) -> Result<(), UpdateError> {
+        match self {
+            Self::Ignore => Ok(()),
+            Self::Return => result,
+        }
+    }
+}
+
+/// Configures a new engine, so the state's settings don't each need their
+/// own constructor.
+///
+/// Every setting starts off as it is on a new `State` (no policies, and
+/// rejected actions ignored), and the engine is made with `build` (or
+/// `build_multi_threaded`, or just the state with `into_state`).
+#[derive(Debug, Default)]
+pub struct EngineBuilder {
+    state: State,
+    errors: ErrorPolicy,
+}
+
+impl EngineBuilder {
+    /// Start from an empty state kept in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from an empty state that keeps its records in the given stores,
+    /// see `State::with_stores`
+    pub fn with_stores<A, T>(accounts: A, transactions: T) -> Self
+    where
+        A: AccountStore + 'static,
+        T: TransactionStore + 'static,
+    {
+        Self::from_state(State::with_stores(accounts, transactions))
+    }
+
+    /// Carry on from an existing state (e.g. one restored from disk), with
+    /// any settings it already has
+    pub fn from_state(state: State) -> Self {
+        Self {
+            state,
+            errors: ErrorPolicy::Ignore,
+        }
+    }
+
+    /// Make room for this many accounts and transactions up front, see
+    /// `State::reserve`
+    pub fn with_capacity(mut self, accounts: usize, transactions: usize) -> Self {
+        self.state.reserve(accounts, transactions);
+        self
+    }
+
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.errors = policy;
+        self
+    }
+
+    /// Build a strict or lenient (the default) engine, see `EngineMode`
+    pub fn mode(self, mode: EngineMode) -> Self {
+        self.error_policy(mode.into())
+    }
+
+    pub fn dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.state.set_dispute_window(Some(window));
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsPolicy) -> Self {
+        self.state.set_limits(Some(limits));
+        self
+    }
+
+    pub fn chargeback_policy(mut self, policy: ChargebackPolicy) -> Self {
+        self.state.set_chargeback_policy(Some(policy));
+        self
+    }
+
+    pub fn max_open_disputes(mut self, max: usize) -> Self {
+        self.state.set_max_open_disputes(Some(max));
+        self
+    }
+
+    pub fn currency<S: Into<String>>(mut self, currency: S) -> Self {
+        self.state.set_currency(Some(currency.into()));
+        self
+    }
+
+    pub fn observer<O: EventObserver + 'static>(mut self, observer: O) -> Self {
+        self.state.set_observer(observer);
+        self
+    }
+
+    pub fn audit_trail(mut self, enabled: bool) -> Self {
+        self.state.set_audit_trail(enabled);
+        self
+    }
+
+    pub fn running_balances(mut self, enabled: bool) -> Self {
+        self.state.set_running_balances(enabled);
+        self
+    }
+
+    pub fn output(mut self, output: OutputConfig) -> Self {
+        self.state.set_output(output);
+        self
+    }
+
+    pub fn build(self) -> SingleThreadedEngine {
+        SingleThreadedEngine::from_state(self.state).with_error_policy(self.errors)
+    }
+
+    /// Build a `MultiThreadedEngine`, which can then be given a journal,
+    /// replicas or a soak monitor with its own `with_*` methods
+    pub fn build_multi_threaded(self) -> MultiThreadedEngine {
+        MultiThreadedEngine::from_state(self.state).with_error_policy(self.errors)
+    }
+
+    /// Just the configured state, e.g. to hand to an `EngineHandle`. The
+    /// error policy is left behind.
+    pub fn into_state(self) -> State {
+        self.state
+    }
+}
+
+/// Whether an applied withdrawal was recorded as failed. A withdrawal
+/// without an id is found by its reference.
+pub(crate) fn withdrawal_outcome(
+    state: &State,
+    id: Option<TransactionId>,
+    reference: Option<&str>,
+) -> Result<(), UpdateError> {
+    let id = match (id, reference) {
+        (Some(id), _) => id,
+        (None, Some(reference)) => match state.transaction_for_reference(reference) {
+            Some(id) => id,
+            None => return Ok(()),
+        },
+        (None, None) => return Ok(()),
+    };
+    match state.transaction(id)? {
+        Some(Transaction {
+            state: TransactionState::Failed(error),
+            ..
+        }) => Err(UpdateError::TransactionFailed {
+            transaction: id,
+            error,
+        }),
+        _ => Ok(()),
     }
 }
 
 // TODO: impl AsyncEngine for MultiThreadedEngine
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread};
+
+    use super::*;
+    use crate::{
+        io::CsvSource, persist, AccountError, Amount, ClientId, RawClientId, RawTransactionId,
+    };
+
+    fn withdrawal(client: RawClientId, transaction: RawTransactionId, amount: u32) -> Action {
+        Action {
+            transaction_id: Some(TransactionId(transaction)),
+            client_id: ClientId(client),
+            kind: ActionKind::Withdrawal,
+            amount: Some(Amount::from(amount)),
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+
+    /// Fund `clients` accounts with 10 each, then have `threads` threads all
+    /// try to withdraw 6 from every one of them at once
+    fn race<F>(
+        clients: RawClientId,
+        threads: u32,
+        withdraw: F,
+    ) -> (MultiThreadedEngine, Vec<Vec<bool>>)
+    where
+        F: Fn(&MultiThreadedEngine, Action) -> Result<(), UpdateError> + Send + Sync + 'static,
+    {
+        let engine = MultiThreadedEngine::new();
+        for client in 0..clients {
+            let mut deposit = withdrawal(client, RawTransactionId::from(client) + 1, 10);
+            deposit.kind = ActionKind::Deposit;
+            engine.try_process(deposit).unwrap();
+        }
+
+        let withdraw = Arc::new(withdraw);
+        let barrier = Arc::new(Barrier::new(threads as usize));
+        let handles: Vec<_> = (1..=RawTransactionId::from(threads))
+            .map(|thread| {
+                let (engine, withdraw, barrier) =
+                    (engine.clone(), withdraw.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    (0..clients)
+                        .map(|client| {
+                            let id = thread * 100_000 + RawTransactionId::from(client);
+                            match withdraw(&engine, withdrawal(client, id, 6)) {
+                                Ok(()) => true,
+                                Err(UpdateError::TransactionFailed {
+                                    error: AccountError::InsufficientFunds,
+                                    ..
+                                }) => false,
+                                Err(e) => panic!("unexpected error: {}", e),
+                            }
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        let outcomes = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        (engine, outcomes)
+    }
+
+    #[test]
+    fn test_racing_withdrawals_cannot_double_spend() {
+        let (clients, threads) = (200, 4);
+        let (engine, outcomes) = race(clients, threads, |engine, action| {
+            engine.try_process(action)
+        });
+
+        let state = engine.state();
+        let state = state.read().unwrap();
+        for client in 0..clients {
+            let succeeded = outcomes.iter().filter(|o| o[client as usize]).count();
+            assert_eq!(succeeded, 1, "client {}", client);
+            let account = state.account(ClientId(client)).unwrap();
+            assert_eq!(account.available, Amount::from(4u32));
+        }
+        // Every loser is recorded as failed
+        let failed = clients as usize * (threads as usize - 1);
+        assert_eq!(state.failed_transactions().count(), failed);
+    }
+
+    #[test]
+    fn test_racing_prepared_withdrawals_cannot_double_spend() {
+        let (clients, threads) = (200, 4);
+        let (engine, outcomes) = race(clients, threads, |engine, action| {
+            engine.prepare(action).map(|_| ())
+        });
+
+        let state = engine.state();
+        let state = state.read().unwrap();
+        for client in 0..clients {
+            let succeeded = outcomes.iter().filter(|o| o[client as usize]).count();
+            assert_eq!(succeeded, 1, "client {}", client);
+            let account = state.account(ClientId(client)).unwrap();
+            assert_eq!(account.held, Amount::from(6u32));
+            assert_eq!(account.available, Amount::from(4u32));
+        }
+        assert_eq!(state.prepared().count(), clients as usize);
+    }
+
+    #[test]
+    fn test_engines_as_trait_objects() {
+        let input = "type,client,tx,amount\ndeposit,1,1,3.0\nwithdrawal,1,2,1.0\n";
+        for threaded in [false, true] {
+            let mut engine: Box<dyn SyncEngine> = if threaded {
+                Box::new(MultiThreadedEngine::new())
+            } else {
+                Box::new(SingleThreadedEngine::new())
+            };
+            let actions = CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+            engine.process_all(actions).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_process_by_reference() {
+        let input = "type,client,tx,amount,ref\n\
+            deposit,1,1,3.0,\n\
+            deposit,1,,2.0,upstream-a\n\
+            withdrawal,1,3,1.0,\n\
+            dispute,1,,,upstream-a\n\
+            withdrawal,1,4,9.0,\n";
+        let actions: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        let mut expected = SingleThreadedEngine::new();
+        expected.process_all(actions.clone()).unwrap();
+        let expected = format!("{:?}", expected.state().account(ClientId(1)).unwrap());
+
+        let mut single = SingleThreadedEngine::new();
+        single.process_all_ref(&actions).unwrap();
+        let mut multi: Box<dyn SyncEngine> = Box::new(MultiThreadedEngine::new());
+        multi.process_all_ref(&actions).unwrap();
+        // The batch is still there to be used again
+        assert_eq!(actions[1].transaction_id, None);
+        assert_eq!(
+            format!("{:?}", single.state().account(ClientId(1)).unwrap()),
+            expected
+        );
+        #[cfg(feature = "concurrent-engine")]
+        {
+            let mut concurrent = crate::ConcurrentEngine::new(2);
+            concurrent.process_all_ref(&actions).unwrap();
+            assert_eq!(
+                format!("{:?}", concurrent.account(ClientId(1)).unwrap()),
+                expected
+            );
+        }
+
+        let multi = MultiThreadedEngine::new();
+        for action in &actions[..4] {
+            multi.try_process_ref(action).unwrap();
+        }
+        assert!(matches!(
+            multi.try_process_ref(&actions[4]),
+            Err(UpdateError::TransactionFailed { .. })
+        ));
+        let state = multi.state();
+        let account = state.read().unwrap().account(ClientId(1)).unwrap();
+        assert_eq!(format!("{:?}", account), expected);
+        assert_eq!(account.held, Amount::from(2u32));
+    }
+
+    #[test]
+    fn test_engine_builder() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,3.0\n\
+            deposit,1,2,1.0\n\
+            withdrawal,1,3,0.5\n\
+            dispute,1,1,\n\
+            dispute,1,2,\n\
+            deposit,1,4,1.0\n";
+        let actions = || CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut engine = EngineBuilder::new()
+            .with_capacity(1, 4)
+            .chargeback_policy(crate::ChargebackPolicy::Strict)
+            .max_open_disputes(1)
+            .observer(sender)
+            .error_policy(ErrorPolicy::Return)
+            .build();
+        assert_eq!(engine.state().max_open_disputes(), Some(1));
+
+        // The second dispute is over the limit, which stops the run there
+        assert!(matches!(
+            engine.process_all(actions()),
+            Err(UpdateError::DisputeLimitReached { .. })
+        ));
+        assert!(engine
+            .state()
+            .transaction(TransactionId(4))
+            .unwrap()
+            .is_none());
+        let events: Vec<_> = receiver
+            .try_iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].clone())
+            .collect();
+        assert_eq!(events, ["opened", "funds_held", "dispute_refused"]);
+
+        // Ignored by default, as with `new`
+        let mut engine = EngineBuilder::new()
+            .max_open_disputes(1)
+            .build_multi_threaded();
+        engine.process_all(actions()).unwrap();
+        let state = engine.state();
+        let account = state.read().unwrap().account(ClientId(1)).unwrap();
+        assert_eq!(account.held, Amount::from(3u32));
+        assert_eq!(account.available, Amount::from(3u32) / Amount::from(2u32));
+    }
+
+    #[test]
+    fn test_process_all_counted() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,3.0\n\
+            deposit,1,1,1.0\n\
+            withdrawal,1,2,0.5\n";
+        let actions = || CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+
+        let mut strict = EngineBuilder::new().mode(EngineMode::Strict).build();
+        let stopped = strict.process_all_counted(actions()).unwrap_err();
+        assert_eq!(stopped.processed, 1);
+        assert!(matches!(stopped.error, UpdateError::TransactionUsed(_)));
+        assert!(strict
+            .state()
+            .transaction(TransactionId(2))
+            .unwrap()
+            .is_none());
+
+        let mut lenient = SingleThreadedEngine::new();
+        assert_eq!(lenient.process_all_counted(actions()).unwrap(), 3);
+
+        let mut strict = SingleThreadedEngine::new().with_mode(EngineMode::Strict);
+        assert!(matches!(
+            strict.process_all(actions()),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+        let mut strict = MultiThreadedEngine::new().with_mode(EngineMode::Strict);
+        assert!(strict
+            .process_all_ref(&actions().collect::<Vec<_>>())
+            .is_err());
+    }
+
+    #[test]
+    fn test_journal_records_apply_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("journal.jsonl");
+        let engine = MultiThreadedEngine::new().with_journal(Journal::create(&path).unwrap());
+
+        // Every thread works on the same account, so which withdrawals fail
+        // depends on how the threads interleave
+        let threads: Vec<_> = (0..4u32)
+            .map(|thread| {
+                let mut engine = engine.clone();
+                thread::spawn(move || {
+                    let mut input = String::from("type,client,tx,amount\n");
+                    for i in 0..50 {
+                        let tx = thread * 100 + i * 2;
+                        input += &format!("deposit,1,{},1.0\nwithdrawal,1,{},1.5\n", tx, tx + 1);
+                    }
+                    let actions = CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+                    engine.process_all(actions).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        engine.flush_journal().unwrap();
+
+        let mut replayed = State::new();
+        assert_eq!(persist::replay_journal(&path, &mut replayed).unwrap(), 400);
+
+        let state = engine.state();
+        let state = state.read().unwrap();
+        let failed = |state: &State| {
+            let mut ids: Vec<_> = state.failed_transactions().map(|t| t.id).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(failed(&replayed), failed(&state));
+        assert_eq!(
+            format!("{:?}", replayed.account(crate::ClientId(1))),
+            format!("{:?}", state.account(crate::ClientId(1)))
+        );
+    }
+
+    #[test]
+    fn test_group_commit_syncs_batches() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("journal.jsonl");
+        let engine = MultiThreadedEngine::new()
+            .with_journal(Journal::create(&path).unwrap())
+            .with_group_commit(GroupCommit {
+                max_actions: 8,
+                max_delay: std::time::Duration::from_millis(50),
+            });
+
+        let threads: Vec<_> = (0..RawTransactionId::from(8u8))
+            .map(|thread| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for i in 0..10 {
+                        let mut deposit = withdrawal(1, thread * 100 + i, 1);
+                        deposit.kind = ActionKind::Deposit;
+                        engine.try_process(deposit).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every acknowledged action is already in the file, without a flush
+        let mut replayed = State::new();
+        assert_eq!(persist::replay_journal(&path, &mut replayed).unwrap(), 80);
+        let syncs = engine.group_commit_syncs().unwrap();
+        assert!((1..80).contains(&syncs), "{}", syncs);
+    }
+}
+
+// Model checked with loom, run with:
+// RUSTFLAGS="--cfg transaction_engine_loom" cargo test --release --lib loom
+#[cfg(all(test, transaction_engine_loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::{MultiThreadedEngine, SyncEngine, SyncEngineExt};
+    use crate::{
+        AccountData, Action, ActionKind, Amount, ClientId, RawTransactionId, TransactionId,
+    };
+
+    fn action(kind: ActionKind, transaction: RawTransactionId, amount: Option<u32>) -> Action {
+        Action {
+            transaction_id: Some(TransactionId(transaction)),
+            client_id: ClientId(1),
+            kind,
+            amount: amount.map(Amount::from),
+            timestamp: None,
+            reverses: None,
+        }
+    }
+
+    fn account(engine: &MultiThreadedEngine) -> AccountData {
+        let state = engine.state();
+        let state = state.read().unwrap();
+        state.account(ClientId(1)).expect("no account!")
+    }
+
+    /// Run `actions` on their own thread against a shared engine
+    fn spawn(engine: &MultiThreadedEngine, actions: Vec<Action>) -> thread::JoinHandle<()> {
+        let mut engine = engine.clone();
+        thread::spawn(move || engine.process_all(actions).unwrap())
+    }
+
+    #[test]
+    fn loom_deposit_races_chargeback() {
+        loom::model(|| {
+            let mut engine = MultiThreadedEngine::new();
+            engine
+                .process(action(ActionKind::Deposit, 1, Some(10)))
+                .unwrap();
+
+            let deposit = spawn(&engine, vec![action(ActionKind::Deposit, 2, Some(5))]);
+            let chargeback = spawn(
+                &engine,
+                vec![
+                    action(ActionKind::Dispute, 1, None),
+                    action(ActionKind::Chargeback, 1, None),
+                ],
+            );
+            deposit.join().unwrap();
+            chargeback.join().unwrap();
+
+            // The second deposit either lands before the lock or is refused
+            let account = account(&engine);
+            assert!(account.locked);
+            assert_eq!(account.held, Amount::from(0u32));
+            assert_eq!(account.available, account.total);
+            assert!(account.total == Amount::from(0u32) || account.total == Amount::from(5u32));
+        });
+    }
+
+    #[test]
+    fn loom_withdrawals_cannot_double_spend() {
+        loom::model(|| {
+            let mut engine = MultiThreadedEngine::new();
+            engine
+                .process(action(ActionKind::Deposit, 1, Some(10)))
+                .unwrap();
+
+            let first = spawn(&engine, vec![action(ActionKind::Withdrawal, 2, Some(6))]);
+            let second = spawn(&engine, vec![action(ActionKind::Withdrawal, 3, Some(6))]);
+            first.join().unwrap();
+            second.join().unwrap();
+
+            let account = account(&engine);
+            assert_eq!(account.available, Amount::from(4u32));
+
+            let state = engine.state();
+            assert_eq!(state.read().unwrap().failed_transactions().count(), 1);
+        });
+    }
+
+    #[test]
+    fn loom_dispute_races_withdrawal() {
+        loom::model(|| {
+            let mut engine = MultiThreadedEngine::new();
+            engine
+                .process(action(ActionKind::Deposit, 1, Some(10)))
+                .unwrap();
+
+            let withdrawal = spawn(&engine, vec![action(ActionKind::Withdrawal, 2, Some(6))]);
+            let dispute = spawn(&engine, vec![action(ActionKind::Dispute, 1, None)]);
+            withdrawal.join().unwrap();
+            dispute.join().unwrap();
+
+            // Whichever runs first leaves too little for the other
+            let account = account(&engine);
+            assert_eq!(account.total, account.available + account.held);
+            if account.held == Amount::from(0u32) {
+                assert_eq!(account.available, Amount::from(4u32));
+            } else {
+                assert_eq!(account.held, Amount::from(10u32));
+                assert_eq!(account.available, Amount::from(0u32));
+            }
+        });
+    }
+}
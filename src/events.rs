@@ -0,0 +1,333 @@
+//! Typed events for each step of a dispute, for change data capture
+//!
+//! An observer given to `State::set_observer` is told about every step of a
+//! dispute as it's applied, with the amount moved and the account's balances
+//! afterwards, so downstream accounting systems can book each step directly
+//! rather than working it out from before and after snapshots of the state.
+//! It's also told when a dispute is refused for going over the open dispute
+//! limit, when an account is frozen with `State::freeze_until`, and when that
+//! freeze lifts by itself.
+//!
+//! Observers can also ask for every account write with `account_changed`,
+//! which is given the account as it would be output, so pushing live balances
+//! (see `push`) doesn't need a query after every action.
+//!
+//! Steps inside a savepoint (including `State::update_atomic` groups) are
+//! only sent once every open savepoint has been released, and are dropped if
+//! they're rolled back.
+
+use std::sync::mpsc::Sender;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    persist::exact_amount, Account, AccountData, Amount, ClientId, FreezeReason, LockExpiry,
+    Timestamp, TransactionId,
+};
+
+/// One step of a dispute's lifecycle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DisputeEvent {
+    /// A deposit was disputed. Always followed by `FundsHeld`.
+    Opened {
+        client: ClientId,
+        transaction: TransactionId,
+        #[serde(with = "exact_amount")]
+        amount: Amount,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at: Option<Timestamp>,
+    },
+
+    /// The disputed amount was moved from the available to the held balance
+    FundsHeld {
+        client: ClientId,
+        transaction: TransactionId,
+        #[serde(with = "exact_amount")]
+        amount: Amount,
+        balances: Balances,
+    },
+
+    /// The dispute was settled in the client's favour, and the held amount
+    /// returned to the available balance
+    Resolved {
+        client: ClientId,
+        transaction: TransactionId,
+        #[serde(with = "exact_amount")]
+        amount: Amount,
+        balances: Balances,
+    },
+
+    /// The held amount was taken out of the account, which is frozen
+    ChargedBack {
+        client: ClientId,
+        transaction: TransactionId,
+        #[serde(with = "exact_amount")]
+        amount: Amount,
+        balances: Balances,
+    },
+
+    /// A settled deposit or withdrawal was undone by a reversal transaction.
+    /// The amount is what the reversal moved: negative for a deposit, positive
+    /// for a withdrawal.
+    Reversed {
+        client: ClientId,
+        /// The reversal's own transaction
+        transaction: TransactionId,
+        /// The transaction it undid
+        reverses: TransactionId,
+        #[serde(with = "exact_amount")]
+        amount: Amount,
+        balances: Balances,
+    },
+
+    /// A dispute was rejected because the client already had as many open as
+    /// `State::set_max_open_disputes` allows
+    DisputeRefused {
+        client: ClientId,
+        transaction: TransactionId,
+        /// The client's open disputes
+        open: usize,
+    },
+
+    /// An account was frozen until `expiry`
+    Locked {
+        client: ClientId,
+        reason: FreezeReason,
+        expiry: LockExpiry,
+    },
+
+    /// A temporary freeze ran out, and the account was unfrozen before the
+    /// action at `at` was applied
+    Unlocked { client: ClientId, at: Timestamp },
+}
+
+impl DisputeEvent {
+    /// The client whose account the event is about
+    pub fn client(&self) -> ClientId {
+        match self {
+            DisputeEvent::Opened { client, .. }
+            | DisputeEvent::FundsHeld { client, .. }
+            | DisputeEvent::Resolved { client, .. }
+            | DisputeEvent::ChargedBack { client, .. }
+            | DisputeEvent::Reversed { client, .. }
+            | DisputeEvent::DisputeRefused { client, .. }
+            | DisputeEvent::Locked { client, .. }
+            | DisputeEvent::Unlocked { client, .. } => *client,
+        }
+    }
+}
+
+/// An account's balances just after an event
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Balances {
+    #[serde(with = "exact_amount")]
+    pub available: Amount,
+    #[serde(with = "exact_amount")]
+    pub held: Amount,
+    #[serde(with = "exact_amount")]
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl From<&Account> for Balances {
+    fn from(account: &Account) -> Self {
+        Self {
+            available: account.available_funds(),
+            held: account.held_funds(),
+            total: account.total_funds(),
+            locked: account.is_locked(),
+        }
+    }
+}
+
+/// Receives dispute events. Implemented for closures taking a
+/// `&DisputeEvent`, and for channel senders (which are sent a copy, and
+/// ignore a closed receiver).
+pub trait EventObserver: Send + Sync {
+    fn observe(&mut self, event: &DisputeEvent);
+
+    /// Called with an account each time it's written, which can be several
+    /// times for one action. Ignored unless implemented.
+    fn account_changed(&mut self, _account: &AccountData) {}
+}
+
+impl<F: FnMut(&DisputeEvent) + Send + Sync> EventObserver for F {
+    fn observe(&mut self, event: &DisputeEvent) {
+        self(event)
+    }
+}
+
+impl EventObserver for Sender<DisputeEvent> {
+    fn observe(&mut self, event: &DisputeEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// The observer given to a `State`, if any, and the events held back by open
+/// savepoints
+#[derive(Default)]
+pub(crate) struct Events {
+    observer: Option<Box<dyn EventObserver>>,
+    pending: Vec<Notice>,
+}
+
+/// Something held back for the observer
+enum Notice {
+    Event(DisputeEvent),
+    Account(AccountData),
+}
+
+impl Events {
+    pub(crate) fn set_observer(&mut self, observer: Option<Box<dyn EventObserver>>) {
+        self.observer = observer;
+    }
+
+    pub(crate) fn is_observed(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    /// Send an event now, or hold it back until the savepoints are released
+    pub(crate) fn emit(&mut self, event: DisputeEvent, in_savepoint: bool) {
+        self.notify(Notice::Event(event), in_savepoint);
+    }
+
+    /// Send an account that's been written, or hold it back the same way
+    pub(crate) fn emit_account(&mut self, account: AccountData, in_savepoint: bool) {
+        self.notify(Notice::Account(account), in_savepoint);
+    }
+
+    fn notify(&mut self, notice: Notice, in_savepoint: bool) {
+        let Some(observer) = &mut self.observer else {
+            return;
+        };
+        if in_savepoint {
+            self.pending.push(notice);
+        } else {
+            notice.send(observer.as_mut());
+        }
+    }
+
+    /// How many events are held back, to `truncate` to on a rollback
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.pending.truncate(len);
+    }
+
+    /// Send everything held back, once no savepoints are open
+    pub(crate) fn flush(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(observer) = &mut self.observer {
+            for notice in pending {
+                notice.send(observer.as_mut());
+            }
+        }
+    }
+}
+
+impl Notice {
+    fn send(self, observer: &mut dyn EventObserver) {
+        match self {
+            Notice::Event(event) => observer.observe(&event),
+            Notice::Account(account) => observer.account_changed(&account),
+        }
+    }
+}
+
+impl std::fmt::Debug for Events {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Events")
+            .field("observed", &self.is_observed())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{io::CsvSource, Action, State};
+
+    fn actions(input: &str) -> Vec<Action> {
+        let input = format!("type,client,tx,amount,reverses\n{}", input);
+        CsvSource::from_reader(input.as_bytes())
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_dispute_lifecycle_events() {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = State::new();
+        state.set_observer(sender);
+
+        let setup = actions("deposit,1,1,10,\ndeposit,1,2,4,\ndeposit,2,3,7,\n");
+        for action in setup {
+            state.update(action).unwrap();
+        }
+        // Rolled back groups don't send anything
+        let group = actions("dispute,2,3,,\nwithdrawal,2,4,100,\n");
+        state.update_atomic(&group).unwrap_err();
+        assert!(receiver.try_recv().is_err());
+
+        let lifecycle = actions(
+            "dispute,1,1,,\nresolve,1,1,,\nreversal,1,5,,2\ndispute,1,1,,\nchargeback,1,1,,\n",
+        );
+        for action in lifecycle {
+            state.update(action).unwrap();
+        }
+        let events: Vec<_> = receiver.try_iter().collect();
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].clone())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "opened",
+                "funds_held",
+                "resolved",
+                "reversed",
+                "opened",
+                "funds_held",
+                "charged_back"
+            ]
+        );
+
+        let amount = |amount: u32| Amount::from(amount);
+        assert_eq!(
+            events[3],
+            DisputeEvent::Reversed {
+                client: ClientId(1),
+                transaction: TransactionId(5),
+                reverses: TransactionId(2),
+                amount: -amount(4),
+                balances: Balances {
+                    available: amount(10),
+                    held: amount(0),
+                    total: amount(10),
+                    locked: false,
+                },
+            }
+        );
+        assert_eq!(
+            events[6],
+            DisputeEvent::ChargedBack {
+                client: ClientId(1),
+                transaction: TransactionId(1),
+                amount: amount(10),
+                balances: Balances {
+                    available: amount(0),
+                    held: amount(0),
+                    total: amount(0),
+                    locked: true,
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,7 @@
+//! Exporting a whole state to other tools, each behind a feature of its own
+//!
+//! Unlike the account outputs in `io`, exports include transactions and the
+//! audit trail, so results can be queried without replaying anything.
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
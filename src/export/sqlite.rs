@@ -0,0 +1,340 @@
+//! Writing a state's accounts, transactions and audit trail to a SQLite
+//! database, for querying results with SQL
+//!
+//! The schema is stable: new columns and tables may be added, but existing
+//! ones won't be renamed or change meaning without bumping
+//! `SCHEMA_VERSION`, which is kept in the `meta` table.
+//!
+//! ```sql
+//! CREATE TABLE accounts (
+//!     client INTEGER PRIMARY KEY,
+//!     available TEXT NOT NULL,
+//!     held TEXT NOT NULL,
+//!     total TEXT NOT NULL,
+//!     locked INTEGER NOT NULL,         -- 0 or 1
+//!     status TEXT NOT NULL,            -- active, frozen, dormant or closed
+//!     freeze_reason TEXT,
+//!     version INTEGER NOT NULL         -- see `Account::version`
+//! );
+//! CREATE TABLE transactions (
+//!     id INTEGER PRIMARY KEY,
+//!     client INTEGER NOT NULL,
+//!     state TEXT NOT NULL,             -- succeeded, failed, disputed,
+//!                                      -- cancelled or reversed
+//!     failure TEXT,                    -- why it failed, if it did
+//!     reversed_by INTEGER,
+//!     amount TEXT NOT NULL,
+//!     timestamp INTEGER,
+//!     reverses INTEGER,
+//!     reference TEXT,
+//!     source TEXT,                     -- where its action came from, and
+//!     record INTEGER                   -- its record there (see `ActionMeta`)
+//! );
+//! CREATE TABLE audit_log (
+//!     client INTEGER NOT NULL,
+//!     position INTEGER NOT NULL,       -- the entry's place in the client's trail
+//!     at INTEGER NOT NULL,
+//!     event TEXT NOT NULL,             -- action, status_changed or restated
+//!     kind TEXT,                       -- for actions, e.g. deposit
+//!     transaction_id INTEGER,
+//!     amount TEXT,
+//!     outcome TEXT,                    -- for actions: applied, failed or rejected
+//!     source TEXT,                     -- for actions, as in transactions
+//!     record INTEGER,
+//!     entry TEXT NOT NULL,             -- the whole entry as JSON
+//!     PRIMARY KEY (client, position)
+//! );
+//! -- schema_version, exported_at, sequence and (if set) currency
+//! CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+//! ```
+//!
+//! Amounts are written as decimal strings so none are rounded by SQLite's
+//! floats. Cast them to compare or sum, e.g.
+//! `SELECT SUM(CAST(total AS REAL)) FROM accounts`. Timestamps are seconds
+//! since the Unix epoch. Account balances are rounded by the state's
+//! `OutputConfig`, as in every other account output; transaction amounts are
+//! as recorded.
+
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::{
+    audit::{AuditEvent, Outcome},
+    store::StoreError,
+    ActionKind, Amount, State, Timestamp, TransactionState,
+};
+
+/// The version of the schema written, in the `meta` table as
+/// `schema_version`
+pub const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        freeze_reason TEXT,
+        version INTEGER NOT NULL
+    );
+    CREATE TABLE transactions (
+        id INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        failure TEXT,
+        reversed_by INTEGER,
+        amount TEXT NOT NULL,
+        timestamp INTEGER,
+        reverses INTEGER,
+        reference TEXT,
+        source TEXT,
+        record INTEGER
+    );
+    CREATE INDEX transactions_client ON transactions (client);
+    CREATE TABLE audit_log (
+        client INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        at INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        kind TEXT,
+        transaction_id INTEGER,
+        amount TEXT,
+        outcome TEXT,
+        source TEXT,
+        record INTEGER,
+        entry TEXT NOT NULL,
+        PRIMARY KEY (client, position)
+    );
+    CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SqliteError {
+    #[error("couldn't replace the existing file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// Write every account and transaction in `state`, and its audit trail (if
+/// it was kept, see `State::set_audit_trail`), to a new SQLite database at
+/// `path`. A file already at `path` is replaced.
+///
+/// Everything is written in one SQLite transaction, so a failed export
+/// leaves an empty database rather than a partial one.
+pub fn write<P: AsRef<Path>>(state: &State, path: P) -> Result<(), SqliteError> {
+    let path = path.as_ref();
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut connection = Connection::open(path)?;
+    let export = connection.transaction()?;
+    export.execute_batch(SCHEMA)?;
+
+    let mut insert = export.prepare(
+        "INSERT INTO accounts
+            (client, available, held, total, locked, status, freeze_reason, version)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for account in state.accounts() {
+        insert.execute(params![
+            account.client.0,
+            account.available.to_string(),
+            account.held.to_string(),
+            account.total.to_string(),
+            account.locked,
+            account.status.name(),
+            account.freeze_reason,
+            account.version,
+        ])?;
+    }
+    drop(insert);
+
+    let mut insert = export.prepare(
+        "INSERT INTO transactions
+            (id, client, state, failure, reversed_by, amount, timestamp, reverses, reference,
+            source, record)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )?;
+    for entry in state.raw_transactions() {
+        let (id, transaction) = entry?;
+        let (name, failure, reversed_by) = match transaction.state {
+            TransactionState::Succeeded => ("succeeded", None, None),
+            TransactionState::Failed(error) => ("failed", Some(error.to_string()), None),
+            TransactionState::Disputed => ("disputed", None, None),
+            TransactionState::Cancelled => ("cancelled", None, None),
+            TransactionState::Reversed(by) => ("reversed", None, Some(by.0)),
+        };
+        insert.execute(params![
+            id.0,
+            transaction.client.0,
+            name,
+            failure,
+            reversed_by,
+            transaction.amount.to_string(),
+            transaction.timestamp.map(seconds),
+            transaction.reverses.map(|reverses| reverses.0),
+            transaction.reference,
+            transaction
+                .meta
+                .as_ref()
+                .and_then(|meta| meta.source.as_deref()),
+            transaction.meta.as_ref().and_then(|meta| meta.record),
+        ])?;
+    }
+    drop(insert);
+
+    let mut insert = export.prepare(
+        "INSERT INTO audit_log
+            (client, position, at, event, kind, transaction_id, amount, outcome, source, record,
+            entry)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )?;
+    for (client, entries) in state.audit().iter() {
+        for (position, entry) in entries.iter().enumerate() {
+            let (event, kind, transaction, amount, outcome) = match &entry.event {
+                AuditEvent::Action {
+                    kind,
+                    transaction,
+                    amount,
+                    outcome,
+                    ..
+                } => (
+                    "action",
+                    Some(kind_name(*kind)),
+                    transaction.map(|transaction| transaction.0),
+                    amount.map(|amount: Amount| amount.to_string()),
+                    Some(match outcome {
+                        Outcome::Applied => "applied",
+                        Outcome::Failed(_) => "failed",
+                        Outcome::Rejected(_) => "rejected",
+                    }),
+                ),
+                AuditEvent::StatusChanged { .. } => ("status_changed", None, None, None, None),
+                AuditEvent::Restated { transaction, .. } => {
+                    ("restated", None, Some(transaction.0), None, None)
+                }
+            };
+            let meta = match &entry.event {
+                AuditEvent::Action { meta, .. } => meta.as_deref(),
+                _ => None,
+            };
+            let json = serde_json::to_string(entry).expect("audit entries always serialize");
+            insert.execute(params![
+                client.0,
+                position as i64,
+                seconds(entry.at),
+                event,
+                kind,
+                transaction,
+                amount,
+                outcome,
+                meta.and_then(|meta| meta.source.as_deref()),
+                meta.and_then(|meta| meta.record),
+                json,
+            ])?;
+        }
+    }
+    drop(insert);
+
+    let mut insert = export.prepare("INSERT INTO meta (key, value) VALUES (?1, ?2)")?;
+    let mut meta = vec![
+        ("schema_version", SCHEMA_VERSION.to_string()),
+        ("exported_at", Timestamp::now().to_string()),
+        ("sequence", state.sequence().to_string()),
+    ];
+    if let Some(currency) = state.currency() {
+        meta.push(("currency", currency.to_string()));
+    }
+    for (key, value) in meta {
+        insert.execute(params![key, value])?;
+    }
+    drop(insert);
+
+    export.commit()?;
+    Ok(())
+}
+
+/// SQLite integers are signed, so timestamps past 2^63 (which no real one
+/// is) are clamped
+fn seconds(timestamp: Timestamp) -> i64 {
+    i64::try_from(timestamp.as_secs()).unwrap_or(i64::MAX)
+}
+
+fn kind_name(kind: ActionKind) -> &'static str {
+    match kind {
+        ActionKind::Deposit => "deposit",
+        ActionKind::Withdrawal => "withdrawal",
+        ActionKind::Dispute => "dispute",
+        ActionKind::Resolve => "resolve",
+        ActionKind::Chargeback => "chargeback",
+        ActionKind::Reversal => "reversal",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::action, ActionKind::*, SingleThreadedEngine};
+
+    #[test]
+    fn test_write() {
+        let mut state = State::new();
+        state.set_audit_trail(true);
+        let mut engine = SingleThreadedEngine::from_state(state);
+        for action in [
+            action(Deposit, 1, 1, Some("10.5")),
+            action(Withdrawal, 1, 2, Some("20")),
+            action(Deposit, 2, 3, Some("4")),
+            action(Dispute, 2, 3, None),
+        ] {
+            let _ = engine.try_process(action);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.sqlite");
+        // Exports replace whatever was there
+        std::fs::write(&path, "not a database").unwrap();
+        write(engine.state(), &path).unwrap();
+
+        let db = Connection::open(&path).unwrap();
+        let held: String = db
+            .query_row("SELECT held FROM accounts WHERE client = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(held, "4");
+        let failed: (i64, String) = db
+            .query_row(
+                "SELECT id, failure FROM transactions WHERE state = 'failed'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(failed.0, 2);
+        let audited: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM audit_log WHERE event = 'action' AND client = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(audited, 2);
+        let version: String = db
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'schema_version'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION.to_string());
+    }
+}
@@ -0,0 +1,419 @@
+//! A C ABI over `SingleThreadedEngine`, for embedding the engine in a C or
+//! C++ process rather than running the csv binary
+//!
+//! The declarations are in `include/transaction_engine.h`, generated from
+//! this module with cbindgen (see `cbindgen.toml`):
+//!
+//! ```sh
+//! cbindgen --config cbindgen.toml --output include/transaction_engine.h
+//! ```
+//!
+//! - `te_engine_new` creates an engine, which `te_engine_free` frees again
+//! - `te_engine_submit` applies a `TeAction`
+//! - `te_engine_account` fills in a `TeAccount` with a client's balances
+//!
+//! Submitting and querying give a `TeStatus`. When an action is
+//! rejected (including a withdrawal that was recorded as failed, e.g. for
+//! insufficient funds), `te_engine_last_error_code` gives the error's code
+//! (as in `UpdateError::code`) and `te_engine_last_error` its message, until
+//! the next call on the engine.
+//!
+//! Amounts are decimal strings both ways, so nothing is lost converting
+//! them. Ids are taken as the widest id types and rejected as invalid if
+//! they don't fit the ones the crate was built with. An engine isn't thread
+//! safe: calls on the same engine mustn't overlap, though separate engines
+//! can be used from separate threads.
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    ptr,
+};
+
+use crate::{
+    engine::withdrawal_outcome, AccountData, Action, ActionKind, ClientId, Money,
+    SingleThreadedEngine, TransactionId, UpdateError,
+};
+
+/// The size of each amount in a `TeAccount`, including its NUL
+pub const TE_AMOUNT_LEN: usize = 64;
+
+/// An engine, from `te_engine_new`
+#[derive(Debug, Default)]
+pub struct TeEngine {
+    engine: SingleThreadedEngine,
+    /// The code and message of the last call's error, if it had one
+    error: Option<(CString, CString)>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeStatus {
+    Ok = 0,
+    /// The engine refused the action, see `te_engine_last_error`
+    Rejected = 1,
+    /// The client has no account
+    NotFound = 2,
+    /// A pointer was null, or the action couldn't be read (e.g. an id out of
+    /// range or an invalid amount)
+    InvalidArgument = -1,
+    /// The engine's storage failed
+    StoreError = -2,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeActionKind {
+    Deposit = 0,
+    Withdrawal = 1,
+    Dispute = 2,
+    Resolve = 3,
+    Chargeback = 4,
+    Reversal = 5,
+}
+
+/// An action to submit, as a row of the csv input
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TeAction {
+    /// One of the `TeActionKind` values (anything else is undefined
+    /// behaviour, as for any Rust enum)
+    pub kind: TeActionKind,
+    pub client: u32,
+    pub tx: u64,
+    /// A NUL terminated decimal string, or null for actions without an
+    /// amount
+    pub amount: *const c_char,
+    /// The transaction a reversal undoes, ignored for other actions
+    pub reverses: u64,
+}
+
+/// A client's account, as `te_engine_account` fills it in
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TeAccount {
+    pub client: u32,
+    /// NUL terminated decimal strings, rounded as in the csv output
+    pub available: [c_char; TE_AMOUNT_LEN],
+    pub held: [c_char; TE_AMOUNT_LEN],
+    pub total: [c_char; TE_AMOUNT_LEN],
+    pub locked: bool,
+    /// See `Account::version`
+    pub version: u64,
+}
+
+impl From<TeActionKind> for ActionKind {
+    fn from(kind: TeActionKind) -> Self {
+        match kind {
+            TeActionKind::Deposit => Self::Deposit,
+            TeActionKind::Withdrawal => Self::Withdrawal,
+            TeActionKind::Dispute => Self::Dispute,
+            TeActionKind::Resolve => Self::Resolve,
+            TeActionKind::Chargeback => Self::Chargeback,
+            TeActionKind::Reversal => Self::Reversal,
+        }
+    }
+}
+
+impl TeEngine {
+    fn fail(&mut self, status: TeStatus, code: &str, message: String) -> TeStatus {
+        // Neither has a NUL unless an input did, which is replaced
+        let c_string = |s: String| CString::new(s.replace('\0', "?")).unwrap_or_default();
+        self.error = Some((c_string(code.to_string()), c_string(message)));
+        status
+    }
+
+    fn reject(&mut self, error: UpdateError) -> TeStatus {
+        let status = match error {
+            UpdateError::Store(_) => TeStatus::StoreError,
+            _ => TeStatus::Rejected,
+        };
+        self.fail(status, error.code(), error.to_string())
+    }
+
+    /// # Safety
+    ///
+    /// As for `read_action`
+    unsafe fn submit(&mut self, action: &TeAction) -> TeStatus {
+        let action = match read_action(action) {
+            Ok(action) => action,
+            Err(message) => return self.fail(TeStatus::InvalidArgument, "invalid_action", message),
+        };
+        let withdrawal = action.kind == ActionKind::Withdrawal;
+        let id = action.transaction_id;
+        let result = self.engine.try_process(action).and_then(|()| {
+            if withdrawal {
+                withdrawal_outcome(self.engine.state(), id, None)
+            } else {
+                Ok(())
+            }
+        });
+        match result {
+            Ok(()) => TeStatus::Ok,
+            Err(e) => self.reject(e),
+        }
+    }
+}
+
+/// Build an action from its C form
+///
+/// # Safety
+///
+/// `action.amount` is null or points to a NUL terminated string
+// The conversions are no-ops with the wide id features
+#[allow(clippy::useless_conversion)]
+unsafe fn read_action(action: &TeAction) -> Result<Action, String> {
+    let client = action
+        .client
+        .try_into()
+        .map_err(|_| format!("client id {} is out of range", action.client))?;
+    let id = |id: u64| {
+        id.try_into()
+            .map(TransactionId)
+            .map_err(|_| format!("transaction id {id} is out of range"))
+    };
+    let amount = if action.amount.is_null() {
+        None
+    } else {
+        // SAFETY: the caller guarantees it's NUL terminated
+        let bytes = CStr::from_ptr(action.amount).to_bytes();
+        Some(Money::from_ascii(bytes).map_err(|e| e.to_string())?.get())
+    };
+    let kind = ActionKind::from(action.kind);
+    Ok(Action {
+        transaction_id: Some(id(action.tx)?),
+        client_id: ClientId(client),
+        kind,
+        amount,
+        timestamp: None,
+        reverses: match kind {
+            ActionKind::Reversal => Some(id(action.reverses)?),
+            _ => None,
+        },
+        reference: None,
+        evidence: None,
+        category: None,
+        currency: None,
+        meta: None,
+    })
+}
+
+/// Copy `text` into a NUL terminated buffer, if it fits
+fn copy_amount(text: &str, buffer: &mut [c_char; TE_AMOUNT_LEN]) -> bool {
+    if text.len() >= TE_AMOUNT_LEN {
+        return false;
+    }
+    buffer.fill(0);
+    for (to, from) in buffer.iter_mut().zip(text.bytes()) {
+        *to = from as c_char;
+    }
+    true
+}
+
+#[allow(clippy::useless_conversion)]
+fn fill_account(account: &AccountData, out: &mut TeAccount) -> bool {
+    out.client = account.client.0.into();
+    out.locked = account.locked;
+    out.version = account.version;
+    copy_amount(&account.available.to_string(), &mut out.available)
+        && copy_amount(&account.held.to_string(), &mut out.held)
+        && copy_amount(&account.total.to_string(), &mut out.total)
+}
+
+/// Create an engine, to be freed with `te_engine_free`
+#[no_mangle]
+pub extern "C" fn te_engine_new() -> *mut TeEngine {
+    Box::into_raw(Box::default())
+}
+
+/// Free an engine from `te_engine_new`. Does nothing if it's null.
+///
+/// # Safety
+///
+/// `engine` is null or from `te_engine_new`, and isn't used again
+#[no_mangle]
+pub unsafe extern "C" fn te_engine_free(engine: *mut TeEngine) {
+    if !engine.is_null() {
+        // SAFETY: it came from `Box::into_raw` in `te_engine_new`
+        drop(Box::from_raw(engine));
+    }
+}
+
+/// Apply an action to the engine
+///
+/// # Safety
+///
+/// `engine` is from `te_engine_new`, and `action` points to a `TeAction`
+/// whose `amount` is null or NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn te_engine_submit(
+    engine: *mut TeEngine,
+    action: *const TeAction,
+) -> TeStatus {
+    let Some(engine) = engine.as_mut() else {
+        return TeStatus::InvalidArgument;
+    };
+    engine.error = None;
+    match action.as_ref() {
+        Some(action) => engine.submit(action),
+        None => engine.fail(
+            TeStatus::InvalidArgument,
+            "invalid_action",
+            "the action is null".into(),
+        ),
+    }
+}
+
+/// Fill in `out` with a client's account, or give `TE_STATUS_NOT_FOUND` if
+/// it doesn't have one
+///
+/// # Safety
+///
+/// `engine` is from `te_engine_new`, and `out` points to a `TeAccount`
+#[no_mangle]
+#[allow(clippy::useless_conversion, irrefutable_let_patterns)]
+pub unsafe extern "C" fn te_engine_account(
+    engine: *mut TeEngine,
+    client: u32,
+    out: *mut TeAccount,
+) -> TeStatus {
+    let Some(engine) = engine.as_mut() else {
+        return TeStatus::InvalidArgument;
+    };
+    engine.error = None;
+    let Some(out) = out.as_mut() else {
+        return engine.fail(
+            TeStatus::InvalidArgument,
+            "invalid_argument",
+            "the account is null".into(),
+        );
+    };
+    let Ok(client) = client.try_into() else {
+        return engine.fail(
+            TeStatus::InvalidArgument,
+            "invalid_argument",
+            format!("client id {client} is out of range"),
+        );
+    };
+    match engine.engine.state().try_account(ClientId(client)) {
+        Ok(Some(account)) if fill_account(&account, out) => TeStatus::Ok,
+        Ok(Some(_)) => engine.fail(
+            TeStatus::InvalidArgument,
+            "amount_too_long",
+            format!("an amount is longer than {} characters", TE_AMOUNT_LEN - 1),
+        ),
+        Ok(None) => TeStatus::NotFound,
+        Err(e) => engine.reject(e.into()),
+    }
+}
+
+/// The message of the last call's error, or null if it succeeded. Valid
+/// until the next call on the engine.
+///
+/// # Safety
+///
+/// `engine` is from `te_engine_new`
+#[no_mangle]
+pub unsafe extern "C" fn te_engine_last_error(engine: *const TeEngine) -> *const c_char {
+    match engine.as_ref().and_then(|engine| engine.error.as_ref()) {
+        Some((_, message)) => message.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// The code of the last call's error (e.g. `insufficient_funds`), or null
+/// if it succeeded. Valid until the next call on the engine.
+///
+/// # Safety
+///
+/// `engine` is from `te_engine_new`
+#[no_mangle]
+pub unsafe extern "C" fn te_engine_last_error_code(engine: *const TeEngine) -> *const c_char {
+    match engine.as_ref().and_then(|engine| engine.error.as_ref()) {
+        Some((code, _)) => code.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(kind: TeActionKind, client: u32, tx: u64, amount: Option<&CStr>) -> TeAction {
+        TeAction {
+            kind,
+            client,
+            tx,
+            amount: amount.map_or(ptr::null(), CStr::as_ptr),
+            reverses: 0,
+        }
+    }
+
+    fn text(buffer: &[c_char; TE_AMOUNT_LEN]) -> &str {
+        unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        use TeActionKind::*;
+
+        let engine = te_engine_new();
+        let submit = |action: TeAction| unsafe { te_engine_submit(engine, &action) };
+        let last_code = || unsafe {
+            let code = te_engine_last_error_code(engine);
+            (!code.is_null()).then(|| CStr::from_ptr(code).to_str().unwrap().to_string())
+        };
+
+        assert_eq!(submit(action(Deposit, 1, 1, Some(c"10.5"))), TeStatus::Ok);
+        assert_eq!(last_code(), None);
+        assert_eq!(
+            submit(action(Withdrawal, 1, 2, Some(c"11"))),
+            TeStatus::Rejected
+        );
+        assert_eq!(last_code().as_deref(), Some("insufficient_funds"));
+        assert!(!unsafe { te_engine_last_error(engine) }.is_null());
+        assert_eq!(
+            submit(action(Deposit, 1, 1, Some(c"1"))),
+            TeStatus::Rejected
+        );
+        assert_eq!(last_code().as_deref(), Some("transaction_used"));
+        assert_eq!(submit(action(Dispute, 1, 1, None)), TeStatus::Ok);
+        assert_eq!(
+            submit(action(Deposit, 1, 3, Some(c"-1"))),
+            TeStatus::InvalidArgument
+        );
+        // Every `u32` is a client id with the `wide-client-ids` feature
+        #[cfg(not(feature = "wide-client-ids"))]
+        assert_eq!(
+            submit(action(Deposit, u32::MAX, 3, Some(c"1"))),
+            TeStatus::InvalidArgument
+        );
+        // The conversion is a no-op with the `wide-client-ids` feature
+        #[allow(clippy::useless_conversion)]
+        let tombstone = u32::from(ClientId::TOMBSTONE.0);
+        assert_eq!(
+            submit(action(Deposit, tombstone, 3, Some(c"1"))),
+            TeStatus::Rejected
+        );
+        assert_eq!(last_code().as_deref(), Some("reserved_client"));
+        assert_eq!(
+            unsafe { te_engine_submit(engine, ptr::null()) },
+            TeStatus::InvalidArgument
+        );
+
+        let mut account = std::mem::MaybeUninit::<TeAccount>::zeroed();
+        let status = unsafe { te_engine_account(engine, 1, account.as_mut_ptr()) };
+        assert_eq!(status, TeStatus::Ok);
+        let account = unsafe { account.assume_init() };
+        assert_eq!(account.client, 1);
+        assert_eq!(text(&account.available).parse::<f64>().unwrap(), 0.0);
+        assert_eq!(text(&account.held), "10.5");
+        assert_eq!(text(&account.total), "10.5");
+        assert!(!account.locked);
+
+        let mut missing = account;
+        let status = unsafe { te_engine_account(engine, 2, &mut missing) };
+        assert_eq!(status, TeStatus::NotFound);
+
+        unsafe { te_engine_free(engine) };
+    }
+}
@@ -0,0 +1,197 @@
+//! Build a `State` directly from a description of its accounts and
+//! transactions, for tests
+//!
+//! Setting up an edge case by replaying actions can take a lot of them (and
+//! some states can't be reached through actions at all). A `Fixture` is loaded
+//! the same way as a snapshot instead, so the state is exactly what was
+//! described. Nothing checks that the accounts and transactions agree with
+//! each other, which is up to the test.
+//!
+//! Amounts are given as strings, so the same fixture works with and without
+//! the `decimal` feature, and ids as the narrowest id types.
+
+use std::collections::HashMap;
+
+use crate::{
+    audit::{AuditTrail, EvidenceLog},
+    policy::WithdrawalHistory,
+    Account, AccountStatus, Amount, ClientId, State, Timestamp, Transaction, TransactionId,
+    TransactionState,
+};
+
+/// A description of a state, from which it's built with `build`
+#[derive(Debug, Default, Clone)]
+pub struct Fixture {
+    accounts: HashMap<ClientId, Account>,
+    transactions: HashMap<TransactionId, Transaction>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an active account with the given balances, replacing any earlier
+    /// one for the client.
+    ///
+    /// # Panics
+    ///
+    /// If either amount isn't a number
+    pub fn account(mut self, client: u16, available: &str, held: &str) -> Self {
+        let account =
+            Account::with_balances(amount(available), amount(held), AccountStatus::Active);
+        self.accounts.insert(client_id(client), account);
+        self
+    }
+
+    /// Give an account a different status (e.g. frozen by a chargeback),
+    /// leaving its balances alone.
+    ///
+    /// # Panics
+    ///
+    /// If the account hasn't been added
+    pub fn status(mut self, client: u16, status: AccountStatus) -> Self {
+        let account = self
+            .accounts
+            .get_mut(&client_id(client))
+            .expect("the account should be added before its status is set");
+        *account = Account::with_balances(account.available_funds(), account.held_funds(), status);
+        self
+    }
+
+    /// Add a deposit in the given state.
+    ///
+    /// # Panics
+    ///
+    /// If the amount isn't a number
+    pub fn deposit(self, tx: u32, client: u16, amount: &str, state: TransactionState) -> Self {
+        self.transaction(tx, client, self::amount(amount), state)
+    }
+
+    /// Add a withdrawal in the given state. The amount is given as a positive
+    /// number, as it would be in a withdrawal action.
+    ///
+    /// # Panics
+    ///
+    /// If the amount isn't a number
+    pub fn withdrawal(self, tx: u32, client: u16, amount: &str, state: TransactionState) -> Self {
+        self.transaction(tx, client, -self::amount(amount), state)
+    }
+
+    /// Set when a transaction happened, e.g. to test a dispute window.
+    ///
+    /// # Panics
+    ///
+    /// If the transaction hasn't been added
+    pub fn timestamp(mut self, tx: u32, at: Timestamp) -> Self {
+        self.transactions
+            .get_mut(&transaction_id(tx))
+            .expect("the transaction should be added before its timestamp is set")
+            .timestamp = Some(at);
+        self
+    }
+
+    /// Build the state, with the default policies
+    pub fn build(self) -> State {
+        State::from_parts(
+            self.accounts,
+            self.transactions,
+            None,
+            HashMap::new(),
+            WithdrawalHistory::default(),
+            AuditTrail::default(),
+            EvidenceLog::default(),
+        )
+    }
+
+    fn transaction(
+        mut self,
+        tx: u32,
+        client: u16,
+        amount: Amount,
+        state: TransactionState,
+    ) -> Self {
+        let id = transaction_id(tx);
+        self.transactions.insert(
+            id,
+            Transaction {
+                id,
+                client: client_id(client),
+                state,
+                amount,
+                timestamp: None,
+                reverses: None,
+                reference: None,
+                meta: None,
+                balances: None,
+                history: Vec::new(),
+                format: Transaction::FORMAT,
+            },
+        );
+        self
+    }
+}
+
+fn amount(amount: &str) -> Amount {
+    amount
+        .parse()
+        .unwrap_or_else(|_| panic!("{:?} isn't an amount", amount))
+}
+
+// The conversions are no-ops without the wide id features
+#[allow(clippy::useless_conversion)]
+fn client_id(client: u16) -> ClientId {
+    ClientId(client.into())
+}
+
+#[allow(clippy::useless_conversion)]
+fn transaction_id(tx: u32) -> TransactionId {
+    TransactionId(tx.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Action, ActionKind, UpdateError};
+
+    fn action(kind: ActionKind, client: u16, tx: u32) -> Action {
+        Action {
+            transaction_id: Some(transaction_id(tx)),
+            client_id: client_id(client),
+            kind,
+            amount: None,
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn test_dispute_edge_cases() {
+        let mut state = Fixture::new()
+            .account(1, "2.5", "10")
+            .deposit(1, 1, "10", TransactionState::Disputed)
+            .withdrawal(2, 1, "7.5", TransactionState::Succeeded)
+            .account(2, "0", "0")
+            .status(2, AccountStatus::Closed)
+            .build();
+
+        state.update(action(ActionKind::Resolve, 1, 1)).unwrap();
+        let account = state.account(client_id(1)).unwrap();
+        assert_eq!(account.available, Amount::from(25u32) / Amount::from(2u32));
+        assert_eq!(account.held, Amount::from(0u32));
+
+        // Ids in the fixture are claimed, like those in a snapshot
+        let mut deposit = action(ActionKind::Deposit, 2, 2);
+        deposit.amount = Some(Amount::from(1u32));
+        assert!(matches!(
+            state.update(deposit),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+        assert!(state.account(client_id(2)).unwrap().locked);
+    }
+}
@@ -0,0 +1,154 @@
+//! A channel-based frontend for running an engine on its own thread
+
+use std::thread::{self, JoinHandle};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    engine::AsyncEngine, AccountData, Action, ClientId, SingleThreadedEngine, SyncEngine,
+    UpdateError,
+};
+
+/// Messages sent from handles to the engine thread
+enum Command {
+    Process(Action),
+    ProcessAndReply(Action, oneshot::Sender<Result<(), UpdateError>>),
+    QueryAccount(ClientId, oneshot::Sender<Option<AccountData>>),
+}
+
+/// A cloneable handle to an engine running on a dedicated thread.
+///
+/// Actions are passed to the engine over a bounded channel, so producers wait
+/// (asynchronously) when the engine falls behind instead of queueing without
+/// limit. Since only the engine thread ever touches the state, no locking is
+/// needed no matter how many producers there are.
+///
+/// The engine thread stops once every handle has been dropped.
+#[derive(Debug, Clone)]
+pub struct EngineHandle {
+    sender: mpsc::Sender<Command>,
+}
+
+impl EngineHandle {
+    /// Start `engine` on a new thread, buffering at most `capacity` pending
+    /// commands.
+    ///
+    /// The returned `JoinHandle` gives the engine back after all handles are
+    /// dropped and any queued actions have been processed.
+    pub fn spawn(
+        engine: SingleThreadedEngine,
+        capacity: usize,
+    ) -> (Self, JoinHandle<SingleThreadedEngine>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let thread = thread::spawn(move || run(engine, receiver));
+        (Self { sender }, thread)
+    }
+
+    /// Queue an action for processing, waiting for space in the channel if
+    /// the engine is busy
+    pub async fn send(&self, action: Action) -> Result<(), EngineClosed> {
+        self.sender
+            .send(Command::Process(action))
+            .await
+            .map_err(|_| EngineClosed)
+    }
+
+    /// Process an action and wait for the engine's verdict on it, for callers
+    /// that can't report success until it's been applied (e.g. before
+    /// acknowledging a webhook)
+    pub async fn process(&self, action: Action) -> Result<Result<(), UpdateError>, EngineClosed> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(Command::ProcessAndReply(action, reply))
+            .await
+            .map_err(|_| EngineClosed)?;
+        response.await.map_err(|_| EngineClosed)
+    }
+
+    /// Get the current state of a client's account, after all previously sent
+    /// actions have been applied
+    pub async fn query_account(
+        &self,
+        client: ClientId,
+    ) -> Result<Option<AccountData>, EngineClosed> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(Command::QueryAccount(client, reply))
+            .await
+            .map_err(|_| EngineClosed)?;
+        response.await.map_err(|_| EngineClosed)
+    }
+}
+
+#[async_trait]
+impl AsyncEngine for EngineHandle {
+    async fn process_async(&self, action: Action) {
+        // Nothing to report to the caller if the engine is gone, the same as
+        // ignored errors in the sync engines
+        let _ = self.send(action).await;
+    }
+}
+
+fn run(
+    mut engine: SingleThreadedEngine,
+    mut receiver: mpsc::Receiver<Command>,
+) -> SingleThreadedEngine {
+    while let Some(command) = receiver.blocking_recv() {
+        match command {
+            Command::Process(action) => {
+                let _ = engine.process(action);
+            }
+            Command::ProcessAndReply(action, reply) => {
+                let _ = reply.send(engine.try_process(action));
+            }
+            Command::QueryAccount(client, reply) => {
+                // The requester may have given up waiting, which is fine
+                let _ = reply.send(engine.state().account(client));
+            }
+        }
+    }
+    engine
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("the engine thread has stopped")]
+pub struct EngineClosed;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::CsvSource;
+
+    #[tokio::test]
+    async fn test_many_producers() {
+        let (handle, thread) = EngineHandle::spawn(SingleThreadedEngine::new(), 2);
+
+        let producers: Vec<_> = (1..=4u16)
+            .map(|client| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    let input = format!(
+                        "type,client,tx,amount\ndeposit,{client},{},2.0\nwithdrawal,{client},{},0.5\n",
+                        client * 10,
+                        client * 10 + 1
+                    );
+                    for action in CsvSource::from_reader(input.as_bytes()) {
+                        handle.send(action.unwrap()).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.await.unwrap();
+        }
+
+        let account = handle.query_account(ClientId(3)).await.unwrap().unwrap();
+        assert_eq!(account.available.to_string(), "1.5");
+        assert!(handle.query_account(ClientId(9)).await.unwrap().is_none());
+
+        drop(handle);
+        let engine = thread.join().unwrap();
+        assert_eq!(engine.state().accounts().len(), 4);
+    }
+}
@@ -0,0 +1,95 @@
+//! Pre-validated batches for `State::ingest_unchecked`
+//!
+//! Replaying a large trusted history through `update` pays for checks that
+//! can't fail on it (amounts being there, ids and references to resolve) on
+//! every action. A `TrustedBatch` makes those checks once, up front, and keeps
+//! each action in a compact form that the fast path can apply without them.
+
+use crate::{Action, ActionKind, Amount, ClientId, Timestamp, TransactionId};
+
+/// Actions checked to be simple enough for `State::ingest_unchecked`
+#[derive(Debug, Clone, Default)]
+pub struct TrustedBatch {
+    pub(crate) actions: Vec<TrustedAction>,
+}
+
+/// An action that's known to have what its kind needs. `amount` is zero for
+/// the kinds without one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TrustedAction {
+    pub kind: ActionKind,
+    pub client: ClientId,
+    pub transaction: TransactionId,
+    pub amount: Amount,
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TrustedBatchError {
+    #[error("action {index} is a deposit or withdrawal without an amount")]
+    NoAmount { index: u64 },
+
+    #[error("action {index} has no transaction id")]
+    NoTransactionId { index: u64 },
+
+    #[error("action {index} is for the client reserved for erased records")]
+    ReservedClient { index: u64 },
+
+    /// Reversals, and actions with a reference, evidence, category or
+    /// currency, need the checks the fast path skips
+    #[error("action {index} needs the full checks of `update` or `bulk_load`")]
+    Unsupported { index: u64 },
+}
+
+impl TrustedBatch {
+    /// Check `actions` and keep them, in order
+    pub fn new<I: IntoIterator<Item = Action>>(actions: I) -> Result<Self, TrustedBatchError> {
+        let mut batch = Self::default();
+        for action in actions {
+            batch.push(action)?;
+        }
+        Ok(batch)
+    }
+
+    /// Check an action and add it to the end of the batch
+    pub fn push(&mut self, action: Action) -> Result<(), TrustedBatchError> {
+        let index = self.actions.len() as u64;
+        if action.kind == ActionKind::Reversal
+            || action.reference.is_some()
+            || action.evidence.is_some()
+            || action.category.is_some()
+            || action.currency.is_some()
+        {
+            return Err(TrustedBatchError::Unsupported { index });
+        }
+        if action.client_id == ClientId::TOMBSTONE {
+            return Err(TrustedBatchError::ReservedClient { index });
+        }
+        let Some(transaction) = action.transaction_id else {
+            return Err(TrustedBatchError::NoTransactionId { index });
+        };
+        let amount = match (action.kind, action.amount) {
+            (ActionKind::Deposit | ActionKind::Withdrawal, None) => {
+                return Err(TrustedBatchError::NoAmount { index })
+            }
+            (ActionKind::Deposit | ActionKind::Withdrawal, Some(amount)) => amount,
+            _ => Amount::default(),
+        };
+        self.actions.push(TrustedAction {
+            kind: action.kind,
+            client: action.client_id,
+            transaction,
+            amount,
+            timestamp: action.timestamp,
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
@@ -0,0 +1,430 @@
+//! Avro encoding for actions and account summaries
+//!
+//! Records are encoded as single Avro binary datums with the schemas in
+//! `schema/action.avsc` and `schema/account.avsc` (also available as
+//! `AvroRecord::SCHEMA`, e.g. for registering with a schema registry). Object
+//! container files and schema resolution aren't supported, so readers have to
+//! use the same schema.
+//!
+//! Action amounts are decimal strings, so nothing is lost whatever precision
+//! they came in with. Account amounts have already been rounded, so they're
+//! Avro decimals with a scale of 4.
+
+use super::{parse_amount, ConversionError};
+use crate::{
+    AccountData, AccountStatus, Action, ActionKind, Amount, ClientId, OutputColumns, Timestamp,
+    TransactionId,
+};
+
+const ACTION_KINDS: [ActionKind; 6] = [
+    ActionKind::Deposit,
+    ActionKind::Withdrawal,
+    ActionKind::Dispute,
+    ActionKind::Resolve,
+    ActionKind::Chargeback,
+    ActionKind::Reversal,
+];
+
+const STATUSES: [&str; 4] = ["active", "frozen", "dormant", "closed"];
+
+/// The scale of account amounts
+const SCALE: u32 = 4;
+
+/// A type with an Avro schema it can be encoded to and decoded from
+pub trait AvroRecord: Sized {
+    /// The schema, as JSON
+    const SCHEMA: &'static str;
+
+    fn to_avro(&self) -> Result<Vec<u8>, ConversionError>;
+
+    /// Decode a record, which must take up all of `bytes`
+    fn from_avro(bytes: &[u8]) -> Result<Self, ConversionError>;
+}
+
+impl AvroRecord for Action {
+    const SCHEMA: &'static str = include_str!("../../schema/action.avsc");
+
+    fn to_avro(&self) -> Result<Vec<u8>, ConversionError> {
+        let mut buf = Vec::new();
+        let kind = ACTION_KINDS
+            .iter()
+            .position(|kind| *kind == self.kind)
+            .expect("every kind has a symbol");
+        write_long(&mut buf, kind as i64);
+        write_long(&mut buf, to_long("client", self.client_id.0)?);
+        let tx = self
+            .transaction_id
+            .ok_or(ConversionError::Unsupported("reference-only action"))?;
+        write_long(&mut buf, to_long("tx", tx.0)?);
+        write_optional(&mut buf, self.amount, |buf, amount| {
+            write_str(buf, &amount.to_string());
+            Ok(())
+        })?;
+        write_optional(&mut buf, self.timestamp, |buf, timestamp| {
+            let millis = to_long("timestamp", timestamp.as_secs())?
+                .checked_mul(1000)
+                .ok_or(ConversionError::OutOfRange {
+                    field: "timestamp",
+                    value: timestamp.as_secs().into(),
+                })?;
+            write_long(buf, millis);
+            Ok(())
+        })?;
+        write_optional(&mut buf, self.reverses, |buf, id| {
+            write_long(buf, to_long("reverses", id.0)?);
+            Ok(())
+        })?;
+        Ok(buf)
+    }
+
+    fn from_avro(mut bytes: &[u8]) -> Result<Self, ConversionError> {
+        let bytes = &mut bytes;
+        let kind = read_long(bytes)?;
+        let kind = usize::try_from(kind)
+            .ok()
+            .and_then(|kind| ACTION_KINDS.get(kind))
+            .ok_or_else(|| ConversionError::UnknownActionKind(kind.to_string()))?;
+        let client = read_long(bytes)?;
+        let transaction = read_long(bytes)?;
+        let amount = read_optional(bytes, |bytes| parse_amount(read_str(bytes)?))?;
+        let timestamp = read_optional(bytes, |bytes| {
+            let millis = read_long(bytes)?;
+            u64::try_from(millis.div_euclid(1000))
+                .map(Timestamp::from_secs)
+                .map_err(|_| ConversionError::OutOfRange {
+                    field: "timestamp",
+                    value: millis.into(),
+                })
+        })?;
+        let reverses = read_optional(bytes, |bytes| from_long("reverses", read_long(bytes)?))?;
+        finish(bytes)?;
+
+        Ok(Self {
+            transaction_id: Some(TransactionId(from_long("tx", transaction)?)),
+            client_id: ClientId(from_long("client", client)?),
+            kind: *kind,
+            amount,
+            timestamp,
+            reverses: reverses.map(TransactionId),
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        })
+    }
+}
+
+impl AvroRecord for AccountData {
+    const SCHEMA: &'static str = include_str!("../../schema/account.avsc");
+
+    fn to_avro(&self) -> Result<Vec<u8>, ConversionError> {
+        let mut buf = Vec::new();
+        write_long(&mut buf, to_long("client", self.client.0)?);
+        for amount in [self.available, self.held, self.total] {
+            write_bytes(&mut buf, &decimal_bytes(to_unscaled(amount)?));
+        }
+        buf.push(self.locked.into());
+        let status = STATUSES
+            .iter()
+            .position(|name| *name == self.status.name())
+            .expect("every status has a symbol");
+        write_long(&mut buf, status as i64);
+        write_optional(&mut buf, self.freeze_reason.as_deref(), |buf, reason| {
+            write_str(buf, reason);
+            Ok(())
+        })?;
+        buf.push(self.quarantined.into());
+        write_long(&mut buf, to_long("version", self.version)?);
+        Ok(buf)
+    }
+
+    fn from_avro(mut bytes: &[u8]) -> Result<Self, ConversionError> {
+        let bytes = &mut bytes;
+        let client = read_long(bytes)?;
+        let available = from_unscaled(read_decimal(bytes)?)?;
+        let held = from_unscaled(read_decimal(bytes)?)?;
+        let total = from_unscaled(read_decimal(bytes)?)?;
+        let locked = read_bool(bytes)?;
+        let status = read_long(bytes)?;
+        let status = usize::try_from(status)
+            .ok()
+            .and_then(|status| STATUSES.get(status))
+            .ok_or_else(|| ConversionError::UnknownStatus(status.to_string()))?;
+        let freeze_reason = read_optional(bytes, |bytes| read_str(bytes).map(String::from))?;
+        let quarantined = read_bool(bytes)?;
+        let version = from_long("version", read_long(bytes)?)?;
+        finish(bytes)?;
+
+        Ok(Self {
+            client: ClientId(from_long("client", client)?),
+            available,
+            held,
+            total,
+            locked,
+            status: AccountStatus::from_parts(status, freeze_reason.as_deref())
+                .expect("every symbol is a status"),
+            freeze_reason,
+            quarantined,
+            version,
+            // Every field is in the record, so none are dropped again
+            columns: OutputColumns::Extended,
+        })
+    }
+}
+
+fn to_long<T: Into<u64>>(field: &'static str, value: T) -> Result<i64, ConversionError> {
+    let value = value.into();
+    i64::try_from(value).map_err(|_| ConversionError::OutOfRange {
+        field,
+        value: value.into(),
+    })
+}
+
+fn from_long<T: TryFrom<i64>>(field: &'static str, value: i64) -> Result<T, ConversionError> {
+    T::try_from(value).map_err(|_| ConversionError::OutOfRange {
+        field,
+        value: value.into(),
+    })
+}
+
+#[cfg(feature = "decimal")]
+fn to_unscaled(mut amount: Amount) -> Result<i128, ConversionError> {
+    amount.rescale(SCALE);
+    Ok(amount.mantissa())
+}
+
+#[cfg(feature = "decimal")]
+fn from_unscaled(unscaled: i128) -> Result<Amount, ConversionError> {
+    Amount::try_from_i128_with_scale(unscaled, SCALE)
+        .map(|amount| amount.normalize())
+        .map_err(|_| ConversionError::OutOfRange {
+            field: "amount",
+            value: unscaled,
+        })
+}
+
+#[cfg(not(feature = "decimal"))]
+fn to_unscaled(amount: Amount) -> Result<i128, ConversionError> {
+    Ok((amount * 10f64.powi(SCALE as i32)).round() as i128)
+}
+
+#[cfg(not(feature = "decimal"))]
+fn from_unscaled(unscaled: i128) -> Result<Amount, ConversionError> {
+    Ok(unscaled as f64 / 10f64.powi(SCALE as i32))
+}
+
+/// A zig-zag encoded variable length integer
+fn write_long(buf: &mut Vec<u8>, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        buf.push(zigzag as u8 | 0x80);
+        zigzag >>= 7;
+    }
+    buf.push(zigzag as u8);
+}
+
+fn read_long(bytes: &mut &[u8]) -> Result<i64, ConversionError> {
+    let mut zigzag = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(bytes)?;
+        zigzag |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64));
+        }
+    }
+    Err(ConversionError::Malformed("integer is too long"))
+}
+
+fn read_byte(bytes: &mut &[u8]) -> Result<u8, ConversionError> {
+    let (byte, rest) = bytes
+        .split_first()
+        .ok_or(ConversionError::Malformed("unexpected end of record"))?;
+    *bytes = rest;
+    Ok(*byte)
+}
+
+fn read_bool(bytes: &mut &[u8]) -> Result<bool, ConversionError> {
+    match read_byte(bytes)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(ConversionError::Malformed("invalid boolean")),
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(buf, bytes.len() as i64);
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], ConversionError> {
+    let len = read_long(bytes)?;
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= bytes.len())
+        .ok_or(ConversionError::Malformed("invalid length"))?;
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(value)
+}
+
+fn write_str(buf: &mut Vec<u8>, value: &str) {
+    write_bytes(buf, value.as_bytes());
+}
+
+fn read_str<'a>(bytes: &mut &'a [u8]) -> Result<&'a str, ConversionError> {
+    std::str::from_utf8(read_bytes(bytes)?).map_err(|_| ConversionError::Malformed("invalid utf-8"))
+}
+
+/// A `["null", T]` union
+fn write_optional<T, F>(
+    buf: &mut Vec<u8>,
+    value: Option<T>,
+    write: F,
+) -> Result<(), ConversionError>
+where
+    F: FnOnce(&mut Vec<u8>, T) -> Result<(), ConversionError>,
+{
+    match value {
+        None => {
+            write_long(buf, 0);
+            Ok(())
+        }
+        Some(value) => {
+            write_long(buf, 1);
+            write(buf, value)
+        }
+    }
+}
+
+fn read_optional<T, F>(bytes: &mut &[u8], read: F) -> Result<Option<T>, ConversionError>
+where
+    F: FnOnce(&mut &[u8]) -> Result<T, ConversionError>,
+{
+    match read_long(bytes)? {
+        0 => Ok(None),
+        1 => read(bytes).map(Some),
+        _ => Err(ConversionError::Malformed("invalid union branch")),
+    }
+}
+
+/// The shortest big endian two's complement form, as Avro decimals are stored
+fn decimal_bytes(unscaled: i128) -> Vec<u8> {
+    let bytes = unscaled.to_be_bytes();
+    let mut start = 0;
+    // A leading byte can go if it's only sign extension of the next one
+    while start < bytes.len() - 1
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+fn read_decimal(bytes: &mut &[u8]) -> Result<i128, ConversionError> {
+    let value = read_bytes(bytes)?;
+    if value.is_empty() || value.len() > 16 {
+        return Err(ConversionError::Malformed("invalid decimal"));
+    }
+    let fill = if value[0] & 0x80 != 0 { 0xff } else { 0 };
+    let mut full = [fill; 16];
+    full[16 - value.len()..].copy_from_slice(value);
+    Ok(i128::from_be_bytes(full))
+}
+
+fn finish(bytes: &[u8]) -> Result<(), ConversionError> {
+    if bytes.is_empty() {
+        Ok(())
+    } else {
+        Err(ConversionError::Malformed(
+            "trailing bytes after the record",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FreezeReason;
+
+    #[test]
+    fn test_round_trip() {
+        let action = Action {
+            transaction_id: Some(TransactionId(7)),
+            client_id: ClientId(3),
+            kind: ActionKind::Reversal,
+            amount: Some(Amount::from(3u32) / Amount::from(2u32)),
+            timestamp: Some(Timestamp::from_secs(1_600_000_000)),
+            reverses: Some(TransactionId(2)),
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        };
+        let decoded = Action::from_avro(&action.to_avro().unwrap()).unwrap();
+        assert_eq!(decoded.transaction_id, action.transaction_id);
+        assert_eq!(decoded.client_id, action.client_id);
+        assert_eq!(decoded.kind, action.kind);
+        assert_eq!(decoded.amount, action.amount);
+        assert_eq!(decoded.timestamp, action.timestamp);
+        assert_eq!(decoded.reverses, action.reverses);
+
+        let account = AccountData {
+            client: ClientId(3),
+            available: Amount::from(1u32) / Amount::from(4u32),
+            held: Amount::from(0u32) - Amount::from(200u32),
+            total: Amount::from(40_000u32),
+            locked: true,
+            status: AccountStatus::Frozen {
+                reason: FreezeReason::Chargeback,
+            },
+            freeze_reason: Some("chargeback".into()),
+            quarantined: true,
+            version: 12,
+            columns: OutputColumns::Extended,
+        };
+        let decoded = AccountData::from_avro(&account.to_avro().unwrap()).unwrap();
+        assert_eq!(decoded, account);
+    }
+
+    #[test]
+    fn test_encoding_matches_spec() {
+        // Examples from the Avro specification
+        let mut buf = Vec::new();
+        for value in [0, -1, 1, -2, 2, -64, 64] {
+            write_long(&mut buf, value);
+        }
+        assert_eq!(buf, [0x00, 0x01, 0x02, 0x03, 0x04, 0x7f, 0x80, 0x01]);
+
+        assert_eq!(decimal_bytes(0), [0x00]);
+        assert_eq!(decimal_bytes(-1), [0xff]);
+        assert_eq!(decimal_bytes(128), [0x00, 0x80]);
+        assert_eq!(decimal_bytes(-129), [0xff, 0x7f]);
+    }
+
+    #[test]
+    fn test_malformed_records_are_rejected() {
+        let action = Action {
+            transaction_id: Some(TransactionId(1)),
+            client_id: ClientId(1),
+            kind: ActionKind::Deposit,
+            amount: None,
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        };
+        let mut bytes = action.to_avro().unwrap();
+        assert!(Action::from_avro(&bytes[..bytes.len() - 1]).is_err());
+        bytes.push(0);
+        assert!(matches!(
+            Action::from_avro(&bytes),
+            Err(ConversionError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+//! A compact, fixed size binary encoding for actions
+//!
+//! Each action is a `RECORD_LEN` byte record, with every field little endian:
+//!
+//! | offset | size | field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 1    | kind: 1 deposit, 2 withdrawal, 3 dispute, 4 resolve, 5 chargeback |
+//! | 1      | 2    | client id (`u16`)                                      |
+//! | 3      | 4    | transaction id (`u32`)                                 |
+//! | 7      | 8    | amount in ten thousandths (`i64`), `i64::MIN` for none |
+//!
+//! Records are packed back to back with no header, so a file (or memory
+//! mapped region) of them can be read in place with `BinaryReader`, without
+//! the per field parsing that csv needs.
+//!
+//! Timestamps, reversals and references can't be represented, and neither
+//! can ids wider than the default widths, so those actions fail to encode.
+
+use std::slice::ChunksExact;
+
+use super::ConversionError;
+use crate::{Action, ActionKind, Amount, ClientId, TransactionId};
+
+/// The size of an encoded action
+pub const RECORD_LEN: usize = 15;
+
+/// The number of decimal places kept in amounts
+const SCALE: u32 = 4;
+
+/// Marks an action without an amount
+const NO_AMOUNT: i64 = i64::MIN;
+
+/// Encode an action as a single record
+// The conversions are no-ops without the wide id features
+#[allow(clippy::useless_conversion, clippy::unnecessary_fallible_conversions)]
+pub fn encode(action: &Action) -> Result<[u8; RECORD_LEN], ConversionError> {
+    if action.timestamp.is_some() {
+        return Err(ConversionError::Unsupported("timestamp"));
+    }
+    if action.reverses.is_some() || action.kind == ActionKind::Reversal {
+        return Err(ConversionError::Unsupported("reversal"));
+    }
+
+    let kind: u8 = match action.kind {
+        ActionKind::Deposit => 1,
+        ActionKind::Withdrawal => 2,
+        ActionKind::Dispute => 3,
+        ActionKind::Resolve => 4,
+        ActionKind::Chargeback => 5,
+        ActionKind::Reversal => unreachable!("rejected above"),
+    };
+    let client = u16::try_from(action.client_id.0).map_err(|_| ConversionError::OutOfRange {
+        field: "client",
+        value: action.client_id.0.into(),
+    })?;
+    let id = action
+        .transaction_id
+        .ok_or(ConversionError::Unsupported("reference-only action"))?;
+    let transaction = u32::try_from(id.0).map_err(|_| ConversionError::OutOfRange {
+        field: "tx",
+        value: id.0.into(),
+    })?;
+    let amount = match action.amount {
+        Some(amount) => to_minor_units(amount)?,
+        None => NO_AMOUNT,
+    };
+
+    let mut record = [0; RECORD_LEN];
+    record[0] = kind;
+    record[1..3].copy_from_slice(&client.to_le_bytes());
+    record[3..7].copy_from_slice(&transaction.to_le_bytes());
+    record[7..15].copy_from_slice(&amount.to_le_bytes());
+    Ok(record)
+}
+
+/// A single encoded action, borrowed from the buffer it's in. Fields are only
+/// decoded when they're read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActionRecord<'a>(&'a [u8; RECORD_LEN]);
+
+impl<'a> ActionRecord<'a> {
+    /// Wrap a single record, which must be exactly `RECORD_LEN` bytes
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ConversionError> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| ConversionError::Malformed("record has the wrong length"))
+    }
+
+    pub fn kind(&self) -> Result<ActionKind, ConversionError> {
+        Ok(match self.0[0] {
+            1 => ActionKind::Deposit,
+            2 => ActionKind::Withdrawal,
+            3 => ActionKind::Dispute,
+            4 => ActionKind::Resolve,
+            5 => ActionKind::Chargeback,
+            kind => return Err(ConversionError::UnknownActionKind(kind.to_string())),
+        })
+    }
+
+    // The conversions are no-ops without the wide id features
+    #[allow(clippy::useless_conversion)]
+    pub fn client_id(&self) -> ClientId {
+        ClientId(u16::from_le_bytes([self.0[1], self.0[2]]).into())
+    }
+
+    #[allow(clippy::useless_conversion)]
+    pub fn transaction_id(&self) -> TransactionId {
+        TransactionId(u32::from_le_bytes([self.0[3], self.0[4], self.0[5], self.0[6]]).into())
+    }
+
+    /// The amount in ten thousandths, if there is one
+    pub fn minor_units(&self) -> Option<i64> {
+        let mut amount = [0; 8];
+        amount.copy_from_slice(&self.0[7..15]);
+        Some(i64::from_le_bytes(amount)).filter(|amount| *amount != NO_AMOUNT)
+    }
+
+    pub fn amount(&self) -> Option<Amount> {
+        self.minor_units().map(from_minor_units)
+    }
+
+    /// Decode the whole record
+    pub fn to_action(&self) -> Result<Action, ConversionError> {
+        Ok(Action {
+            transaction_id: Some(self.transaction_id()),
+            client_id: self.client_id(),
+            kind: self.kind()?,
+            amount: self.amount(),
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8; RECORD_LEN] {
+        self.0
+    }
+}
+
+/// Reads actions straight out of a buffer of records, e.g. a file read into
+/// memory in one go.
+///
+/// Iterating gives decoded `Action`s, so it can be passed anywhere the other
+/// sources can; use `records` to look at fields without decoding the rest.
+#[derive(Debug, Clone)]
+pub struct BinaryReader<'a> {
+    records: ChunksExact<'a, u8>,
+}
+
+impl<'a> BinaryReader<'a> {
+    /// Read the records in `bytes`, which must hold a whole number of them
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ConversionError> {
+        if !bytes.len().is_multiple_of(RECORD_LEN) {
+            return Err(ConversionError::Malformed(
+                "input isn't a whole number of records",
+            ));
+        }
+        Ok(Self {
+            records: bytes.chunks_exact(RECORD_LEN),
+        })
+    }
+
+    /// The records left to read
+    pub fn records(self) -> impl ExactSizeIterator<Item = ActionRecord<'a>> {
+        self.records
+            .map(|record| ActionRecord(record.try_into().expect("chunks are whole records")))
+    }
+}
+
+impl<'a> Iterator for BinaryReader<'a> {
+    type Item = Result<Action, ConversionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        Some(ActionRecord(record.try_into().expect("chunks are whole records")).to_action())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.records.size_hint()
+    }
+}
+
+impl ExactSizeIterator for BinaryReader<'_> {}
+
+#[cfg(feature = "decimal")]
+fn to_minor_units(amount: Amount) -> Result<i64, ConversionError> {
+    let invalid = || ConversionError::InvalidAmount(amount.to_string());
+    if amount.normalize().scale() > SCALE {
+        return Err(invalid());
+    }
+    let mut scaled = amount;
+    scaled.rescale(SCALE);
+    i64::try_from(scaled.mantissa())
+        .ok()
+        .filter(|units| *units != NO_AMOUNT)
+        .ok_or_else(invalid)
+}
+
+#[cfg(feature = "decimal")]
+fn from_minor_units(units: i64) -> Amount {
+    Amount::new(units, SCALE).normalize()
+}
+
+#[cfg(not(feature = "decimal"))]
+fn to_minor_units(amount: Amount) -> Result<i64, ConversionError> {
+    let units = (amount * 10f64.powi(SCALE as i32)).round();
+    if units.is_finite() && units > NO_AMOUNT as f64 && units < i64::MAX as f64 {
+        Ok(units as i64)
+    } else {
+        Err(ConversionError::InvalidAmount(amount.to_string()))
+    }
+}
+
+#[cfg(not(feature = "decimal"))]
+fn from_minor_units(units: i64) -> Amount {
+    units as f64 / 10f64.powi(SCALE as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(kind: ActionKind, amount: Option<Amount>) -> Action {
+        Action {
+            transaction_id: Some(TransactionId(70_000)),
+            client_id: ClientId(513),
+            kind,
+            amount,
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let actions = [
+            action(
+                ActionKind::Deposit,
+                Some(Amount::from(12_345u32) / Amount::from(10_000u32)),
+            ),
+            action(ActionKind::Withdrawal, Some(Amount::from(2u32))),
+            action(ActionKind::Dispute, None),
+        ];
+        let mut bytes = Vec::new();
+        for action in &actions {
+            bytes.extend_from_slice(&encode(action).unwrap());
+        }
+        assert_eq!(bytes.len(), actions.len() * RECORD_LEN);
+        assert_eq!(
+            &bytes[..RECORD_LEN],
+            [1, 0x01, 0x02, 0x70, 0x11, 0x01, 0x00, 0x39, 0x30, 0, 0, 0, 0, 0, 0]
+        );
+
+        let reader = BinaryReader::new(&bytes).unwrap();
+        assert_eq!(reader.len(), 3);
+        for (decoded, action) in reader.zip(&actions) {
+            let decoded = decoded.unwrap();
+            assert_eq!(decoded.transaction_id, action.transaction_id);
+            assert_eq!(decoded.client_id, action.client_id);
+            assert_eq!(decoded.kind, action.kind);
+            assert_eq!(decoded.amount, action.amount);
+        }
+
+        let records: Vec<_> = BinaryReader::new(&bytes).unwrap().records().collect();
+        assert_eq!(records[1].minor_units(), Some(20_000));
+        assert_eq!(records[2].minor_units(), None);
+    }
+
+    #[test]
+    fn test_unrepresentable_actions_are_rejected() {
+        let mut reversal = action(ActionKind::Reversal, None);
+        reversal.reverses = Some(TransactionId(1));
+        assert!(matches!(
+            encode(&reversal),
+            Err(ConversionError::Unsupported("reversal"))
+        ));
+
+        #[cfg(feature = "decimal")]
+        assert!(matches!(
+            encode(&action(
+                ActionKind::Deposit,
+                Some(Amount::from(1u32) / Amount::from(100_000u32))
+            )),
+            Err(ConversionError::InvalidAmount(_))
+        ));
+
+        assert!(BinaryReader::new(&[0; RECORD_LEN + 1]).is_err());
+        let mut record = encode(&action(ActionKind::Deposit, None)).unwrap();
+        record[0] = 9;
+        assert!(matches!(
+            ActionRecord::new(&record).unwrap().to_action(),
+            Err(ConversionError::UnknownActionKind(_))
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+//! Transparent decompression of inputs, picked by their file extension
+//!
+//! `.gz` files are decoded with the `gzip` feature and `.zst` files with the
+//! `zstd` feature. Compressed files are still recognised without them, so
+//! they fail with an error naming the missing feature rather than as garbled
+//! csv.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+/// How an input is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Guess from a file's extension, assuming anything unrecognised is
+    /// uncompressed
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// Decode `reader`, failing if support for the format wasn't built in
+    pub fn decoder<R: Read>(self, reader: R) -> io::Result<Decoder<R>> {
+        let inner = match self {
+            Self::None => Inner::Plain(reader),
+            #[cfg(feature = "gzip")]
+            Self::Gzip => Inner::Gzip(flate2::read::MultiGzDecoder::new(reader)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Inner::Zstd(zstd::Decoder::new(reader)?),
+            #[allow(unreachable_patterns)]
+            _ => return Err(self.unsupported()),
+        };
+        Ok(Decoder(inner))
+    }
+
+    /// Open a file, decoding it according to its extension
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Decoder<File>> {
+        let file = File::open(&path)?;
+        Self::from_path(path).decoder(file)
+    }
+
+    #[allow(dead_code)]
+    fn unsupported(self) -> io::Error {
+        let feature = match self {
+            Self::None => "",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        };
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("reading {} input needs the `{}` feature", feature, feature),
+        )
+    }
+}
+
+/// A reader that decompresses its input as it's read, from
+/// `Compression::decoder`
+pub struct Decoder<R: Read>(Inner<R>);
+
+enum Inner<R: Read> {
+    Plain(R),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::read::MultiGzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, io::BufReader<R>>),
+}
+
+impl<R: Read> Decoder<R> {
+    /// The compressed input, e.g. to see how much of it has been read
+    pub fn get_ref(&self) -> &R {
+        match &self.0 {
+            Inner::Plain(reader) => reader,
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(decoder) => decoder.get_ref(),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(decoder) => decoder.get_ref().get_ref(),
+        }
+    }
+
+    /// Unwrap the compressed input. Anything buffered but not yet decoded is
+    /// lost.
+    pub fn into_inner(self) -> R {
+        match self.0 {
+            Inner::Plain(reader) => reader,
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(decoder) => decoder.into_inner(),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(decoder) => decoder.finish().into_inner(),
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Inner::Plain(reader) => reader.read(buf),
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+impl<R: Read> std::fmt::Debug for Decoder<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let compression = match &self.0 {
+            Inner::Plain(_) => Compression::None,
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(_) => Compression::Gzip,
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(_) => Compression::Zstd,
+        };
+        f.debug_tuple("Decoder").field(&compression).finish()
+    }
+}
+
+#[cfg(all(test, feature = "gzip", feature = "zstd"))]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::io::CsvSource;
+
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,0.5\n";
+
+    #[test]
+    fn test_compressed_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let gz = dir.path().join("actions.csv.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&gz).unwrap(), Default::default());
+        encoder.write_all(INPUT.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let zst = dir.path().join("actions.csv.zst");
+        zstd::stream::copy_encode(INPUT.as_bytes(), File::create(&zst).unwrap(), 0).unwrap();
+
+        let plain = dir.path().join("actions.csv");
+        std::fs::write(&plain, INPUT).unwrap();
+
+        for path in [gz, zst, plain] {
+            let actions: Vec<_> = CsvSource::from_path(&path)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(actions.len(), 2, "{}", path.display());
+        }
+        assert_eq!(Compression::from_path("a.csv.zst"), Compression::Zstd);
+        assert_eq!(Compression::from_path("a.csv"), Compression::None);
+    }
+}
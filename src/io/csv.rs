@@ -0,0 +1,784 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use csv::{ByteRecord, Reader, ReaderBuilder, StringRecord};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{Compression, Decoder};
+use crate::{
+    Action, ActionKind, ActionMeta, ClientId, Money, MoneyError, Timestamp, TransactionId,
+};
+
+/// The columns in the original format, which is all a v1 input can have
+const V1_COLUMNS: [&str; 4] = ["amount", "client", "tx", "type"];
+
+/// A source of `Action`s read from csv data with a header row.
+///
+/// Whitespace around fields is trimmed, so both the dense and "pretty"
+/// (column aligned) formats can be read.
+pub struct CsvSource<R> {
+    reader: Reader<R>,
+    schema: CsvSchema,
+    headers: Option<StringRecord>,
+    record: StringRecord,
+    /// The header row didn't fit the schema, so there's nothing to read
+    rejected: bool,
+    /// Parse records by hand instead of through serde, see `fast`
+    fast: bool,
+    /// Where each field is, for the fast parser
+    columns: Columns,
+    bytes: ByteRecord,
+    /// Records read (or skipped) after the header row
+    records: u64,
+    /// Given to every action read, with its record number, see `with_meta`
+    meta: Option<ActionMeta>,
+}
+
+/// The index of each field's column, if the input has it
+#[derive(Debug, Default, Clone, Copy)]
+struct Columns {
+    kind: Option<usize>,
+    client: Option<usize>,
+    transaction: Option<usize>,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    reverses: Option<usize>,
+    reference: Option<usize>,
+    evidence: Option<usize>,
+    category: Option<usize>,
+    currency: Option<usize>,
+}
+
+/// Where the fields are in a record without a header row
+const POSITIONAL: Columns = Columns {
+    kind: Some(0),
+    client: Some(1),
+    transaction: Some(2),
+    amount: Some(3),
+    timestamp: None,
+    reverses: None,
+    reference: None,
+    evidence: None,
+    category: None,
+    currency: None,
+};
+
+/// Why a csv record isn't an action, down to the column
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum RecordError {
+    #[error("missing {column}")]
+    Missing { column: &'static str },
+
+    /// `index` is the field's place in the record
+    #[error("invalid {column} '{value}'")]
+    Invalid {
+        column: &'static str,
+        index: usize,
+        value: String,
+    },
+
+    /// The amount is a number, but not one an action can have (e.g. it's
+    /// negative)
+    #[error("{source}")]
+    Amount { index: usize, source: MoneyError },
+
+    /// The text given to `Action::from_str` isn't one csv record
+    #[error("malformed line: {0}")]
+    Malformed(String),
+}
+
+impl RecordError {
+    /// The column the error is in, if it's in one
+    pub fn column(&self) -> Option<&'static str> {
+        match self {
+            Self::Missing { column } | Self::Invalid { column, .. } => Some(column),
+            Self::Amount { .. } => Some("amount"),
+            Self::Malformed(_) => None,
+        }
+    }
+}
+
+/// Enough about a csv file to tell whether it's the same file later, without
+/// reading all of it: a hash of its header row and its length.
+///
+/// For a compressed file, the header row is the decompressed one and the
+/// length is of the file as stored. Anything appended to the file, or any
+/// change to its columns, changes the fingerprint. Edits to the records that
+/// keep the length the same don't, so this is a guard against running the
+/// wrong file rather than against tampering.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputFingerprint {
+    /// The SHA-256 of the header row, in hex
+    pub header_sha256: String,
+    pub len: u64,
+}
+
+/// Which columns a csv input has.
+///
+/// v1 is the original format: exactly the `type`, `client`, `tx` and `amount`
+/// columns (in any order), and nothing else. Inputs in it will always be read
+/// the same way.
+///
+/// v2 adds optional columns, each of which can be left out: `ts` (or
+/// `timestamp`), `currency`, `memo`, `idempotency` (or `ref`), `reverses`,
+/// `evidence` and `category`. `idempotency` is the action's external
+/// reference. `memo` is free text for whoever reads the file, and isn't kept.
+/// Columns v2 doesn't know are ignored, so files with columns added later can
+/// still be read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CsvSchema {
+    /// v1 if the header row has exactly the v1 columns, otherwise v2
+    #[default]
+    Detect,
+    V1,
+    V2,
+}
+
+impl CsvSource<Decoder<File>> {
+    /// Open a csv file as a source of actions, decompressing it if it's a
+    /// `.gz` or `.zst` file (see `Compression`)
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Ok(Self::from_reader(Compression::open(path)?))
+    }
+}
+
+impl InputFingerprint {
+    /// Fingerprint the csv file at `path`
+    pub fn of_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let len = std::fs::metadata(path)?.len();
+        let mut header = Vec::new();
+        BufReader::new(Compression::open(path)?).read_until(b'\n', &mut header)?;
+        let hash = Sha256::digest(&header);
+        Ok(Self {
+            header_sha256: hash.iter().map(|b| format!("{:02x}", b)).collect(),
+            len,
+        })
+    }
+}
+
+impl<R: Read> CsvSource<R> {
+    /// Read actions from any csv data stream
+    pub fn from_reader(reader: R) -> Self {
+        Self::new(Self::builder().from_reader(reader))
+    }
+
+    /// How far into the data the records read so far go, in bytes
+    pub fn bytes_read(&self) -> u64 {
+        self.reader.position().byte()
+    }
+
+    /// The last record read, as it was in the input (with its fields
+    /// trimmed), e.g. to report one that couldn't be parsed. Empty before
+    /// the first record, and after one that wasn't valid UTF-8 (unless
+    /// reading `fast`).
+    pub fn last_record(&self) -> &ByteRecord {
+        if self.fast {
+            &self.bytes
+        } else {
+            self.record.as_byte_record()
+        }
+    }
+
+    /// How many records after the header row have been read (or skipped)
+    pub fn records_read(&self) -> u64 {
+        self.records
+    }
+
+    /// Skip ahead so the next record read is record `n` (counting from 0,
+    /// after the header row), e.g. to carry on from where an interrupted run
+    /// left off. The records in between are read but not parsed, so this
+    /// works on any input, compressed or not. It only goes forwards, and
+    /// stops at the end of the input, returning how many records it skipped.
+    pub fn seek_to_record(&mut self, n: u64) -> Result<u64, csv::Error> {
+        if self.rejected {
+            return Ok(0);
+        }
+        if self.headers.is_none() {
+            self.read_headers()?;
+        }
+        let from = self.records;
+        while self.records < n && self.reader.read_byte_record(&mut self.bytes)? {
+            self.records += 1;
+        }
+        Ok(self.records - from)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    /// Unwrap the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    /// Read the input as this schema, rather than going by its header row.
+    /// A header row that doesn't fit is an error, and nothing is read after
+    /// it.
+    pub fn with_schema(mut self, schema: CsvSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Parse records straight from their bytes rather than through serde,
+    /// which is much faster on large inputs. Ids, amounts and timestamps are
+    /// read without copying them anywhere first, and only the text fields
+    /// (like `ref`) are allocated, when they aren't empty. Inputs read the
+    /// same either way, though errors for bad records are worded
+    /// differently.
+    pub fn fast(mut self) -> Self {
+        self.fast = true;
+        self
+    }
+
+    /// Tag every action read with `meta` (see `Action::meta`), its `record`
+    /// set to the action's record number. Off by default, since it adds an
+    /// allocation to every action, and to every transaction kept.
+    pub fn with_meta(mut self, meta: ActionMeta) -> Self {
+        self.meta = Some(meta);
+        self
+    }
+
+    /// The schema the input is read as, which is only known for
+    /// `CsvSchema::Detect` once the first action has been read
+    pub fn schema(&self) -> CsvSchema {
+        self.schema
+    }
+
+    fn new(reader: Reader<R>) -> Self {
+        Self {
+            reader,
+            schema: CsvSchema::Detect,
+            headers: None,
+            record: StringRecord::new(),
+            rejected: false,
+            fast: false,
+            columns: Columns::default(),
+            bytes: ByteRecord::new(),
+            records: 0,
+            meta: None,
+        }
+    }
+
+    fn builder() -> ReaderBuilder {
+        // `csv`'s default is to assume there is a header, but be explicit about it
+        let mut builder = ReaderBuilder::new();
+        builder.has_headers(true).trim(csv::Trim::All);
+        builder
+    }
+
+    fn read_action(&mut self) -> Result<Option<Action>, csv::Error> {
+        let action = self.read_record_action()?;
+        Ok(match &self.meta {
+            Some(meta) => action.map(|action| Action {
+                meta: Some(Box::new(ActionMeta {
+                    record: Some(self.records),
+                    ..meta.clone()
+                })),
+                ..action
+            }),
+            None => action,
+        })
+    }
+
+    fn read_record_action(&mut self) -> Result<Option<Action>, csv::Error> {
+        if self.rejected {
+            return Ok(None);
+        }
+        if self.headers.is_none() {
+            self.read_headers()?;
+        }
+        if self.fast {
+            if !self.reader.read_byte_record(&mut self.bytes)? {
+                return Ok(None);
+            }
+            self.records += 1;
+            return self.columns.parse(&self.bytes).map(Some);
+        }
+        if !self.reader.read_record(&mut self.record)? {
+            return Ok(None);
+        }
+        self.records += 1;
+        self.record.deserialize(self.headers.as_ref()).map(Some)
+    }
+
+    fn read_headers(&mut self) -> Result<(), csv::Error> {
+        let headers = self.reader.headers()?;
+        let is_v1 = {
+            let mut columns: Vec<_> = headers.iter().collect();
+            columns.sort_unstable();
+            columns == V1_COLUMNS
+        };
+        self.schema = match self.schema {
+            CsvSchema::Detect if is_v1 => CsvSchema::V1,
+            CsvSchema::Detect => CsvSchema::V2,
+            CsvSchema::V1 if !is_v1 => {
+                self.rejected = true;
+                let found: Vec<_> = headers.iter().collect();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "a v1 input has exactly the columns type, client, tx and amount, not {}",
+                        found.join(", ")
+                    ),
+                )
+                .into());
+            }
+            schema => schema,
+        };
+        let headers = match self.schema {
+            // The names v2 adds for existing fields
+            CsvSchema::V2 => headers
+                .iter()
+                .map(|column| match column {
+                    "ts" => "timestamp",
+                    "idempotency" => "ref",
+                    column => column,
+                })
+                .collect(),
+            _ => headers.clone(),
+        };
+        self.columns = Columns::new(&headers);
+        self.headers = Some(headers);
+        Ok(())
+    }
+}
+
+impl Columns {
+    /// Find the columns in a header row, by either their field names or v2's
+    /// names for them
+    fn new(headers: &StringRecord) -> Self {
+        let mut columns = Self::default();
+        for (index, column) in headers.iter().enumerate() {
+            let field = match column {
+                "type" => &mut columns.kind,
+                "client" => &mut columns.client,
+                "tx" => &mut columns.transaction,
+                "amount" => &mut columns.amount,
+                "timestamp" | "ts" => &mut columns.timestamp,
+                "reverses" => &mut columns.reverses,
+                "ref" | "idempotency" => &mut columns.reference,
+                "evidence" => &mut columns.evidence,
+                "category" => &mut columns.category,
+                "currency" => &mut columns.currency,
+                _ => continue,
+            };
+            field.get_or_insert(index);
+        }
+        columns
+    }
+
+    fn parse(&self, record: &ByteRecord) -> Result<Action, csv::Error> {
+        self.action(record).map_err(|e| {
+            let line = record.position().map_or(0, |position| position.line());
+            let message = format!("record on line {}: {}", line, e);
+            io::Error::new(io::ErrorKind::InvalidData, message).into()
+        })
+    }
+
+    fn action(&self, record: &ByteRecord) -> Result<Action, RecordError> {
+        let field = |column: Option<usize>| {
+            let index = column?;
+            let field = record.get(index)?.trim_ascii();
+            (!field.is_empty()).then_some((index, field))
+        };
+        let invalid = |column: &'static str, (index, value): (usize, &[u8])| RecordError::Invalid {
+            column,
+            index,
+            value: String::from_utf8_lossy(value).into_owned(),
+        };
+        let required = |column: &'static str, index: Option<usize>| {
+            field(index).ok_or(RecordError::Missing { column })
+        };
+
+        let kind = required("type", self.kind)?;
+        let kind = match kind.1 {
+            b"deposit" => ActionKind::Deposit,
+            b"withdrawal" => ActionKind::Withdrawal,
+            b"dispute" => ActionKind::Dispute,
+            b"resolve" => ActionKind::Resolve,
+            b"chargeback" => ActionKind::Chargeback,
+            b"reversal" => ActionKind::Reversal,
+            _ => return Err(invalid("type", kind)),
+        };
+        let client = required("client", self.client)?;
+        let client = parse_digits(client.1)
+            .map(ClientId)
+            .ok_or_else(|| invalid("client", client))?;
+        let transaction = match field(self.transaction) {
+            Some(id) => Some(
+                parse_digits(id.1)
+                    .map(TransactionId)
+                    .ok_or_else(|| invalid("tx", id))?,
+            ),
+            None => None,
+        };
+        let amount = field(self.amount)
+            .map(|amount| match Money::from_ascii(amount.1) {
+                Ok(money) => Ok(money.get()),
+                Err(MoneyError::Invalid(_)) => Err(invalid("amount", amount)),
+                Err(source) => Err(RecordError::Amount {
+                    index: amount.0,
+                    source,
+                }),
+            })
+            .transpose()?;
+        let timestamp = field(self.timestamp)
+            .map(|secs| {
+                parse_digits(secs.1)
+                    .map(Timestamp::from_secs)
+                    .ok_or_else(|| invalid("timestamp", secs))
+            })
+            .transpose()?;
+        let reverses = field(self.reverses)
+            .map(|id| {
+                parse_digits(id.1)
+                    .map(TransactionId)
+                    .ok_or_else(|| invalid("reverses", id))
+            })
+            .transpose()?;
+        let text = |column: &'static str, index: Option<usize>| {
+            field(index)
+                .map(|text| {
+                    std::str::from_utf8(text.1)
+                        .map(String::from)
+                        .map_err(|_| invalid(column, text))
+                })
+                .transpose()
+        };
+
+        Ok(Action {
+            transaction_id: transaction,
+            client_id: client,
+            kind,
+            amount,
+            timestamp,
+            reverses,
+            reference: text("ref", self.reference)?,
+            evidence: text("evidence", self.evidence)?,
+            category: text("category", self.category)?,
+            currency: text("currency", self.currency)?,
+            meta: None,
+        })
+    }
+}
+
+impl TryFrom<&StringRecord> for Action {
+    type Error = RecordError;
+
+    /// Parse a record without a header row, in v1's column order: `type`,
+    /// `client`, `tx` and `amount`. Fields are trimmed, and an empty (or
+    /// missing) `tx` or `amount` is left out, as in a csv input. For records
+    /// with other columns, see `Action::from_csv_record`.
+    fn try_from(record: &StringRecord) -> Result<Self, Self::Error> {
+        POSITIONAL.action(record.as_byte_record())
+    }
+}
+
+impl FromStr for Action {
+    type Err = RecordError;
+
+    /// Parse one csv line in v1's column order, e.g. `deposit, 1, 1, 1.5`
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(line.as_bytes());
+        let malformed = |e: csv::Error| RecordError::Malformed(e.to_string());
+        let mut record = StringRecord::new();
+        if !reader.read_record(&mut record).map_err(malformed)? {
+            return Err(RecordError::Malformed("the line is empty".into()));
+        }
+        if reader
+            .read_record(&mut StringRecord::new())
+            .map_err(malformed)?
+        {
+            return Err(RecordError::Malformed("there's more than one line".into()));
+        }
+        Self::try_from(&record)
+    }
+}
+
+impl Action {
+    /// Parse a record with the columns `headers` names, which can be any of
+    /// a csv input's (v1 or v2, in any order, see `CsvSchema`). Columns v2
+    /// doesn't know are ignored.
+    ///
+    /// This is the same parsing as `CsvSource`'s, for reading actions out of
+    /// csv data handled some other way.
+    pub fn from_csv_record(
+        record: &StringRecord,
+        headers: &StringRecord,
+    ) -> Result<Self, RecordError> {
+        Columns::new(headers).action(record.as_byte_record())
+    }
+}
+
+/// An unsigned integer from its decimal digits, if it's all digits and fits
+fn parse_digits<T: TryFrom<u64>>(digits: &[u8]) -> Option<T> {
+    let mut value: u64 = 0;
+    for digit in digits {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        value = value
+            .checked_mul(10)?
+            .checked_add(u64::from(digit - b'0'))?;
+    }
+    T::try_from(value).ok()
+}
+
+impl<R: Read> Iterator for CsvSource<R> {
+    type Item = Result<Action, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_action().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ActionKind, ClientId, RawTransactionId, State, Timestamp, TransactionId, UpdateError,
+    };
+
+    const V1: &str = "type, client, tx, amount\n\
+        deposit, 1, 1, 1.5\n\
+        dispute, 1, 1,\n";
+
+    const V2: &str = "type,client,tx,amount,ts,currency,memo,idempotency\n\
+        deposit,1,1,1.5,1700000000,USD,rent,upstream-1\n\
+        withdrawal,1,2,0.5,,,,\n";
+
+    fn read(input: &str, schema: CsvSchema) -> (Vec<Result<Action, csv::Error>>, CsvSchema) {
+        let mut source = CsvSource::from_reader(input.as_bytes()).with_schema(schema);
+        let actions = source.by_ref().collect();
+        (actions, source.schema())
+    }
+
+    #[test]
+    fn test_fast_reads_the_same() {
+        let input =
+            "type,client,tx,amount,ts,currency,memo,idempotency,reverses,evidence,category\n\
+            deposit,1,1,1.5,1700000000,USD,rent,upstream-1,,,\n\
+            withdrawal, 2, 2, 0.25 ,,,,,,,groceries\n\
+            dispute,1,,,,,,upstream-1,,case-9,\n\
+            reversal,2,3,,,,,,2,,\n";
+        let slow: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        let fast: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .fast()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(fast.len(), 4);
+        // `Action` isn't `PartialEq`, but its serialization covers every field
+        assert_eq!(
+            serde_json::to_string(&fast).unwrap(),
+            serde_json::to_string(&slow).unwrap()
+        );
+        assert_eq!(fast[2].transaction_id, None);
+
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,abc\n\
+            refund,1,2,1.0\n\
+            deposit,70000,3,1.0\n\
+            deposit,,4,1.0\n\
+            deposit,1,5,1.0\n";
+        let errors: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .fast()
+            .map(|item| item.err().map(|e| e.to_string()))
+            .collect();
+        assert!(errors[0].as_ref().unwrap().contains("invalid amount 'abc'"));
+        assert!(errors[1]
+            .as_ref()
+            .unwrap()
+            .contains("invalid type 'refund'"));
+        #[cfg(not(feature = "wide-client-ids"))]
+        assert!(errors[2]
+            .as_ref()
+            .unwrap()
+            .contains("invalid client '70000'"));
+        assert!(errors[3]
+            .as_ref()
+            .unwrap()
+            .contains("line 5: missing client"));
+        assert!(errors[4].is_none());
+    }
+
+    #[test]
+    fn test_schemas() {
+        let (actions, schema) = read(V1, CsvSchema::Detect);
+        assert_eq!(schema, CsvSchema::V1);
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1].as_ref().unwrap().kind, ActionKind::Dispute);
+        // A v1 input is a v2 input too
+        let (actions, _) = read(V1, CsvSchema::V2);
+        assert!(actions.iter().all(Result::is_ok));
+
+        let (actions, schema) = read(V2, CsvSchema::Detect);
+        assert_eq!(schema, CsvSchema::V2);
+        let deposit = actions[0].as_ref().unwrap();
+        assert_eq!(deposit.transaction_id, Some(TransactionId(1)));
+        assert_eq!(deposit.client_id, ClientId(1));
+        assert_eq!(deposit.timestamp, Some(Timestamp::from_secs(1_700_000_000)));
+        assert_eq!(deposit.currency.as_deref(), Some("USD"));
+        assert_eq!(deposit.reference.as_deref(), Some("upstream-1"));
+        let withdrawal = actions[1].as_ref().unwrap();
+        assert_eq!(
+            (withdrawal.timestamp, withdrawal.reference.as_deref()),
+            (None, None)
+        );
+
+        // Only the header row is an error
+        let (actions, _) = read(V2, CsvSchema::V1);
+        assert_eq!(actions.len(), 1);
+        let error = actions[0].as_ref().unwrap_err().to_string();
+        assert!(
+            error.contains("not type, client, tx, amount, ts"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_seek_to_record() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1\n\
+            deposit,1,2,2\n\
+            deposit,1,3,3\n";
+        for fast in [false, true] {
+            let mut source = CsvSource::from_reader(input.as_bytes());
+            if fast {
+                source = source.fast();
+            }
+            assert_eq!(source.seek_to_record(2).unwrap(), 2);
+            // Only forwards
+            assert_eq!(source.seek_to_record(1).unwrap(), 0);
+            let action = source.next().unwrap().unwrap();
+            assert_eq!(action.transaction_id, Some(TransactionId(3)));
+            assert_eq!(source.records_read(), 3);
+            assert_eq!(source.seek_to_record(10).unwrap(), 0);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, input).unwrap();
+        let fingerprint = InputFingerprint::of_path(&path).unwrap();
+        assert_eq!(fingerprint.len, input.len() as u64);
+        std::fs::write(&path, format!("{input}deposit,1,4,4\n")).unwrap();
+        assert_ne!(InputFingerprint::of_path(&path).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let action: Action = " deposit, 1, 7, 1.5 ".parse().unwrap();
+        assert_eq!(action.kind, ActionKind::Deposit);
+        assert_eq!(action.client_id, ClientId(1));
+        assert_eq!(action.transaction_id, Some(TransactionId(7)));
+        assert_eq!(action.amount, Some("1.5".parse().unwrap()));
+        let action: Action = "dispute,1,7".parse().unwrap();
+        assert_eq!(action.amount, None);
+
+        let error = "deposit,x,7,1".parse::<Action>().unwrap_err();
+        assert_eq!(
+            error,
+            RecordError::Invalid {
+                column: "client",
+                index: 1,
+                value: "x".into()
+            }
+        );
+        assert_eq!(error.to_string(), "invalid client 'x'");
+        let error = "deposit,1,7,-1".parse::<Action>().unwrap_err();
+        assert!(matches!(error, RecordError::Amount { index: 3, .. }));
+        assert_eq!(error.column(), Some("amount"));
+        assert_eq!(
+            ",1,7,1".parse::<Action>().unwrap_err(),
+            RecordError::Missing { column: "type" }
+        );
+        assert!(matches!(
+            "deposit,1,7,1\ndeposit,1,8,1".parse::<Action>(),
+            Err(RecordError::Malformed(_))
+        ));
+
+        let headers = StringRecord::from(vec!["client", "idempotency", "type", "amount", "ts"]);
+        let record = StringRecord::from(vec!["2", "upstream-1", "withdrawal", "3", "1700000000"]);
+        let action = Action::from_csv_record(&record, &headers).unwrap();
+        assert_eq!(action.kind, ActionKind::Withdrawal);
+        assert_eq!(action.transaction_id, None);
+        assert_eq!(action.reference.as_deref(), Some("upstream-1"));
+        assert_eq!(action.timestamp, Some(Timestamp::from_secs(1700000000)));
+    }
+
+    #[test]
+    fn test_largest_transaction_id() {
+        let input = format!(
+            "type,client,tx,amount\ndeposit,1,{},5.0\nwithdrawal,1,,1.0\n",
+            RawTransactionId::MAX
+        );
+        let slow: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        let fast: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .fast()
+            .map(Result::unwrap)
+            .collect();
+        for actions in [slow, fast] {
+            let largest = TransactionId(RawTransactionId::MAX);
+            assert_eq!(actions[0].transaction_id, Some(largest));
+            assert_eq!(actions[1].transaction_id, None);
+
+            let mut state = State::new();
+            state.update(actions[0].clone()).unwrap();
+            assert!(matches!(
+                state.update(actions[1].clone()),
+                Err(UpdateError::NoTransactionId)
+            ));
+            assert!(state.transaction(largest).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_meta() {
+        use crate::audit::AuditEvent;
+
+        let meta = ActionMeta {
+            source: Some("in.csv".into()),
+            ingest: Some("batch-7".into()),
+            ..ActionMeta::default()
+        };
+        for fast in [false, true] {
+            let mut source = CsvSource::from_reader(V2.as_bytes()).with_meta(meta.clone());
+            if fast {
+                source = source.fast();
+            }
+            let mut state = crate::State::new();
+            state.set_audit_trail(true);
+            for action in source {
+                state.update(action.unwrap()).unwrap();
+            }
+
+            let withdrawal = state.transaction(TransactionId(2)).unwrap().unwrap();
+            let found = withdrawal.meta.unwrap();
+            assert_eq!(found.source.as_deref(), Some("in.csv"));
+            assert_eq!(found.record, Some(2));
+            assert_eq!(found.ingest.as_deref(), Some("batch-7"));
+            let export = state.export_client(ClientId(1)).unwrap();
+            assert!(matches!(
+                &export.audit_trail[0].event,
+                AuditEvent::Action { meta: Some(meta), .. } if meta.record == Some(1)
+            ));
+        }
+
+        // Nothing is added unless asked for
+        let action = CsvSource::from_reader(V1.as_bytes()).next().unwrap();
+        assert!(action.unwrap().meta.is_none());
+    }
+}
@@ -0,0 +1,504 @@
+//! Reading actions from ISO 20022 payment messages, as exported by banks
+//!
+//! Two messages are supported, in any of their recent versions (elements are
+//! matched by name, whatever the namespace):
+//!
+//! - `pain.001` customer credit transfer initiations. Each credit transfer
+//!   (`CdtTrfTxInf`) is a withdrawal from the debtor's account
+//!   (`PmtInf/DbtrAcct`), dated by the requested execution date.
+//! - `camt.054` debit and credit notifications. Each booked entry (`Ntry`) on
+//!   the notified account is a deposit (`CRDT`) or withdrawal (`DBIT`), dated
+//!   by its booking date. Entries batching several transactions are split by
+//!   their `TxDtls`. Pending entries are skipped, and reversal entries can't be
+//!   matched to the transaction they reverse, so they're errors.
+//!
+//! Messages don't carry the engine's transaction ids, so actions are given
+//! the payment's end to end id as their external reference instead (see
+//! `Action::reference`), falling back to the bank's own references, and the
+//! engine assigns the ids. Accounts (IBANs or other ids) are mapped to clients
+//! with `Iso20022Adapter::with_account`, or taken as the client id if they're
+//! a plain number.
+//!
+//! Date times without an offset are taken as UTC.
+
+use std::{collections::HashMap, fs, path::Path, vec};
+
+use roxmltree::{Document, Node};
+
+use super::{days_from_civil, parse_amount};
+use crate::{Action, ActionKind, Amount, ClientId, Timestamp};
+
+/// The placeholder banks use for a missing end to end id
+const NOT_PROVIDED: &str = "NOTPROVIDED";
+
+/// Which message a source was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// `pain.001` customer credit transfer initiation
+    Pain001,
+    /// `camt.054` bank to customer debit credit notification
+    Camt054,
+}
+
+/// How to turn messages into actions
+#[derive(Debug, Default, Clone)]
+pub struct Iso20022Adapter {
+    accounts: HashMap<String, ClientId>,
+    currency: Option<String>,
+}
+
+/// The actions read from one message, from `Iso20022Adapter::read_str`.
+///
+/// The whole message is parsed up front. Like `CsvSource`, a payment that
+/// can't be turned into an action is yielded as an error, and the rest are
+/// still read.
+#[derive(Debug)]
+pub struct Iso20022Source {
+    kind: MessageKind,
+    actions: vec::IntoIter<Result<Action, Iso20022Error>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Iso20022Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("failed to parse xml: {0}")]
+    Xml(#[from] roxmltree::Error),
+
+    #[error("not a pain.001 or camt.054 message (found {0})")]
+    UnknownMessage(String),
+
+    #[error("payment {payment} is not a valid action: {reason}")]
+    InvalidPayment { payment: u64, reason: String },
+}
+
+impl Iso20022Adapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map an account (its IBAN, or other id) to a client
+    pub fn with_account<S: Into<String>>(mut self, account: S, client: ClientId) -> Self {
+        self.accounts.insert(account.into(), client);
+        self
+    }
+
+    /// Only accept amounts in this currency (e.g. `"EUR"`). Otherwise the
+    /// currency is ignored, since the engine doesn't keep one.
+    pub fn with_currency<S: Into<String>>(mut self, currency: S) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    pub fn read_path<P: AsRef<Path>>(&self, path: P) -> Result<Iso20022Source, Iso20022Error> {
+        self.read_str(&fs::read_to_string(path)?)
+    }
+
+    /// Read the actions from a message. Only a document that isn't xml, or
+    /// isn't one of the supported messages, fails as a whole.
+    pub fn read_str(&self, xml: &str) -> Result<Iso20022Source, Iso20022Error> {
+        let document = Document::parse(xml)?;
+        let message = document
+            .root_element()
+            .children()
+            .find(Node::is_element)
+            .ok_or_else(|| Iso20022Error::UnknownMessage("an empty document".into()))?;
+
+        let mut reader = Reader {
+            adapter: self,
+            payments: 0,
+            actions: Vec::new(),
+        };
+        let kind = match message.tag_name().name() {
+            "CstmrCdtTrfInitn" => {
+                reader.read_pain001(message);
+                MessageKind::Pain001
+            }
+            "BkToCstmrDbtCdtNtfctn" => {
+                reader.read_camt054(message);
+                MessageKind::Camt054
+            }
+            other => return Err(Iso20022Error::UnknownMessage(other.into())),
+        };
+        Ok(Iso20022Source {
+            kind,
+            actions: reader.actions.into_iter(),
+        })
+    }
+
+    fn client(&self, account: Option<Node>) -> Result<ClientId, String> {
+        let id = account
+            .and_then(|account| child(account, "Id"))
+            .and_then(|id| text(id, &["IBAN"]).or_else(|| text(id, &["Othr", "Id"])))
+            .ok_or("no account id")?;
+        if let Some(client) = self.accounts.get(id) {
+            return Ok(*client);
+        }
+        id.parse()
+            .map(ClientId)
+            .map_err(|_| format!("account {} isn't mapped to a client", id))
+    }
+
+    fn amount(&self, amount: Node) -> Result<Amount, String> {
+        if let (Some(expected), Some(found)) = (&self.currency, amount.attribute("Ccy")) {
+            if expected != found {
+                return Err(format!("the amount is in {}, not {}", found, expected));
+            }
+        }
+        let amount = amount.text().ok_or("no amount")?;
+        parse_amount(amount).map_err(|e| e.to_string())
+    }
+}
+
+impl Iso20022Source {
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+}
+
+impl Iterator for Iso20022Source {
+    type Item = Result<Action, Iso20022Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.actions.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.actions.size_hint()
+    }
+}
+
+/// Collects the actions from one message, numbering the payments for errors
+struct Reader<'a> {
+    adapter: &'a Iso20022Adapter,
+    payments: u64,
+    actions: Vec<Result<Action, Iso20022Error>>,
+}
+
+impl Reader<'_> {
+    fn push(&mut self, action: Result<Action, String>) {
+        self.payments += 1;
+        let payment = self.payments;
+        self.actions
+            .push(action.map_err(|reason| Iso20022Error::InvalidPayment { payment, reason }));
+    }
+
+    fn read_pain001(&mut self, message: Node) {
+        for info in children(message, "PmtInf") {
+            let client = self.adapter.client(child(info, "DbtrAcct"));
+            let timestamp = child(info, "ReqdExctnDt").map(date);
+            for transfer in children(info, "CdtTrfTxInf") {
+                let action = (|| {
+                    let amount = child(transfer, "Amt")
+                        .and_then(|amount| child(amount, "InstdAmt"))
+                        .ok_or("no instructed amount")?;
+                    let reference = child(transfer, "PmtId")
+                        .and_then(|id| {
+                            text(id, &["EndToEndId"])
+                                .filter(|id| *id != NOT_PROVIDED)
+                                .or_else(|| text(id, &["InstrId"]))
+                        })
+                        .ok_or("no end to end or instruction id")?;
+                    Ok(action(
+                        ActionKind::Withdrawal,
+                        client.clone()?,
+                        self.adapter.amount(amount)?,
+                        amount.attribute("Ccy"),
+                        timestamp.clone().transpose()?,
+                        reference,
+                    ))
+                })();
+                self.push(action);
+            }
+        }
+    }
+
+    fn read_camt054(&mut self, message: Node) {
+        for notification in children(message, "Ntfctn") {
+            let client = self.adapter.client(child(notification, "Acct"));
+            for entry in children(notification, "Ntry") {
+                // Only booked entries have actually moved money
+                let status = text(entry, &["Sts", "Cd"]).or_else(|| text(entry, &["Sts"]));
+                if status.is_some_and(|status| status != "BOOK") {
+                    continue;
+                }
+                let details: Vec<_> = children(entry, "NtryDtls")
+                    .flat_map(|details| children(details, "TxDtls"))
+                    .collect();
+                if details.len() <= 1 {
+                    let action = self.camt054_action(entry, details.first().copied(), &client);
+                    self.push(action);
+                } else {
+                    for transaction in details {
+                        let action = self.camt054_action(entry, Some(transaction), &client);
+                        self.push(action);
+                    }
+                }
+            }
+        }
+    }
+
+    /// An action for an entry, or for one of the transactions batched in it
+    fn camt054_action(
+        &self,
+        entry: Node,
+        transaction: Option<Node>,
+        client: &Result<ClientId, String>,
+    ) -> Result<Action, String> {
+        if text(entry, &["RvslInd"]) == Some("true") {
+            return Err("reversal entries aren't supported".into());
+        }
+        let indicator = transaction
+            .and_then(|transaction| text(transaction, &["CdtDbtInd"]))
+            .or_else(|| text(entry, &["CdtDbtInd"]));
+        let kind = match indicator {
+            Some("CRDT") => ActionKind::Deposit,
+            Some("DBIT") => ActionKind::Withdrawal,
+            Some(other) => return Err(format!("unknown credit debit indicator {}", other)),
+            None => return Err("no credit debit indicator".into()),
+        };
+
+        // A batched transaction has its own amount, either directly or in its
+        // amount details
+        let amount = match transaction {
+            Some(transaction) if entry_has_batch(entry) => child(transaction, "Amt")
+                .or_else(|| {
+                    child(transaction, "AmtDtls")
+                        .and_then(|details| child(details, "TxAmt"))
+                        .and_then(|amount| child(amount, "Amt"))
+                })
+                .ok_or("no amount for a batched transaction")?,
+            _ => child(entry, "Amt").ok_or("no amount")?,
+        };
+
+        let reference = transaction
+            .and_then(|transaction| child(transaction, "Refs"))
+            .and_then(|refs| {
+                text(refs, &["EndToEndId"])
+                    .filter(|id| *id != NOT_PROVIDED)
+                    .or_else(|| text(refs, &["AcctSvcrRef"]))
+            })
+            .or_else(|| text(entry, &["AcctSvcrRef"]))
+            .or_else(|| text(entry, &["NtryRef"]))
+            .ok_or("no end to end id or bank reference")?;
+
+        let timestamp = child(entry, "BookgDt").map(date).transpose()?;
+        Ok(action(
+            kind,
+            client.clone()?,
+            self.adapter.amount(amount)?,
+            amount.attribute("Ccy"),
+            timestamp,
+            reference,
+        ))
+    }
+}
+
+fn entry_has_batch(entry: Node) -> bool {
+    children(entry, "NtryDtls")
+        .flat_map(|details| children(details, "TxDtls"))
+        .nth(1)
+        .is_some()
+}
+
+fn action(
+    kind: ActionKind,
+    client: ClientId,
+    amount: Amount,
+    currency: Option<&str>,
+    timestamp: Option<Timestamp>,
+    reference: &str,
+) -> Action {
+    Action {
+        transaction_id: None,
+        client_id: client,
+        kind,
+        amount: Some(amount),
+        timestamp,
+        reverses: None,
+        reference: Some(reference.into()),
+        evidence: None,
+        category: None,
+        currency: currency.map(String::from),
+        meta: None,
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+/// The trimmed text at a path of child elements
+fn text<'a>(node: Node<'a, '_>, path: &[&str]) -> Option<&'a str> {
+    let mut node = node;
+    for name in path {
+        node = child(node, name)?;
+    }
+    node.text().map(str::trim).filter(|text| !text.is_empty())
+}
+
+/// A date element, which holds either a `Dt` or `DtTm` (or, in older
+/// versions, the date directly)
+fn date(node: Node) -> Result<Timestamp, String> {
+    let value = text(node, &["DtTm"])
+        .or_else(|| text(node, &["Dt"]))
+        .or_else(|| text(node, &[]))
+        .ok_or("no date")?;
+    parse_date_time(value).ok_or_else(|| format!("invalid date '{}'", value))
+}
+
+/// Parse `YYYY-MM-DD`, optionally followed by `Thh:mm:ss`, fractional seconds
+/// and a `Z` or `±hh:mm` offset
+fn parse_date_time(value: &str) -> Option<Timestamp> {
+    let number = |s: &str| s.parse::<i64>().ok();
+    let (date, time) = value.split_once('T').unwrap_or((value, ""));
+    let mut parts = date.splitn(3, '-');
+    let year = number(parts.next()?)?;
+    let month = number(parts.next()?)?;
+    let day = number(parts.next()?)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+    if !time.is_empty() {
+        let (clock, offset) = match time.find(['Z', '+', '-']) {
+            Some(at) => time.split_at(at),
+            None => (time, ""),
+        };
+        let clock = clock.split('.').next()?;
+        let mut parts = clock.splitn(3, ':');
+        let hours = number(parts.next()?)?;
+        let minutes = number(parts.next()?)?;
+        let secs = parts.next().map_or(Some(0), number)?;
+        seconds += hours * 3600 + minutes * 60 + secs;
+
+        if let Some(offset) = offset.strip_prefix(['+', '-']) {
+            let (hours, minutes) = offset.split_once(':')?;
+            let offset = number(hours)? * 3600 + number(minutes)? * 60;
+            // Local time is ahead of UTC by a positive offset
+            seconds += if time.contains('+') { -offset } else { offset };
+        }
+    }
+    u64::try_from(seconds).ok().map(Timestamp::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::State;
+
+    const PAIN001: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:pain.001.001.09">
+  <CstmrCdtTrfInitn>
+    <GrpHdr><MsgId>MSG-1</MsgId><NbOfTxs>2</NbOfTxs></GrpHdr>
+    <PmtInf>
+      <PmtInfId>PMT-1</PmtInfId>
+      <ReqdExctnDt><Dt>2024-03-01</Dt></ReqdExctnDt>
+      <DbtrAcct><Id><IBAN>DE89370400440532013000</IBAN></Id></DbtrAcct>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>E2E-1</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">2.50</InstdAmt></Amt>
+      </CdtTrfTxInf>
+      <CdtTrfTxInf>
+        <PmtId><InstrId>INSTR-2</InstrId><EndToEndId>NOTPROVIDED</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="USD">1.00</InstdAmt></Amt>
+      </CdtTrfTxInf>
+    </PmtInf>
+  </CstmrCdtTrfInitn>
+</Document>"#;
+
+    const CAMT054: &str = r#"<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.054.001.08">
+  <BkToCstmrDbtCdtNtfctn>
+    <Ntfctn>
+      <Acct><Id><Othr><Id>7</Id></Othr></Id></Acct>
+      <Ntry>
+        <Amt Ccy="EUR">10.00</Amt><CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts><Cd>BOOK</Cd></Sts>
+        <BookgDt><DtTm>2024-03-01T10:00:00+01:00</DtTm></BookgDt>
+        <NtryDtls><TxDtls><Refs><EndToEndId>IN-1</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">6.00</Amt><CdtDbtInd>DBIT</CdtDbtInd>
+        <Sts><Cd>BOOK</Cd></Sts>
+        <NtryDtls>
+          <TxDtls><Refs><EndToEndId>OUT-1</EndToEndId></Refs><Amt Ccy="EUR">4.00</Amt></TxDtls>
+          <TxDtls><Refs><EndToEndId>OUT-2</EndToEndId></Refs><Amt Ccy="EUR">2.00</Amt></TxDtls>
+        </NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">99.00</Amt><CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts><Cd>PDNG</Cd></Sts>
+        <NtryRef>PENDING-1</NtryRef>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">10.00</Amt><CdtDbtInd>DBIT</CdtDbtInd><RvslInd>true</RvslInd>
+        <Sts><Cd>BOOK</Cd></Sts>
+        <NtryRef>REVERSAL-1</NtryRef>
+      </Ntry>
+    </Ntfctn>
+  </BkToCstmrDbtCdtNtfctn>
+</Document>"#;
+
+    #[test]
+    fn test_pain001() {
+        let adapter = Iso20022Adapter::new()
+            .with_account("DE89370400440532013000", ClientId(3))
+            .with_currency("EUR");
+        let source = adapter.read_str(PAIN001).unwrap();
+        assert_eq!(source.kind(), MessageKind::Pain001);
+        let actions: Vec<_> = source.collect();
+        assert_eq!(actions.len(), 2);
+
+        let first = actions[0].as_ref().unwrap();
+        assert_eq!(first.kind, ActionKind::Withdrawal);
+        assert_eq!(first.client_id, ClientId(3));
+        assert_eq!(first.reference.as_deref(), Some("E2E-1"));
+        assert_eq!(first.timestamp, Some(Timestamp::from_secs(1_709_251_200)));
+        assert!(matches!(
+            actions[1],
+            Err(Iso20022Error::InvalidPayment { payment: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_camt054() {
+        let source = Iso20022Adapter::new().read_str(CAMT054).unwrap();
+        assert_eq!(source.kind(), MessageKind::Camt054);
+        let (actions, errors): (Vec<_>, Vec<_>) = source.partition(Result::is_ok);
+        assert_eq!(errors.len(), 1, "only the reversal fails");
+
+        let mut state = State::new();
+        for action in actions {
+            let action = action.unwrap();
+            assert_eq!(action.client_id, ClientId(7));
+            state.update(action).unwrap();
+        }
+        let account = state.account(ClientId(7)).unwrap();
+        assert_eq!(account.available, Amount::from(4u32));
+        assert_eq!(state.seen_transactions().len(), 3);
+        assert!(state.transaction_for_reference("OUT-2").is_some());
+    }
+
+    #[test]
+    fn test_parse_date_time() {
+        let parse = |value| parse_date_time(value).map(|t| t.as_secs());
+        assert_eq!(parse("1970-01-01"), Some(0));
+        assert_eq!(parse("2024-02-29"), Some(1_709_164_800));
+        assert_eq!(parse("2024-03-01T10:00:00.123Z"), Some(1_709_287_200));
+        assert_eq!(parse("2024-03-01T10:00:00+01:00"), Some(1_709_283_600));
+        assert_eq!(parse("2024-03-01T10:00:00-01:30"), Some(1_709_292_600));
+        assert_eq!(parse("1969-12-31"), None);
+        assert_eq!(parse("2024-13-01"), None);
+    }
+}
@@ -0,0 +1,63 @@
+//! Input sources for feeding `Action`s into an engine, and output formats for
+//! what comes out
+
+#[cfg(feature = "avro")]
+pub mod avro;
+pub mod binary;
+mod compression;
+mod csv;
+#[cfg(feature = "iso20022")]
+pub mod iso20022;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod statement;
+
+#[cfg(feature = "parquet")]
+pub use self::parquet::ParquetSource;
+pub use self::{
+    compression::{Compression, Decoder},
+    csv::{CsvSchema, CsvSource, InputFingerprint, RecordError},
+};
+
+/// A record from another format couldn't be converted to or from the
+/// engine's types
+#[derive(Debug, thiserror::Error)]
+pub enum ConversionError {
+    #[error("unknown action type {0}")]
+    UnknownActionKind(String),
+
+    #[error("unknown account status {0}")]
+    UnknownStatus(String),
+
+    #[error("invalid amount '{0}'")]
+    InvalidAmount(String),
+
+    #[error("{field} {value} is out of range")]
+    OutOfRange { field: &'static str, value: i128 },
+
+    #[error("malformed record: {0}")]
+    Malformed(&'static str),
+
+    #[error("the format has no way to represent a {0}")]
+    Unsupported(&'static str),
+}
+
+fn parse_amount(amount: &str) -> Result<crate::Amount, ConversionError> {
+    amount
+        .trim()
+        .parse()
+        .map_err(|_| ConversionError::InvalidAmount(amount.into()))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, from Howard
+/// Hinnant's `days_from_civil`
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
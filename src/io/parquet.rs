@@ -0,0 +1,468 @@
+//! Reading actions from, and writing account summaries to, Parquet files
+//!
+//! Input files need `type`, `client`, `tx` and `amount` columns, plus
+//! optionally `timestamp` and `reverses`, named the same as the csv headers.
+//! Any integer type works for the ids, and amounts can be decimals, floats or
+//! strings. Decimal amounts are read exactly. Timestamps can be Arrow
+//! timestamps of any unit, or plain integers of seconds since the Unix epoch.
+
+use std::{fs::File, io::Write, path::Path, str::FromStr, sync::Arc, vec};
+
+use arrow_array::{
+    cast::AsArray,
+    types::{Int64Type, UInt64Type},
+    Array, ArrayAccessor, ArrayRef, BooleanArray, RecordBatch, StringArray, UInt32Array,
+    UInt64Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, TimeUnit};
+use parquet::{
+    arrow::{
+        arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder},
+        ArrowWriter,
+    },
+    basic::Compression,
+    file::properties::WriterProperties,
+};
+
+use crate::{AccountData, Action, ActionKind, Amount, ClientId, Timestamp, TransactionId};
+
+/// Rows decoded at a time
+const BATCH_SIZE: usize = 8192;
+
+/// A source of `Action`s read from a Parquet file.
+///
+/// Rows are decoded a batch at a time. Like `CsvSource`, a row that can't be
+/// turned into an action is yielded as an error and reading carries on, but a
+/// batch that can't be read at all ends the source.
+pub struct ParquetSource {
+    batches: ParquetRecordBatchReader,
+    current: vec::IntoIter<Result<Action, ParquetError>>,
+    /// Rows in all the batches before `current`
+    rows: u64,
+    failed: bool,
+}
+
+impl ParquetSource {
+    /// Open a Parquet file as a source of actions
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ParquetError> {
+        Self::from_file(File::open(path)?)
+    }
+
+    pub fn from_file(file: File) -> Result<Self, ParquetError> {
+        let batches = ParquetRecordBatchReaderBuilder::try_new(file)?
+            .with_batch_size(BATCH_SIZE)
+            .build()?;
+        Ok(Self {
+            batches,
+            current: Vec::new().into_iter(),
+            rows: 0,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for ParquetSource {
+    type Item = Result<Action, ParquetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(action) = self.current.next() {
+                return Some(action);
+            }
+            if self.failed {
+                return None;
+            }
+
+            let batch = match self.batches.next()? {
+                Ok(batch) => batch,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            match decode_batch(&batch, self.rows) {
+                Ok(actions) => self.current = actions.into_iter(),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+            self.rows += batch.num_rows() as u64;
+        }
+    }
+}
+
+/// The columns of a batch, cast to the types actions are built from
+struct Columns {
+    kind: StringArray,
+    client: UInt64Array,
+    transaction: UInt64Array,
+    amount: StringArray,
+    timestamp: Option<UInt64Array>,
+    reverses: Option<UInt64Array>,
+}
+
+fn decode_batch(
+    batch: &RecordBatch,
+    first_row: u64,
+) -> Result<Vec<Result<Action, ParquetError>>, ParquetError> {
+    let columns = Columns {
+        kind: cast(required(batch, "type")?, &DataType::Utf8)?
+            .as_string::<i32>()
+            .clone(),
+        client: cast_id(required(batch, "client")?)?,
+        transaction: cast_id(required(batch, "tx")?)?,
+        amount: cast(required(batch, "amount")?, &DataType::Utf8)?
+            .as_string::<i32>()
+            .clone(),
+        timestamp: batch
+            .column_by_name("timestamp")
+            .map(cast_timestamp)
+            .transpose()?,
+        reverses: batch.column_by_name("reverses").map(cast_id).transpose()?,
+    };
+
+    Ok((0..batch.num_rows())
+        .map(|i| {
+            decode_row(&columns, i).map_err(|reason| ParquetError::InvalidRow {
+                row: first_row + i as u64,
+                reason,
+            })
+        })
+        .collect())
+}
+
+// The conversions are no-ops with the wide id features
+#[allow(clippy::useless_conversion)]
+fn decode_row(columns: &Columns, i: usize) -> Result<Action, String> {
+    let kind = match non_null(&columns.kind, i, "type")? {
+        "deposit" => ActionKind::Deposit,
+        "withdrawal" => ActionKind::Withdrawal,
+        "dispute" => ActionKind::Dispute,
+        "resolve" => ActionKind::Resolve,
+        "chargeback" => ActionKind::Chargeback,
+        "reversal" => ActionKind::Reversal,
+        other => return Err(format!("unknown action type '{}'", other)),
+    };
+    let client = non_null(&columns.client, i, "client")?;
+    let transaction = non_null(&columns.transaction, i, "tx")?;
+    let amount = optional(&columns.amount, i)
+        .map(|amount| parse_amount(amount.trim()))
+        .transpose()?;
+
+    Ok(Action {
+        transaction_id: Some(TransactionId(
+            transaction
+                .try_into()
+                .map_err(|_| format!("transaction id {} is out of range", transaction))?,
+        )),
+        client_id: ClientId(
+            client
+                .try_into()
+                .map_err(|_| format!("client id {} is out of range", client))?,
+        ),
+        kind,
+        amount,
+        timestamp: columns
+            .timestamp
+            .as_ref()
+            .and_then(|column| optional(column, i))
+            .map(Timestamp::from_secs),
+        reverses: match columns.reverses.as_ref().and_then(|c| optional(c, i)) {
+            Some(id) => {
+                Some(TransactionId(id.try_into().map_err(|_| {
+                    format!("transaction id {} is out of range", id)
+                })?))
+            }
+            None => None,
+        },
+        reference: None,
+        evidence: None,
+        category: None,
+        currency: None,
+        meta: None,
+    })
+}
+
+fn required<'a>(batch: &'a RecordBatch, name: &'static str) -> Result<&'a ArrayRef, ParquetError> {
+    batch
+        .column_by_name(name)
+        .ok_or(ParquetError::MissingColumn(name))
+}
+
+fn cast(column: &ArrayRef, to: &DataType) -> Result<ArrayRef, ParquetError> {
+    Ok(arrow_cast::cast(column, to)?)
+}
+
+fn cast_id(column: &ArrayRef) -> Result<UInt64Array, ParquetError> {
+    Ok(cast(column, &DataType::UInt64)?
+        .as_primitive::<UInt64Type>()
+        .clone())
+}
+
+/// Timestamps in seconds, whatever unit they were stored in. Anything before
+/// the epoch is treated as missing.
+fn cast_timestamp(column: &ArrayRef) -> Result<UInt64Array, ParquetError> {
+    let per_second = match column.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => 1,
+        DataType::Timestamp(TimeUnit::Millisecond, _) => 1_000,
+        DataType::Timestamp(TimeUnit::Microsecond, _) => 1_000_000,
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => 1_000_000_000,
+        _ => return cast_id(column),
+    };
+    let raw = cast(column, &DataType::Int64)?;
+    Ok(raw
+        .as_primitive::<Int64Type>()
+        .unary_opt::<_, UInt64Type>(|t| u64::try_from(t.div_euclid(per_second)).ok()))
+}
+
+/// Ids that didn't fit in a `u64` (e.g. negative ones) were cast to nulls, so
+/// they're reported the same as missing ones
+fn non_null<'a, A>(
+    column: &'a A,
+    i: usize,
+    name: &str,
+) -> Result<<&'a A as ArrayAccessor>::Item, String>
+where
+    &'a A: ArrayAccessor,
+{
+    optional(column, i).ok_or_else(|| format!("missing or invalid {}", name))
+}
+
+fn optional<'a, A>(column: &'a A, i: usize) -> Option<<&'a A as ArrayAccessor>::Item>
+where
+    &'a A: ArrayAccessor,
+{
+    if column.is_null(i) {
+        None
+    } else {
+        Some(column.value(i))
+    }
+}
+
+#[cfg(feature = "decimal")]
+fn parse_amount(amount: &str) -> Result<Amount, String> {
+    // Floats cast to strings can end up in scientific notation
+    Amount::from_str(amount)
+        .or_else(|_| Amount::from_scientific(amount))
+        .map_err(|_| format!("invalid amount '{}'", amount))
+}
+
+#[cfg(not(feature = "decimal"))]
+fn parse_amount(amount: &str) -> Result<Amount, String> {
+    Amount::from_str(amount).map_err(|_| format!("invalid amount '{}'", amount))
+}
+
+/// Write account summaries (e.g. from `State::accounts`) to a Parquet file.
+///
+/// With the `decimal` feature, amounts are written as exact 4 decimal place
+/// decimals, otherwise as doubles.
+pub fn write_accounts<W, I>(writer: W, accounts: I) -> Result<(), ParquetError>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = AccountData>,
+{
+    let schema = Arc::new(account_schema());
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), Some(properties))?;
+
+    let mut accounts = accounts.into_iter().peekable();
+    while accounts.peek().is_some() {
+        let chunk: Vec<_> = accounts.by_ref().take(BATCH_SIZE).collect();
+        writer.write(&account_batch(&schema, &chunk)?)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+fn account_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("client", DataType::UInt32, false),
+        Field::new("available", AMOUNT_TYPE, false),
+        Field::new("held", AMOUNT_TYPE, false),
+        Field::new("total", AMOUNT_TYPE, false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("status", DataType::Utf8, false),
+        Field::new("freeze_reason", DataType::Utf8, true),
+        Field::new("quarantined", DataType::Boolean, false),
+        Field::new("version", DataType::UInt64, false),
+    ])
+}
+
+// The conversion is a no-op with the `wide-client-ids` feature
+#[allow(clippy::useless_conversion)]
+fn account_batch(
+    schema: &Arc<Schema>,
+    accounts: &[AccountData],
+) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt32Array::from_iter_values(
+            accounts.iter().map(|a| u32::from(a.client.0)),
+        )),
+        amount_array(accounts.iter().map(|a| a.available))?,
+        amount_array(accounts.iter().map(|a| a.held))?,
+        amount_array(accounts.iter().map(|a| a.total))?,
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|a| Some(a.locked)),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            accounts.iter().map(|a| a.status.name()),
+        )),
+        Arc::new(StringArray::from_iter(
+            accounts.iter().map(|a| a.freeze_reason.as_deref()),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|a| Some(a.quarantined)),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            accounts.iter().map(|a| a.version),
+        )),
+    ];
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+#[cfg(feature = "decimal")]
+const AMOUNT_TYPE: DataType = DataType::Decimal128(38, 4);
+
+#[cfg(not(feature = "decimal"))]
+const AMOUNT_TYPE: DataType = DataType::Float64;
+
+#[cfg(feature = "decimal")]
+fn amount_array<I: Iterator<Item = Amount>>(amounts: I) -> Result<ArrayRef, ArrowError> {
+    let array = arrow_array::Decimal128Array::from_iter_values(amounts.map(|mut amount| {
+        amount.rescale(4);
+        amount.mantissa()
+    }))
+    .with_precision_and_scale(38, 4)?;
+    Ok(Arc::new(array))
+}
+
+#[cfg(not(feature = "decimal"))]
+fn amount_array<I: Iterator<Item = Amount>>(amounts: I) -> Result<ArrayRef, ArrowError> {
+    Ok(Arc::new(arrow_array::Float64Array::from_iter_values(
+        amounts,
+    )))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParquetError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("failed to read or write parquet: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    #[error("failed to convert columns: {0}")]
+    Arrow(#[from] ArrowError),
+
+    #[error("the input has no `{0}` column")]
+    MissingColumn(&'static str),
+
+    #[error("row {row} is not a valid action: {reason}")]
+    InvalidRow { row: u64, reason: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Decimal128Array, Int32Array, Int64Array, TimestampMillisecondArray};
+
+    use super::*;
+    use crate::State;
+
+    fn write_actions(file: &File) {
+        let batch = RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "deposit",
+                    "withdrawal",
+                    "dispute",
+                    "refund",
+                ])) as ArrayRef,
+            ),
+            ("client", Arc::new(Int32Array::from(vec![1, 2, 1, 2, 1]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 3, 2, 4]))),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![
+                        Some(15_000),
+                        Some(20_000),
+                        Some(5_000),
+                        None,
+                        Some(1),
+                    ])
+                    .with_precision_and_scale(10, 4)
+                    .unwrap(),
+                ),
+            ),
+            (
+                "timestamp",
+                Arc::new(TimestampMillisecondArray::from(vec![
+                    Some(1_000),
+                    Some(2_500),
+                    None,
+                    Some(4_000),
+                    None,
+                ])),
+            ),
+        ])
+        .unwrap();
+
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = tempfile::tempfile().unwrap();
+        write_actions(&input);
+
+        let actions: Vec<_> = ParquetSource::from_file(input).unwrap().collect();
+        assert_eq!(actions.len(), 5);
+        assert!(matches!(
+            actions[4],
+            Err(ParquetError::InvalidRow { row: 4, .. })
+        ));
+        let first = actions[0].as_ref().unwrap();
+        assert_eq!(first.amount, Some(Amount::from(3u32) / Amount::from(2u32)));
+        assert_eq!(first.timestamp, Some(Timestamp::from_secs(1)));
+
+        let mut state = State::new();
+        for action in actions.into_iter().flatten() {
+            state.update(action).unwrap();
+        }
+        let mut accounts: Vec<_> = state.accounts().collect();
+        accounts.sort_by_key(|a| a.client);
+
+        let output = tempfile::tempfile().unwrap();
+        write_accounts(&output, accounts).unwrap();
+
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(output)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.schema().as_ref(), &account_schema());
+
+        let available = cast(batch.column_by_name("available").unwrap(), &DataType::Utf8).unwrap();
+        let held = cast(batch.column_by_name("held").unwrap(), &DataType::Utf8).unwrap();
+        #[cfg(feature = "decimal")]
+        {
+            assert_eq!(available.as_string::<i32>().value(0), "1.0000");
+            assert_eq!(held.as_string::<i32>().value(1), "2.0000");
+        }
+        #[cfg(not(feature = "decimal"))]
+        {
+            assert_eq!(available.as_string::<i32>().value(0), "1.0");
+            assert_eq!(held.as_string::<i32>().value(1), "2.0");
+        }
+    }
+}
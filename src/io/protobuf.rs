@@ -0,0 +1,269 @@
+//! Protobuf messages for actions and account summaries
+//!
+//! These match `schema/transaction_engine.proto`, in the same form
+//! `prost-build` generates, so they can be used with any prost based gRPC or
+//! Kafka client. Keep the two in sync when changing either.
+
+use super::{parse_amount, ConversionError};
+use crate::{AccountStatus, ClientId, OutputColumns, Timestamp, TransactionId};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Action {
+    #[prost(uint64, tag = "1")]
+    pub tx: u64,
+    #[prost(uint32, tag = "2")]
+    pub client: u32,
+    #[prost(enumeration = "ActionKind", tag = "3")]
+    pub r#type: i32,
+    /// A decimal string (e.g. "1.5"), so no precision is lost
+    #[prost(string, optional, tag = "4")]
+    pub amount: Option<String>,
+    /// Seconds since the Unix epoch
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+    /// For a reversal, the transaction being reversed
+    #[prost(uint64, optional, tag = "6")]
+    pub reverses: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ActionKind {
+    Unspecified = 0,
+    Deposit = 1,
+    Withdrawal = 2,
+    Dispute = 3,
+    Resolve = 4,
+    Chargeback = 5,
+    Reversal = 6,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AccountData {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    /// Decimal strings, rounded to 4 places
+    #[prost(string, tag = "2")]
+    pub available: String,
+    #[prost(string, tag = "3")]
+    pub held: String,
+    #[prost(string, tag = "4")]
+    pub total: String,
+    #[prost(bool, tag = "5")]
+    pub locked: bool,
+    /// One of "active", "frozen", "dormant" or "closed"
+    #[prost(string, tag = "6")]
+    pub status: String,
+    /// Why the account is frozen, if it is
+    #[prost(string, optional, tag = "7")]
+    pub freeze_reason: Option<String>,
+    #[prost(bool, tag = "8")]
+    pub quarantined: bool,
+    /// Bumped every time the account is written
+    #[prost(uint64, tag = "9")]
+    pub version: u64,
+}
+
+impl From<crate::ActionKind> for ActionKind {
+    fn from(kind: crate::ActionKind) -> Self {
+        match kind {
+            crate::ActionKind::Deposit => Self::Deposit,
+            crate::ActionKind::Withdrawal => Self::Withdrawal,
+            crate::ActionKind::Dispute => Self::Dispute,
+            crate::ActionKind::Resolve => Self::Resolve,
+            crate::ActionKind::Chargeback => Self::Chargeback,
+            crate::ActionKind::Reversal => Self::Reversal,
+        }
+    }
+}
+
+impl TryFrom<ActionKind> for crate::ActionKind {
+    type Error = ConversionError;
+
+    fn try_from(kind: ActionKind) -> Result<Self, Self::Error> {
+        Ok(match kind {
+            ActionKind::Unspecified => {
+                return Err(ConversionError::UnknownActionKind("unspecified".into()))
+            }
+            ActionKind::Deposit => Self::Deposit,
+            ActionKind::Withdrawal => Self::Withdrawal,
+            ActionKind::Dispute => Self::Dispute,
+            ActionKind::Resolve => Self::Resolve,
+            ActionKind::Chargeback => Self::Chargeback,
+            ActionKind::Reversal => Self::Reversal,
+        })
+    }
+}
+
+/// Fails for an action with only a reference, which the message has no way
+/// to carry
+// The conversions are no-ops with the wide id features
+#[allow(clippy::useless_conversion)]
+impl TryFrom<&crate::Action> for Action {
+    type Error = ConversionError;
+
+    fn try_from(action: &crate::Action) -> Result<Self, Self::Error> {
+        let tx = action
+            .transaction_id
+            .ok_or(ConversionError::Unsupported("reference-only action"))?;
+        Ok(Self {
+            tx: tx.0.into(),
+            client: action.client_id.0.into(),
+            r#type: ActionKind::from(action.kind).into(),
+            amount: action.amount.map(|amount| amount.to_string()),
+            timestamp: action.timestamp.map(|t| t.as_secs()),
+            reverses: action.reverses.map(|id| id.0.into()),
+        })
+    }
+}
+
+impl TryFrom<Action> for crate::Action {
+    type Error = ConversionError;
+
+    fn try_from(action: Action) -> Result<Self, Self::Error> {
+        let kind = ActionKind::try_from(action.r#type)
+            .map_err(|_| ConversionError::UnknownActionKind(action.r#type.to_string()))?;
+        Ok(Self {
+            transaction_id: Some(transaction_id("tx", action.tx)?),
+            client_id: client_id(action.client)?,
+            kind: kind.try_into()?,
+            amount: action.amount.as_deref().map(parse_amount).transpose()?,
+            timestamp: action.timestamp.map(Timestamp::from_secs),
+            reverses: action
+                .reverses
+                .map(|id| transaction_id("reverses", id))
+                .transpose()?,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        })
+    }
+}
+
+// The conversion is a no-op with the `wide-client-ids` feature
+#[allow(clippy::useless_conversion)]
+impl From<&crate::AccountData> for AccountData {
+    fn from(account: &crate::AccountData) -> Self {
+        Self {
+            client: account.client.0.into(),
+            available: account.available.to_string(),
+            held: account.held.to_string(),
+            total: account.total.to_string(),
+            locked: account.locked,
+            status: account.status.name().into(),
+            freeze_reason: account.freeze_reason.clone(),
+            quarantined: account.quarantined,
+            version: account.version,
+        }
+    }
+}
+
+impl TryFrom<AccountData> for crate::AccountData {
+    type Error = ConversionError;
+
+    fn try_from(account: AccountData) -> Result<Self, Self::Error> {
+        let status = AccountStatus::from_parts(&account.status, account.freeze_reason.as_deref())
+            .ok_or_else(|| ConversionError::UnknownStatus(account.status.clone()))?;
+        Ok(Self {
+            client: client_id(account.client)?,
+            available: parse_amount(&account.available)?,
+            held: parse_amount(&account.held)?,
+            total: parse_amount(&account.total)?,
+            locked: account.locked,
+            status,
+            freeze_reason: account.freeze_reason,
+            quarantined: account.quarantined,
+            version: account.version,
+            // Every field is in the message, so none are dropped again
+            columns: OutputColumns::Extended,
+        })
+    }
+}
+
+// The conversions are no-ops with the wide id features
+#[allow(clippy::useless_conversion)]
+fn client_id(id: u32) -> Result<ClientId, ConversionError> {
+    id.try_into()
+        .map(ClientId)
+        .map_err(|_| ConversionError::OutOfRange {
+            field: "client",
+            value: id.into(),
+        })
+}
+
+#[allow(clippy::useless_conversion)]
+fn transaction_id(field: &'static str, id: u64) -> Result<TransactionId, ConversionError> {
+    id.try_into()
+        .map(TransactionId)
+        .map_err(|_| ConversionError::OutOfRange {
+            field,
+            value: id.into(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::{Amount, FreezeReason};
+
+    #[test]
+    fn test_round_trip() {
+        let action = crate::Action {
+            transaction_id: Some(TransactionId(7)),
+            client_id: ClientId(3),
+            kind: crate::ActionKind::Reversal,
+            amount: Some(Amount::from(3u32) / Amount::from(2u32)),
+            timestamp: Some(Timestamp::from_secs(1_600_000_000)),
+            reverses: Some(TransactionId(2)),
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        };
+        let bytes = Action::try_from(&action).unwrap().encode_to_vec();
+        let decoded: crate::Action = Action::decode(&bytes[..]).unwrap().try_into().unwrap();
+        assert_eq!(decoded.transaction_id, action.transaction_id);
+        assert_eq!(decoded.client_id, action.client_id);
+        assert_eq!(decoded.kind, action.kind);
+        assert_eq!(decoded.amount, action.amount);
+        assert_eq!(decoded.timestamp, action.timestamp);
+        assert_eq!(decoded.reverses, action.reverses);
+
+        let account = crate::AccountData {
+            client: ClientId(3),
+            available: Amount::from(1u32),
+            held: Amount::from(2u32),
+            total: Amount::from(3u32),
+            locked: true,
+            status: AccountStatus::Frozen {
+                reason: FreezeReason::Manual("fraud review".into()),
+            },
+            freeze_reason: Some("manual: fraud review".into()),
+            quarantined: false,
+            version: 4,
+            columns: OutputColumns::Extended,
+        };
+        let bytes = AccountData::from(&account).encode_to_vec();
+        let decoded: crate::AccountData =
+            AccountData::decode(&bytes[..]).unwrap().try_into().unwrap();
+        assert_eq!(decoded, account);
+    }
+
+    #[test]
+    fn test_unspecified_kind_is_rejected() {
+        let action = Action {
+            tx: 1,
+            client: 1,
+            ..Action::default()
+        };
+        assert!(matches!(
+            crate::Action::try_from(action),
+            Err(ConversionError::UnknownActionKind(_))
+        ));
+    }
+}
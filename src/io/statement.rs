@@ -0,0 +1,724 @@
+//! Reading actions from personal finance statements: OFX (what banks offer
+//! for download as `.ofx` or `.qfx` files, in either the older SGML or the
+//! newer XML flavour) and QIF (Quicken's older interchange format)
+//!
+//! Each entry is a deposit if it put money into the account and a withdrawal
+//! if it took money out, by the sign of its amount. The payee and memo, which
+//! the engine has no place for, come alongside the action in a
+//! `StatementEntry` for ledgers that want to keep them.
+//!
+//! Neither format carries the engine's transaction ids, so actions are given
+//! an external reference instead (see `Action::reference`), and the engine
+//! assigns the ids:
+//!
+//! - OFX transactions use the bank's `FITID`.
+//! - QIF has no ids at all, so the reference is a hash of the account, date,
+//!   amount, payee and memo (and how many identical entries came before it in
+//!   the file). Importing an overlapping export again then fails with
+//!   `UpdateError::ReferenceUsed` for the entries that were already imported,
+//!   rather than doubling them up.
+//!
+//! Accounts (the OFX `ACCTID`, or the name of a QIF `!Account`) are mapped to
+//! clients with `with_account`, or taken as the client id if they're a plain
+//! number. `with_client` gives the client for everything else, including
+//! statements that don't name their account.
+
+use std::{collections::HashMap, fs, path::Path, vec};
+
+use super::{days_from_civil, parse_amount};
+use crate::{Action, ActionKind, Amount, ClientId, Timestamp};
+
+/// One entry from a statement
+#[derive(Debug, Clone)]
+pub struct StatementEntry {
+    /// A deposit or withdrawal of the entry's amount
+    pub action: Action,
+    /// Who the money came from or went to (the OFX `NAME`, or the QIF `P`
+    /// line)
+    pub payee: Option<String>,
+    pub memo: Option<String>,
+}
+
+/// The entries read from one statement, from `OfxAdapter::read_str` or
+/// `QifAdapter::read_str`.
+///
+/// The whole statement is parsed up front. Like `CsvSource`, an entry that
+/// can't be turned into an action is yielded as an error, and the rest are
+/// still read.
+#[derive(Debug)]
+pub struct StatementSource {
+    entries: vec::IntoIter<Result<StatementEntry, StatementError>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatementError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("not an OFX statement (there's no <OFX> element)")]
+    NotOfx,
+
+    #[error("entry {entry} is not a valid action: {reason}")]
+    InvalidEntry { entry: u64, reason: String },
+}
+
+/// How to turn OFX statements into actions
+#[derive(Debug, Default, Clone)]
+pub struct OfxAdapter {
+    accounts: Accounts,
+    currency: Option<String>,
+}
+
+/// How to turn QIF files into actions
+#[derive(Debug, Default, Clone)]
+pub struct QifAdapter {
+    accounts: Accounts,
+    day_first: bool,
+}
+
+impl OfxAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map an account (its `ACCTID`) to a client
+    pub fn with_account<S: Into<String>>(mut self, account: S, client: ClientId) -> Self {
+        self.accounts.map.insert(account.into(), client);
+        self
+    }
+
+    /// The client for accounts that aren't mapped and aren't a client id
+    pub fn with_client(mut self, client: ClientId) -> Self {
+        self.accounts.default = Some(client);
+        self
+    }
+
+    /// Only accept statements (and transactions) in this currency (e.g.
+    /// `"USD"`). Otherwise the currency is ignored, since the engine doesn't
+    /// keep one.
+    pub fn with_currency<S: Into<String>>(mut self, currency: S) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    /// Read a file, which can be in any ASCII compatible encoding (older
+    /// files are often Windows-1252, and anything outside ASCII only turns up
+    /// in payees and memos)
+    pub fn read_path<P: AsRef<Path>>(&self, path: P) -> Result<StatementSource, StatementError> {
+        self.read_str(&String::from_utf8_lossy(&fs::read(path)?))
+    }
+
+    /// Read the entries from a statement (or several, for a file with more
+    /// than one account). Only a file without an `<OFX>` element fails as a
+    /// whole.
+    pub fn read_str(&self, ofx: &str) -> Result<StatementSource, StatementError> {
+        // Everything before is the SGML header, or the XML declarations
+        let body = ofx
+            .find("<OFX>")
+            .map(|at| &ofx[at..])
+            .ok_or(StatementError::NotOfx)?;
+
+        let mut entries = Entries::default();
+        let mut account = None;
+        let mut currency = None;
+        let mut transaction: Option<OfxTransaction> = None;
+        for tag in tags(body) {
+            match (tag.name, tag.closing) {
+                ("STMTTRN", false) => transaction = Some(OfxTransaction::default()),
+                ("STMTTRN", true) => {
+                    if let Some(transaction) = transaction.take() {
+                        let entry = self.entry(transaction, account.as_deref(), &currency);
+                        entries.push(entry);
+                    }
+                }
+                (_, true) => {}
+                (name, false) => match &mut transaction {
+                    Some(transaction) => transaction.set(name, tag.value),
+                    None => match name {
+                        "STMTRS" | "CCSTMTRS" => (account, currency) = (None, None),
+                        "ACCTID" => account = tag.value,
+                        "CURDEF" => currency = tag.value,
+                        _ => {}
+                    },
+                },
+            }
+        }
+        Ok(entries.into_source())
+    }
+
+    fn entry(
+        &self,
+        transaction: OfxTransaction,
+        account: Option<&str>,
+        currency: &Option<String>,
+    ) -> Result<StatementEntry, String> {
+        let found = transaction.currency.as_ref().or(currency.as_ref());
+        if let (Some(expected), Some(found)) = (&self.currency, found) {
+            if expected != found {
+                return Err(format!("the amount is in {}, not {}", found, expected));
+            }
+        }
+        // Amounts can use a decimal comma, but never have thousands separators
+        let amount = transaction.amount.ok_or("no amount")?.replace(',', ".");
+        let amount = parse_amount(&amount).map_err(|e| e.to_string())?;
+        let timestamp = match &transaction.posted {
+            Some(posted) => {
+                Some(parse_ofx_date(posted).ok_or_else(|| format!("invalid date '{}'", posted))?)
+            }
+            None => None,
+        };
+        let reference = transaction.fitid.ok_or("no FITID")?;
+        Ok(StatementEntry {
+            action: action(
+                self.accounts.client(account)?,
+                amount,
+                found.cloned(),
+                timestamp,
+                reference,
+            ),
+            payee: transaction.name,
+            memo: transaction.memo,
+        })
+    }
+}
+
+impl QifAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map an account (the name in its `!Account` block) to a client
+    pub fn with_account<S: Into<String>>(mut self, account: S, client: ClientId) -> Self {
+        self.accounts.map.insert(account.into(), client);
+        self
+    }
+
+    /// The client for accounts that aren't mapped and aren't a client id,
+    /// and for files without an `!Account` block
+    pub fn with_client(mut self, client: ClientId) -> Self {
+        self.accounts.default = Some(client);
+        self
+    }
+
+    /// Read dates as day/month/year rather than the US month/day/year
+    pub fn with_day_first(mut self) -> Self {
+        self.day_first = true;
+        self
+    }
+
+    /// Read a file, which can be in any ASCII compatible encoding
+    pub fn read_path<P: AsRef<Path>>(&self, path: P) -> Result<StatementSource, StatementError> {
+        Ok(self.read_str(&String::from_utf8_lossy(&fs::read(path)?)))
+    }
+
+    /// Read the entries from the bank, cash and credit card sections of a
+    /// file. Investment accounts, and lists like categories and memorized
+    /// transactions, are skipped.
+    pub fn read_str(&self, qif: &str) -> StatementSource {
+        let mut entries = Entries::default();
+        let mut account: Option<String> = None;
+        let mut in_account = false;
+        let mut in_money = false;
+        let mut record = QifRecord::default();
+        // How many times each entry has been seen, to tell identical ones
+        // apart
+        let mut seen = HashMap::new();
+
+        for line in qif
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+        {
+            if let Some(header) = line.strip_prefix('!') {
+                record = QifRecord::default();
+                in_account = header.trim().eq_ignore_ascii_case("account");
+                if let Some(kind) = header.strip_prefix("Type:") {
+                    in_money = matches!(
+                        kind.trim().to_ascii_lowercase().as_str(),
+                        "bank" | "cash" | "ccard" | "oth a" | "oth l"
+                    );
+                } else if in_account {
+                    in_money = false;
+                }
+                continue;
+            }
+
+            let mut chars = line.chars();
+            let code = chars.next().unwrap_or_default();
+            let value = chars.as_str().trim();
+            if in_account {
+                if code == 'N' {
+                    account = Some(value.into());
+                }
+                continue;
+            }
+            if !in_money {
+                continue;
+            }
+            match code {
+                'D' => record.date = Some(value.into()),
+                'T' => record.amount = Some(value.into()),
+                'U' if record.amount.is_none() => record.amount = Some(value.into()),
+                'P' => record.payee = Some(value.into()),
+                'M' => record.memo = Some(value.into()),
+                '^' => {
+                    let record = std::mem::take(&mut record);
+                    if record != QifRecord::default() {
+                        let entry = self.entry(record, account.as_deref(), &mut seen);
+                        entries.push(entry);
+                    }
+                }
+                // Check numbers, categories, cleared flags, splits and
+                // addresses don't change what the entry moved
+                _ => {}
+            }
+        }
+        entries.into_source()
+    }
+
+    fn entry(
+        &self,
+        record: QifRecord,
+        account: Option<&str>,
+        seen: &mut HashMap<u64, u64>,
+    ) -> Result<StatementEntry, String> {
+        let date = record.date.ok_or("no date")?;
+        let timestamp = parse_qif_date(&date, self.day_first)
+            .ok_or_else(|| format!("invalid date '{}'", date))?;
+        let amount = record.amount.ok_or("no amount")?.replace(',', "");
+        let amount = parse_amount(&amount).map_err(|e| e.to_string())?;
+        let client = self.accounts.client(account)?;
+
+        let mut hash = Fnv1a::default();
+        for part in [
+            account.unwrap_or_default(),
+            &timestamp.as_secs().to_string(),
+            &amount.to_string(),
+            record.payee.as_deref().unwrap_or_default(),
+            record.memo.as_deref().unwrap_or_default(),
+        ] {
+            hash.write(part.as_bytes());
+            hash.write(&[0]);
+        }
+        let occurrence = seen.entry(hash.0).or_insert(0);
+        let reference = match *occurrence {
+            0 => format!("qif-{:016x}", hash.0),
+            n => format!("qif-{:016x}-{}", hash.0, n),
+        };
+        *occurrence += 1;
+
+        Ok(StatementEntry {
+            action: action(client, amount, None, Some(timestamp), reference),
+            payee: record.payee,
+            memo: record.memo,
+        })
+    }
+}
+
+impl StatementSource {
+    /// Just the actions, without the payees and memos
+    pub fn actions(self) -> impl Iterator<Item = Result<Action, StatementError>> {
+        self.map(|entry| entry.map(|entry| entry.action))
+    }
+}
+
+impl Iterator for StatementSource {
+    type Item = Result<StatementEntry, StatementError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+/// Account names and ids mapped to clients
+#[derive(Debug, Default, Clone)]
+struct Accounts {
+    map: HashMap<String, ClientId>,
+    default: Option<ClientId>,
+}
+
+impl Accounts {
+    fn client(&self, account: Option<&str>) -> Result<ClientId, String> {
+        if let Some(account) = account {
+            if let Some(client) = self.map.get(account) {
+                return Ok(*client);
+            }
+            if let Ok(client) = account.parse() {
+                return Ok(ClientId(client));
+            }
+        }
+        self.default.ok_or_else(|| match account {
+            Some(account) => format!("account {} isn't mapped to a client", account),
+            None => "the statement doesn't name its account".into(),
+        })
+    }
+}
+
+/// Collects the entries from one statement, numbering them for errors
+#[derive(Default)]
+struct Entries {
+    count: u64,
+    entries: Vec<Result<StatementEntry, StatementError>>,
+}
+
+impl Entries {
+    fn push(&mut self, entry: Result<StatementEntry, String>) {
+        self.count += 1;
+        let entry_number = self.count;
+        self.entries
+            .push(entry.map_err(|reason| StatementError::InvalidEntry {
+                entry: entry_number,
+                reason,
+            }));
+    }
+
+    fn into_source(self) -> StatementSource {
+        StatementSource {
+            entries: self.entries.into_iter(),
+        }
+    }
+}
+
+/// The fields of an OFX `STMTTRN` that are used
+#[derive(Debug, Default)]
+struct OfxTransaction {
+    posted: Option<String>,
+    amount: Option<String>,
+    fitid: Option<String>,
+    name: Option<String>,
+    memo: Option<String>,
+    /// The transaction's own currency, if it isn't the statement's
+    currency: Option<String>,
+}
+
+impl OfxTransaction {
+    fn set(&mut self, name: &str, value: Option<String>) {
+        let field = match name {
+            "DTPOSTED" => &mut self.posted,
+            "TRNAMT" => &mut self.amount,
+            "FITID" => &mut self.fitid,
+            "NAME" => &mut self.name,
+            "MEMO" => &mut self.memo,
+            "CURSYM" => &mut self.currency,
+            _ => return,
+        };
+        // A payee aggregate repeats the name
+        if field.is_none() {
+            *field = value;
+        }
+    }
+}
+
+/// The lines of a QIF entry that are used
+#[derive(Debug, Default, PartialEq)]
+struct QifRecord {
+    date: Option<String>,
+    amount: Option<String>,
+    payee: Option<String>,
+    memo: Option<String>,
+}
+
+/// An OFX tag, with the text up to the next one
+struct Tag<'a> {
+    name: &'a str,
+    closing: bool,
+    value: Option<String>,
+}
+
+/// The tags in an OFX body. SGML files don't close elements that only hold
+/// text, but both flavours put an element's text straight after its opening
+/// tag, so they can be read the same way.
+fn tags(body: &str) -> impl Iterator<Item = Tag<'_>> {
+    body.split('<').skip(1).filter_map(|part| {
+        let (tag, text) = part.split_once('>')?;
+        let (name, closing) = match tag.strip_prefix('/') {
+            Some(name) => (name, true),
+            None => (tag, false),
+        };
+        // Processing instructions and comments
+        if name.starts_with(['?', '!']) {
+            return None;
+        }
+        let text = text.trim();
+        Some(Tag {
+            name: name.trim(),
+            closing,
+            value: (!text.is_empty()).then(|| unescape(text)),
+        })
+    })
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn action(
+    client: ClientId,
+    amount: Amount,
+    currency: Option<String>,
+    timestamp: Option<Timestamp>,
+    reference: String,
+) -> Action {
+    let kind = if amount.is_sign_negative() {
+        ActionKind::Withdrawal
+    } else {
+        ActionKind::Deposit
+    };
+    Action {
+        transaction_id: None,
+        client_id: client,
+        kind,
+        amount: Some(amount.abs()),
+        timestamp,
+        reverses: None,
+        reference: Some(reference),
+        evidence: None,
+        category: None,
+        currency,
+        meta: None,
+    }
+}
+
+/// Parse an OFX date time, `YYYYMMDD[HHMM[SS[.XXX]]]`, optionally followed by
+/// the offset from UTC in hours and a zone name (e.g. `[-5:EST]`). Times
+/// without an offset are UTC.
+fn parse_ofx_date(value: &str) -> Option<Timestamp> {
+    let (stamp, zone) = match value.split_once('[') {
+        Some((stamp, zone)) => (stamp, Some(zone.trim_end_matches(']'))),
+        None => (value, None),
+    };
+    let stamp = stamp.split('.').next()?.trim();
+    if !matches!(stamp.len(), 8 | 12 | 14) || !stamp.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |at: usize| stamp.get(at..at + 2).map_or(Some(0), |s| s.parse().ok());
+    let year = stamp[..4].parse().ok()?;
+    let (month, day) = (field(4)?, field(6)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut seconds = days_from_civil(year, month, day) * 86_400
+        + field(8)? * 3600
+        + field(10)? * 60
+        + field(12)?;
+    if let Some(zone) = zone {
+        // Offsets can be fractional, like `[5.5:IST]`
+        let hours: f64 = zone.split(':').next()?.trim().parse().ok()?;
+        seconds -= (hours * 3600.0).round() as i64;
+    }
+    u64::try_from(seconds).ok().map(Timestamp::from_secs)
+}
+
+/// Parse a QIF date: `03/01/2024`, `3/ 1/24` or `3/ 1'24` (where the
+/// apostrophe marks a year in the 2000s), or `2024-03-01`. Other two digit
+/// years are taken as 1950 to 2049.
+fn parse_qif_date(value: &str, day_first: bool) -> Option<Timestamp> {
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut parts = value.split(['/', '\'', '-', '.']);
+    let first = parts.next()?;
+    let numbers: Vec<i64> = [Some(first), parts.next(), parts.next()]
+        .into_iter()
+        .map(|part| part?.parse().ok())
+        .collect::<Option<_>>()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let (year, month, day) = match (first.len(), day_first) {
+        (4, _) => (numbers[0], numbers[1], numbers[2]),
+        (_, true) => (numbers[2], numbers[1], numbers[0]),
+        (_, false) => (numbers[2], numbers[0], numbers[1]),
+    };
+    let year = match year {
+        0..=99 if value.contains('\'') => 2000 + year,
+        0..=49 => 2000 + year,
+        50..=99 => 1900 + year,
+        _ => year,
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    u64::try_from(days_from_civil(year, month, day) * 86_400)
+        .ok()
+        .map(Timestamp::from_secs)
+}
+
+/// 64 bit FNV-1a, for references that stay the same across runs and Rust
+/// versions
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{State, UpdateError};
+
+    const OFX_SGML: &str = "OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+ENCODING:USASCII
+
+<OFX>
+<SIGNONMSGSRSV1><SONRS><STATUS><CODE>0<SEVERITY>INFO</STATUS></SONRS></SIGNONMSGSRSV1>
+<BANKMSGSRSV1><STMTTRNRS><TRNUID>1<STMTRS>
+<CURDEF>USD
+<BANKACCTFROM><BANKID>121000248<ACCTID>000123<ACCTTYPE>CHECKING</BANKACCTFROM>
+<BANKTRANLIST><DTSTART>20240301<DTEND>20240331
+<STMTTRN><TRNTYPE>CREDIT<DTPOSTED>20240301120000.000[-5:EST]<TRNAMT>1500.00
+<FITID>F-1<NAME>ACME PAYROLL<MEMO>March salary</STMTTRN>
+<STMTTRN><TRNTYPE>DEBIT<DTPOSTED>20240302<TRNAMT>-42,50
+<FITID>F-2<NAME>Fish &amp; Chips</STMTTRN>
+<STMTTRN><TRNTYPE>DEBIT<DTPOSTED>20240303<TRNAMT>-1.00<NAME>NO ID</STMTTRN>
+</BANKTRANLIST></STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+";
+
+    const OFX_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<?OFX OFXHEADER="200" VERSION="220" SECURITY="NONE"?>
+<OFX>
+  <CREDITCARDMSGSRSV1><CCSTMTTRNRS><CCSTMTRS>
+    <CURDEF>EUR</CURDEF>
+    <CCACCTFROM><ACCTID>XXXX-4000</ACCTID></CCACCTFROM>
+    <BANKTRANLIST>
+      <STMTTRN>
+        <TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240305</DTPOSTED><TRNAMT>-9.99</TRNAMT>
+        <FITID>CC-1</FITID><NAME>Streaming</NAME>
+      </STMTTRN>
+    </BANKTRANLIST>
+  </CCSTMTRS></CCSTMTTRNRS></CREDITCARDMSGSRSV1>
+</OFX>"#;
+
+    const QIF: &str = "!Type:Cat
+NGroceries
+^
+!Account
+NChecking
+TBank
+^
+!Type:Bank
+D03/01/2024
+T1,500.00
+PACME Payroll
+MMarch salary
+^
+D3/ 2'24
+T-42.50
+PGrocer
+LGroceries
+^
+D3/ 2'24
+T-42.50
+PGrocer
+LGroceries
+^
+D13/45/2024
+T-1.00
+^
+";
+
+    #[test]
+    fn test_ofx() {
+        let adapter = OfxAdapter::new()
+            .with_account("000123", ClientId(1))
+            .with_client(ClientId(2));
+        let entries: Vec<_> = adapter.read_str(OFX_SGML).unwrap().collect();
+        assert_eq!(entries.len(), 3);
+
+        let salary = entries[0].as_ref().unwrap();
+        assert_eq!(salary.action.kind, ActionKind::Deposit);
+        assert_eq!(salary.action.client_id, ClientId(1));
+        assert_eq!(salary.action.reference.as_deref(), Some("F-1"));
+        // Noon in New York
+        assert_eq!(
+            salary.action.timestamp,
+            Some(Timestamp::from_secs(1_709_312_400))
+        );
+        assert_eq!(salary.payee.as_deref(), Some("ACME PAYROLL"));
+        assert_eq!(salary.memo.as_deref(), Some("March salary"));
+
+        let chips = entries[1].as_ref().unwrap();
+        assert_eq!(chips.action.kind, ActionKind::Withdrawal);
+        assert_eq!(chips.action.amount, Some("42.5".parse().unwrap()));
+        assert_eq!(chips.payee.as_deref(), Some("Fish & Chips"));
+        assert!(matches!(
+            entries[2],
+            Err(StatementError::InvalidEntry { entry: 3, .. })
+        ));
+
+        // Unmapped accounts go to the default client, and the currency is
+        // checked
+        let card: Vec<_> = adapter.read_str(OFX_XML).unwrap().collect();
+        assert_eq!(card[0].as_ref().unwrap().action.client_id, ClientId(2));
+        let dollars = adapter.with_currency("USD").read_str(OFX_XML).unwrap();
+        assert!(dollars.actions().all(|action| action.is_err()));
+        assert!(matches!(
+            OfxAdapter::new().read_str("<html></html>"),
+            Err(StatementError::NotOfx)
+        ));
+    }
+
+    #[test]
+    fn test_qif() {
+        let adapter = QifAdapter::new().with_account("Checking", ClientId(5));
+        let entries: Vec<_> = adapter.read_str(QIF).collect();
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(
+            entries[3],
+            Err(StatementError::InvalidEntry { entry: 4, .. })
+        ));
+
+        let mut state = State::new();
+        for entry in entries.into_iter().take(3) {
+            let entry = entry.unwrap();
+            assert_eq!(entry.action.client_id, ClientId(5));
+            state.update(entry.action).unwrap();
+        }
+        let account = state.account(ClientId(5)).unwrap();
+        assert_eq!(account.available, Amount::from(1415u32));
+
+        // Reading the same file again makes the same references
+        let again = adapter.read_str(QIF).actions().next().unwrap().unwrap();
+        assert!(matches!(
+            state.update(again),
+            Err(UpdateError::ReferenceUsed(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_dates() {
+        let ofx = |value| parse_ofx_date(value).map(|t| t.as_secs());
+        assert_eq!(ofx("19700101"), Some(0));
+        assert_eq!(ofx("20240301100000"), Some(1_709_287_200));
+        assert_eq!(ofx("20240301100000.123[5.5:IST]"), Some(1_709_267_400));
+        assert_eq!(ofx("2024031"), None);
+
+        let qif = |value, day_first| parse_qif_date(value, day_first).map(|t| t.as_secs());
+        assert_eq!(qif("03/01/2024", false), Some(1_709_251_200));
+        assert_eq!(qif("01/03/2024", true), Some(1_709_251_200));
+        assert_eq!(qif(" 3/ 1'24", false), Some(1_709_251_200));
+        assert_eq!(qif("2024-03-01", true), Some(1_709_251_200));
+        assert_eq!(qif("12/31/99", false), Some(946_598_400));
+        assert_eq!(qif("31/12/99", false), None);
+    }
+}
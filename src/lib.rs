@@ -1,15 +1,95 @@
 use serde::{Deserialize, Serialize};
 
+// Features that can't work on some targets, caught here rather than with a
+// confusing build failure deep in a dependency
+#[cfg(all(target_arch = "wasm32", feature = "sled"))]
+compile_error!("the `sled` feature (or the `durable` preset) needs a filesystem and threads, so it isn't supported on wasm32");
+#[cfg(all(target_arch = "wasm32", feature = "parquet"))]
+compile_error!("the `parquet` feature (or the `batch` preset) builds C compression libraries, so it isn't supported on wasm32");
+#[cfg(all(target_arch = "wasm32", feature = "postgres"))]
+compile_error!("the `postgres` feature needs TCP sockets, so it isn't supported on wasm32");
+#[cfg(all(target_arch = "wasm32", feature = "redis"))]
+compile_error!("the `redis` feature needs TCP sockets, so it isn't supported on wasm32");
+#[cfg(all(target_arch = "wasm32", feature = "sqlite"))]
+compile_error!("the `sqlite` feature builds SQLite from C and writes to a file, so it isn't supported on wasm32");
+
+pub mod io;
+
 mod account;
 mod action;
+pub mod audit;
+mod bulk;
+mod client_format;
+#[cfg(feature = "concurrent-engine")]
+mod concurrent;
+pub mod conformance;
+pub mod crash;
+#[cfg(feature = "crypto")]
+pub mod crypto;
 mod engine;
+pub mod events;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixtures;
+#[cfg(feature = "async-engine")]
+mod handle;
+mod ingest;
+mod money;
+mod parallel;
+pub mod persist;
+mod policy;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod progress;
+#[cfg(feature = "push")]
+pub mod push;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod report;
+mod seen;
+pub mod soak;
 mod state;
+pub mod store;
+mod sync;
+pub mod testing;
 mod transaction;
+pub mod validate;
+mod view;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
-pub use account::{Account, AccountData, AccountError};
-pub use action::{Action, ActionKind};
-pub use engine::{MultiThreadedEngine, SingleThreadedEngine, SyncEngine};
-pub use transaction::{Transaction, TransactionState};
+pub use account::{
+    Account, AccountData, AccountError, AccountFilter, AccountOrder, AccountStatus,
+    AccountsSummary, Envelope, FreezeReason, HoldId, LockExpiry, LockedOperation, OutputColumns,
+    OutputConfig, Rounding, StatusError,
+};
+pub use action::{Action, ActionKind, ActionMeta};
+pub use bulk::{BulkExport, BulkFailure, BulkOperation, BulkReport};
+pub use client_format::ClientFormat;
+#[cfg(feature = "concurrent-engine")]
+pub use concurrent::ConcurrentEngine;
+#[cfg(feature = "async-engine")]
+pub use engine::AsyncEngine;
+pub use engine::{
+    EngineBuilder, EngineMode, ErrorPolicy, MultiThreadedEngine, SingleThreadedEngine, Stopped,
+    SyncEngine, SyncEngineExt,
+};
+#[cfg(feature = "async-engine")]
+pub use handle::{EngineClosed, EngineHandle};
+pub use ingest::{TrustedBatch, TrustedBatchError};
+pub use money::{Money, MoneyError, INPUT_DECIMALS};
+pub use parallel::{ParallelCsvProcessor, Tuning};
+pub use policy::{ChargebackPolicy, DisputeWindow, Limit, LimitsPolicy};
+pub use seen::SeenTransactions;
+pub use state::{
+    AccountsIter, BulkLoadError, ErasureError, GroupError, MergeError, PreparedAction,
+    RestatementError, Savepoint, State, StateDiff, UpdateError,
+};
+pub use transaction::{Transaction, TransactionEvent, TransactionEventKind, TransactionState};
+pub use view::ClientView;
 
 #[cfg(feature = "decimal")]
 type Amount = rust_decimal::Decimal;
@@ -17,9 +97,37 @@ type Amount = rust_decimal::Decimal;
 #[cfg(not(feature = "decimal"))]
 type Amount = f64;
 
+/// The integer behind `ClientId`, widened to a `u32` by the `wide-client-ids`
+/// feature
+#[cfg(not(feature = "wide-client-ids"))]
+pub(crate) type RawClientId = u16;
+#[cfg(feature = "wide-client-ids")]
+pub(crate) type RawClientId = u32;
+
+/// The integer behind `TransactionId`, widened to a `u64` by the
+/// `wide-transaction-ids` feature
+#[cfg(not(feature = "wide-transaction-ids"))]
+pub(crate) type RawTransactionId = u32;
+#[cfg(feature = "wide-transaction-ids")]
+pub(crate) type RawTransactionId = u64;
+
 /// Newtype'd client id, so it can never be mixed up with `TransactionId`
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
-pub struct ClientId(pub(crate) u16);
+pub struct ClientId(pub(crate) RawClientId);
+
+impl ClientId {
+    /// The id that the records of erased clients are moved to (see
+    /// `State::erase_client`), so it can't be used by a real client
+    pub const TOMBSTONE: Self = Self(RawClientId::MAX);
+}
+
+impl std::str::FromStr for ClientId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
 
 impl std::fmt::Display for ClientId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -28,11 +136,56 @@ impl std::fmt::Display for ClientId {
 }
 
 /// Newtype'd transaction id, so it can never be mixed up with `ClientId`
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
-pub struct TransactionId(pub(crate) u32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct TransactionId(pub(crate) RawTransactionId);
+
+impl std::str::FromStr for TransactionId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
 
 impl std::fmt::Display for TransactionId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
+
+/// When an action happened, as whole seconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn from_secs(secs: u64) -> Self {
+        Self(secs)
+    }
+
+    /// The current system time
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub fn now() -> Self {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self(since_epoch.as_secs())
+    }
+
+    /// The current time, from the browser's clock, since `SystemTime` isn't
+    /// available on `wasm32-unknown-unknown`
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    pub fn now() -> Self {
+        Self((js_sys::Date::now() / 1000.0) as u64)
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
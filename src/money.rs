@@ -0,0 +1,240 @@
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+
+use crate::{Amount, Rounding};
+
+/// Decimal places an input amount can have
+#[cfg(not(feature = "crypto"))]
+pub const INPUT_DECIMALS: u32 = 4;
+#[cfg(feature = "crypto")]
+pub const INPUT_DECIMALS: u32 = crate::crypto::DECIMALS;
+
+/// An amount that's been checked as fit for input: finite, not negative, and
+/// with no more than `INPUT_DECIMALS` decimal places.
+///
+/// Action amounts are deserialized through it, so a malformed amount fails
+/// to read with a `MoneyError` rather than reaching an engine. Balances and
+/// the engine's arithmetic stay as plain `Amount`s, since they can go
+/// negative.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(transparent)]
+pub struct Money(Amount);
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum MoneyError {
+    #[error("the amount {0} is negative")]
+    Negative(Amount),
+
+    #[error("the amount {amount} has more than {decimals} decimal places")]
+    TooPrecise { amount: Amount, decimals: u32 },
+
+    #[error("the amount {0} isn't a finite number")]
+    NotFinite(Amount),
+
+    #[error("'{0}' isn't an amount")]
+    Invalid(String),
+}
+
+impl Money {
+    pub fn get(self) -> Amount {
+        self.0
+    }
+
+    /// Parse an amount straight from an input's bytes, as `from_str` would.
+    ///
+    /// Amounts in the usual shape (digits, then optionally a point and no
+    /// more than `INPUT_DECIMALS` digits) are built from their digits
+    /// directly, which is several times faster than going through
+    /// `Amount`'s own parser. Anything else (a sign, an exponent, `NaN`, or
+    /// too many digits to build exactly) goes through `from_str`, so the
+    /// result is the same either way.
+    pub fn from_ascii(bytes: &[u8]) -> Result<Self, MoneyError> {
+        if let Some(amount) = parse_plain(bytes) {
+            return Ok(Self(amount));
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(text) => text.parse(),
+            Err(_) => Err(MoneyError::Invalid(
+                String::from_utf8_lossy(bytes).into_owned(),
+            )),
+        }
+    }
+}
+
+/// A plain decimal like `12.5`, if `bytes` is one with at most
+/// `INPUT_DECIMALS` decimal places that can be built exactly
+fn parse_plain(bytes: &[u8]) -> Option<Amount> {
+    // Any 18 digits fit in an `i64`
+    const MAX_DIGITS: usize = 18;
+
+    let (whole, fraction) = match bytes.iter().position(|&byte| byte == b'.') {
+        Some(point) => (&bytes[..point], Some(&bytes[point + 1..])),
+        None => (bytes, None),
+    };
+    let fraction = match fraction {
+        // `1.` and `.5` are left to the full parser
+        Some([]) => return None,
+        Some(fraction) => fraction,
+        None => &[],
+    };
+    if whole.is_empty()
+        || fraction.len() > INPUT_DECIMALS as usize
+        || whole.len() + fraction.len() > MAX_DIGITS
+    {
+        return None;
+    }
+    let mut mantissa: i64 = 0;
+    for &digit in whole.iter().chain(fraction) {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        mantissa = mantissa * 10 + i64::from(digit - b'0');
+    }
+    let scale = fraction.len() as u32;
+
+    #[cfg(feature = "decimal")]
+    return Some(Amount::new(mantissa, scale));
+
+    // Both sides of the division are exact, so it rounds the same way as
+    // parsing the string would
+    #[cfg(not(feature = "decimal"))]
+    return (mantissa < 1 << f64::MANTISSA_DIGITS)
+        .then(|| mantissa as f64 / 10f64.powi(scale as i32));
+}
+
+impl TryFrom<Amount> for Money {
+    type Error = MoneyError;
+
+    fn try_from(amount: Amount) -> Result<Self, MoneyError> {
+        #[cfg(not(feature = "decimal"))]
+        if !amount.is_finite() {
+            return Err(MoneyError::NotFinite(amount));
+        }
+        if amount < Amount::default() {
+            return Err(MoneyError::Negative(amount));
+        }
+        if Rounding::ToZero.round(amount, INPUT_DECIMALS) != amount {
+            return Err(MoneyError::TooPrecise {
+                amount,
+                decimals: INPUT_DECIMALS,
+            });
+        }
+        Ok(Self(amount))
+    }
+}
+
+impl From<Money> for Amount {
+    fn from(money: Money) -> Self {
+        money.0
+    }
+}
+
+impl std::str::FromStr for Money {
+    type Err = MoneyError;
+
+    fn from_str(s: &str) -> Result<Self, MoneyError> {
+        let amount: Amount = s.parse().map_err(|_| MoneyError::Invalid(s.into()))?;
+        Self::try_from(amount)
+    }
+}
+
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[cfg(feature = "crypto")]
+        let amount = crate::persist::exact_amount::deserialize(deserializer)?;
+        // Strings are taken too, so the same JSON works with every feature
+        #[cfg(not(feature = "crypto"))]
+        let amount = match NumberOrString::deserialize(deserializer)? {
+            NumberOrString::Number(amount) => amount,
+            NumberOrString::String(amount) => return amount.parse().map_err(D::Error::custom),
+        };
+        Self::try_from(amount).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(Amount),
+    String(String),
+}
+
+/// Deserialize an optional amount through `Money`, for `Action::amount`
+pub(crate) fn deserialize_optional<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Amount>, D::Error> {
+    Ok(Option::<Money>::deserialize(deserializer)?.map(Money::get))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::CsvSource;
+
+    #[test]
+    fn test_input_amounts() {
+        let money = |s: &str| s.parse::<Money>();
+        assert_eq!(money("1.2345").unwrap().to_string(), "1.2345");
+        assert_eq!(money("0").unwrap().get(), Amount::default());
+        assert!(matches!(money("-1.0"), Err(MoneyError::Negative(_))));
+        #[cfg(not(feature = "crypto"))]
+        assert!(matches!(
+            money("1.23456"),
+            Err(MoneyError::TooPrecise { decimals: 4, .. })
+        ));
+        assert!(matches!(money("1.2.3"), Err(MoneyError::Invalid(_))));
+        #[cfg(not(feature = "decimal"))]
+        assert!(matches!(money("inf"), Err(MoneyError::NotFinite(_))));
+        assert!(money("NaN").is_err());
+
+        for amount in [
+            "0",
+            "1",
+            "1.5",
+            "007.50",
+            "1.2345",
+            "123456789.0001",
+            "1.23450",
+            "1.",
+            ".5",
+            "1e3",
+            "-1",
+            "+1",
+            "1.2.3",
+            "",
+            "1,5",
+            "99999999999999999999",
+        ] {
+            assert_eq!(
+                Money::from_ascii(amount.as_bytes()).map(|m| m.to_string()),
+                money(amount).map(|m| m.to_string()),
+                "{amount}"
+            );
+        }
+        assert!(Money::from_ascii(b"\xff").is_err());
+
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,-2.5\n\
+            deposit,1,2,1.5\n\
+            withdrawal,1,3,NaN\n";
+        for source in [
+            CsvSource::from_reader(input.as_bytes()),
+            CsvSource::from_reader(input.as_bytes()).fast(),
+        ] {
+            let read: Vec<_> = source.collect();
+            assert!(read[0]
+                .as_ref()
+                .unwrap_err()
+                .to_string()
+                .contains("negative"));
+            assert!(read[1].is_ok());
+            assert!(read[2].is_err());
+        }
+    }
+}
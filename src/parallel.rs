@@ -0,0 +1,609 @@
+//! Multi-threaded batch processing of large csv inputs
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::mpsc,
+    thread,
+};
+
+use crate::{
+    io::{Compression, CsvSource},
+    persist::{Journal, PersistError},
+    Action, ActionKind, ClientId, SeenTransactions, State,
+};
+
+/// Number of actions sent to a worker at once. Sending every action
+/// individually spends more time on channel synchronization than on the
+/// actual updates.
+const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// Number of batches that can be queued for each worker before the reader
+/// blocks, so a slow worker can't make us buffer the whole file in memory.
+const CHANNEL_DEPTH: usize = 16;
+
+/// Records read from the start of the input by `ParallelCsvProcessor::autotune`
+const AUTOTUNE_SAMPLE: usize = 10_000;
+
+/// Inputs with fewer records than this are processed on a single worker by
+/// `ParallelCsvProcessor::autotune`, since starting more costs more than it
+/// saves
+const AUTOTUNE_MIN_PARALLEL_RECORDS: u64 = 50_000;
+
+/// Processes actions across a pool of worker threads.
+///
+/// Actions are read on the calling thread and partitioned by client, so each
+/// client's actions are always handled by the same worker, in input order.
+/// Since every account only depends on its own actions, the merged result is
+/// the same as processing the input on a single thread.
+#[derive(Debug, Clone)]
+pub struct ParallelCsvProcessor {
+    num_workers: usize,
+    batch_size: usize,
+
+    /// Accounts and transactions each worker's state is sized for up front
+    capacity: (usize, usize),
+
+    /// What the settings were picked from, if they were picked automatically
+    tuning: Option<Tuning>,
+}
+
+/// What `ParallelCsvProcessor::autotune` found in its sample of the input,
+/// and the settings it picked from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// Records read from the start of the input
+    pub sampled: usize,
+    /// The estimated number of records in the whole input, if its size is
+    /// known (it isn't for compressed inputs)
+    pub estimated_records: Option<u64>,
+    /// The estimated number of clients in the whole input
+    pub estimated_clients: u64,
+    /// The estimated number of deposits, withdrawals and reversals in the
+    /// whole input
+    pub estimated_transactions: u64,
+    /// The largest amount in the sample, if any had amounts
+    pub max_amount: Option<crate::Amount>,
+
+    pub num_workers: usize,
+    pub batch_size: usize,
+    pub accounts_per_worker: usize,
+    pub transactions_per_worker: usize,
+}
+
+impl ParallelCsvProcessor {
+    /// Create a processor with the given number of worker threads (at least
+    /// one worker is always used)
+    pub fn new(num_workers: usize) -> Self {
+        Self {
+            num_workers: num_workers.max(1),
+            batch_size: DEFAULT_BATCH_SIZE,
+            capacity: (0, 0),
+            tuning: None,
+        }
+    }
+
+    /// Set the number of actions handed to a worker at a time
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Size each worker's state for this many accounts and transactions up
+    /// front, rather than growing it as they come in
+    pub fn with_capacity(mut self, accounts: usize, transactions: usize) -> Self {
+        self.capacity = (accounts, transactions);
+        self
+    }
+
+    /// Pick the settings for processing a csv file from a sample of its
+    /// first records, for when the right settings aren't known up front.
+    ///
+    /// The number of clients and transactions in the whole file are
+    /// extrapolated from the sample (and the file's size), then:
+    /// - small inputs get a single worker, and larger ones a worker per
+    ///   available core, but never more than there are clients
+    /// - batches are made smaller when there'd otherwise be too few of them to
+    ///   keep every worker busy
+    /// - each worker's state is sized up front for its share of the accounts
+    ///   and transactions
+    ///
+    /// These are only estimates, so a very unrepresentative start of the file
+    /// makes for worse (but still correct) settings. See `tuning` for what
+    /// was picked.
+    pub fn autotune<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        let path = path.as_ref();
+        let mut source = CsvSource::from_path(path)?.fast();
+        let mut sample = Sample::default();
+        for action in source.by_ref().take(AUTOTUNE_SAMPLE).flatten() {
+            sample.add(&action);
+        }
+
+        // Compressed files don't say how much csv they hold
+        let file_len = match Compression::from_path(path) {
+            Compression::None => Some(std::fs::metadata(path)?.len()),
+            _ => None,
+        };
+        let bytes_read = source.bytes_read();
+        let estimated_records = match file_len {
+            Some(len) if bytes_read > 0 => {
+                Some((sample.records as f64 * len as f64 / bytes_read as f64) as u64)
+            }
+            _ => None,
+        };
+        Ok(Self::tuned(sample, estimated_records))
+    }
+
+    fn tuned(sample: Sample, estimated_records: Option<u64>) -> Self {
+        let records = estimated_records
+            .unwrap_or(sample.records)
+            .max(sample.records);
+        let scale = records as f64 / sample.records.max(1) as f64;
+        let estimated_clients = sample.estimated_clients(records);
+        let estimated_transactions = (sample.new_transactions as f64 * scale) as u64;
+
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        let num_workers = if records < AUTOTUNE_MIN_PARALLEL_RECORDS {
+            1
+        } else {
+            // One core is left for the reader thread
+            (cores.saturating_sub(1).max(1) as u64).min(estimated_clients.max(1)) as usize
+        };
+        // Enough batches for each worker to have a few queued
+        let batch_size = (records / (num_workers * CHANNEL_DEPTH) as u64)
+            .clamp(1, DEFAULT_BATCH_SIZE as u64) as usize;
+        let accounts_per_worker = estimated_clients.div_ceil(num_workers as u64) as usize;
+        let transactions_per_worker = estimated_transactions.div_ceil(num_workers as u64) as usize;
+
+        let tuning = Tuning {
+            sampled: sample.records as usize,
+            estimated_records,
+            estimated_clients,
+            estimated_transactions,
+            max_amount: sample.max_amount,
+            num_workers,
+            batch_size,
+            accounts_per_worker,
+            transactions_per_worker,
+        };
+        Self {
+            tuning: Some(tuning),
+            ..Self::new(num_workers)
+                .with_batch_size(batch_size)
+                .with_capacity(accounts_per_worker, transactions_per_worker)
+        }
+    }
+
+    /// What the settings were picked from, for a processor from `autotune`
+    pub fn tuning(&self) -> Option<&Tuning> {
+        self.tuning.as_ref()
+    }
+
+    /// Process a csv file with settings picked by `autotune`, returning the
+    /// merged state
+    pub fn run_autotuned<P: AsRef<Path>>(path: P) -> Result<State, csv::Error> {
+        let processor = Self::autotune(&path)?;
+        let source = CsvSource::from_path(path)?.fast();
+        Ok(processor.process(source.filter_map(Result::ok)))
+    }
+
+    /// Process a csv file with `num_workers` threads, returning the merged
+    /// state.
+    ///
+    /// Like the binary, records that can't be deserialized are skipped. Since
+    /// nothing is said about them, the file is read with `CsvSource::fast`.
+    pub fn run<P: AsRef<Path>>(path: P, num_workers: usize) -> Result<State, csv::Error> {
+        let source = CsvSource::from_path(path)?.fast();
+        Ok(Self::new(num_workers).process(source.filter_map(Result::ok)))
+    }
+
+    /// Process a stream of actions, returning the merged state of all workers
+    pub fn process<I: IntoIterator<Item = Action>>(&self, actions: I) -> State {
+        self.process_inner(actions, None)
+            .expect("nothing else can fail without a journal")
+    }
+
+    /// Process a stream of actions like `process`, recording each one in
+    /// `journal` as it's handed to a worker.
+    ///
+    /// Clients are independent of each other and each client's actions are
+    /// applied in the order they're recorded, so replaying the journal on a
+    /// single thread (with `persist::replay_journal`) reproduces the merged
+    /// state exactly.
+    pub fn process_journaled<I: IntoIterator<Item = Action>>(
+        &self,
+        actions: I,
+        journal: &mut Journal,
+    ) -> Result<State, PersistError> {
+        self.process_inner(actions, Some(journal))
+    }
+
+    fn process_inner<I: IntoIterator<Item = Action>>(
+        &self,
+        actions: I,
+        mut journal: Option<&mut Journal>,
+    ) -> Result<State, PersistError> {
+        thread::scope(|scope| {
+            let (senders, workers): (Vec<_>, Vec<_>) = (0..self.num_workers)
+                .map(|_| {
+                    let (sender, receiver) = mpsc::sync_channel::<Vec<Action>>(CHANNEL_DEPTH);
+                    let (accounts, transactions) = self.capacity;
+                    let worker = scope.spawn(move || {
+                        let mut state = State::new();
+                        state.reserve(accounts, transactions);
+                        for action in receiver.into_iter().flatten() {
+                            // Errors are ignored, the same as `SingleThreadedEngine`
+                            let _ = state.update(action);
+                        }
+                        state
+                    });
+                    (sender, worker)
+                })
+                .unzip();
+
+            let mut batches: Vec<Vec<Action>> = (0..self.num_workers)
+                .map(|_| Vec::with_capacity(self.batch_size))
+                .collect();
+            let mut claims = Claims::default();
+
+            for mut action in actions {
+                if !claims.claim(&mut action) {
+                    continue;
+                }
+                // Bailing out drops the senders, which stops the workers
+                if let Some(journal) = journal.as_mut() {
+                    journal.append(&action)?;
+                }
+
+                let worker = self.worker_for(action.client_id);
+                let batch = &mut batches[worker];
+                batch.push(action);
+                if batch.len() >= self.batch_size {
+                    let full = std::mem::replace(batch, Vec::with_capacity(self.batch_size));
+                    senders[worker]
+                        .send(full)
+                        .expect("worker thread exited early");
+                }
+            }
+
+            // Flush what's left. Dropping the senders lets the workers finish
+            for (sender, batch) in senders.into_iter().zip(batches) {
+                if !batch.is_empty() {
+                    sender.send(batch).expect("worker thread exited early");
+                }
+            }
+
+            workers
+                .into_iter()
+                .try_fold(State::new(), |mut merged, worker| {
+                    merged.absorb(worker.join().expect("worker thread panicked"))?;
+                    Ok(merged)
+                })
+        })
+    }
+
+    fn worker_for(&self, client: ClientId) -> usize {
+        client.0 as usize % self.num_workers
+    }
+}
+
+/// Transaction ids and references are unique across all clients, but each
+/// worker can only see its own clients' transactions. So we track new
+/// deposit/withdrawal ids and references on the reader thread, dropping any
+/// reused ones the same way `State::update` would.
+#[derive(Debug, Default)]
+struct Claims {
+    ids: SeenTransactions,
+    references: HashSet<String>,
+    /// The ledger's currency, see `State::set_currency`
+    currency: Option<String>,
+}
+
+impl Claims {
+    fn claim(&mut self, action: &mut Action) -> bool {
+        let claims_id = match action.kind {
+            ActionKind::Deposit | ActionKind::Withdrawal => action.amount.is_some(),
+            ActionKind::Reversal => action.reverses.is_some(),
+            _ => false,
+        };
+        // Actions the worker will turn away before they take an id don't
+        // claim one, so a later action can still use it
+        if !claims_id || State::check_up_front(action, self.currency.as_deref()).is_err() {
+            return true;
+        }
+        if let Some(reference) = &action.reference {
+            if self.references.contains(reference) {
+                return false;
+            }
+            // Assigned here, since the workers would each assign the same ids
+            if action.transaction_id.is_none() {
+                match self.ids.next_free() {
+                    Some(id) => action.transaction_id = Some(id),
+                    None => return false,
+                }
+            }
+        }
+        let Some(id) = action.transaction_id else {
+            // Left for the worker to reject
+            return true;
+        };
+        if !self.ids.insert(id) {
+            return false;
+        }
+        if let Some(reference) = &action.reference {
+            self.references.insert(reference.clone());
+        }
+        true
+    }
+}
+
+/// Counts taken from the start of an input by `autotune`
+#[derive(Debug, Default)]
+struct Sample {
+    records: u64,
+    new_transactions: u64,
+    max_amount: Option<crate::Amount>,
+    /// How many records each client had
+    clients: HashMap<ClientId, u64>,
+}
+
+impl Sample {
+    fn add(&mut self, action: &Action) {
+        self.records += 1;
+        if action.kind.creates_transaction() {
+            self.new_transactions += 1;
+        }
+        if let Some(amount) = action.amount {
+            self.max_amount = Some(self.max_amount.map_or(amount, |max| max.max(amount)));
+        }
+        *self.clients.entry(action.client_id).or_default() += 1;
+    }
+
+    /// Extrapolate the number of clients in `records` records, with the
+    /// Chao1 estimator (which goes by how many clients were only seen once or
+    /// twice, as a sign of how many haven't been seen yet)
+    fn estimated_clients(&self, records: u64) -> u64 {
+        let seen = self.clients.len() as f64;
+        let once = self.clients.values().filter(|n| **n == 1).count() as f64;
+        let twice = self.clients.values().filter(|n| **n == 2).count() as f64;
+        let unseen = if twice > 0.0 {
+            once * once / (2.0 * twice)
+        } else {
+            once * (once - 1.0).max(0.0) / 2.0
+        };
+        ((seen + unseen) as u64).min(records)
+    }
+}
+
+/// Apply a batch of actions to an empty state across rayon's thread pool,
+/// for `SingleThreadedEngine::process_all_par`.
+///
+/// The actions are grouped by client (after claiming their transaction ids
+/// and references, the same as `ParallelCsvProcessor`), and rayon splits the
+/// groups between states configured like `state`, each applying its groups'
+/// actions in order. The states' accounts and transactions are then merged
+/// into `state`.
+#[cfg(feature = "rayon")]
+pub(crate) fn process_par<I>(state: &mut State, actions: I) -> Result<(), crate::BulkLoadError>
+where
+    I: IntoIterator<Item = Action>,
+{
+    use rayon::prelude::*;
+
+    if !state.is_pristine() {
+        return Err(crate::BulkLoadError::NotEmpty);
+    }
+
+    let mut claims = Claims {
+        currency: state.currency().map(String::from),
+        ..Claims::default()
+    };
+    let mut groups: HashMap<ClientId, Vec<Action>> = HashMap::new();
+    for mut action in actions {
+        if claims.claim(&mut action) {
+            groups.entry(action.client_id).or_default().push(action);
+        }
+    }
+
+    let (window, limits, chargebacks, max_disputes, currency, audit, balances) = (
+        state.dispute_window(),
+        state.limits(),
+        state.chargeback_policy(),
+        state.max_open_disputes(),
+        state.currency(),
+        state.audit_trail_enabled(),
+        state.running_balances_enabled(),
+    );
+    let shards: Vec<State> = groups
+        .into_par_iter()
+        .fold(
+            || {
+                let mut shard = State::new();
+                shard.set_dispute_window(window);
+                shard.set_limits(limits);
+                shard.set_chargeback_policy(chargebacks);
+                shard.set_max_open_disputes(max_disputes);
+                shard.set_currency(currency.map(String::from));
+                shard.set_audit_trail(audit);
+                shard.set_running_balances(balances);
+                shard
+            },
+            |mut shard, (_, actions)| {
+                for action in actions {
+                    // Errors are ignored, the same as `SingleThreadedEngine`
+                    let _ = shard.update(action);
+                }
+                shard
+            },
+        )
+        .collect();
+    for shard in shards {
+        state.absorb(shard)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SingleThreadedEngine, SyncEngineExt};
+
+    const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+deposit, 3, 3, 2.5
+withdrawal, 1, 4, 2.0
+deposit, 2, 1, 100.0
+dispute, 1, 1,
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 2, 5, 1.0
+withdrawal, 3, 6, 3.0
+dispute, 3, 1,
+resolve, 1, 1,
+";
+
+    fn summarize(state: &State) -> Vec<String> {
+        let mut accounts: Vec<_> = state.accounts().collect();
+        accounts.sort_by_key(|a| a.client);
+        accounts.iter().map(|a| format!("{:?}", a)).collect()
+    }
+
+    #[test]
+    fn test_matches_single_threaded() {
+        let actions = || CsvSource::from_reader(INPUT.as_bytes()).map(Result::unwrap);
+
+        let mut engine = SingleThreadedEngine::new();
+        engine.process_all(actions()).unwrap();
+
+        for workers in 1..=4 {
+            let state = ParallelCsvProcessor::new(workers)
+                .with_batch_size(2)
+                .process(actions());
+            assert_eq!(summarize(&state), summarize(engine.state()));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_process_all_par() {
+        let actions = || CsvSource::from_reader(INPUT.as_bytes()).map(Result::unwrap);
+
+        let mut engine = SingleThreadedEngine::new();
+        engine.process_all(actions()).unwrap();
+
+        let mut parallel = SingleThreadedEngine::new();
+        parallel.process_all_par(actions()).unwrap();
+        assert_eq!(summarize(parallel.state()), summarize(engine.state()));
+        assert_eq!(
+            parallel.state().failed_transactions().count(),
+            engine.state().failed_transactions().count()
+        );
+
+        // Only into an empty engine
+        assert!(matches!(
+            parallel.process_all_par(actions()),
+            Err(crate::BulkLoadError::NotEmpty)
+        ));
+    }
+
+    #[test]
+    fn test_rejected_actions_dont_claim_ids() {
+        // The engine turns the first deposit away before it takes its id
+        let input = format!(
+            "type,client,tx,amount\n\
+            deposit,{},1,5.0\n\
+            deposit,1,1,10.0\n\
+            deposit,2,2,3.0\n",
+            ClientId::TOMBSTONE.0
+        );
+        let actions = || CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+
+        let mut engine = SingleThreadedEngine::new();
+        engine.process_all(actions()).unwrap();
+        assert_eq!(engine.state().accounts().count(), 2);
+        for workers in 1..=3 {
+            let state = ParallelCsvProcessor::new(workers).process(actions());
+            assert_eq!(summarize(&state), summarize(engine.state()));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_process_all_par_checks_currency_before_claiming() {
+        let input = "type,client,tx,amount,currency
+deposit,1,1,5.0,EUR
+deposit,2,1,10.0,USD
+deposit,1,2,3.0,USD
+";
+        let actions = || CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+        let engine = || {
+            let mut state = State::new();
+            state.set_currency(Some("USD".into()));
+            SingleThreadedEngine::from_state(state)
+        };
+
+        let mut single = engine();
+        single.process_all(actions()).unwrap();
+        assert_eq!(single.state().accounts().count(), 2);
+        let mut parallel = engine();
+        parallel.process_all_par(actions()).unwrap();
+        assert_eq!(summarize(parallel.state()), summarize(single.state()));
+    }
+
+    #[test]
+    fn test_autotune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("actions.csv");
+        std::fs::write(&path, INPUT).unwrap();
+
+        // Too small to be worth more than one worker
+        let processor = ParallelCsvProcessor::autotune(&path).unwrap();
+        let tuning = processor.tuning().unwrap();
+        assert_eq!(tuning.sampled, 12);
+        assert_eq!(tuning.estimated_records, Some(12));
+        assert_eq!(tuning.num_workers, 1);
+        assert_eq!(tuning.estimated_transactions, 7);
+
+        let mut engine = SingleThreadedEngine::new();
+        engine
+            .process_all(CsvSource::from_reader(INPUT.as_bytes()).map(Result::unwrap))
+            .unwrap();
+        let state = ParallelCsvProcessor::run_autotuned(&path).unwrap();
+        assert_eq!(summarize(&state), summarize(engine.state()));
+
+        // A large input is never split across more workers than clients
+        let mut sample = Sample::default();
+        for action in CsvSource::from_reader(INPUT.as_bytes()) {
+            sample.add(&action.unwrap());
+        }
+        let tuning = ParallelCsvProcessor::tuned(sample, Some(1_200_000))
+            .tuning
+            .unwrap();
+        assert_eq!(tuning.estimated_clients, 3);
+        assert!(tuning.num_workers <= 3);
+        assert_eq!(tuning.batch_size, DEFAULT_BATCH_SIZE);
+        assert_eq!(
+            tuning.transactions_per_worker,
+            700_000usize.div_ceil(tuning.num_workers)
+        );
+    }
+
+    #[test]
+    fn test_journal_replays_to_same_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("journal.jsonl");
+        let actions = CsvSource::from_reader(INPUT.as_bytes()).map(Result::unwrap);
+
+        let mut journal = Journal::create(&path).unwrap();
+        let state = ParallelCsvProcessor::new(3)
+            .with_batch_size(2)
+            .process_journaled(actions, &mut journal)
+            .unwrap();
+        journal.flush().unwrap();
+
+        let mut replayed = State::new();
+        crate::persist::replay_journal(&path, &mut replayed).unwrap();
+        assert_eq!(summarize(&replayed), summarize(&state));
+    }
+}
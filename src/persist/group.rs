@@ -0,0 +1,125 @@
+//! Group commit, for syncing the journal once per batch of actions instead
+//! of once per action
+//!
+//! An fsync costs about the same whether it covers one entry or hundreds, so
+//! when many threads are waiting for their actions to be durable, the first
+//! one to need a sync does it for everyone appended so far. A sync is started
+//! once `max_actions` are waiting, or the first of them has waited
+//! `max_delay`, and every thread it covers is then let go together.
+
+use std::{
+    sync::{Condvar, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use super::{Journal, PersistError};
+use crate::sync::Mutex;
+
+/// How long actions can wait to be synced to the journal together, see
+/// `MultiThreadedEngine::with_group_commit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    /// Sync as soon as this many actions are waiting
+    pub max_actions: usize,
+
+    /// The longest an action waits for others to join its sync
+    pub max_delay: Duration,
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        Self {
+            max_actions: 256,
+            max_delay: Duration::from_millis(2),
+        }
+    }
+}
+
+/// Hands out syncs of a shared journal to the threads waiting on them
+#[derive(Debug)]
+pub(crate) struct Committer {
+    config: GroupCommit,
+    progress: StdMutex<Progress>,
+    /// Notified whenever a sync finishes
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    /// The latest sequence number anyone has waited for
+    appended: u64,
+    /// Every entry up to this one is on disk
+    synced: u64,
+    /// Whether a thread is syncing right now
+    syncing: bool,
+    /// When the first action still waiting started to
+    window_start: Option<Instant>,
+    /// How many syncs have been made
+    syncs: u64,
+}
+
+impl Committer {
+    pub(crate) fn new(config: GroupCommit) -> Self {
+        Self {
+            config: GroupCommit {
+                max_actions: config.max_actions.max(1),
+                ..config
+            },
+            progress: StdMutex::new(Progress::default()),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Block until the entry `seq` of `journal` is on disk, syncing it (and
+    /// everything else appended by then) if it's this thread's turn to
+    pub(crate) fn wait(&self, seq: u64, journal: &Mutex<Journal>) -> Result<(), PersistError> {
+        let mut progress = self.progress.lock().expect("poisoned!");
+        progress.appended = progress.appended.max(seq);
+        loop {
+            if progress.synced >= seq {
+                return Ok(());
+            }
+            let now = Instant::now();
+            let deadline = *progress.window_start.get_or_insert(now) + self.config.max_delay;
+            let waiting = progress.appended - progress.synced;
+            if !progress.syncing && (waiting >= self.config.max_actions as u64 || now >= deadline) {
+                progress.syncing = true;
+                drop(progress);
+                let result = sync(journal);
+                progress = self.progress.lock().expect("poisoned!");
+                progress.syncing = false;
+                progress.window_start = None;
+                if let Ok(synced) = result {
+                    progress.synced = progress.synced.max(synced);
+                    progress.syncs += 1;
+                }
+                self.synced.notify_all();
+                // Anyone else still waiting tries again themselves
+                result?;
+                continue;
+            }
+            progress = if progress.syncing {
+                self.synced.wait(progress).expect("poisoned!")
+            } else {
+                let timeout = deadline.saturating_duration_since(now);
+                self.synced
+                    .wait_timeout(progress, timeout)
+                    .expect("poisoned!")
+                    .0
+            };
+        }
+    }
+
+    pub(crate) fn syncs(&self) -> u64 {
+        self.progress.lock().expect("poisoned!").syncs
+    }
+}
+
+/// Sync everything appended to `journal` so far, returning the last sequence
+/// number it covers. Only the buffer is written out under the journal's lock,
+/// so other threads can keep appending while the disk catches up.
+fn sync(journal: &Mutex<Journal>) -> Result<u64, PersistError> {
+    let (seq, file) = journal.lock().expect("poisoned!").write_out()?;
+    file.sync_data()?;
+    Ok(seq)
+}
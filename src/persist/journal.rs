@@ -0,0 +1,152 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::PersistError;
+use crate::{Action, State};
+
+#[derive(Serialize)]
+pub(super) struct EntryRef<'a> {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub action: &'a Action,
+}
+
+#[derive(Deserialize)]
+pub(super) struct Entry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// An append-only log of received actions, each tagged with a sequence number
+#[derive(Debug)]
+pub struct Journal {
+    writer: BufWriter<File>,
+    seq: u64,
+}
+
+impl Journal {
+    /// Start a new journal at `path`, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, PersistError> {
+        let file = File::create(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            seq: 0,
+        })
+    }
+
+    pub(crate) fn open(path: &Path, seq: u64) -> Result<Self, PersistError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            seq,
+        })
+    }
+
+    /// Record an action, returning its sequence number.
+    ///
+    /// Entries are buffered, so they're only durable after a `flush`.
+    pub fn append(&mut self, action: &Action) -> Result<u64, PersistError> {
+        let seq = self.seq + 1;
+        serde_json::to_writer(&mut self.writer, &EntryRef { seq, action })?;
+        self.writer.write_all(b"\n")?;
+        self.seq = seq;
+        Ok(seq)
+    }
+
+    /// Write all buffered entries and sync them to disk
+    pub fn flush(&mut self) -> Result<(), PersistError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Write out the buffered entries without syncing them, returning the
+    /// last sequence number written and a handle to sync the file through
+    pub(crate) fn write_out(&mut self) -> Result<(u64, File), PersistError> {
+        self.writer.flush()?;
+        Ok((self.seq, self.writer.get_ref().try_clone()?))
+    }
+
+    /// The sequence number of the last appended action
+    pub fn sequence(&self) -> u64 {
+        self.seq
+    }
+
+    /// Drop all entries, after they've been captured in a snapshot
+    pub(crate) fn clear(&mut self) -> Result<(), PersistError> {
+        self.writer.flush()?;
+        let file = self.writer.get_ref();
+        file.set_len(0)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Apply all journal entries after `after` to `state`, returning the last
+/// sequence number in the journal.
+///
+/// An incomplete final line (from a crash mid-write) is ignored, and with
+/// `repair` it's also cut off the file, so new entries aren't appended onto it.
+pub(crate) fn replay(
+    path: &Path,
+    after: u64,
+    state: &mut State,
+    repair: bool,
+) -> Result<u64, PersistError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(after),
+        Err(e) => return Err(e.into()),
+    };
+    let total_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut seq = after;
+    let mut valid_len = 0;
+    let mut line = String::new();
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        line_number += 1;
+
+        if !line.ends_with('\n') {
+            // Torn write, the rest of the entry never made it
+            break;
+        }
+
+        let entry: Entry =
+            serde_json::from_str(&line).map_err(|source| PersistError::CorruptJournal {
+                line: line_number,
+                source,
+            })?;
+        valid_len += read as u64;
+
+        // Entries already covered by the snapshot
+        if entry.seq <= seq {
+            continue;
+        }
+        seq = entry.seq;
+        // Errors are ignored, the same as they were when the action was
+        // first received
+        let _ = state.update(entry.action);
+    }
+
+    if repair && valid_len < total_len {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len)?;
+    }
+
+    Ok(seq)
+}
@@ -0,0 +1,372 @@
+//! Persisting engine state between runs
+//!
+//! A `StateDir` holds two files:
+//!
+//! - `snapshot.json`: the full state as of some sequence number
+//! - `journal.jsonl`: every action received since that snapshot, one JSON
+//!   object per line
+//!
+//! Actions should be appended to the journal before they are applied, so if a
+//! run dies before its next checkpoint, restoring replays the journal on top of
+//! the last snapshot and nothing that was received is lost.
+//!
+//! A journal can also be used on its own, to record the exact order a
+//! concurrent engine applied actions in (see
+//! `MultiThreadedEngine::with_journal`). Replaying it with `replay_journal`
+//! reproduces the same final state on a single thread.
+//!
+//! With many threads appending, `GroupCommit` syncs the journal once for a
+//! whole batch of their actions (see `MultiThreadedEngine::with_group_commit`).
+//!
+//! The same formats are used to keep a warm standby in step with a running
+//! engine over the network, see `Primary` and `Replica`.
+//!
+//! A run over a csv file can also leave a `ResumePoint` (in `resume.json`),
+//! saying how far through the file it got, so an interrupted run can carry
+//! on from there rather than reading the file again from the start.
+//!
+//! A `ConcurrentEngine`'s shards are snapshotted to a directory of their own,
+//! one file per shard, see `ConcurrentEngine::checkpoint`.
+
+mod group;
+mod journal;
+mod replication;
+mod resume;
+#[cfg(feature = "concurrent-engine")]
+pub(crate) mod sharded;
+mod snapshot;
+
+use std::path::{Path, PathBuf};
+
+pub(crate) use group::Committer;
+pub use group::GroupCommit;
+pub use journal::Journal;
+pub use replication::{Primary, Replica};
+pub use resume::ResumePoint;
+
+use crate::State;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const JOURNAL_FILE: &str = "journal.jsonl";
+const RESUME_FILE: &str = "resume.json";
+
+/// A directory used to carry engine state from one run to the next
+#[derive(Debug, Clone)]
+pub struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    /// Use `path` as a state directory, creating it if it doesn't exist
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, PersistError> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the last snapshot (if any) and replay the journal on top of it,
+    /// returning the state and the journal to continue appending to
+    pub fn restore(&self) -> Result<(State, Journal), PersistError> {
+        let (mut state, seq) = snapshot::read(&self.snapshot_path())?.unwrap_or_default();
+        let seq = journal::replay(&self.journal_path(), seq, &mut state, true)?;
+        let journal = Journal::open(&self.journal_path(), seq)?;
+        Ok((state, journal))
+    }
+
+    /// Write a new snapshot of `state`, which must include every action in
+    /// `journal`, then clear the journal.
+    ///
+    /// The snapshot is written to a temporary file and renamed into place, so
+    /// a crash part way through leaves the previous snapshot intact. Since the
+    /// snapshot records the last sequence number it includes, a crash between
+    /// the rename and clearing the journal is also safe: those entries are
+    /// skipped on the next restore.
+    pub fn checkpoint(&self, state: &State, journal: &mut Journal) -> Result<(), PersistError> {
+        journal.flush()?;
+        snapshot::write(&self.snapshot_path(), state, journal.sequence())?;
+        sync_dir(&self.path)?;
+        journal.clear()
+    }
+
+    /// Where the last run that didn't finish got to, if it left a resume
+    /// point
+    pub fn resume_point(&self) -> Result<Option<ResumePoint>, PersistError> {
+        resume::read(&self.path.join(RESUME_FILE))
+    }
+
+    /// Record how far through an input a run is, replacing any earlier resume
+    /// point. It should only be set once the journal has been flushed past
+    /// the actions it covers, e.g. straight after a `checkpoint`.
+    pub fn set_resume_point(&self, point: &ResumePoint) -> Result<(), PersistError> {
+        resume::write(&self.path.join(RESUME_FILE), point)
+    }
+
+    /// Drop the resume point, once the run it was for has finished
+    pub fn clear_resume_point(&self) -> Result<(), PersistError> {
+        resume::remove(&self.path.join(RESUME_FILE))
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.path.join(SNAPSHOT_FILE)
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.path.join(JOURNAL_FILE)
+    }
+}
+
+/// Apply every action in the journal at `path` to `state`, in the order they
+/// were recorded, returning the last sequence number.
+///
+/// `state` should be set up the same way as the engine that wrote the
+/// journal (e.g. with the same dispute window). Unlike restoring a
+/// `StateDir`, the journal file is never modified.
+pub fn replay_journal<P: AsRef<Path>>(path: P, state: &mut State) -> Result<u64, PersistError> {
+    journal::replay(path.as_ref(), 0, state, false)
+}
+
+/// Load a state to look at, without changing anything on disk.
+///
+/// `path` is either a snapshot file, or a state directory, whose journal is
+/// replayed on top of its snapshot as `StateDir::restore` would (but without
+/// cutting off a torn final entry).
+pub fn read_state<P: AsRef<Path>>(path: P) -> Result<State, PersistError> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        let file = std::fs::File::open(path)?;
+        return snapshot::read_from(std::io::BufReader::new(file)).map(|(state, _)| state);
+    }
+    let (mut state, seq) = snapshot::read(&path.join(SNAPSHOT_FILE))?.unwrap_or_default();
+    journal::replay(&path.join(JOURNAL_FILE), seq, &mut state, false)?;
+    Ok(state)
+}
+
+/// Make sure a rename within `dir` has hit the disk
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("failed to (de)serialize state: {0}")]
+    Serde(#[from] serde_json::Error),
+
+    #[error("journal entry on line {line} is corrupt: {source}")]
+    CorruptJournal {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("expected replicated action {expected} but got {found}")]
+    OutOfSequence { expected: u64, found: u64 },
+
+    #[error("snapshot format version {0} is not supported")]
+    UnsupportedSnapshot(u32),
+
+    #[error(transparent)]
+    Store(#[from] crate::store::StoreError),
+}
+
+/// Serialize amounts as strings in persisted state.
+///
+/// The `serde-float` representation used for csv output goes through an `f64`,
+/// which is fine for display but isn't guaranteed to round trip a balance
+/// exactly.
+pub(crate) mod exact_amount {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::Amount;
+
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(amount)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// `exact_amount` for the values of a map. Keys are written as strings
+/// too, since maps in flattened structs can't have numbers as keys.
+pub(crate) mod exact_amounts {
+    use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::Amount;
+
+    pub fn serialize<K, S>(amounts: &BTreeMap<K, Amount>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Display,
+        S: Serializer,
+    {
+        serializer.collect_map(
+            amounts
+                .iter()
+                .map(|(key, amount)| (key.to_string(), amount.to_string())),
+        )
+    }
+
+    pub fn deserialize<'de, K, D>(deserializer: D) -> Result<BTreeMap<K, Amount>, D::Error>
+    where
+        K: FromStr + Ord,
+        K::Err: Display,
+        D: Deserializer<'de>,
+    {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, amount)| {
+                let key = key.parse().map_err(D::Error::custom)?;
+                Ok((key, amount.parse().map_err(D::Error::custom)?))
+            })
+            .collect()
+    }
+}
+
+/// `exact_amount` for an optional amount
+pub(crate) mod optional_amount {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::exact_amount;
+    use crate::Amount;
+
+    pub fn serialize<S: Serializer>(
+        amount: &Option<Amount>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match amount {
+            Some(amount) => exact_amount::serialize(amount, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Amount>, D::Error> {
+        #[derive(Deserialize)]
+        struct Exact(#[serde(with = "exact_amount")] Amount);
+
+        Ok(Option::<Exact>::deserialize(deserializer)?.map(|exact| exact.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{io::CsvSource, ClientId};
+
+    const FIRST: &str = "type,client,tx,amount
+deposit,1,1,10.25
+deposit,2,2,5.0
+withdrawal,1,3,0.25
+dispute,2,2,
+";
+
+    const SECOND: &str = "type,client,tx,amount
+resolve,2,2,
+deposit,1,1,99.0
+deposit,1,4,1.5
+";
+
+    fn apply(dir: &StateDir, input: &str) -> State {
+        let (mut state, mut journal) = dir.restore().unwrap();
+        for action in CsvSource::from_reader(input.as_bytes()) {
+            let action = action.unwrap();
+            journal.append(&action).unwrap();
+            let _ = state.update(action);
+        }
+        dir.checkpoint(&state, &mut journal).unwrap();
+        state
+    }
+
+    fn balances(state: &State, client: crate::RawClientId) -> (String, String) {
+        let account = state.account(ClientId(client)).unwrap();
+        (account.available.to_string(), account.held.to_string())
+    }
+
+    #[test]
+    fn test_state_carries_over_runs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = StateDir::open(tmp.path()).unwrap();
+
+        apply(&dir, FIRST);
+        let state = apply(&dir, SECOND);
+        assert_eq!(balances(&state, 1), ("11.5".into(), "0".into()));
+        assert_eq!(balances(&state, 2), ("5".into(), "0".into()));
+
+        let (restored, journal) = dir.restore().unwrap();
+        assert_eq!(journal.sequence(), 7);
+        assert_eq!(balances(&restored, 1), balances(&state, 1));
+        assert_eq!(balances(&restored, 2), balances(&state, 2));
+    }
+
+    #[test]
+    fn test_journal_replayed_without_checkpoint() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = StateDir::open(tmp.path()).unwrap();
+        apply(&dir, FIRST);
+
+        {
+            // A run that dies before checkpointing, part way through a write
+            let (_, mut journal) = dir.restore().unwrap();
+            for action in CsvSource::from_reader(SECOND.as_bytes()) {
+                journal.append(&action.unwrap()).unwrap();
+            }
+            journal.flush().unwrap();
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(dir.journal_path())
+                .unwrap();
+            file.write_all(br#"{"seq":8,"tx":5,"cli"#).unwrap();
+        }
+
+        let (state, journal) = dir.restore().unwrap();
+        assert_eq!(journal.sequence(), 7);
+        assert_eq!(balances(&state, 1), ("11.5".into(), "0".into()));
+        assert_eq!(balances(&state, 2), ("5".into(), "0".into()));
+
+        // The torn entry was dropped, so appending again is fine
+        let state = apply(&dir, "type,client,tx,amount\ndeposit,2,5,1.0\n");
+        assert_eq!(balances(&state, 2), ("6".into(), "0".into()));
+        assert_eq!(dir.restore().unwrap().1.sequence(), 8);
+    }
+
+    #[test]
+    fn test_forgotten_transactions_stay_claimed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = StateDir::open(tmp.path()).unwrap();
+        let mut state = apply(&dir, FIRST);
+
+        // Disputed transactions are kept
+        assert_eq!(state.forget_transactions(|_| true).unwrap(), 2);
+        let (_, mut journal) = dir.restore().unwrap();
+        dir.checkpoint(&state, &mut journal).unwrap();
+
+        let state = apply(
+            &dir,
+            "type,client,tx,amount
+deposit,1,1,10.25
+deposit,1,3,1.0
+",
+        );
+        assert_eq!(balances(&state, 1), ("10".into(), "0".into()));
+        assert_eq!(state.seen_transactions().len(), 3);
+    }
+}
@@ -0,0 +1,241 @@
+//! Warm standby replicas, kept up to date from a running primary over TCP
+//!
+//! A replica connecting to `Primary::serve` is sent a snapshot of the
+//! primary's state (as a single line, in the same format as `snapshot.json`),
+//! followed by every action the primary applies from then on (as journal
+//! lines). The snapshot and the start of the tail are taken under the state
+//! lock, so nothing is missed or applied twice.
+//!
+//! Each replica has a bounded queue on the primary. One that falls too far
+//! behind is disconnected rather than slowing the primary down, and has to
+//! reconnect for a new snapshot.
+
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::{
+    journal::{Entry, EntryRef},
+    snapshot, PersistError,
+};
+use crate::{Action, State};
+
+/// A serialized journal entry, shared between every replica's queue
+type Line = Arc<[u8]>;
+
+/// The primary's side of replication, given to
+/// `MultiThreadedEngine::with_replication`
+#[derive(Debug, Clone)]
+pub struct Primary {
+    followers: Arc<Mutex<Followers>>,
+
+    /// Entries queued for each replica before it's dropped
+    buffer: usize,
+}
+
+#[derive(Debug, Default)]
+struct Followers {
+    /// The sequence number of the last published action
+    seq: u64,
+    senders: Vec<SyncSender<Line>>,
+}
+
+impl Primary {
+    /// A primary that queues up to `buffer` actions for each replica
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            followers: Arc::default(),
+            buffer,
+        }
+    }
+
+    /// The sequence number of the last action sent to replicas
+    pub fn sequence(&self) -> u64 {
+        self.followers.lock().expect("poisoned!").seq
+    }
+
+    /// Send an applied action to every replica. Must be called while holding
+    /// the state's write lock, so replicas see actions in the order they were
+    /// applied.
+    pub(crate) fn publish(&self, action: &Action) -> Result<(), PersistError> {
+        let mut followers = self.followers.lock().expect("poisoned!");
+        followers.seq += 1;
+        if followers.senders.is_empty() {
+            return Ok(());
+        }
+
+        let mut line = serde_json::to_vec(&EntryRef {
+            seq: followers.seq,
+            action,
+        })?;
+        line.push(b'\n');
+        let line: Line = line.into();
+        // Replicas that are full (too far behind) or gone are dropped
+        followers
+            .senders
+            .retain(|sender| sender.try_send(Arc::clone(&line)).is_ok());
+        Ok(())
+    }
+
+    /// Accept replicas on `listener` from a background thread, each streamed
+    /// from its own thread
+    pub fn serve(&self, listener: TcpListener, state: Arc<RwLock<State>>) -> JoinHandle<()> {
+        let primary = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                // A failed connection only affects that replica
+                let Ok(stream) = stream else { continue };
+                let primary = primary.clone();
+                let state = Arc::clone(&state);
+                thread::spawn(move || {
+                    let _ = primary.stream_to(stream, &state);
+                });
+            }
+        })
+    }
+
+    fn subscribe(&self, state: &RwLock<State>) -> Result<(Vec<u8>, Receiver<Line>), PersistError> {
+        // Holding the read lock keeps out any writers (which publish with the
+        // write lock held) until the replica is subscribed
+        let state = state.read().expect("poisoned!");
+        let mut followers = self.followers.lock().expect("poisoned!");
+        let mut snapshot = Vec::new();
+        snapshot::write_to(&mut snapshot, &state, followers.seq)?;
+        snapshot.push(b'\n');
+
+        let (sender, receiver) = mpsc::sync_channel(self.buffer);
+        followers.senders.push(sender);
+        Ok((snapshot, receiver))
+    }
+
+    fn stream_to(&self, stream: TcpStream, state: &RwLock<State>) -> Result<(), PersistError> {
+        let (snapshot, receiver) = self.subscribe(state)?;
+        let mut writer = BufWriter::new(stream);
+        writer.write_all(&snapshot)?;
+        writer.flush()?;
+
+        // Ends when the replica is dropped for falling behind
+        while let Ok(line) = receiver.recv() {
+            writer.write_all(&line)?;
+            // Send whatever else has queued up in one go
+            while let Ok(line) = receiver.try_recv() {
+                writer.write_all(&line)?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A standby copy of a primary's state.
+///
+/// Policies like the dispute window aren't sent by the primary, so set them
+/// up on `state_mut` (the same way as the primary) before applying anything.
+/// To promote the replica, take its state with `into_state`.
+#[derive(Debug)]
+pub struct Replica {
+    reader: BufReader<TcpStream>,
+    state: State,
+    seq: u64,
+    line: String,
+}
+
+impl Replica {
+    /// Connect to a primary and load its snapshot
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, PersistError> {
+        let mut reader = BufReader::new(TcpStream::connect(addr)?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let (state, seq) = snapshot::read_from(line.as_bytes())?;
+        Ok(Self {
+            reader,
+            state,
+            seq,
+            line,
+        })
+    }
+
+    /// Wait for the next action from the primary and apply it, returning its
+    /// sequence number, or `None` once the primary has disconnected (e.g. it
+    /// shut down, or this replica fell too far behind).
+    pub fn apply_next(&mut self) -> Result<Option<u64>, PersistError> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 || !self.line.ends_with('\n') {
+            return Ok(None);
+        }
+        let entry: Entry = serde_json::from_str(&self.line)?;
+        if entry.seq != self.seq + 1 {
+            return Err(PersistError::OutOfSequence {
+                expected: self.seq + 1,
+                found: entry.seq,
+            });
+        }
+        // Errors are ignored, the same as they were on the primary
+        let _ = self.state.update(entry.action);
+        self.seq = entry.seq;
+        Ok(Some(self.seq))
+    }
+
+    /// The sequence number of the last action applied
+    pub fn sequence(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    pub fn into_state(self) -> State {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::CsvSource, ClientId, MultiThreadedEngine, SyncEngineExt};
+
+    fn process(engine: &mut MultiThreadedEngine, input: &str) {
+        let input = format!("type,client,tx,amount\n{}", input);
+        let actions = CsvSource::from_reader(input.as_bytes()).map(Result::unwrap);
+        engine.process_all(actions).unwrap();
+    }
+
+    #[test]
+    fn test_replica_catches_up() {
+        let primary = Primary::new(16);
+        let mut engine = MultiThreadedEngine::new().with_replication(primary.clone());
+        process(&mut engine, "deposit,1,1,10\ndeposit,2,2,5\n");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        primary.serve(listener, engine.state());
+
+        let mut replica = Replica::connect(addr).unwrap();
+        assert_eq!(replica.sequence(), 2);
+        assert_eq!(replica.state().accounts().len(), 2);
+
+        process(&mut engine, "withdrawal,1,3,4\ndispute,2,2,\n");
+        assert_eq!(replica.apply_next().unwrap(), Some(3));
+        assert_eq!(replica.apply_next().unwrap(), Some(4));
+
+        let state = engine.state();
+        let state = state.read().unwrap();
+        for client in [ClientId(1), ClientId(2)] {
+            assert_eq!(replica.state().account(client), state.account(client));
+        }
+    }
+}
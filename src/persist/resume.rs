@@ -0,0 +1,65 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{sync_dir, PersistError};
+use crate::io::InputFingerprint;
+
+/// How far a run over a csv input got, for carrying on from there after it
+/// was interrupted (see `StateDir::set_resume_point`)
+///
+/// `seq` is the journal's sequence number once the first `records` records
+/// of `input` were applied. Every action read after them was journalled
+/// before it was applied, so after restoring the directory, the first
+/// `journal.sequence() - seq` actions read past `records` are already in the
+/// state, and should be skipped rather than applied again. That holds
+/// however many checkpoints there were in between.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumePoint {
+    pub input: PathBuf,
+    /// To check `input` hasn't changed since
+    pub fingerprint: InputFingerprint,
+    /// Records of `input` read, after its header row
+    pub records: u64,
+    pub seq: u64,
+}
+
+impl ResumePoint {
+    /// How many actions read after `records` were applied before the run
+    /// stopped, given the sequence number of the restored journal
+    pub fn applied_since(&self, journal_seq: u64) -> u64 {
+        journal_seq.saturating_sub(self.seq)
+    }
+}
+
+pub(super) fn read(path: &Path) -> Result<Option<ResumePoint>, PersistError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+}
+
+/// Atomically replace the resume point at `path`
+pub(super) fn write(path: &Path, point: &ResumePoint) -> Result<(), PersistError> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, point)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(tmp, path)?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")))?;
+    Ok(())
+}
+
+pub(super) fn remove(path: &Path) -> Result<(), PersistError> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,129 @@
+//! Snapshots of a sharded state (see `ConcurrentEngine`), one file per shard
+//!
+//! The shards are written and read on as many threads as the machine can
+//! run, so checkpoints and restarts take about as long as the largest shard
+//! rather than the whole state. A manifest lists the shard files of the
+//! latest complete snapshot. Each snapshot's files are named for its
+//! generation, and the manifest is only replaced once they're all on disk,
+//! so a crash part way through leaves the previous snapshot to restore.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{snapshot, sync_dir, PersistError};
+use crate::State;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Bumped whenever the manifest layout changes incompatibly
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    generation: u64,
+    /// The shards' snapshot files, in shard order
+    shards: Vec<String>,
+}
+
+/// Snapshot every shard into `dir`, replacing the last snapshot there
+pub(crate) fn write(dir: &Path, shards: &[&State]) -> Result<(), PersistError> {
+    std::fs::create_dir_all(dir)?;
+    let generation = read_manifest(dir)?.map_or(0, |manifest| manifest.generation + 1);
+    let files: Vec<String> = (0..shards.len())
+        .map(|shard| format!("shard-{generation}-{shard}.json"))
+        .collect();
+    let jobs: Vec<_> = shards.iter().zip(&files).collect();
+    in_parallel(&jobs, |(state, file)| {
+        snapshot::write(&dir.join(file), state, 0)
+    })?;
+    // Make sure every shard is in place before the manifest points at them
+    sync_dir(dir)?;
+
+    let manifest = Manifest {
+        version: VERSION,
+        generation,
+        shards: files,
+    };
+    let path = dir.join(MANIFEST_FILE);
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, &manifest)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(tmp, path)?;
+    sync_dir(dir)?;
+
+    // Older generations are no longer needed. Failing to remove them only
+    // wastes space, so it isn't an error.
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("shard-") && !manifest.shards.iter().any(|file| *file == name) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Read every shard of the last snapshot in `dir`, in shard order, if there
+/// is one
+pub(crate) fn read(dir: &Path) -> Result<Option<Vec<State>>, PersistError> {
+    let Some(manifest) = read_manifest(dir)? else {
+        return Ok(None);
+    };
+    let paths: Vec<PathBuf> = manifest.shards.iter().map(|file| dir.join(file)).collect();
+    let shards = in_parallel(&paths, |path| {
+        let reader = BufReader::new(File::open(path)?);
+        snapshot::read_from(reader).map(|(state, _)| state)
+    })?;
+    Ok(Some(shards))
+}
+
+fn read_manifest(dir: &Path) -> Result<Option<Manifest>, PersistError> {
+    let file = match File::open(dir.join(MANIFEST_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let manifest: Manifest = serde_json::from_reader(BufReader::new(file))?;
+    if manifest.version != VERSION {
+        return Err(PersistError::UnsupportedSnapshot(manifest.version));
+    }
+    Ok(Some(manifest))
+}
+
+/// Run `job` on every item, split between a thread for each core, and
+/// collect the results in order
+fn in_parallel<T, R, F>(items: &[T], job: F) -> Result<Vec<R>, PersistError>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, PersistError> + Sync,
+{
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let chunk = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(chunk)
+            .map(|items| {
+                let job = &job;
+                scope.spawn(move || items.iter().map(job).collect::<Result<Vec<_>, _>>())
+            })
+            .collect();
+        let mut results = Vec::with_capacity(items.len());
+        for worker in workers {
+            results.extend(worker.join().expect("snapshot thread panicked")?);
+        }
+        Ok(results)
+    })
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::PersistError;
+use crate::{
+    audit::{AuditTrail, EvidenceLog},
+    policy::WithdrawalHistory,
+    seen::SeenTransactions,
+    Account, Action, ClientId, State, Transaction, TransactionId,
+};
+
+/// Bumped whenever the snapshot layout changes incompatibly
+const VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    seq: u64,
+    accounts: Vec<AccountEntry>,
+    transactions: Vec<Transaction>,
+    seen: &'a SeenTransactions,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    references: &'a HashMap<String, TransactionId>,
+    withdrawals: &'a WithdrawalHistory,
+    audit: &'a AuditTrail,
+    evidence: &'a EvidenceLog,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    prepared: Vec<&'a Action>,
+    sequence: u64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    forgotten: bool,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    version: u32,
+    seq: u64,
+    accounts: Vec<AccountEntry>,
+    transactions: Vec<Transaction>,
+    #[serde(default)]
+    seen: Option<SeenTransactions>,
+    #[serde(default)]
+    references: HashMap<String, TransactionId>,
+    #[serde(default)]
+    withdrawals: WithdrawalHistory,
+    #[serde(default)]
+    audit: AuditTrail,
+    #[serde(default)]
+    evidence: EvidenceLog,
+    #[serde(default)]
+    prepared: Vec<Action>,
+    #[serde(default)]
+    sequence: u64,
+    #[serde(default)]
+    forgotten: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccountEntry {
+    client: ClientId,
+    #[serde(flatten)]
+    account: Account,
+}
+
+/// Atomically replace the snapshot at `path`
+pub(crate) fn write(path: &Path, state: &State, seq: u64) -> Result<(), PersistError> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    write_to(&mut writer, state, seq)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Write a snapshot as a single line of JSON (without the newline)
+pub(crate) fn write_to<W: Write>(writer: W, state: &State, seq: u64) -> Result<(), PersistError> {
+    let snapshot = SnapshotRef {
+        version: VERSION,
+        seq,
+        accounts: state
+            .raw_accounts()
+            .map(|entry| entry.map(|(client, account)| AccountEntry { client, account }))
+            .collect::<Result<_, _>>()?,
+        transactions: state
+            .raw_transactions()
+            .map(|entry| entry.map(|(_, transaction)| transaction))
+            .collect::<Result<_, _>>()?,
+        seen: state.seen_transactions(),
+        references: state.references(),
+        withdrawals: state.withdrawal_history(),
+        audit: state.audit(),
+        evidence: state.evidence(),
+        prepared: state.prepared_actions().collect(),
+        sequence: state.sequence(),
+        forgotten: state.has_forgotten(),
+    };
+    serde_json::to_writer(writer, &snapshot)?;
+    Ok(())
+}
+
+/// Read the snapshot at `path`, if there is one
+pub(crate) fn read(path: &Path) -> Result<Option<(State, u64)>, PersistError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    read_from(BufReader::new(file)).map(Some)
+}
+
+pub(crate) fn read_from<R: Read>(reader: R) -> Result<(State, u64), PersistError> {
+    let snapshot: Snapshot = serde_json::from_reader(reader)?;
+    if snapshot.version != VERSION {
+        return Err(PersistError::UnsupportedSnapshot(snapshot.version));
+    }
+
+    let accounts: HashMap<_, _> = snapshot
+        .accounts
+        .into_iter()
+        .map(|entry| (entry.client, entry.account))
+        .collect();
+    let transactions: HashMap<_, _> = snapshot
+        .transactions
+        .into_iter()
+        .map(|transaction| (transaction.id, transaction))
+        .collect();
+
+    let mut state = State::from_parts(
+        accounts,
+        transactions,
+        snapshot.seen,
+        snapshot.references,
+        snapshot.withdrawals,
+        snapshot.audit,
+        snapshot.evidence,
+    );
+    state.restore_prepared(snapshot.prepared);
+    state.set_sequence(snapshot.sequence);
+    state.set_forgotten(snapshot.forgotten);
+    Ok((state, snapshot.seq))
+}
@@ -0,0 +1,219 @@
+//! Configurable rules applied on top of the basic account operations
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{persist::exact_amount, state::UpdateError, Amount, ClientId, Timestamp, Transaction};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Rejects disputes placed too long after the transaction they dispute.
+///
+/// The window can only be enforced when both the transaction and the dispute
+/// were given a timestamp, so disputes without one (or against a transaction
+/// without one) are always let through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeWindow {
+    max_age: u64,
+}
+
+impl DisputeWindow {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age: max_age.as_secs(),
+        }
+    }
+
+    /// A window of a whole number of days (e.g. the usual 60 day chargeback
+    /// window)
+    pub fn days(days: u64) -> Self {
+        Self {
+            max_age: days * SECONDS_PER_DAY,
+        }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age)
+    }
+
+    /// Check that a dispute at `at` is still within the window for
+    /// `transaction`
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        at: Option<Timestamp>,
+    ) -> Result<(), UpdateError> {
+        let (Some(made), Some(at)) = (transaction.timestamp, at) else {
+            return Ok(());
+        };
+
+        // A dispute timestamped before the transaction is just clock skew
+        let age = at.as_secs().saturating_sub(made.as_secs());
+        if age > self.max_age {
+            return Err(UpdateError::DisputeWindowExpired {
+                transaction: transaction.id,
+                age_days: age / SECONDS_PER_DAY,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// What to do about a dispute of a deposit whose funds have already been
+/// withdrawn, so that holding (and charging back) the whole amount would take
+/// the account below zero.
+///
+/// Without a policy, the dispute can't hold the funds and the deposit is
+/// recorded as failed with insufficient funds, which hides the shortfall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargebackPolicy {
+    /// Reject the dispute with `UpdateError::DisputeExceedsAvailable`,
+    /// leaving the deposit as it was
+    Strict,
+
+    /// Hold the whole amount anyway, taking the available balance negative
+    /// (and the total too, once it's charged back), so the shortfall shows up
+    /// in the account and in `State::negative_balances`
+    AllowNegative,
+}
+
+/// Caps on how much money can move, applied to every client. Any limit left as
+/// `None` isn't enforced.
+///
+/// Deposits and withdrawals over a limit are rejected without touching the
+/// account, but their transaction id is still used up.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LimitsPolicy {
+    /// The largest amount for any single deposit or withdrawal
+    pub max_transaction: Option<Amount>,
+
+    /// The largest amount for a single withdrawal
+    pub max_withdrawal: Option<Amount>,
+
+    /// The most a client can withdraw in any rolling 24 hour period
+    pub max_daily_withdrawal: Option<Amount>,
+}
+
+/// Which limit an action ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Transaction,
+    Withdrawal,
+    DailyWithdrawal,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Transaction => "transaction",
+            Self::Withdrawal => "withdrawal",
+            Self::DailyWithdrawal => "daily withdrawal",
+        })
+    }
+}
+
+impl LimitsPolicy {
+    pub(crate) fn check_deposit(&self, amount: Amount) -> Result<(), UpdateError> {
+        check(Limit::Transaction, self.max_transaction, amount)
+    }
+
+    /// `withdrawn_today` is how much the client has already withdrawn in the
+    /// last 24 hours
+    pub(crate) fn check_withdrawal(
+        &self,
+        amount: Amount,
+        withdrawn_today: Amount,
+    ) -> Result<(), UpdateError> {
+        check(Limit::Transaction, self.max_transaction, amount)?;
+        check(Limit::Withdrawal, self.max_withdrawal, amount)?;
+        check(
+            Limit::DailyWithdrawal,
+            self.max_daily_withdrawal,
+            withdrawn_today + amount,
+        )
+    }
+}
+
+fn check(limit: Limit, max: Option<Amount>, amount: Amount) -> Result<(), UpdateError> {
+    match max {
+        Some(max) if amount > max => Err(UpdateError::LimitExceeded { limit, amount, max }),
+        _ => Ok(()),
+    }
+}
+
+/// Each client's withdrawals over the last day, for the rolling daily limit
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct WithdrawalHistory(HashMap<ClientId, Vec<Withdrawal>>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Withdrawal {
+    at: Timestamp,
+    #[serde(with = "exact_amount")]
+    amount: Amount,
+}
+
+impl WithdrawalHistory {
+    /// The total withdrawn by `client` in the 24 hours up to `at`
+    pub(crate) fn total_in_day_before(&self, client: ClientId, at: Timestamp) -> Amount {
+        self.0
+            .get(&client)
+            .into_iter()
+            .flatten()
+            .filter(|w| w.at.as_secs() + SECONDS_PER_DAY > at.as_secs())
+            .map(|w| w.amount)
+            .sum()
+    }
+
+    /// Record a withdrawal, dropping any that are too old to matter
+    pub(crate) fn record(&mut self, client: ClientId, at: Timestamp, amount: Amount) {
+        let history = self.0.entry(client).or_default();
+        history.retain(|w| w.at.as_secs() + SECONDS_PER_DAY > at.as_secs());
+        history.push(Withdrawal { at, amount });
+    }
+
+    /// Take back a withdrawal recorded with `record` that didn't go ahead
+    pub(crate) fn cancel(&mut self, client: ClientId, at: Timestamp, amount: Amount) {
+        if let Some(history) = self.0.get_mut(&client) {
+            if let Some(index) = history
+                .iter()
+                .position(|w| w.at == at && w.amount == amount)
+            {
+                history.remove(index);
+            }
+        }
+    }
+
+    /// Correct the amount of a withdrawal recorded with `record`, if it's
+    /// still in the history
+    pub(crate) fn restate(&mut self, client: ClientId, at: Timestamp, from: Amount, to: Amount) {
+        if let Some(withdrawal) = self
+            .0
+            .get_mut(&client)
+            .and_then(|history| history.iter_mut().find(|w| w.at == at && w.amount == from))
+        {
+            withdrawal.amount = to;
+        }
+    }
+
+    /// A copy of one client's history, to `restore` later
+    pub(crate) fn save(&self, client: ClientId) -> Option<Vec<Withdrawal>> {
+        self.0.get(&client).cloned()
+    }
+
+    pub(crate) fn restore(&mut self, client: ClientId, saved: Option<Vec<Withdrawal>>) {
+        match saved {
+            Some(history) => self.0.insert(client, history),
+            None => self.0.remove(&client),
+        };
+    }
+
+    pub(crate) fn forget(&mut self, client: ClientId) {
+        self.0.remove(&client);
+    }
+
+    pub(crate) fn extend(&mut self, other: WithdrawalHistory) {
+        self.0.extend(other.0);
+    }
+}
@@ -0,0 +1,527 @@
+//! Loading pending actions from, and writing account balances back to,
+//! PostgreSQL tables
+//!
+//! `PostgresBridge::run` takes actions that haven't been processed yet from
+//! an actions table, a batch at a time, applies them to an engine, upserts
+//! the accounts they touched into an accounts table, and marks them
+//! processed. Each batch is one database transaction, and the engine is
+//! rolled back to where the batch started if it can't be committed, so the
+//! engine and the database never disagree about which actions were applied.
+//! Rows are locked with `FOR UPDATE SKIP LOCKED`, so runs that overlap don't
+//! take the same actions.
+//!
+//! The tables look like this (see `PostgresBridge::create_tables`), and
+//! anything else can add rows to the actions table with `processed_at` left
+//! null:
+//!
+//! ```sql
+//! CREATE TABLE engine_actions (
+//!     seq BIGSERIAL PRIMARY KEY,       -- the order actions are applied in
+//!     type TEXT NOT NULL,              -- deposit, withdrawal, dispute, ...
+//!     client BIGINT NOT NULL,
+//!     tx BIGINT NOT NULL,
+//!     amount NUMERIC,
+//!     ts BIGINT,                       -- seconds since the Unix epoch
+//!     reverses BIGINT,
+//!     ref TEXT,
+//!     processed_at TIMESTAMPTZ,        -- set once applied (or rejected)
+//!     error TEXT                       -- why it was rejected, if it was
+//! );
+//! CREATE TABLE engine_accounts (
+//!     client BIGINT PRIMARY KEY,
+//!     available NUMERIC NOT NULL,
+//!     held NUMERIC NOT NULL,
+//!     total NUMERIC NOT NULL,
+//!     locked BOOLEAN NOT NULL,
+//!     status TEXT NOT NULL,
+//!     updated_at TIMESTAMPTZ NOT NULL,
+//!     version BIGINT NOT NULL          -- see `Account::version`
+//! );
+//! ```
+//!
+//! Amounts go to and from the database as text, so they're exact whichever
+//! `Amount` the crate is built with.
+//!
+//! Tools that change accounts while the bridge is running (freezing one for
+//! review, say) should go through `PostgresBridge::change_status`, giving
+//! the `version` they read from the accounts table. If the engine wrote the
+//! account since, the change is refused with `StatusError::VersionMismatch`
+//! rather than made over a balance the tool never saw, and two tools can't
+//! overwrite each other's changes.
+
+use std::collections::BTreeSet;
+
+use postgres::{Client, GenericClient, Row, ToStatement};
+
+use crate::{
+    engine::withdrawal_outcome, io::ConversionError, store::StoreError, Account, AccountData,
+    Action, ActionKind, ClientId, Money, RawClientId, SingleThreadedEngine, StatusError, Timestamp,
+    TransactionId, UpdateError,
+};
+
+/// Moves actions from an actions table through an engine, and the resulting
+/// accounts into an accounts table
+#[derive(Debug, Clone)]
+pub struct PostgresBridge {
+    actions: String,
+    accounts: String,
+    batch_size: usize,
+}
+
+/// What one or more batches did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchReport {
+    /// Actions taken from the table
+    pub actions: usize,
+    pub applied: usize,
+    /// Actions the engine rejected, or rows that weren't valid actions. Both
+    /// are marked processed with the reason in `error`, so they aren't tried
+    /// again.
+    pub rejected: usize,
+    /// Account rows written
+    pub accounts: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PostgresError {
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
+
+    /// The engine's storage failed, and the batch was rolled back
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    /// An account change was refused (e.g. the account was at another
+    /// version), and nothing was written
+    #[error(transparent)]
+    Status(#[from] StatusError),
+
+    #[error("'{0}' isn't a valid table name")]
+    InvalidTable(String),
+}
+
+impl Default for PostgresBridge {
+    fn default() -> Self {
+        Self {
+            actions: "engine_actions".into(),
+            accounts: "engine_accounts".into(),
+            batch_size: 1000,
+        }
+    }
+}
+
+impl PostgresBridge {
+    /// Use the `engine_actions` and `engine_accounts` tables, 1000 actions a
+    /// batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use other tables, which can be schema qualified (e.g.
+    /// `ledger.actions`)
+    pub fn with_tables(actions: &str, accounts: &str) -> Result<Self, PostgresError> {
+        Ok(Self {
+            actions: table_name(actions)?,
+            accounts: table_name(accounts)?,
+            ..Self::default()
+        })
+    }
+
+    /// Take up to `size` actions (at least one) in each database transaction
+    pub fn with_batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    /// Create the tables if they don't already exist (adding the `version`
+    /// column to an accounts table created before it)
+    pub fn create_tables(&self, client: &mut Client) -> Result<(), PostgresError> {
+        client.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {actions} (
+                seq BIGSERIAL PRIMARY KEY,
+                type TEXT NOT NULL,
+                client BIGINT NOT NULL,
+                tx BIGINT NOT NULL,
+                amount NUMERIC,
+                ts BIGINT,
+                reverses BIGINT,
+                ref TEXT,
+                processed_at TIMESTAMPTZ,
+                error TEXT
+            );
+            CREATE TABLE IF NOT EXISTS {accounts} (
+                client BIGINT PRIMARY KEY,
+                available NUMERIC NOT NULL,
+                held NUMERIC NOT NULL,
+                total NUMERIC NOT NULL,
+                locked BOOLEAN NOT NULL,
+                status TEXT NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL,
+                version BIGINT NOT NULL DEFAULT 0
+            );
+            ALTER TABLE {accounts} ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 0;",
+            actions = self.actions,
+            accounts = self.accounts,
+        ))?;
+        Ok(())
+    }
+
+    /// Process batches until there are no pending actions left
+    pub fn run(
+        &self,
+        client: &mut Client,
+        engine: &mut SingleThreadedEngine,
+    ) -> Result<BatchReport, PostgresError> {
+        let mut total = BatchReport::default();
+        loop {
+            let batch = self.run_batch(client, engine)?;
+            if batch.actions == 0 {
+                return Ok(total);
+            }
+            total.actions += batch.actions;
+            total.applied += batch.applied;
+            total.rejected += batch.rejected;
+            total.accounts += batch.accounts;
+        }
+    }
+
+    /// Process one batch of pending actions, oldest first, in a single
+    /// database transaction. If anything fails, the engine is rolled back
+    /// and the actions are left pending.
+    pub fn run_batch(
+        &self,
+        client: &mut Client,
+        engine: &mut SingleThreadedEngine,
+    ) -> Result<BatchReport, PostgresError> {
+        let savepoint = engine.savepoint();
+        match self.apply_batch(client, engine) {
+            Ok(report) => {
+                engine.release(savepoint);
+                Ok(report)
+            }
+            Err(e) => {
+                engine.rollback_to(savepoint)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_batch(
+        &self,
+        client: &mut Client,
+        engine: &mut SingleThreadedEngine,
+    ) -> Result<BatchReport, PostgresError> {
+        let mut transaction = client.transaction()?;
+        let rows = transaction.query(
+            &format!(
+                "SELECT seq, type, client, tx, amount::TEXT, ts, reverses, ref FROM {}
+                WHERE processed_at IS NULL ORDER BY seq LIMIT $1 FOR UPDATE SKIP LOCKED",
+                self.actions
+            ),
+            &[&(self.batch_size as i64)],
+        )?;
+        let mark = transaction.prepare(&format!(
+            "UPDATE {} SET processed_at = now(), error = $2 WHERE seq = $1",
+            self.actions
+        ))?;
+
+        let mut report = BatchReport {
+            actions: rows.len(),
+            ..BatchReport::default()
+        };
+        let mut touched = BTreeSet::new();
+        for row in &rows {
+            let seq: i64 = row.get(0);
+            let result = match decode(row) {
+                Ok(action) => {
+                    let client = action.client_id;
+                    let withdrawal = (action.kind == ActionKind::Withdrawal)
+                        .then(|| (action.transaction_id, action.reference.clone()));
+                    let result = match engine.try_process(action) {
+                        Err(UpdateError::Store(e)) => return Err(e.into()),
+                        // A withdrawal without the funds is recorded as
+                        // failed rather than rejected, but isn't applied
+                        Ok(()) => match withdrawal {
+                            Some((id, reference)) => {
+                                withdrawal_outcome(engine.state(), id, reference.as_deref())
+                            }
+                            None => Ok(()),
+                        },
+                        result => result,
+                    };
+                    touched.insert(client);
+                    result.map_err(|e| e.to_string())
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            let error = result.err();
+            match error {
+                None => report.applied += 1,
+                Some(_) => report.rejected += 1,
+            }
+            transaction.execute(&mark, &[&seq, &error])?;
+        }
+
+        let upsert = transaction.prepare(&self.upsert_query())?;
+        for client in touched {
+            // Rejected actions can name clients without accounts
+            let Some(account) = engine.state().account(client) else {
+                continue;
+            };
+            upsert_account(&mut transaction, &upsert, &account)?;
+            report.accounts += 1;
+        }
+
+        transaction.commit()?;
+        Ok(report)
+    }
+
+    /// Change a client's status with one of the transition methods on
+    /// `Account` (as `State::change_status_at`), but only if the account is
+    /// still at `version`, and write its row to the accounts table. Returns
+    /// the account as it is now.
+    ///
+    /// If the row can't be written, the engine is rolled back, so the two
+    /// still agree.
+    pub fn change_status<F>(
+        &self,
+        client: &mut Client,
+        engine: &mut SingleThreadedEngine,
+        account: ClientId,
+        version: u64,
+        transition: F,
+    ) -> Result<AccountData, PostgresError>
+    where
+        F: FnOnce(&mut Account) -> Result<(), StatusError>,
+    {
+        let savepoint = engine.savepoint();
+        let result = engine
+            .change_status_at(account, version, transition)
+            .map_err(PostgresError::from)
+            .and_then(|()| {
+                let data = engine
+                    .state()
+                    .try_account(account)?
+                    .ok_or(StatusError::AccountMissing(account))?;
+                upsert_account(client, &self.upsert_query(), &data)?;
+                Ok(data)
+            });
+        match result {
+            Ok(data) => {
+                engine.release(savepoint);
+                Ok(data)
+            }
+            Err(e) => {
+                engine.rollback_to(savepoint)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Insert or replace an account's row, see `upsert_account`
+    fn upsert_query(&self) -> String {
+        format!(
+            "INSERT INTO {} (client, available, held, total, locked, status, updated_at, version)
+            VALUES ($1, $2::TEXT::NUMERIC, $3::TEXT::NUMERIC, $4::TEXT::NUMERIC, $5, $6, now(), $7)
+            ON CONFLICT (client) DO UPDATE SET
+                available = EXCLUDED.available,
+                held = EXCLUDED.held,
+                total = EXCLUDED.total,
+                locked = EXCLUDED.locked,
+                status = EXCLUDED.status,
+                updated_at = EXCLUDED.updated_at,
+                version = EXCLUDED.version",
+            self.accounts
+        )
+    }
+}
+
+fn upsert_account<C, T>(
+    client: &mut C,
+    upsert: &T,
+    account: &AccountData,
+) -> Result<u64, postgres::Error>
+where
+    C: GenericClient,
+    T: ToStatement + ?Sized,
+{
+    client.execute(
+        upsert,
+        &[
+            &i64::from(account.client.0),
+            &account.available.to_string(),
+            &account.held.to_string(),
+            &account.total.to_string(),
+            &account.locked,
+            &account.status.name(),
+            &(account.version as i64),
+        ],
+    )
+}
+
+/// Turn a row of the actions table into an action
+fn decode(row: &Row) -> Result<Action, ConversionError> {
+    let kind: String = row.get(1);
+    let kind = match kind.as_str() {
+        "deposit" => ActionKind::Deposit,
+        "withdrawal" => ActionKind::Withdrawal,
+        "dispute" => ActionKind::Dispute,
+        "resolve" => ActionKind::Resolve,
+        "chargeback" => ActionKind::Chargeback,
+        "reversal" => ActionKind::Reversal,
+        _ => return Err(ConversionError::UnknownActionKind(kind)),
+    };
+    let amount = row
+        .get::<_, Option<String>>(4)
+        .map(|amount| {
+            amount
+                .parse::<Money>()
+                .map(Money::get)
+                .map_err(|_| ConversionError::InvalidAmount(amount))
+        })
+        .transpose()?;
+    let timestamp = row
+        .get::<_, Option<i64>>(5)
+        .map(|ts| range("ts", ts).map(Timestamp::from_secs))
+        .transpose()?;
+    let reverses = row
+        .get::<_, Option<i64>>(6)
+        .map(|id| range("reverses", id).map(TransactionId))
+        .transpose()?;
+    Ok(Action {
+        transaction_id: Some(TransactionId(range("tx", row.get(3))?)),
+        client_id: ClientId(range::<RawClientId>("client", row.get(2))?),
+        kind,
+        amount,
+        timestamp,
+        reverses,
+        reference: row.get(7),
+        evidence: None,
+        category: None,
+        currency: None,
+        meta: None,
+    })
+}
+
+fn range<T: TryFrom<i64>>(field: &'static str, value: i64) -> Result<T, ConversionError> {
+    T::try_from(value).map_err(|_| ConversionError::OutOfRange {
+        field,
+        value: value.into(),
+    })
+}
+
+/// Check a table name can go into a query as it is
+fn table_name(name: &str) -> Result<String, PostgresError> {
+    let valid = name.split('.').all(|part| {
+        part.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+    if valid {
+        Ok(name.into())
+    } else {
+        Err(PostgresError::InvalidTable(name.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_names() {
+        assert!(PostgresBridge::with_tables("actions", "ledger.accounts").is_ok());
+        assert!(matches!(
+            PostgresBridge::with_tables("actions; DROP TABLE x", "accounts"),
+            Err(PostgresError::InvalidTable(_))
+        ));
+        assert!(PostgresBridge::with_tables("1actions", "accounts").is_err());
+    }
+
+    /// Runs against a real database when `TRANSACTION_ENGINE_POSTGRES` is set
+    /// to a connection string, e.g. `host=localhost user=postgres`
+    #[test]
+    fn test_run() {
+        let Ok(config) = std::env::var("TRANSACTION_ENGINE_POSTGRES") else {
+            return;
+        };
+        let mut client = Client::connect(&config, postgres::NoTls).unwrap();
+        let bridge = PostgresBridge::with_tables("test_actions", "test_accounts")
+            .unwrap()
+            .with_batch_size(2);
+        client
+            .batch_execute("DROP TABLE IF EXISTS test_actions, test_accounts")
+            .unwrap();
+        bridge.create_tables(&mut client).unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO test_actions (type, client, tx, amount) VALUES
+                    ('deposit', 1, 1, 10.5),
+                    ('withdrawal', 1, 2, 20),
+                    ('deposit', 2, 3, 4),
+                    ('refund', 2, 4, 1),
+                    ('dispute', 2, 3, NULL)",
+            )
+            .unwrap();
+
+        let mut engine = SingleThreadedEngine::new();
+        let report = bridge.run(&mut client, &mut engine).unwrap();
+        assert_eq!(
+            report,
+            BatchReport {
+                actions: 5,
+                applied: 3,
+                rejected: 2,
+                accounts: 3,
+            }
+        );
+        let held: String = client
+            .query_one("SELECT held::TEXT FROM test_accounts WHERE client = 2", &[])
+            .unwrap()
+            .get(0);
+        assert_eq!(
+            held.parse::<crate::Amount>().unwrap(),
+            crate::Amount::from(4u32)
+        );
+        let pending: i64 = client
+            .query_one(
+                "SELECT COUNT(*) FROM test_actions WHERE processed_at IS NULL",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(pending, 0);
+        // Nothing left, so another run does nothing
+        assert_eq!(bridge.run(&mut client, &mut engine).unwrap().actions, 0);
+
+        // Status changes need the version the row was read at
+        let version: i64 = client
+            .query_one("SELECT version FROM test_accounts WHERE client = 2", &[])
+            .unwrap()
+            .get(0);
+        let freeze = |account: &mut Account| account.freeze(crate::FreezeReason::Chargeback);
+        assert!(matches!(
+            bridge.change_status(&mut client, &mut engine, ClientId(2), 1, freeze),
+            Err(PostgresError::Status(StatusError::VersionMismatch { .. }))
+        ));
+        let account = bridge
+            .change_status(
+                &mut client,
+                &mut engine,
+                ClientId(2),
+                version as u64,
+                freeze,
+            )
+            .unwrap();
+        assert!(account.locked);
+        let row = client
+            .query_one(
+                "SELECT locked, version FROM test_accounts WHERE client = 2",
+                &[],
+            )
+            .unwrap();
+        assert!(row.get::<_, bool>(0));
+        assert_eq!(row.get::<_, i64>(1), version + 1);
+    }
+}
@@ -0,0 +1,200 @@
+//! Progress reporting for long batch runs
+
+use std::time::{Duration, Instant};
+
+/// Records between reports, unless set with `ProgressTracker::every_records`
+const DEFAULT_EVERY_RECORDS: u64 = 100_000;
+
+/// Time between reports, unless set with `ProgressTracker::every`
+const DEFAULT_EVERY: Duration = Duration::from_secs(5);
+
+/// How far a run has got, as passed to a `ProgressReporter`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Records read so far, including rejected ones
+    pub records: u64,
+    /// Records that couldn't be applied
+    pub rejected: u64,
+    pub elapsed: Duration,
+    /// How much of the input has been read (from 0 to 1), if its size is
+    /// known
+    pub fraction_done: Option<f64>,
+}
+
+impl Progress {
+    /// Records per second over the whole run so far
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.records as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// An estimate of the time left, assuming the rest of the input goes at
+    /// the same rate
+    pub fn remaining(&self) -> Option<Duration> {
+        let done = self.fraction_done.filter(|done| *done > 0.0)?;
+        let total = self.elapsed.as_secs_f64() / done.min(1.0);
+        Some(Duration::from_secs_f64(total - self.elapsed.as_secs_f64()))
+    }
+}
+
+/// Receives progress reports. Implemented for closures taking a `&Progress`.
+pub trait ProgressReporter {
+    fn report(&mut self, progress: &Progress);
+}
+
+impl<F: FnMut(&Progress)> ProgressReporter for F {
+    fn report(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Counts records as they're processed, reporting every so many records or
+/// so much time, whichever comes first
+#[derive(Debug)]
+pub struct ProgressTracker<R> {
+    reporter: R,
+    every_records: u64,
+    every: Duration,
+
+    started: Instant,
+    last_report: Instant,
+    records_at_last_report: u64,
+
+    records: u64,
+    rejected: u64,
+    total_records: Option<u64>,
+    bytes_read: u64,
+    total_bytes: Option<u64>,
+}
+
+impl<R: ProgressReporter> ProgressTracker<R> {
+    pub fn new(reporter: R) -> Self {
+        let now = Instant::now();
+        Self {
+            reporter,
+            every_records: DEFAULT_EVERY_RECORDS,
+            every: DEFAULT_EVERY,
+            started: now,
+            last_report: now,
+            records_at_last_report: 0,
+            records: 0,
+            rejected: 0,
+            total_records: None,
+            bytes_read: 0,
+            total_bytes: None,
+        }
+    }
+
+    /// Report after this many records
+    pub fn every_records(mut self, records: u64) -> Self {
+        self.every_records = records.max(1);
+        self
+    }
+
+    /// Report after this much time (checked as records come in)
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.every = interval;
+        self
+    }
+
+    /// The number of records expected, for estimating how long is left
+    pub fn with_total_records(mut self, records: u64) -> Self {
+        self.total_records = Some(records);
+        self
+    }
+
+    /// The size of the input in bytes, for estimating how long is left. Keep
+    /// the position up to date with `set_bytes_read`.
+    pub fn with_total_bytes(mut self, bytes: u64) -> Self {
+        self.total_bytes = Some(bytes);
+        self
+    }
+
+    pub fn set_bytes_read(&mut self, bytes: u64) {
+        self.bytes_read = bytes;
+    }
+
+    /// Count a processed record, reporting if it's time to
+    pub fn record(&mut self, rejected: bool) {
+        self.records += 1;
+        if rejected {
+            self.rejected += 1;
+        }
+        if self.records - self.records_at_last_report >= self.every_records
+            || self.last_report.elapsed() >= self.every
+        {
+            self.report();
+        }
+    }
+
+    /// Send a report now
+    pub fn report(&mut self) {
+        let progress = self.progress();
+        self.reporter.report(&progress);
+        self.last_report = Instant::now();
+        self.records_at_last_report = self.records;
+    }
+
+    /// Send a final report, returning it
+    pub fn finish(mut self) -> Progress {
+        self.report();
+        self.progress()
+    }
+
+    pub fn progress(&self) -> Progress {
+        let fraction_done = match (self.total_bytes, self.total_records) {
+            (Some(total), _) if total > 0 => Some(self.bytes_read as f64 / total as f64),
+            (_, Some(total)) if total > 0 => Some(self.records as f64 / total as f64),
+            _ => None,
+        };
+        Progress {
+            records: self.records,
+            rejected: self.rejected,
+            elapsed: self.started.elapsed(),
+            fraction_done: fraction_done.map(|done| done.min(1.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_every_n_records() {
+        let mut reports = Vec::new();
+        let mut tracker = ProgressTracker::new(|progress: &Progress| reports.push(*progress))
+            .every_records(2)
+            .every(Duration::from_secs(3600))
+            .with_total_records(5);
+        for i in 0..5 {
+            tracker.record(i == 3);
+        }
+        let last = tracker.finish();
+
+        assert_eq!(last.records, 5);
+        assert_eq!(last.rejected, 1);
+        assert_eq!(last.fraction_done, Some(1.0));
+        assert_eq!(
+            reports.iter().map(|p| p.records).collect::<Vec<_>>(),
+            [2, 4, 5]
+        );
+        assert_eq!(reports[0].fraction_done, Some(0.4));
+    }
+
+    #[test]
+    fn test_remaining_estimate() {
+        let progress = Progress {
+            records: 100,
+            rejected: 0,
+            elapsed: Duration::from_secs(10),
+            fraction_done: Some(0.25),
+        };
+        assert_eq!(progress.throughput(), 10.0);
+        assert_eq!(progress.remaining(), Some(Duration::from_secs(30)));
+    }
+}
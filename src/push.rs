@@ -0,0 +1,253 @@
+//! Pushing account changes to subscribers as actions are applied
+//!
+//! An `AccountFeed` is an observer (see `events`) that broadcasts every
+//! account write, and every dispute event (including freezes and charge
+//! backs locking an account), to any number of `Subscription`s. Dashboards
+//! that poll for balances miss whatever happened in between polls, like funds
+//! held for a dispute that resolved a moment later; a subscriber sees each
+//! step.
+//!
+//! Like `webhook`, it doesn't run a server of its own. A streaming endpoint
+//! in whatever HTTP framework the service already uses subscribes, and writes
+//! each `Update` out as a Server-Sent Events frame with `Update::to_sse`, or
+//! as a WebSocket text message with `Update::to_json`:
+//!
+//! ```text
+//! event: account
+//! data: {"type":"account","client":1,"available":"7.5",...}
+//! ```
+//!
+//! Updates are kept for subscribers that fall behind, up to the feed's
+//! capacity. Past that, a subscriber skips ahead and is sent
+//! `Update::Lagged` with how many it missed, and should fetch the balances it
+//! shows again rather than trust them.
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    events::{DisputeEvent, EventObserver},
+    AccountData, ClientId,
+};
+
+/// Broadcasts account writes and dispute events to subscribers. Give a clone
+/// to the state (with `State::set_observer` or `EngineBuilder::observer`),
+/// and keep one to subscribe with.
+#[derive(Debug, Clone)]
+pub struct AccountFeed {
+    sender: broadcast::Sender<Update>,
+}
+
+/// One message for subscribers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    /// An account as it was just written
+    Account(AccountData),
+    /// A step of a dispute, or an account being frozen or unfrozen
+    Event(DisputeEvent),
+    /// The subscriber fell behind, and `missed` updates were skipped
+    Lagged { missed: u64 },
+}
+
+/// A subscriber's end of an `AccountFeed`
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: broadcast::Receiver<Update>,
+    client: Option<ClientId>,
+}
+
+impl AccountFeed {
+    /// A feed that keeps up to `capacity` updates for subscribers that are
+    /// behind
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    /// Receive every update from now on
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+            client: None,
+        }
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    fn send(&self, update: impl FnOnce() -> Update) {
+        // Nothing is built for a feed nobody is listening to
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(update());
+        }
+    }
+}
+
+impl EventObserver for AccountFeed {
+    fn observe(&mut self, event: &DisputeEvent) {
+        self.send(|| Update::Event(event.clone()));
+    }
+
+    fn account_changed(&mut self, account: &AccountData) {
+        self.send(|| Update::Account(account.clone()));
+    }
+}
+
+impl Subscription {
+    /// Only pass on updates for one client (lag notices are still sent)
+    pub fn only(mut self, client: ClientId) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Wait for the next update, or `None` once the feed has been dropped
+    /// (along with the state it was given to)
+    pub async fn next(&mut self) -> Option<Update> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(update) if self.wants(&update) => return Some(update),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => return Some(Update::Lagged { missed }),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn wants(&self, update: &Update) -> bool {
+        match (self.client, update.client()) {
+            (Some(wanted), Some(client)) => wanted == client,
+            _ => true,
+        }
+    }
+}
+
+impl Update {
+    /// The client the update is about, if it's about one
+    pub fn client(&self) -> Option<ClientId> {
+        match self {
+            Update::Account(account) => Some(account.client),
+            Update::Event(event) => Some(event.client()),
+            Update::Lagged { .. } => None,
+        }
+    }
+
+    /// The update as JSON, e.g. for a WebSocket text message
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("updates always serialize")
+    }
+
+    /// The update as a Server-Sent Events frame, named after its type
+    pub fn to_sse(&self) -> String {
+        let name = match self {
+            Update::Account(_) => "account",
+            Update::Event(_) => "event",
+            Update::Lagged { .. } => "lagged",
+        };
+        format!("event: {}\ndata: {}\n\n", name, self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::CsvSource, EngineBuilder, FreezeReason, LockExpiry, State, SyncEngineExt};
+
+    fn names(updates: &[Update]) -> Vec<String> {
+        updates
+            .iter()
+            .map(|update| match update {
+                Update::Event(event) => serde_json::to_value(event).unwrap()["event"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+                other => other
+                    .to_sse()
+                    .lines()
+                    .next()
+                    .unwrap()
+                    .replace("event: ", ""),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_account_feed() {
+        let feed = AccountFeed::new(16);
+        let mut everything = feed.subscribe();
+        let mut second = feed.subscribe().only(ClientId(2));
+        assert_eq!(feed.subscribers(), 2);
+
+        let mut state = State::new();
+        state.set_observer(feed.clone());
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            deposit,2,2,5.0\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n";
+        for action in CsvSource::from_reader(input.as_bytes()) {
+            state.update(action.unwrap()).unwrap();
+        }
+        let reason = FreezeReason::Manual("review".into());
+        state
+            .freeze_until(ClientId(2), reason, LockExpiry::AfterActions(3))
+            .unwrap();
+
+        // Subscriptions end once the feed and the state are gone
+        drop((state, feed));
+
+        let mut updates = Vec::new();
+        while let Some(update) = everything.next().await {
+            updates.push(update);
+        }
+        assert_eq!(
+            names(&updates),
+            [
+                "account",
+                "account",
+                "opened",
+                "funds_held",
+                "account",
+                "charged_back",
+                "account",
+                "account",
+                "locked"
+            ]
+        );
+        let Update::Account(account) = &updates[6] else {
+            panic!("expected an account, got {:?}", updates[6]);
+        };
+        assert!(account.locked);
+        assert!(updates[0]
+            .to_sse()
+            .starts_with("event: account\ndata: {\"type\":\"account\",\"client\":1,"));
+
+        let mut updates = Vec::new();
+        while let Some(update) = second.next().await {
+            updates.push(update);
+        }
+        assert_eq!(names(&updates), ["account", "account", "locked"]);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber() {
+        let feed = AccountFeed::new(2);
+        let mut subscription = feed.subscribe();
+        let mut engine = EngineBuilder::new().observer(feed.clone()).build();
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.0\n\
+            deposit,2,2,1.0\n\
+            deposit,3,3,1.0\n";
+        engine
+            .process_all(CsvSource::from_reader(input.as_bytes()).map(Result::unwrap))
+            .unwrap();
+
+        assert_eq!(
+            subscription.next().await,
+            Some(Update::Lagged { missed: 1 })
+        );
+        let next = subscription.next().await.unwrap();
+        assert_eq!(next.client(), Some(ClientId(2)));
+    }
+}
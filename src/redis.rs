@@ -0,0 +1,228 @@
+//! Keeping a copy of every account's balances in Redis
+//!
+//! A `RedisPublisher` is an observer (see `events`) that writes each account
+//! to a Redis hash as it changes, so services that only need to check a
+//! balance can read it from Redis rather than asking the engine:
+//!
+//! ```text
+//! > HGETALL account:1
+//! available    7.5
+//! held         0
+//! total        7.5
+//! locked       0
+//! status       active
+//! quarantined  0
+//! version      3
+//! ```
+//!
+//! `freeze_reason` is only set while the account is frozen. Amounts are
+//! written as decimal strings, as in every other account output.
+//!
+//! The engine never waits on Redis. Accounts are handed to a thread of the
+//! publisher's own, which writes them in one `MULTI`/`EXEC` per round, and
+//! only the latest write of each account is kept between rounds. If Redis is
+//! unreachable, the thread keeps retrying with the accounts that changed in
+//! the meantime, so the copy can be behind but is never left with an older
+//! balance than one it already had.
+
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use redis::{Client, Connection, RedisError};
+
+use crate::{events::DisputeEvent, events::EventObserver, AccountData, ClientId};
+
+/// The key prefix used unless another is given
+pub const DEFAULT_PREFIX: &str = "account:";
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Writes accounts to Redis as they change. Give it to the state (with
+/// `State::set_observer` or `EngineBuilder::observer`).
+#[derive(Debug)]
+pub struct RedisPublisher {
+    sender: Sender<AccountData>,
+}
+
+impl RedisPublisher {
+    /// Start writing accounts to `client`'s server, each at `{prefix}{client
+    /// id}` (e.g. `account:1`, see `DEFAULT_PREFIX`).
+    ///
+    /// The returned `JoinHandle` finishes once the publisher is dropped (along
+    /// with the state it was given to) and the last accounts are written. It
+    /// gives the error if they couldn't be, in which case Redis is behind the
+    /// engine.
+    pub fn spawn(
+        client: Client,
+        prefix: impl Into<String>,
+    ) -> (Self, JoinHandle<Result<(), RedisError>>) {
+        let (sender, receiver) = mpsc::channel();
+        let prefix = prefix.into();
+        let thread = thread::spawn(move || run(client, &prefix, receiver));
+        (Self { sender }, thread)
+    }
+}
+
+impl EventObserver for RedisPublisher {
+    // Every event that changes an account (e.g. a chargeback locking it)
+    // writes it too, so events themselves have nothing to add
+    fn observe(&mut self, _event: &DisputeEvent) {}
+
+    fn account_changed(&mut self, account: &AccountData) {
+        let _ = self.sender.send(account.clone());
+    }
+}
+
+fn run(client: Client, prefix: &str, receiver: Receiver<AccountData>) -> Result<(), RedisError> {
+    let mut pending = BTreeMap::new();
+    let mut connection = None;
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if pending.is_empty() {
+            match receiver.recv() {
+                Ok(account) => {
+                    pending.insert(account.client, account);
+                }
+                Err(_) => return Ok(()),
+            }
+        }
+        let closed = loop {
+            match receiver.try_recv() {
+                Ok(account) => {
+                    pending.insert(account.client, account);
+                }
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+
+        match write(&client, &mut connection, prefix, &pending) {
+            Ok(()) => {
+                pending.clear();
+                backoff = MIN_BACKOFF;
+                if closed {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                connection = None;
+                if closed {
+                    return Err(e);
+                }
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn write(
+    client: &Client,
+    connection: &mut Option<Connection>,
+    prefix: &str,
+    accounts: &BTreeMap<ClientId, AccountData>,
+) -> Result<(), RedisError> {
+    let connection = match connection {
+        Some(connection) => connection,
+        None => connection.insert(client.get_connection()?),
+    };
+    pipeline(prefix, accounts.values()).query::<()>(connection)
+}
+
+/// Every account's hash, replaced in one transaction
+fn pipeline<'a>(prefix: &str, accounts: impl Iterator<Item = &'a AccountData>) -> redis::Pipeline {
+    let mut pipeline = redis::pipe();
+    pipeline.atomic();
+    for account in accounts {
+        let key = format!("{prefix}{}", account.client);
+        pipeline
+            .hset_multiple(
+                &key,
+                &[
+                    ("available", account.available.to_string()),
+                    ("held", account.held.to_string()),
+                    ("total", account.total.to_string()),
+                    ("locked", u8::from(account.locked).to_string()),
+                    ("status", account.status.name().to_string()),
+                    ("quarantined", u8::from(account.quarantined).to_string()),
+                    ("version", account.version.to_string()),
+                ],
+            )
+            .ignore();
+        match &account.freeze_reason {
+            Some(reason) => pipeline.hset(&key, "freeze_reason", reason).ignore(),
+            None => pipeline.hdel(&key, "freeze_reason").ignore(),
+        };
+    }
+    pipeline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::action, ActionKind::*, FreezeReason, State};
+
+    #[test]
+    fn test_pipeline() {
+        let mut state = State::new();
+        state.update(action(Deposit, 1, 1, Some("7.5"))).unwrap();
+        state.update(action(Deposit, 2, 2, Some("1"))).unwrap();
+        state
+            .change_status(ClientId(2), |account| {
+                account.freeze(FreezeReason::Manual("review".into()))
+            })
+            .unwrap();
+        let accounts: Vec<_> = state.accounts().collect();
+
+        let packed = pipeline("balances:", accounts.iter()).get_packed_pipeline();
+        let packed = String::from_utf8(packed).unwrap();
+        let words: Vec<_> = packed
+            .split("\r\n")
+            .filter(|word| !word.starts_with(['*', '$']))
+            .collect();
+        assert_eq!(words.first(), Some(&"MULTI"));
+        assert_eq!(words.last(), Some(&""));
+        assert_eq!(words[words.len() - 2], "EXEC");
+        let first = words.iter().position(|word| *word == "balances:1").unwrap();
+        assert_eq!(words[first - 1], "HMSET");
+        assert_eq!(&words[first + 1..first + 3], ["available", "7.5"]);
+        assert!(words
+            .windows(3)
+            .any(|w| w == ["HDEL", "balances:1", "freeze_reason"]));
+        assert!(words
+            .windows(4)
+            .any(|w| w == ["HSET", "balances:2", "freeze_reason", "manual: review"]));
+    }
+
+    /// Runs against a real server when `TRANSACTION_ENGINE_REDIS` is set to a
+    /// connection url, e.g. `redis://127.0.0.1/`
+    #[test]
+    fn test_publish() {
+        let Ok(url) = std::env::var("TRANSACTION_ENGINE_REDIS") else {
+            return;
+        };
+        let client = Client::open(url).unwrap();
+        let (publisher, thread) = RedisPublisher::spawn(client.clone(), "test-account:");
+        let mut state = State::new();
+        state.set_observer(publisher);
+        state.update(action(Deposit, 1, 1, Some("10"))).unwrap();
+        state.update(action(Withdrawal, 1, 2, Some("2.5"))).unwrap();
+        drop(state);
+        thread.join().unwrap().unwrap();
+
+        let mut connection = client.get_connection().unwrap();
+        let account: BTreeMap<String, String> = redis::cmd("HGETALL")
+            .arg("test-account:1")
+            .query(&mut connection)
+            .unwrap();
+        assert_eq!(account["available"], "7.5");
+        assert_eq!(account["status"], "active");
+        assert_eq!(account["version"], "2");
+        assert!(!account.contains_key("freeze_reason"));
+    }
+}
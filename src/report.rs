@@ -0,0 +1,611 @@
+//! Reports over the whole state, for operations and risk teams, and
+//! statements of single clients' accounts for customer support
+//!
+//! `Reconciliation` is the remediation side: what it would take to bring a
+//! state in line with a corrected history, after an upstream data bug.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    events::Balances, Account, Action, ActionKind, Amount, ClientId, Timestamp, Transaction,
+    TransactionId, TransactionState,
+};
+
+/// The funds held by every open dispute, by client, from
+/// `State::open_holds`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpenHoldsReport {
+    /// Clients with at least one open dispute, by id
+    pub clients: Vec<ClientHolds>,
+}
+
+/// One client's open disputes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientHolds {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    pub client: ClientId,
+    /// The disputed transactions, by id
+    pub transactions: Vec<HeldTransaction>,
+    /// The sum of the disputed amounts
+    pub total_held: Amount,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeldTransaction {
+    pub transaction: TransactionId,
+    pub amount: Amount,
+}
+
+/// A row of the csv form, one per disputed transaction
+#[derive(Serialize)]
+struct Row {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    client: ClientId,
+    tx: TransactionId,
+    amount: Amount,
+    client_total_held: Amount,
+}
+
+impl OpenHoldsReport {
+    /// Group disputed transactions by client
+    pub(crate) fn collect<I: IntoIterator<Item = Transaction>>(disputed: I) -> Self {
+        let mut clients: BTreeMap<ClientId, Vec<HeldTransaction>> = BTreeMap::new();
+        for transaction in disputed {
+            clients
+                .entry(transaction.client)
+                .or_default()
+                .push(HeldTransaction {
+                    transaction: transaction.id,
+                    amount: transaction.amount,
+                });
+        }
+
+        let clients = clients
+            .into_iter()
+            .map(|(client, mut transactions)| {
+                transactions.sort_by_key(|t| t.transaction);
+                ClientHolds {
+                    client,
+                    total_held: transactions.iter().map(|t| t.amount).sum(),
+                    transactions,
+                }
+            })
+            .collect();
+        Self { clients }
+    }
+
+    /// Write the report as pretty printed JSON
+    pub fn write_json<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+
+    /// Write the report as csv, with a row for each disputed transaction
+    /// (`client,tx,amount,client_total_held`)
+    pub fn write_csv<W: std::io::Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for client in &self.clients {
+            for transaction in &client.transactions {
+                writer.serialize(Row {
+                    client: client.client,
+                    tx: transaction.transaction,
+                    amount: transaction.amount,
+                    client_total_held: client.total_held,
+                })?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A client's transactions in order, with the balance after each, from
+/// `State::statement`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Statement {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    pub client: ClientId,
+    pub generated_at: Timestamp,
+    /// The balance before the first line, from any transactions that have
+    /// been forgotten
+    pub opening_balance: Amount,
+    /// In order of timestamp (with untimestamped transactions first), then
+    /// id
+    pub lines: Vec<StatementLine>,
+    /// The account's total balance, which is where the last line leaves it
+    pub closing_balance: Amount,
+}
+
+/// One transaction on a statement
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementLine {
+    pub transaction: TransactionId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    pub kind: LineKind,
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// Negative for money leaving the account
+    pub amount: Amount,
+    pub state: TransactionState,
+    /// The total balance after the transaction. Failed and charged back
+    /// transactions don't move it.
+    pub balance: Amount,
+    /// The account's balances straight after the transaction, as they were
+    /// when it was applied (see `State::set_running_balances`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recorded: Option<Balances>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineKind {
+    Deposit,
+    Withdrawal,
+    Reversal,
+}
+
+/// A row of a statement's csv form
+#[derive(Serialize)]
+struct LineRow<'a> {
+    tx: TransactionId,
+    timestamp: Option<Timestamp>,
+    kind: LineKind,
+    #[serde(rename = "ref")]
+    reference: Option<&'a str>,
+    amount: Amount,
+    status: &'static str,
+    balance: Amount,
+    available: Option<Amount>,
+    held: Option<Amount>,
+}
+
+impl Statement {
+    /// Put a client's transactions in order, working the running balance
+    /// back from the account's current total
+    pub(crate) fn collect(
+        client: ClientId,
+        closing_balance: Amount,
+        mut transactions: Vec<Transaction>,
+    ) -> Self {
+        transactions.sort_by_key(|t| (t.timestamp, t.id));
+        let moved: Amount = transactions
+            .iter()
+            .filter(|t| counts(&t.state))
+            .map(|t| t.amount)
+            .sum();
+        let opening_balance = closing_balance - moved;
+
+        let mut balance = opening_balance;
+        let lines = transactions
+            .into_iter()
+            .map(|transaction| {
+                if counts(&transaction.state) {
+                    balance += transaction.amount;
+                }
+                let kind = match (transaction.reverses, transaction.amount.is_sign_negative()) {
+                    (Some(_), _) => LineKind::Reversal,
+                    (None, true) => LineKind::Withdrawal,
+                    (None, false) => LineKind::Deposit,
+                };
+                StatementLine {
+                    transaction: transaction.id,
+                    timestamp: transaction.timestamp,
+                    kind,
+                    reference: transaction.reference,
+                    amount: transaction.amount,
+                    state: transaction.state,
+                    balance,
+                    recorded: transaction.balances,
+                }
+            })
+            .collect();
+        Self {
+            client,
+            generated_at: Timestamp::now(),
+            opening_balance,
+            lines,
+            closing_balance,
+        }
+    }
+
+    /// Write the statement as pretty printed JSON
+    pub fn write_json<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+
+    /// Write the statement as csv, with a row for each transaction
+    /// (`tx,timestamp,kind,ref,amount,status,balance,available,held`). The
+    /// last two are the recorded balances, and are empty if there aren't
+    /// any.
+    pub fn write_csv<W: std::io::Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for line in &self.lines {
+            writer.serialize(LineRow {
+                tx: line.transaction,
+                timestamp: line.timestamp,
+                kind: line.kind,
+                reference: line.reference.as_deref(),
+                amount: line.amount,
+                status: status(&line.state),
+                balance: line.balance,
+                available: line.recorded.map(|b| b.available),
+                held: line.recorded.map(|b| b.held),
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// What it takes to bring a state's accounts in line with a state rebuilt
+/// from a corrected history, from `State::reconcile`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reconciliation {
+    /// A deposit or withdrawal for each client whose total balance is off,
+    /// by client
+    pub adjustments: Vec<Adjustment>,
+    /// Differences adjustments can't make up, to be looked into by hand, by
+    /// client
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Moves a client's total balance to what the corrected history gives.
+/// Funds held differently are left to the dispute that holds them (see
+/// `DiscrepancyReason::Held`), so it goes through the available balance.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Adjustment {
+    pub client: ClientId,
+    pub kind: ActionKind,
+    #[serde(with = "crate::persist::exact_amount")]
+    pub amount: Amount,
+    /// The external reference the adjustment is applied with, so applying it
+    /// twice is rejected
+    #[serde(rename = "ref")]
+    pub reference: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discrepancy {
+    #[serde(serialize_with = "crate::client_format::serialize")]
+    pub client: ClientId,
+    pub reason: DiscrepancyReason,
+    pub current: Balances,
+    pub corrected: Balances,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyReason {
+    /// The held balances differ, so a dispute was opened or settled
+    /// differently
+    Held,
+    /// The account is locked in one history and not the other
+    Locked,
+    /// The total balance is off, but the account is locked, so an adjustment
+    /// would be refused
+    Frozen,
+}
+
+/// A row of a reconciliation's csv form, which is a v2 input (see
+/// `io::CsvSchema`) with the transaction ids left for the engine to assign
+#[derive(Serialize)]
+struct AdjustmentRow<'a> {
+    #[serde(rename = "type")]
+    kind: ActionKind,
+    client: ClientId,
+    tx: (),
+    #[serde(with = "crate::persist::exact_amount")]
+    amount: Amount,
+    idempotency: &'a str,
+}
+
+impl Reconciliation {
+    /// Compare each client's account as it is (`current`) with how the
+    /// corrected history left it, naming adjustments `{prefix}{client}`
+    pub(crate) fn collect<I>(accounts: I, prefix: &str) -> Self
+    where
+        I: IntoIterator<Item = (ClientId, Option<Account>, Option<Account>)>,
+    {
+        let mut reconciliation = Self {
+            adjustments: Vec::new(),
+            discrepancies: Vec::new(),
+        };
+        let mut accounts: Vec<_> = accounts.into_iter().collect();
+        accounts.sort_by_key(|(client, ..)| *client);
+        for (client, current, corrected) in accounts {
+            let (current, corrected) = (current.unwrap_or_default(), corrected.unwrap_or_default());
+            let mut discrepancy = |reason| {
+                reconciliation.discrepancies.push(Discrepancy {
+                    client,
+                    reason,
+                    current: Balances::from(&current),
+                    corrected: Balances::from(&corrected),
+                })
+            };
+            if current.held_funds() != corrected.held_funds() {
+                discrepancy(DiscrepancyReason::Held);
+            }
+            if current.is_locked() != corrected.is_locked() {
+                discrepancy(DiscrepancyReason::Locked);
+            }
+            let difference = corrected.total_funds() - current.total_funds();
+            if difference == Amount::default() {
+                continue;
+            }
+            if current.is_locked() {
+                discrepancy(DiscrepancyReason::Frozen);
+                continue;
+            }
+            let kind = if difference.is_sign_negative() {
+                ActionKind::Withdrawal
+            } else {
+                ActionKind::Deposit
+            };
+            reconciliation.adjustments.push(Adjustment {
+                client,
+                kind,
+                amount: difference.abs(),
+                reference: format!("{}{}", prefix, client),
+            });
+        }
+        reconciliation
+    }
+
+    /// Whether the state already matches the corrected history
+    pub fn is_empty(&self) -> bool {
+        self.adjustments.is_empty() && self.discrepancies.is_empty()
+    }
+
+    /// The adjustments as actions, to apply to the state
+    pub fn actions(&self) -> impl Iterator<Item = Action> + '_ {
+        self.adjustments.iter().map(Adjustment::action)
+    }
+
+    /// Write the reconciliation as pretty printed JSON
+    pub fn write_json<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+
+    /// Write the adjustments as csv (`type,client,tx,amount,idempotency`),
+    /// to be reviewed and then applied like any other input
+    pub fn write_csv<W: std::io::Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for adjustment in &self.adjustments {
+            writer.serialize(AdjustmentRow {
+                kind: adjustment.kind,
+                client: adjustment.client,
+                tx: (),
+                amount: adjustment.amount,
+                idempotency: &adjustment.reference,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Adjustment {
+    pub fn action(&self) -> Action {
+        Action {
+            transaction_id: None,
+            client_id: self.client,
+            kind: self.kind,
+            amount: Some(self.amount),
+            timestamp: None,
+            reverses: None,
+            reference: Some(self.reference.clone()),
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+}
+
+/// Whether a transaction in this state still counts towards the balance
+fn counts(state: &TransactionState) -> bool {
+    !matches!(
+        state,
+        TransactionState::Failed(_) | TransactionState::Cancelled
+    )
+}
+
+fn status(state: &TransactionState) -> &'static str {
+    match state {
+        TransactionState::Succeeded => "succeeded",
+        TransactionState::Failed(_) => "failed",
+        TransactionState::Disputed => "disputed",
+        TransactionState::Cancelled => "charged_back",
+        TransactionState::Reversed(_) => "reversed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiscrepancyReason;
+    use crate::{
+        fixtures::Fixture, io::CsvSource, AccountError, Amount, ClientId, State, TransactionState,
+        UpdateError,
+    };
+
+    #[test]
+    fn test_open_holds() {
+        let state = Fixture::new()
+            .account(1, "0", "7.5")
+            .deposit(3, 1, "5", TransactionState::Disputed)
+            .deposit(1, 1, "2.5", TransactionState::Disputed)
+            .deposit(2, 1, "4", TransactionState::Succeeded)
+            .account(2, "0", "1")
+            .deposit(4, 2, "1", TransactionState::Disputed)
+            .deposit(
+                5,
+                2,
+                "9",
+                TransactionState::Failed(AccountError::InsufficientFunds),
+            )
+            .build();
+        assert_eq!(state.disputed_transactions().count(), 3);
+
+        let report = state.open_holds().unwrap();
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,tx,amount,client_total_held\n1,1,2.5,7.5\n1,3,5.0,7.5\n2,4,1.0,1.0\n"
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["clients"][0]["transactions"][1]["transaction"], 3);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let state = |input: &str| {
+            let input = format!("type,client,tx,amount\n{}", input);
+            let mut state = State::new();
+            for action in CsvSource::from_reader(input.as_bytes()) {
+                let _ = state.update(action.unwrap());
+            }
+            state
+        };
+        // Upstream sent 10 rather than 1, dropped client 3's deposit, and
+        // a dispute that never happened
+        let mut current = state(
+            "deposit,1,1,10\n\
+            deposit,2,2,5\n\
+            dispute,2,2,\n\
+            deposit,4,4,2\n\
+            dispute,4,4,\n\
+            chargeback,4,4,\n",
+        );
+        let corrected = state(
+            "deposit,1,1,1\n\
+            deposit,2,2,5\n\
+            deposit,3,3,0.25\n\
+            deposit,4,4,2\n",
+        );
+
+        let reconciliation = current.reconcile(&corrected, "fix-").unwrap();
+        let mut csv = Vec::new();
+        reconciliation.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,client,tx,amount,idempotency\n\
+            withdrawal,1,,9,fix-1\n\
+            deposit,3,,0.25,fix-3\n"
+        );
+        let reasons: Vec<_> = reconciliation
+            .discrepancies
+            .iter()
+            .map(|d| (d.client.0, d.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (2, DiscrepancyReason::Held),
+                (4, DiscrepancyReason::Locked),
+                (4, DiscrepancyReason::Frozen)
+            ]
+        );
+
+        for action in reconciliation.actions() {
+            current.update(action).unwrap();
+        }
+        let after = current.reconcile(&corrected, "fix-").unwrap();
+        assert!(after.adjustments.is_empty());
+        assert_eq!(after.discrepancies.len(), 3);
+        // Applying them again is caught by the references
+        let again = reconciliation.actions().next().unwrap();
+        assert!(matches!(
+            current.update(again),
+            Err(UpdateError::ReferenceUsed(_))
+        ));
+    }
+
+    #[test]
+    fn test_statement() {
+        let input = "type,client,tx,amount,timestamp\n\
+            deposit,1,1,10,100\n\
+            withdrawal,1,2,2.5,300\n\
+            deposit,1,3,4,200\n\
+            withdrawal,1,4,50,400\n\
+            deposit,2,5,1,100\n\
+            dispute,1,3,,\n\
+            chargeback,1,3,,\n";
+        let mut state = State::new();
+        for action in CsvSource::from_reader(input.as_bytes()) {
+            state.update(action.unwrap()).unwrap();
+        }
+        state.forget_transactions(|t| t.id.0 == 1).unwrap();
+
+        let statement = state.statement(ClientId(1)).unwrap();
+        assert_eq!(statement.opening_balance, Amount::from(10u32));
+        let balances: Vec<_> = statement.lines.iter().map(|l| l.balance).collect();
+        let amount = |amount: u32| Amount::from(amount);
+        // The charged back deposit and the failed withdrawal don't count
+        assert_eq!(
+            balances,
+            [amount(10), amount(15) / amount(2), amount(15) / amount(2)]
+        );
+        assert_eq!(statement.closing_balance, amount(15) / amount(2));
+
+        let mut csv = Vec::new();
+        statement.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let rows: Vec<_> = csv.lines().collect();
+        assert_eq!(
+            rows[0],
+            "tx,timestamp,kind,ref,amount,status,balance,available,held"
+        );
+        assert!(rows[1].starts_with("3,200,deposit,,4"), "{}", csv);
+        assert!(rows[1].contains(",charged_back,10"), "{}", csv);
+        assert!(rows[3].starts_with("4,400,withdrawal,,-50"), "{}", csv);
+
+        let json = serde_json::to_value(&statement).unwrap();
+        assert_eq!(json["lines"][1]["kind"], "withdrawal");
+    }
+
+    #[test]
+    fn test_running_balances() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            dispute,1,1,\n\
+            deposit,1,2,4\n\
+            withdrawal,1,3,1\n";
+        let mut state = State::new();
+        state.set_running_balances(true);
+        for action in CsvSource::from_reader(input.as_bytes()) {
+            state.update(action.unwrap()).unwrap();
+        }
+
+        let recorded: Vec<_> = state
+            .statement(ClientId(1))
+            .unwrap()
+            .lines
+            .iter()
+            .map(|line| line.recorded.unwrap())
+            .map(|balances| (balances.available, balances.held))
+            .collect();
+        let amount = |amount: u32| Amount::from(amount);
+        assert_eq!(
+            recorded,
+            [
+                (amount(10), amount(0)),
+                (amount(4), amount(10)),
+                (amount(3), amount(10))
+            ]
+        );
+        let mut csv = Vec::new();
+        state
+            .statement(ClientId(1))
+            .unwrap()
+            .write_csv(&mut csv)
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let row: Vec<_> = csv.lines().nth(3).unwrap().split(',').collect();
+        let recorded: Vec<Amount> = row[7..].iter().map(|f| f.parse().unwrap()).collect();
+        assert_eq!(recorded, [amount(3), amount(10)]);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Write as _;
+
+use roaring::RoaringBitmap;
+#[cfg(feature = "wide-transaction-ids")]
+use roaring::RoaringTreemap;
+use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::TransactionId;
+
+#[cfg(not(feature = "wide-transaction-ids"))]
+type Bitmap = RoaringBitmap;
+// A map of 32 bit bitmaps, keyed by the upper half of the id
+#[cfg(feature = "wide-transaction-ids")]
+type Bitmap = RoaringTreemap;
+
+/// The set of every transaction id that has been claimed by a deposit or
+/// withdrawal.
+///
+/// This is a compressed bitmap, so it costs a few bytes per id at most (much
+/// less for mostly sequential ids) compared to a full `Transaction` record.
+/// It is persisted with the rest of the state, so redelivered actions are
+/// still rejected after a restart, even for transactions whose records have
+/// since been forgotten.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SeenTransactions(Bitmap);
+
+impl SeenTransactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, id: TransactionId) -> bool {
+        self.0.contains(id.0)
+    }
+
+    /// Claim an id, returning `false` if it was already claimed
+    pub(crate) fn insert(&mut self, id: TransactionId) -> bool {
+        self.0.insert(id.0)
+    }
+
+    /// Release a claimed id (e.g. when rolling back the transaction that
+    /// claimed it)
+    pub(crate) fn remove(&mut self, id: TransactionId) -> bool {
+        self.0.remove(id.0)
+    }
+
+    /// Add all the ids claimed in `other`
+    pub(crate) fn extend(&mut self, other: &SeenTransactions) {
+        self.0 |= &other.0;
+    }
+
+    /// The lowest id claimed in both `self` and `other`, if any is
+    pub fn first_shared(&self, other: &SeenTransactions) -> Option<TransactionId> {
+        (&self.0 & &other.0).min().map(TransactionId)
+    }
+
+    /// The id to assign to a new transaction that doesn't have one: one past
+    /// the highest claimed id, unless they've run out
+    pub(crate) fn next_free(&self) -> Option<TransactionId> {
+        self.0
+            .max()
+            .map_or(Some(0), |max| max.checked_add(1))
+            .map(TransactionId)
+    }
+
+    /// Every claimed id, lowest first
+    #[cfg(feature = "concurrent-engine")]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = TransactionId> + '_ {
+        self.0.iter().map(TransactionId)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromIterator<TransactionId> for SeenTransactions {
+    fn from_iter<I: IntoIterator<Item = TransactionId>>(iter: I) -> Self {
+        Self(iter.into_iter().map(|id| id.0).collect())
+    }
+}
+
+// Stored as a hex string of the standard roaring serialization, since the
+// snapshot it's embedded in is JSON
+impl Serialize for SeenTransactions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::with_capacity(self.0.serialized_size());
+        self.0
+            .serialize_into(&mut bytes)
+            .map_err(S::Error::custom)?;
+
+        let mut hex = String::with_capacity(bytes.len() * 2);
+        for byte in bytes {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for SeenTransactions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(D::Error::custom)?;
+
+        deserialize_bitmap(&bytes)
+            .map(Self)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(not(feature = "wide-transaction-ids"))]
+fn deserialize_bitmap(bytes: &[u8]) -> std::io::Result<Bitmap> {
+    RoaringBitmap::deserialize_from(bytes)
+}
+
+// State saved before the ids were widened has a plain 32 bit bitmap, which
+// becomes the lowest entry of the treemap. A 32 bit bitmap starts with a fixed
+// cookie, which a treemap would only match with thousands of entries (i.e.
+// ids well beyond 2^40).
+#[cfg(feature = "wide-transaction-ids")]
+fn deserialize_bitmap(bytes: &[u8]) -> std::io::Result<Bitmap> {
+    match RoaringBitmap::deserialize_from(bytes) {
+        Ok(narrow) => Ok(std::iter::once((0, narrow)).collect()),
+        Err(_) => RoaringTreemap::deserialize_from(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serde_round_trip() {
+        let seen: SeenTransactions = [1, 2, 3, 1000, 70_000]
+            .into_iter()
+            .map(TransactionId)
+            .collect();
+
+        let json = serde_json::to_string(&seen).unwrap();
+        let restored: SeenTransactions = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, seen);
+    }
+
+    #[cfg(feature = "wide-transaction-ids")]
+    #[test]
+    fn test_wide_ids_read_narrow_bitmaps() {
+        let narrow: RoaringBitmap = [1, 2, u32::MAX].into_iter().collect();
+        let mut bytes = Vec::new();
+        narrow.serialize_into(&mut bytes).unwrap();
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let seen: SeenTransactions = serde_json::from_value(hex.into()).unwrap();
+        assert_eq!(seen.len(), 3);
+        assert!(seen.contains(TransactionId(u32::MAX as u64)));
+        assert!(!seen.contains(TransactionId(u32::MAX as u64 + 1)));
+    }
+}
@@ -0,0 +1,359 @@
+//! Soak mode, for catching corruption early in runs that go on for days
+//!
+//! Checking every account against every transaction is far too slow to do
+//! often against a large state. A `SoakMonitor` is told about each action as
+//! it's applied, and every so many actions checks a random sample of the
+//! clients it has seen instead:
+//!
+//! - neither balance is negative (or only the available balance, under
+//!   `ChargebackPolicy::AllowNegative`)
+//! - closed accounts hold nothing
+//! - the held balance is what the client's open disputes (and prepared
+//!   withdrawals) add up to
+//!
+//! along with the account count in `State::summary` against the store. Each
+//! check only reads the sampled accounts and their disputed transactions, so
+//! the cost doesn't grow with the state.
+//!
+//! To know which transactions a client has disputed without going through
+//! the store, the monitor keeps its own index of the transactions named by
+//! disputes, resolves and chargebacks. It has to see every action for that,
+//! so a monitor for an existing state should be started with `with_state`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    store::StoreError, AccountStatus, Action, ActionKind, Amount, ChargebackPolicy, ClientId,
+    State, TransactionId, TransactionState,
+};
+
+/// Violations kept in `SoakMetrics::recent_violations`
+const MAX_RECENT_VIOLATIONS: usize = 100;
+
+/// Checks a state's invariants on a sample of accounts as it's updated
+#[derive(Debug, Clone)]
+pub struct SoakMonitor {
+    /// Actions between checks
+    every: u64,
+    /// Accounts looked at in each check
+    sample: usize,
+    /// How far a held balance can be from its disputes before it's a
+    /// violation
+    tolerance: Amount,
+    rng: SplitMix64,
+
+    /// Every client an action has been seen for, to sample from
+    clients: Vec<ClientId>,
+    known_clients: HashSet<ClientId>,
+    /// Transactions each client has had disputes (or resolves and
+    /// chargebacks) for, which might still be held
+    disputes: HashMap<ClientId, HashSet<TransactionId>>,
+
+    since_check: u64,
+    metrics: SoakMetrics,
+}
+
+/// What a `SoakMonitor` has found so far
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SoakMetrics {
+    /// Actions observed
+    pub actions: u64,
+    /// Sampled checks run
+    pub checks: u64,
+    /// Accounts looked at, over every check
+    pub accounts_checked: u64,
+    pub violations: u64,
+    /// The furthest any sampled held balance has been from its disputes,
+    /// whether or not it was within the tolerance
+    pub max_held_drift: Amount,
+    /// The latest violations, oldest first
+    pub recent_violations: Vec<Violation>,
+}
+
+/// An invariant that didn't hold
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    /// How many actions had been observed when it was found
+    pub after_actions: u64,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    NegativeAvailable {
+        client: ClientId,
+        available: Amount,
+    },
+    NegativeHeld {
+        client: ClientId,
+        held: Amount,
+    },
+    ClosedWithFunds {
+        client: ClientId,
+        total: Amount,
+    },
+    /// The held balance isn't the sum of the client's disputed transactions
+    /// and prepared withdrawals
+    HeldDrift {
+        client: ClientId,
+        held: Amount,
+        disputed: Amount,
+    },
+    /// The incremental summary has lost track of how many accounts there are
+    ClientCountDrift {
+        summary: u64,
+        stored: u64,
+    },
+}
+
+impl SoakMonitor {
+    /// Check `sample` accounts every `every` actions
+    pub fn new(every: u64, sample: usize) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        Self {
+            every: every.max(1),
+            sample,
+            tolerance: Amount::default(),
+            rng: SplitMix64(seed),
+            clients: Vec::new(),
+            known_clients: HashSet::new(),
+            disputes: HashMap::new(),
+            since_check: 0,
+            metrics: SoakMetrics::default(),
+        }
+    }
+
+    /// Sample with a fixed seed, so the same accounts are picked each run
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = SplitMix64(seed);
+        self
+    }
+
+    /// Allow held balances to be this far from their disputes. Without the
+    /// `decimal` feature balances are float sums, so some drift is expected.
+    pub fn with_tolerance(mut self, tolerance: Amount) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Pick up the accounts and open disputes already in `state`, which has
+    /// to go through every account and transaction once
+    pub fn with_state(mut self, state: &State) -> Result<Self, StoreError> {
+        for entry in state.raw_accounts() {
+            self.add_client(entry?.0);
+        }
+        for entry in state.raw_transactions() {
+            let (id, transaction) = entry?;
+            if matches!(transaction.state, TransactionState::Disputed) {
+                self.disputes
+                    .entry(transaction.client)
+                    .or_default()
+                    .insert(id);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Note an action that's about to be applied to `state`. Call `observe`
+    /// once it has been, whether or not it succeeded.
+    pub fn before(&mut self, action: &Action) {
+        self.add_client(action.client_id);
+        if let (ActionKind::Dispute | ActionKind::Resolve | ActionKind::Chargeback, Some(id)) =
+            (action.kind, action.transaction_id)
+        {
+            self.disputes
+                .entry(action.client_id)
+                .or_default()
+                .insert(id);
+        }
+    }
+
+    /// Count an applied action, running a check on `state` if it's time to.
+    /// Storage errors stop the check, but the action is still counted.
+    pub fn observe(&mut self, state: &State) -> Result<(), StoreError> {
+        self.metrics.actions += 1;
+        self.since_check += 1;
+        if self.since_check >= self.every {
+            self.since_check = 0;
+            self.check(state)?;
+        }
+        Ok(())
+    }
+
+    /// Check a sample of accounts now
+    pub fn check(&mut self, state: &State) -> Result<(), StoreError> {
+        self.metrics.checks += 1;
+
+        let summary = state.summary().clients;
+        let stored = state.account_count() as u64;
+        if summary != stored {
+            self.violation(ViolationKind::ClientCountDrift { summary, stored });
+        }
+
+        for _ in 0..self.sample.min(self.clients.len()) {
+            let client = self.clients[self.rng.below(self.clients.len())];
+            self.check_client(state, client)?;
+        }
+        Ok(())
+    }
+
+    fn check_client(&mut self, state: &State, client: ClientId) -> Result<(), StoreError> {
+        // Accounts aren't opened by every action (and can be erased)
+        let Some(account) = state.try_account(client)? else {
+            return Ok(());
+        };
+        self.metrics.accounts_checked += 1;
+
+        let zero = Amount::default();
+        let negative_allowed = state.chargeback_policy() == Some(ChargebackPolicy::AllowNegative);
+        if account.available < zero && !negative_allowed {
+            self.violation(ViolationKind::NegativeAvailable {
+                client,
+                available: account.available,
+            });
+        }
+        if account.held < zero {
+            self.violation(ViolationKind::NegativeHeld {
+                client,
+                held: account.held,
+            });
+        }
+        if account.status == AccountStatus::Closed && account.total != zero {
+            self.violation(ViolationKind::ClosedWithFunds {
+                client,
+                total: account.total,
+            });
+        }
+
+        let mut disputed = state.prepared_for(client);
+        if let Some(ids) = self.disputes.get_mut(&client) {
+            let mut settled = Vec::new();
+            for id in ids.iter() {
+                match state.transaction(*id)? {
+                    Some(transaction)
+                        if transaction.client == client
+                            && matches!(transaction.state, TransactionState::Disputed) =>
+                    {
+                        disputed += transaction.amount
+                    }
+                    _ => settled.push(*id),
+                }
+            }
+            // Settled disputes can't be reopened without another dispute
+            for id in settled {
+                ids.remove(&id);
+            }
+        }
+        let drift = (account.held - disputed).abs();
+        if drift > self.metrics.max_held_drift {
+            self.metrics.max_held_drift = drift;
+        }
+        if drift > self.tolerance {
+            self.violation(ViolationKind::HeldDrift {
+                client,
+                held: account.held,
+                disputed,
+            });
+        }
+        Ok(())
+    }
+
+    fn add_client(&mut self, client: ClientId) {
+        if self.known_clients.insert(client) {
+            self.clients.push(client);
+        }
+    }
+
+    fn violation(&mut self, kind: ViolationKind) {
+        self.metrics.violations += 1;
+        if self.metrics.recent_violations.len() == MAX_RECENT_VIOLATIONS {
+            self.metrics.recent_violations.remove(0);
+        }
+        self.metrics.recent_violations.push(Violation {
+            after_actions: self.metrics.actions,
+            kind,
+        });
+    }
+
+    pub fn metrics(&self) -> &SoakMetrics {
+        &self.metrics
+    }
+}
+
+/// A small, fast generator for picking samples. It doesn't need to be
+/// unpredictable, only spread out.
+#[derive(Debug, Clone)]
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, close enough to uniform for small `n`
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures::Fixture, io::CsvSource};
+
+    #[test]
+    fn test_clean_run_has_no_violations() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\ndeposit,2,2,5\ndispute,1,1,\nwithdrawal,2,3,2\n\
+            dispute,2,2,\nresolve,1,1,\ndeposit,3,4,1\nchargeback,2,2,\n";
+        let mut state = State::new();
+        let mut monitor = SoakMonitor::new(2, 3).with_seed(7);
+        for action in CsvSource::from_reader(input.as_bytes()).map(Result::unwrap) {
+            monitor.before(&action);
+            let _ = state.update(action);
+            monitor.observe(&state).unwrap();
+        }
+
+        let metrics = monitor.metrics();
+        assert_eq!(metrics.actions, 8);
+        assert_eq!(metrics.checks, 4);
+        assert!(metrics.accounts_checked > 0);
+        assert_eq!(metrics.violations, 0, "{:?}", metrics.recent_violations);
+    }
+
+    #[test]
+    fn test_finds_held_drift() {
+        // Client 1 holds more than its one open dispute
+        let state = Fixture::new()
+            .account(1, "0", "7")
+            .deposit(1, 1, "5", TransactionState::Disputed)
+            .build();
+        let mut monitor = SoakMonitor::new(1, 1).with_state(&state).unwrap();
+        monitor.check(&state).unwrap();
+
+        let metrics = monitor.metrics();
+        assert_eq!(metrics.violations, 1);
+        assert_eq!(metrics.max_held_drift, Amount::from(2u32));
+        assert_eq!(
+            metrics.recent_violations[0].kind,
+            ViolationKind::HeldDrift {
+                client: ClientId(1),
+                held: Amount::from(7u32),
+                disputed: Amount::from(5u32),
+            }
+        );
+    }
+}
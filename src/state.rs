@@ -1,86 +1,578 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
 
 use super::{Action, ActionKind, ClientId, TransactionId, TransactionState};
-use crate::{account::Account, AccountData, Transaction};
+use crate::{
+    account::Account,
+    audit::{
+        AuditEntry, AuditEvent, AuditTrail, ClientExport, DisputeHistory, Evidence, EvidenceLog,
+        Outcome,
+    },
+    bulk::{BulkExport, BulkOperation, BulkReport},
+    engine::withdrawal_outcome,
+    events::{Balances, DisputeEvent, EventObserver, Events},
+    policy::{Withdrawal, WithdrawalHistory},
+    report::{OpenHoldsReport, Reconciliation, Statement},
+    seen::SeenTransactions,
+    store::{AccountStore, MemoryStore, StoreError, StoreIter, TransactionStore},
+    testing::{Invariant, InvariantViolation},
+    AccountData, AccountError, AccountFilter, AccountOrder, AccountStatus, AccountsSummary, Amount,
+    ChargebackPolicy, DisputeWindow, FreezeReason, HoldId, Limit, LimitsPolicy, LockExpiry,
+    OutputConfig, StatusError, Timestamp, Transaction, TransactionEvent, TransactionEventKind,
+    TrustedBatch,
+};
 
 /// The internal state of the engine
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct State {
-    accounts: HashMap<ClientId, Account>,
+    accounts: Box<dyn AccountStore>,
+
+    transactions: Box<dyn TransactionStore>,
+
+    /// Every transaction id ever used, including any forgotten transactions
+    seen: SeenTransactions,
+
+    /// Whether any transactions have been dropped by `forget_transactions`,
+    /// so account totals can't be checked against the ones that are left
+    forgotten: bool,
+
+    /// The transaction each external reference was claimed by
+    references: HashMap<String, TransactionId>,
+
+    /// Totals over `accounts`, updated whenever an account is written
+    summary: AccountsSummary,
+
+    /// Clients whose accounts are frozen until a `LockExpiry`, so only their
+    /// accounts have to be checked for a freeze running out
+    expiring_locks: HashSet<ClientId>,
+
+    /// How long after a transaction it can still be disputed, if limited
+    dispute_window: Option<DisputeWindow>,
+
+    /// Caps on deposit and withdrawal amounts, if any
+    limits: Option<LimitsPolicy>,
+
+    /// How disputes of already withdrawn deposits are handled, if specially
+    chargeback_policy: Option<ChargebackPolicy>,
+
+    /// How many disputes each client can have open at once, if limited
+    max_open_disputes: Option<usize>,
+
+    /// The currency every amount is in, if it's checked
+    currency: Option<String>,
+
+    /// Recent withdrawals, for the daily limit
+    withdrawals: WithdrawalHistory,
+
+    /// Whether actions and status changes are added to `audit`
+    audit_enabled: bool,
+
+    /// What happened to each client's account, if it's being recorded
+    audit: AuditTrail,
 
-    transactions: HashMap<TransactionId, Transaction>,
+    /// Whether new transactions keep the account's balances straight after
+    /// them
+    running_balances: bool,
+
+    /// How balances are rounded in `AccountData`
+    output: OutputConfig,
+
+    /// Evidence attached to disputed transactions
+    evidence: EvidenceLog,
+
+    /// Withdrawals prepared with `prepare`, waiting to be committed or
+    /// aborted
+    prepared: HashMap<TransactionId, Action>,
+
+    /// Where dispute events are sent, if anywhere
+    events: Events,
+
+    /// How to undo each change made since the oldest open savepoint
+    undo_log: Vec<Undo>,
+
+    /// Savepoints that haven't been rolled back to or released yet
+    open_savepoints: usize,
+
+    /// How many actions have been given to `update` and `bulk_load`
+    sequence: u64,
+
+    /// In the middle of a `bulk_load`, where the seen set is known to cover
+    /// every stored transaction
+    bulk_loading: bool,
+
+    /// The sequence number each client's account last changed at, for
+    /// `diff_since`
+    changed: HashMap<ClientId, u64>,
+    /// The lowest sequence number `diff_since` knows every change after
+    changes_known_from: u64,
+    /// In the middle of applying an action, so changes count as part of it
+    /// rather than the next one
+    applying: bool,
     /* TODO: potential improvement, track transaction ordering?
      * Esp for when a previous transaction was disputed/changed and it affects downstream
      * transaction_ordering */
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::with_stores(MemoryStore::new(), MemoryStore::new())
+    }
+}
+
 impl State {
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn update(&mut self, action: Action) -> Result<(), UpdateError> {
+    /// Create an empty state that keeps its records in the given stores
+    /// instead of in memory.
+    ///
+    /// # Panics
+    ///
+    /// If the account store already has accounts in it (which are counted
+    /// into `summary`) and fails to read them
+    pub fn with_stores<A, T>(accounts: A, transactions: T) -> Self
+    where
+        A: AccountStore + 'static,
+        T: TransactionStore + 'static,
+    {
+        let mut summary = AccountsSummary::default();
+        let mut expiring_locks = HashSet::new();
+        let accounts_empty = accounts.is_empty();
+        for entry in accounts.iter() {
+            let (client, account) = entry.expect("account store failed");
+            summary.add(&account);
+            if account.lock_expiry().is_some() {
+                expiring_locks.insert(client);
+            }
+        }
+        Self {
+            accounts: Box::new(accounts),
+            transactions: Box::new(transactions),
+            seen: SeenTransactions::default(),
+            forgotten: false,
+            references: HashMap::new(),
+            summary,
+            expiring_locks,
+            dispute_window: None,
+            limits: None,
+            chargeback_policy: None,
+            max_open_disputes: None,
+            currency: None,
+            withdrawals: WithdrawalHistory::default(),
+            audit_enabled: false,
+            audit: AuditTrail::default(),
+            running_balances: false,
+            output: OutputConfig::default(),
+            evidence: EvidenceLog::default(),
+            prepared: HashMap::new(),
+            events: Events::default(),
+            undo_log: Vec::new(),
+            open_savepoints: 0,
+            sequence: 0,
+            bulk_loading: false,
+            changed: HashMap::new(),
+            // Accounts already in the store were never reported as changes
+            changes_known_from: u64::from(!accounts_empty),
+            applying: false,
+        }
+    }
+
+    /// Limit how long transactions can be disputed for (or remove the limit
+    /// with `None`). Only disputes of later transactions are affected, any
+    /// existing disputes are left alone.
+    pub fn set_dispute_window(&mut self, window: Option<DisputeWindow>) {
+        self.dispute_window = window;
+    }
+
+    pub fn dispute_window(&self) -> Option<DisputeWindow> {
+        self.dispute_window
+    }
+
+    /// Enforce limits on deposits and withdrawals (or remove them with
+    /// `None`).
+    ///
+    /// The daily withdrawal limit goes by the actions' timestamps, or the
+    /// system clock for actions without one.
+    pub fn set_limits(&mut self, limits: Option<LimitsPolicy>) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Option<LimitsPolicy> {
+        self.limits
+    }
+
+    /// Send each step of every dispute (and every reversal) to `observer` as
+    /// it's applied, along with every account write if it asks for them, see
+    /// `events`. Replaces any earlier observer.
+    pub fn set_observer<O: EventObserver + 'static>(&mut self, observer: O) {
+        self.events.set_observer(Some(Box::new(observer)));
+    }
+
+    /// Stop sending dispute events
+    pub fn remove_observer(&mut self) {
+        self.events.set_observer(None);
+    }
+
+    /// Choose how disputes of deposits that have already been (partly)
+    /// withdrawn are handled, or go back to recording them as failed with
+    /// `None`
+    pub fn set_chargeback_policy(&mut self, policy: Option<ChargebackPolicy>) {
+        self.chargeback_policy = policy;
+    }
+
+    pub fn chargeback_policy(&self) -> Option<ChargebackPolicy> {
+        self.chargeback_policy
+    }
+
+    /// Limit how many disputes each client can have open at once (or remove
+    /// the limit with `None`). Disputes past the limit are rejected with
+    /// `UpdateError::DisputeLimitReached`, and observers are sent a
+    /// `DisputeEvent::DisputeRefused`, since a client disputing many
+    /// transactions at once is a common sign of friendly fraud. Disputes
+    /// that are already open are left alone.
+    pub fn set_max_open_disputes(&mut self, max: Option<usize>) {
+        self.max_open_disputes = max;
+    }
+
+    pub fn max_open_disputes(&self) -> Option<usize> {
+        self.max_open_disputes
+    }
+
+    /// Set the currency the ledger is kept in (e.g. `"USD"`), or stop
+    /// checking with `None`. Actions in another currency are rejected with
+    /// `UpdateError::CurrencyMismatch`. Actions that don't say which
+    /// currency they're in are taken to be in the ledger's.
+    pub fn set_currency(&mut self, currency: Option<String>) {
+        self.currency = currency;
+    }
+
+    pub fn currency(&self) -> Option<&str> {
+        self.currency.as_deref()
+    }
+
+    /// Record every action and status change in each client's audit trail
+    /// (for `export_client`), or stop recording them. The trail grows with
+    /// every action, so it's off by default.
+    pub fn set_audit_trail(&mut self, enabled: bool) {
+        self.audit_enabled = enabled;
+    }
+
+    pub fn audit_trail_enabled(&self) -> bool {
+        self.audit_enabled
+    }
+
+    /// Keep the account's balances straight after each new deposit,
+    /// withdrawal and reversal on its `Transaction`, so statements and
+    /// investigations can show them without replaying the history. This
+    /// roughly doubles the size of each stored transaction, so it's off by
+    /// default. Transactions recorded while it was off don't have them.
+    pub fn set_running_balances(&mut self, enabled: bool) {
+        self.running_balances = enabled;
+    }
+
+    pub fn running_balances_enabled(&self) -> bool {
+        self.running_balances
+    }
+
+    /// Choose how balances are rounded in the `AccountData` from `account`
+    /// and `accounts` (e.g. to 2 places with banker's rounding). The
+    /// accounts themselves keep their full precision.
+    pub fn set_output(&mut self, output: OutputConfig) {
+        self.output = output;
+    }
+
+    pub fn output(&self) -> OutputConfig {
+        self.output
+    }
+
+    pub fn update(&mut self, mut action: Action) -> Result<(), UpdateError> {
+        let resolved = self
+            .resolve_reference(&action)
+            .map(|id| action.transaction_id = Some(id));
+        self.update_resolved(&action, resolved)
+    }
+
+    /// Apply an action without taking it, for sources that keep their
+    /// actions (e.g. parsed batches reused between reads). Nothing is cloned
+    /// unless the action's transaction id has to be filled in from its
+    /// reference.
+    pub fn update_ref(&mut self, action: &Action) -> Result<(), UpdateError> {
+        match self.resolve_reference(action) {
+            Ok(id) if Some(id) != action.transaction_id => {
+                let action = Action {
+                    transaction_id: Some(id),
+                    ..action.clone()
+                };
+                self.update_resolved(&action, Ok(()))
+            }
+            resolved => self.update_resolved(action, resolved.map(drop)),
+        }
+    }
+
+    fn update_resolved(
+        &mut self,
+        action: &Action,
+        resolved: Result<(), UpdateError>,
+    ) -> Result<(), UpdateError> {
+        if self.open_savepoints > 0 && resolved.is_ok() {
+            let undo = Undo::action(self, action)?;
+            self.undo_log.push(undo);
+        }
+        if !self.audit_enabled {
+            return resolved.and_then(|()| self.apply(action));
+        }
+
+        // A reference-only action that couldn't be resolved has no id
+        let (client, id) = (action.client_id, action.transaction_id);
+        let before = match id {
+            Some(id) => self.transactions.get(id)?.map(|t| t.state),
+            None => None,
+        };
+        let at = action.timestamp.unwrap_or_else(Timestamp::now);
+        let event = |outcome| AuditEvent::Action {
+            kind: action.kind,
+            transaction: id,
+            amount: action.amount,
+            outcome,
+            meta: action.meta.clone(),
+        };
+        let (event, result) = match resolved.and_then(|()| self.apply(action)) {
+            // Only storage failures stop the action from being audited
+            Err(UpdateError::Store(e)) => return Err(e.into()),
+            Err(e) => (event(Outcome::Rejected(e.to_string())), Err(e)),
+            Ok(()) => match self.check_not_failed(action.id(), before) {
+                Err(UpdateError::TransactionFailed { error, .. }) => {
+                    (event(Outcome::Failed(error)), Ok(()))
+                }
+                Err(e) => return Err(e),
+                Ok(()) => (event(Outcome::Applied), Ok(())),
+            },
+        };
+        let balances = self
+            .accounts
+            .get(client)?
+            .map(|account| Balances::from(&account));
+        self.record_audit(client, at, event, balances);
+        result
+    }
+
+    /// Fill in the transaction id of an action with an external reference.
+    ///
+    /// New transactions without a `tx` are given one past the highest id
+    /// claimed so far, so inputs shouldn't mix assigned and explicit ids.
+    /// Actions on existing transactions look the reference up, and any `tx`
+    /// they also have has to match it.
+    fn resolve_reference(&self, action: &Action) -> Result<TransactionId, UpdateError> {
+        let Some(reference) = &action.reference else {
+            return action.transaction_id.ok_or(UpdateError::NoTransactionId);
+        };
+
+        if action.kind.creates_transaction() {
+            if self.references.contains_key(reference) {
+                return Err(UpdateError::ReferenceUsed(reference.clone()));
+            }
+            return match action.transaction_id {
+                Some(id) => Ok(id),
+                None => self
+                    .seen
+                    .next_free()
+                    .ok_or(UpdateError::NoFreeTransactionId),
+            };
+        }
+
+        let id = *self
+            .references
+            .get(reference)
+            .ok_or_else(|| UpdateError::ReferenceMissing(reference.clone()))?;
+        if action.transaction_id.is_some_and(|given| given != id) {
+            return Err(UpdateError::ReferenceMismatch {
+                reference: reference.clone(),
+                transaction: id,
+            });
+        }
+        Ok(id)
+    }
+
+    /// Apply an action that's had its reference resolved. A reference is
+    /// claimed along with the new transaction's id, so it stays claimed
+    /// whenever the id does (even for a rejected deposit).
+    fn apply(&mut self, action: &Action) -> Result<(), UpdateError> {
+        Self::check_up_front(action, self.currency())?;
+        self.sequence += 1;
+        self.applying = true;
+        let result = self.apply_counted(action);
+        self.applying = false;
+        result
+    }
+
+    fn apply_counted(&mut self, action: &Action) -> Result<(), UpdateError> {
+        if self.expiring_locks.contains(&action.client_id) {
+            self.expire_lock(action)?;
+        }
+        let claim = action
+            .reference
+            .clone()
+            .filter(|_| action.kind.creates_transaction())
+            .filter(|_| !self.seen.contains(action.id()));
+        let id = action.id();
+        let result = self.apply_resolved(action);
+        if let Some(reference) = claim {
+            if self.seen.contains(id) {
+                self.references.insert(reference, id);
+            }
+        }
+        result
+    }
+
+    /// The checks an action has to pass before it can take its transaction
+    /// id, which the parallel processors also make before claiming one for it
+    pub(crate) fn check_up_front(
+        action: &Action,
+        currency: Option<&str>,
+    ) -> Result<(), UpdateError> {
+        if action.client_id == ClientId::TOMBSTONE {
+            return Err(UpdateError::ReservedClient(action.client_id));
+        }
+        match (currency, &action.currency) {
+            (Some(expected), Some(got)) if expected != got => Err(UpdateError::CurrencyMismatch {
+                expected: expected.to_owned(),
+                got: got.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count an action against its account's temporary freeze, unfreezing
+    /// the account before the action is applied if the freeze has run out
+    fn expire_lock(&mut self, action: &Action) -> Result<(), StoreError> {
+        let client = action.client_id;
+        let Some(mut account) = self.accounts.get(client)? else {
+            return Ok(());
+        };
+        let at = action.timestamp.unwrap_or_else(Timestamp::now);
+        let from = account.status().name();
+        if !account.tick_lock(at) {
+            return self.put_account(client, account);
+        }
+        if self.audit_enabled {
+            let event = AuditEvent::StatusChanged {
+                from: from.into(),
+                to: account.status().name().into(),
+                quarantined: account.is_quarantined(),
+            };
+            self.record_audit(client, at, event, Some(Balances::from(&account)));
+        }
+        self.put_account(client, account)?;
+        self.emit(DisputeEvent::Unlocked { client, at });
+        Ok(())
+    }
+
+    fn apply_resolved(&mut self, action: &Action) -> Result<(), UpdateError> {
+        let id = action.id();
         match action.kind {
             ActionKind::Deposit => {
                 let amount = action.amount.ok_or(UpdateError::NoAmount)?;
 
-                // TODO: I'm not super excited about the entry API/match usage for transaction
-                // here (and in Withdrawal), but I think it's be two lookups to
-                // do a `contains` and `insert`, so this may be better?
-                let account = self.accounts.entry(action.client_id);
-                let transaction = self.transactions.entry(action.transaction_id);
-
                 // Should be a new transaction
-                if matches!(transaction, Entry::Occupied(_)) {
-                    return Err(UpdateError::TransactionUsed(action.transaction_id));
+                if self.is_claimed(id)? {
+                    return Err(UpdateError::TransactionUsed(id));
+                }
+
+                if let Some(limits) = &self.limits {
+                    if let Err(e) = limits.check_deposit(amount) {
+                        self.seen.insert(id);
+                        return Err(e);
+                    }
                 }
 
                 // Try doing the deposit
-                let state = match account.or_default().deposit(amount) {
+                let mut account = self.accounts.get(action.client_id)?.unwrap_or_default();
+                let state = match account.deposit(amount) {
                     Ok(()) => TransactionState::Succeeded,
                     Err(e) => TransactionState::Failed(e),
                 };
+                let balances = self.running_balances(&account);
+                self.put_account(action.client_id, account)?;
 
                 // Add the transaction
-                transaction.or_insert(Transaction {
-                    id: action.transaction_id,
-                    client: action.client_id,
-                    state,
-                    amount,
-                });
+                self.transactions.put(
+                    id,
+                    Transaction {
+                        id,
+                        client: action.client_id,
+                        state,
+                        amount,
+                        timestamp: action.timestamp,
+                        reverses: None,
+                        reference: action.reference.clone(),
+                        meta: action.meta.clone(),
+                        balances,
+                        history: Vec::new(),
+                        format: Transaction::FORMAT,
+                    },
+                )?;
+                self.seen.insert(id);
             }
             ActionKind::Withdrawal => {
                 let amount = action.amount.ok_or(UpdateError::NoAmount)?;
 
-                let account = self.accounts.entry(action.client_id);
-                let transaction = self.transactions.entry(action.transaction_id);
-
                 // Should be a new transaction
-                if matches!(transaction, Entry::Occupied(_)) {
-                    return Err(UpdateError::TransactionUsed(action.transaction_id));
+                if self.is_claimed(id)? {
+                    return Err(UpdateError::TransactionUsed(id));
+                }
+
+                let at = action.timestamp.unwrap_or_else(Timestamp::now);
+                if let Some(limits) = &self.limits {
+                    let withdrawn_today =
+                        self.withdrawals.total_in_day_before(action.client_id, at);
+                    if let Err(e) = limits.check_withdrawal(amount, withdrawn_today) {
+                        self.seen.insert(id);
+                        return Err(e);
+                    }
                 }
 
                 // Try doing the withdrawl
                 // TODO: a withdrawl from an empty account will fail due to
                 // insufficient funds. Is that good enough?
-                let state = match account.or_default().withdraw(amount) {
+                let mut account = self.accounts.get(action.client_id)?.unwrap_or_default();
+                let withdrawn = match &action.category {
+                    Some(category) => account.withdraw_from(category, amount),
+                    None => account.withdraw(amount),
+                };
+                let state = match withdrawn {
                     Ok(()) => TransactionState::Succeeded,
                     Err(e) => TransactionState::Failed(e),
                 };
+                let balances = self.running_balances(&account);
+                self.put_account(action.client_id, account)?;
+                let daily_limit = self.limits.and_then(|l| l.max_daily_withdrawal);
+                if daily_limit.is_some() && state == TransactionState::Succeeded {
+                    self.withdrawals.record(action.client_id, at, amount);
+                }
 
                 // Add the transaction
-                transaction.or_insert(Transaction {
-                    id: action.transaction_id,
-                    client: action.client_id,
-                    state,
-                    amount: -amount,
-                });
+                self.transactions.put(
+                    id,
+                    Transaction {
+                        id,
+                        client: action.client_id,
+                        state,
+                        amount: -amount,
+                        timestamp: action.timestamp,
+                        reverses: None,
+                        reference: action.reference.clone(),
+                        meta: action.meta.clone(),
+                        balances,
+                        history: Vec::new(),
+                        format: Transaction::FORMAT,
+                    },
+                )?;
+                self.seen.insert(id);
             }
             ActionKind::Dispute => {
-                let transaction = self
-                    .transactions
-                    .get_mut(&action.transaction_id)
-                    .ok_or(UpdateError::TransactionMissing(action.transaction_id))?;
+                let mut transaction = self.existing_transaction(id)?;
 
                 if action.client_id != transaction.client {
                     return Err(UpdateError::ClientMismatch {
@@ -89,31 +581,131 @@ impl State {
                     });
                 }
 
-                let account = self
-                    .accounts
-                    .get_mut(&action.client_id)
-                    .ok_or(UpdateError::AccountMissing(action.client_id))?;
+                // Reversed transactions no longer have any funds to dispute,
+                // and disputed ones already have theirs held
+                if matches!(
+                    transaction.state,
+                    TransactionState::Reversed(_) | TransactionState::Disputed
+                ) {
+                    return Ok(());
+                }
+                // Failed ones never moved any funds, and charged back ones
+                // have already been taken back
+                if matches!(
+                    transaction.state,
+                    TransactionState::Failed(_) | TransactionState::Cancelled
+                ) {
+                    return Err(UpdateError::NotDisputable(id));
+                }
+
+                if let Some(window) = &self.dispute_window {
+                    window.check(&transaction, action.timestamp)?;
+                }
+
+                let mut account = self.existing_account(action.client_id)?;
 
                 // Try to hold the funds (if it was a deposit)
                 // TODO: what if the transaction was a withdrawl? Is this error type sufficient?
 
                 if transaction.amount.is_sign_positive() {
-                    transaction.state = match account.hold(transaction.amount) {
-                        Ok(()) => TransactionState::Disputed,
-                        Err(e) => TransactionState::Failed(e),
+                    let open = self.open_disputes(&account);
+                    if let Some(max) = self.max_open_disputes.filter(|max| open >= *max) {
+                        self.emit(DisputeEvent::DisputeRefused {
+                            client: action.client_id,
+                            transaction: id,
+                            open,
+                        });
+                        return Err(UpdateError::DisputeLimitReached {
+                            client: action.client_id,
+                            max,
+                        });
+                    }
+
+                    let hold = HoldId(id);
+                    let held = match self.chargeback_policy {
+                        Some(ChargebackPolicy::AllowNegative) => {
+                            account.hold_allowing_negative(hold, transaction.amount)
+                        }
+                        _ => account.hold(hold, transaction.amount),
                     };
+                    if let (Some(ChargebackPolicy::Strict), Err(AccountError::InsufficientFunds)) =
+                        (self.chargeback_policy, held)
+                    {
+                        return Err(UpdateError::DisputeExceedsAvailable {
+                            transaction: id,
+                            amount: transaction.amount,
+                            available: account.available_funds(),
+                        });
+                    }
+                    // A refused hold leaves the transaction as it was
+                    transaction.history.push(TransactionEvent::new(
+                        self.sequence,
+                        TransactionEventKind::Disputed,
+                        action.timestamp,
+                        held,
+                    ));
+                    if held.is_ok() {
+                        transaction.state = TransactionState::Disputed;
+                        self.emit(DisputeEvent::Opened {
+                            client: action.client_id,
+                            transaction: id,
+                            amount: transaction.amount,
+                            at: action.timestamp,
+                        });
+                        self.emit(DisputeEvent::FundsHeld {
+                            client: action.client_id,
+                            transaction: id,
+                            amount: transaction.amount,
+                            balances: Balances::from(&account),
+                        });
+                    }
+                    self.put_account(action.client_id, account)?;
+                    self.transactions.put(id, transaction)?;
+                    self.record_evidence(action);
                 }
             }
             ActionKind::Resolve => {
-                let transaction = self
-                    .transactions
-                    .get_mut(&action.transaction_id)
-                    .ok_or(UpdateError::TransactionMissing(action.transaction_id))?;
+                let mut transaction = self.existing_transaction(id)?;
+
+                if action.client_id != transaction.client {
+                    return Err(UpdateError::ClientMismatch {
+                        action: action.client_id,
+                        transaction: transaction.client,
+                    });
+                }
 
                 // Transaction must be disputed to be resolved
                 if !matches!(transaction.state, TransactionState::Disputed) {
-                    return Ok(());
+                    return Err(UpdateError::NotDisputed(id));
+                }
+
+                let mut account = self.existing_account(action.client_id)?;
+                let hold = HoldId(id);
+                account.adopt_hold(hold, transaction.amount);
+
+                // A refused release leaves the transaction disputed
+                let released = account.release(hold).map(drop);
+                transaction.history.push(TransactionEvent::new(
+                    self.sequence,
+                    TransactionEventKind::Resolved,
+                    action.timestamp,
+                    released,
+                ));
+                if released.is_ok() {
+                    transaction.state = TransactionState::Succeeded;
+                    self.emit(DisputeEvent::Resolved {
+                        client: action.client_id,
+                        transaction: id,
+                        amount: transaction.amount,
+                        balances: Balances::from(&account),
+                    });
                 }
+                self.put_account(action.client_id, account)?;
+                self.transactions.put(id, transaction)?;
+                self.record_evidence(action);
+            }
+            ActionKind::Chargeback => {
+                let mut transaction = self.existing_transaction(id)?;
 
                 if action.client_id != transaction.client {
                     return Err(UpdateError::ClientMismatch {
@@ -122,153 +714,2239 @@ impl State {
                     });
                 }
 
-                let account = self
-                    .accounts
-                    .get_mut(&action.client_id)
-                    .ok_or(UpdateError::AccountMissing(action.client_id))?;
+                // Transaction must be disputed to be charged back
+                if !matches!(transaction.state, TransactionState::Disputed) {
+                    return Err(UpdateError::NotDisputed(id));
+                }
 
-                transaction.state = match account.release(transaction.amount) {
-                    Ok(()) => TransactionState::Succeeded,
-                    Err(e) => TransactionState::Failed(e),
-                };
+                let mut account = self.existing_account(action.client_id)?;
+                let hold = HoldId(id);
+                account.adopt_hold(hold, transaction.amount);
+
+                // A refused chargeback leaves the transaction disputed
+                let charged_back = account.chargeback(hold).map(drop);
+                transaction.history.push(TransactionEvent::new(
+                    self.sequence,
+                    TransactionEventKind::ChargedBack,
+                    action.timestamp,
+                    charged_back,
+                ));
+                // Already frozen or closed accounts keep their current status
+                let _ = account.freeze(FreezeReason::Chargeback);
+                if charged_back.is_ok() {
+                    transaction.state = TransactionState::Cancelled;
+                    self.emit(DisputeEvent::ChargedBack {
+                        client: action.client_id,
+                        transaction: id,
+                        amount: transaction.amount,
+                        balances: Balances::from(&account),
+                    });
+                }
+                self.put_account(action.client_id, account)?;
+                self.transactions.put(id, transaction)?;
+                self.record_evidence(action);
             }
-            ActionKind::Chargeback => {
-                let transaction = self
-                    .transactions
-                    .get_mut(&action.transaction_id)
-                    .ok_or(UpdateError::TransactionMissing(action.transaction_id))?;
+            ActionKind::Reversal => {
+                let target = action.reverses.ok_or(UpdateError::NoReversalTarget)?;
 
-                // Transaction must be disputed to be resolved
-                if !matches!(transaction.state, TransactionState::Disputed) {
-                    return Ok(());
+                // The reversal is recorded as a new transaction of its own
+                if self.is_claimed(id)? {
+                    return Err(UpdateError::TransactionUsed(id));
                 }
+                // The id is used up even if the reversal turns out to be invalid,
+                // since the parallel processor has to claim ids without knowing
+                self.seen.insert(id);
 
-                if action.client_id != transaction.client {
+                let mut original = self.existing_transaction(target)?;
+
+                if action.client_id != original.client {
                     return Err(UpdateError::ClientMismatch {
                         action: action.client_id,
-                        transaction: transaction.client,
+                        transaction: original.client,
                     });
                 }
 
-                let account = self
-                    .accounts
-                    .get_mut(&action.client_id)
-                    .ok_or(UpdateError::AccountMissing(action.client_id))?;
+                // Only settled deposits and withdrawals can be reversed
+                if original.state != TransactionState::Succeeded || original.reverses.is_some() {
+                    return Err(UpdateError::NotReversible(target));
+                }
 
-                transaction.state = match account.chargeback(transaction.amount) {
-                    Ok(()) => TransactionState::Cancelled,
+                let mut account = self.existing_account(action.client_id)?;
+
+                let reversed = account.reverse(original.amount);
+                let state = match reversed {
+                    Ok(()) => {
+                        original.state = TransactionState::Reversed(id);
+                        TransactionState::Succeeded
+                    }
                     Err(e) => TransactionState::Failed(e),
                 };
-                account.lock();
+                original.history.push(TransactionEvent::new(
+                    self.sequence,
+                    TransactionEventKind::Reversed(id),
+                    action.timestamp,
+                    reversed,
+                ));
+                let amount = -original.amount;
+                if state == TransactionState::Succeeded {
+                    self.emit(DisputeEvent::Reversed {
+                        client: action.client_id,
+                        transaction: id,
+                        reverses: target,
+                        amount,
+                        balances: Balances::from(&account),
+                    });
+                }
+                let balances = self.running_balances(&account);
+                self.put_account(action.client_id, account)?;
+                self.transactions.put(target, original)?;
+
+                self.transactions.put(
+                    id,
+                    Transaction {
+                        id,
+                        client: action.client_id,
+                        state,
+                        amount,
+                        timestamp: action.timestamp,
+                        reverses: Some(target),
+                        reference: action.reference.clone(),
+                        meta: action.meta.clone(),
+                        balances,
+                        history: Vec::new(),
+                        format: Transaction::FORMAT,
+                    },
+                )?;
             }
         }
 
         Ok(())
     }
 
-    pub fn accounts(&self) -> AccountsIter<'_> {
-        AccountsIter(self.accounts.iter())
+    /// An account's balances to keep on a new transaction, if they're being
+    /// kept
+    fn running_balances(&self, account: &Account) -> Option<Balances> {
+        self.running_balances.then(|| Balances::from(account))
     }
 
-    pub fn failed_transactions(&self) -> impl Iterator<Item = &Transaction> {
-        self.transactions
-            .values()
-            .filter(|t| matches!(t.state, TransactionState::Failed(_)))
+    /// Add an entry to a client's audit trail, at the current sequence
+    fn record_audit(
+        &mut self,
+        client: ClientId,
+        at: Timestamp,
+        event: AuditEvent,
+        balances: Option<Balances>,
+    ) {
+        let entry = AuditEntry {
+            at,
+            seq: self.sequence,
+            event,
+            balances,
+        };
+        self.audit.record(client, entry);
     }
-}
-
-// Yeah, we could probably just return a vec, but where's the fun in that?
-pub struct AccountsIter<'a>(std::collections::hash_map::Iter<'a, ClientId, Account>);
 
-impl<'a> Iterator for AccountsIter<'a> {
-    type Item = AccountData;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.next().map(AccountData::from)
+    /// How many disputes are holding funds in an account: every hold that
+    /// isn't for a prepared withdrawal
+    fn open_disputes(&self, account: &Account) -> usize {
+        account
+            .holds()
+            .keys()
+            .filter(|hold| !self.prepared.contains_key(&hold.0))
+            .count()
     }
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        self.0.size_hint()
-    }
-}
-impl<'a> ExactSizeIterator for AccountsIter<'a> {
-    fn len(&self) -> usize {
-        self.0.len()
+
+    fn emit(&mut self, event: DisputeEvent) {
+        self.events.emit(event, self.open_savepoints > 0);
     }
-}
 
-#[derive(Debug, thiserror::Error)]
-pub enum UpdateError {
-    #[error(
-        "A deposit or withdrawl as requested with the same id ({0}) as an existing transaction"
-    )]
-    TransactionUsed(TransactionId),
+    /// Keep any evidence that came with an action on a disputed transaction
+    fn record_evidence(&mut self, action: &Action) {
+        if let Some(reference) = &action.evidence {
+            self.evidence.record(
+                action.id(),
+                Evidence {
+                    at: action.timestamp.unwrap_or_else(Timestamp::now),
+                    kind: action.kind,
+                    reference: reference.clone(),
+                },
+            );
+        }
+    }
 
-    #[error(
-        "An action on an existing transaction was requested but transaction {0} does not exist"
-    )]
-    TransactionMissing(TransactionId),
+    /// Whether a transaction id has already been used. The seen set is
+    /// cheaper to check and also covers transactions that have been forgotten,
+    /// but the store still has to be checked in case it was populated
+    /// separately.
+    fn is_claimed(&self, id: TransactionId) -> Result<bool, StoreError> {
+        Ok(self.seen.contains(id) || (!self.bulk_loading && self.transactions.contains(id)?))
+    }
 
-    // Technically this shouldn't happen?
-    #[error("An action on an existing account was requested but account {0} does not exist")]
-    AccountMissing(ClientId),
+    fn existing_transaction(&self, id: TransactionId) -> Result<Transaction, UpdateError> {
+        self.transactions
+            .get(id)?
+            .ok_or(UpdateError::TransactionMissing(id))
+    }
 
-    #[error("The action and transaction it points two reference different clients (action: {action}, transaction: {transaction})")]
-    ClientMismatch {
-        action: ClientId,
-        transaction: ClientId,
-    },
+    fn existing_account(&self, client: ClientId) -> Result<Account, UpdateError> {
+        self.accounts
+            .get(client)?
+            .ok_or(UpdateError::AccountMissing(client))
+    }
 
-    #[error("A deposit or withdrawl was requested with no amount")]
-    NoAmount,
-}
+    /// Whether nothing has been applied to the state yet (configuration
+    /// aside), and no savepoint is open
+    pub(crate) fn is_pristine(&self) -> bool {
+        self.accounts.is_empty()
+            && self.transactions.is_empty()
+            && self.seen.is_empty()
+            && self.references.is_empty()
+            && self.open_savepoints == 0
+    }
 
-// TODO: should this be in the engine module? Or maybe in it's own module?
-#[cfg(test)]
-mod tests {
-    use crate::{Action, ActionKind, ClientId, SingleThreadedEngine, SyncEngine, TransactionId};
+    /// Fill an empty state from a large set of historical actions that are
+    /// already known to be good (e.g. an archive being loaded into a new
+    /// engine), much faster than calling `update` for each one.
+    ///
+    /// The actions must be in the order they should be applied, and every one
+    /// of them should succeed. Checks that only matter for live traffic are
+    /// skipped: limits and the dispute window aren't enforced, withdrawals
+    /// aren't added to the daily limit's history, and nothing is recorded for
+    /// savepoints or the audit trail. The stores are also grown up front from
+    /// the iterator's size hint.
+    ///
+    /// Ordering and failures are only checked once everything is loaded, so
+    /// after an error the state is left part way and should be thrown away.
+    /// Returns the number of actions loaded.
+    pub fn bulk_load<I>(&mut self, actions: I) -> Result<u64, BulkLoadError>
+    where
+        I: IntoIterator<Item = Action>,
+    {
+        if !self.is_pristine() {
+            return Err(BulkLoadError::NotEmpty);
+        }
 
-    #[cfg(feature = "decimal")]
-    use rust_decimal_macros::dec;
+        let limits = self.limits.take();
+        let dispute_window = self.dispute_window.take();
+        self.bulk_loading = true;
+        let result = self.bulk_apply(actions.into_iter());
+        self.bulk_loading = false;
+        self.limits = limits;
+        self.dispute_window = dispute_window;
+        let loaded = result?;
 
-    // Macro for some terseness in tests
-    macro_rules! action {
-        ($kind:ident, $client:expr, $transaction:expr) => {
-            Action {
-                transaction_id: TransactionId($transaction),
-                client_id: ClientId($client),
-                kind: ActionKind::$kind,
-                amount: None,
+        let mut failed = 0;
+        for entry in self.transactions.iter() {
+            if entry?.1.failures().next().is_some() {
+                failed += 1;
             }
-        };
-        ($kind:ident, $client:expr, $transaction:expr, $amount:expr) => {
-            Action {
-                transaction_id: TransactionId($transaction),
-                client_id: ClientId($client),
-                kind: ActionKind::$kind,
+        }
+        if failed > 0 {
+            return Err(BulkLoadError::FailedTransactions(failed));
+        }
+        Ok(loaded)
+    }
 
-                #[cfg(feature = "decimal")]
-                amount: Some(dec!($amount)),
+    fn bulk_apply<I>(&mut self, actions: I) -> Result<u64, BulkLoadError>
+    where
+        I: Iterator<Item = Action>,
+    {
+        self.transactions.reserve(actions.size_hint().0);
 
-                #[cfg(not(feature = "decimal"))]
-                amount: Some($amount),
+        let mut latest = None;
+        let mut out_of_order = None;
+        let mut rejected = None;
+        let mut rejected_count = 0;
+        let mut loaded = 0;
+        for (index, mut action) in (0..).zip(actions) {
+            if let Some(at) = action.timestamp {
+                if latest > Some(at) {
+                    out_of_order.get_or_insert(index);
+                }
+                latest = latest.max(Some(at));
             }
-        };
-    }
 
-    #[test]
-    fn test_account_is_created() {
-        let mut engine = SingleThreadedEngine::new();
-        let _ = engine.process_all(vec![
-            action!(Deposit, 1, 1, 1.5),
-            action!(Withdrawal, 1, 2, 1.0),
-        ]);
+            match self.resolve_reference(&action).and_then(|id| {
+                action.transaction_id = Some(id);
+                self.apply(&action)
+            }) {
+                Ok(()) => loaded += 1,
+                // There's no point carrying on if the backend is down
+                Err(UpdateError::Store(e)) => return Err(e.into()),
+                Err(e) => {
+                    rejected_count += 1;
+                    rejected.get_or_insert((index, e));
+                }
+            }
+        }
 
-        let account = engine.state().accounts().next().expect("no account!");
-        assert_eq!(account.total.to_string(), "0.5");
+        if let Some(index) = out_of_order {
+            return Err(BulkLoadError::OutOfOrder { index });
+        }
+        if let Some((index, source)) = rejected {
+            return Err(BulkLoadError::Rejected {
+                index,
+                count: rejected_count,
+                source,
+            });
+        }
+        Ok(loaded)
     }
 
-    #[test]
-    fn test_disputes_can_be_resolved() {
-        let mut engine = SingleThreadedEngine::new();
+    /// Fill an empty state from trusted history as fast as possible, e.g. to
+    /// replay an archive that was already processed once. Returns the number
+    /// of actions applied.
+    ///
+    /// This goes further than `bulk_load`: the batch was checked when it was
+    /// made, and the accounts and transactions are built up in memory and
+    /// only written to the stores at the end. Transaction ids are taken to be
+    /// unique, disputes over the open dispute limit aren't refused, and
+    /// actions that `update` would reject (like a dispute of a transaction
+    /// that doesn't exist) are skipped without saying so. As with
+    /// `bulk_load`, limits and the dispute window aren't enforced, and
+    /// nothing is sent to observers or recorded in the audit trail.
+    pub fn ingest_unchecked(&mut self, batch: TrustedBatch) -> Result<u64, BulkLoadError> {
+        if !self.is_pristine() {
+            return Err(BulkLoadError::NotEmpty);
+        }
+
+        let mut accounts: HashMap<ClientId, Account> = HashMap::new();
+        let mut transactions: HashMap<TransactionId, Transaction> =
+            HashMap::with_capacity(batch.len());
+        let mut applied = 0;
+        let first = self.sequence + 1;
+        // Every action is counted, as `update` counts rejected ones
+        self.sequence += batch.len() as u64;
+        for (seq, action) in (first..).zip(batch.actions) {
+            let (client, id) = (action.client, action.transaction);
+            match action.kind {
+                ActionKind::Deposit | ActionKind::Withdrawal => {
+                    let account = accounts.entry(client).or_default();
+                    let (done, amount) = match action.kind {
+                        ActionKind::Deposit => (account.deposit(action.amount), action.amount),
+                        _ => (account.withdraw(action.amount), -action.amount),
+                    };
+                    let state = match done {
+                        Ok(()) => TransactionState::Succeeded,
+                        Err(e) => TransactionState::Failed(e),
+                    };
+                    let balances = self.running_balances(account);
+                    transactions.insert(
+                        id,
+                        Transaction {
+                            id,
+                            client,
+                            state,
+                            amount,
+                            timestamp: action.timestamp,
+                            reverses: None,
+                            reference: None,
+                            meta: None,
+                            balances,
+                            history: Vec::new(),
+                            format: Transaction::FORMAT,
+                        },
+                    );
+                    self.seen.insert(id);
+                }
+                ActionKind::Dispute | ActionKind::Resolve | ActionKind::Chargeback => {
+                    let Some(transaction) = transactions
+                        .get_mut(&id)
+                        .filter(|transaction| transaction.client == client)
+                    else {
+                        continue;
+                    };
+                    let Some(account) = accounts.get_mut(&client) else {
+                        continue;
+                    };
+                    let hold = HoldId(id);
+                    let (kind, done) = match action.kind {
+                        ActionKind::Dispute
+                            if transaction.amount.is_sign_positive()
+                                && transaction.state == TransactionState::Succeeded =>
+                        {
+                            let held = match self.chargeback_policy {
+                                Some(ChargebackPolicy::AllowNegative) => {
+                                    account.hold_allowing_negative(hold, transaction.amount)
+                                }
+                                _ => account.hold(hold, transaction.amount),
+                            };
+                            if let (Some(ChargebackPolicy::Strict), Err(_)) =
+                                (self.chargeback_policy, held)
+                            {
+                                continue;
+                            }
+                            let state = held.map(|()| TransactionState::Disputed);
+                            (TransactionEventKind::Disputed, state)
+                        }
+                        ActionKind::Resolve if transaction.state == TransactionState::Disputed => {
+                            let state = account.release(hold).map(|_| TransactionState::Succeeded);
+                            (TransactionEventKind::Resolved, state)
+                        }
+                        ActionKind::Chargeback
+                            if transaction.state == TransactionState::Disputed =>
+                        {
+                            let state = account
+                                .chargeback(hold)
+                                .map(|_| TransactionState::Cancelled);
+                            let _ = account.freeze(FreezeReason::Chargeback);
+                            (TransactionEventKind::ChargedBack, state)
+                        }
+                        _ => continue,
+                    };
+                    // Refused steps leave the transaction as it was
+                    transaction.history.push(TransactionEvent::new(
+                        seq,
+                        kind,
+                        action.timestamp,
+                        done.map(drop),
+                    ));
+                    if let Ok(state) = done {
+                        transaction.state = state;
+                    }
+                }
+                ActionKind::Reversal => continue,
+            }
+            applied += 1;
+        }
+
+        self.transactions.reserve(transactions.len());
+        for (id, transaction) in transactions {
+            self.transactions.put(id, transaction)?;
+        }
+        self.accounts.reserve(accounts.len());
+        // Counted as changes at the last action, rather than the next one
+        self.applying = true;
+        let written = accounts
+            .into_iter()
+            .try_for_each(|(client, account)| self.put_account(client, account));
+        self.applying = false;
+        written?;
+        Ok(applied)
+    }
+
+    /// Apply a group of actions all-or-nothing (e.g. a withdrawal and its
+    /// fee).
+    ///
+    /// If any action is rejected, or its transaction fails (e.g. a withdrawal
+    /// with insufficient funds), everything the group changed is rolled back
+    /// and the error for that action is returned.
+    pub fn update_atomic(&mut self, actions: &[Action]) -> Result<(), GroupError> {
+        let savepoint = self.savepoint();
+        for (index, action) in actions.iter().enumerate() {
+            // Resolved the same way `update_ref` will, to find the
+            // transaction it ends up on
+            let result = match self.resolve_reference(action) {
+                Ok(id) => self
+                    .transactions
+                    .get(id)
+                    .map_err(UpdateError::from)
+                    .and_then(|before| {
+                        self.update_ref(action)?;
+                        self.check_not_failed(id, before.map(|t| t.state))
+                    }),
+                Err(_) => self.update_ref(action),
+            };
+
+            if let Err(source) = result {
+                // If the rollback itself fails, that's the more pressing error
+                let source = match self.rollback_to(savepoint) {
+                    Ok(()) => source,
+                    Err(e) => e.into(),
+                };
+                return Err(GroupError { index, source });
+            }
+        }
+        self.release(savepoint);
+        Ok(())
+    }
+
+    /// Failed transactions and refused dispute steps are still recorded, so
+    /// `update` doesn't report them as errors
+    fn check_not_failed(
+        &self,
+        id: TransactionId,
+        before: Option<TransactionState>,
+    ) -> Result<(), UpdateError> {
+        let Some(transaction) = self.transactions.get(id)? else {
+            return Ok(());
+        };
+        let failed = match transaction.state {
+            TransactionState::Failed(error) if Some(transaction.state) != before => Some(error),
+            // A step of the last action on it
+            _ => transaction
+                .history
+                .last()
+                .filter(|event| event.seq == self.sequence)
+                .and_then(|event| event.failed),
+        };
+        match failed {
+            Some(error) => Err(UpdateError::TransactionFailed {
+                transaction: id,
+                error,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Validate a withdrawal and reserve its funds, without applying it yet,
+    /// for coordinating with an external system (e.g. a payout provider).
+    ///
+    /// The withdrawal has to pass every check `update` would make. If it
+    /// does, the amount is moved from the available to the held balance and
+    /// the transaction id (and reference) are claimed, so nothing else can
+    /// spend the funds or reuse the id. It then has to be finished with
+    /// `commit` or `abort`. If it doesn't, nothing is changed or recorded and
+    /// the reason is returned (with `TransactionFailed` for reasons that
+    /// `update` would record as a failed transaction). A withdrawal without a
+    /// timestamp is given the time it was prepared.
+    ///
+    /// Two-phase withdrawals can't be mixed with savepoints.
+    pub fn prepare(&mut self, mut action: Action) -> Result<PreparedAction, UpdateError> {
+        if self.open_savepoints > 0 {
+            return Err(UpdateError::SavepointOpen);
+        }
+        action.transaction_id = Some(self.resolve_reference(&action)?);
+        if action.kind != ActionKind::Withdrawal {
+            return Err(UpdateError::NotPreparable(action.kind));
+        }
+        Self::check_up_front(&action, self.currency())?;
+        let amount = action.amount.ok_or(UpdateError::NoAmount)?;
+        let id = action.id();
+        if self.is_claimed(id)? {
+            return Err(UpdateError::TransactionUsed(id));
+        }
+
+        // Kept with the prepared action, so `abort` can find it in the
+        // withdrawal history again
+        let at = *action.timestamp.get_or_insert_with(Timestamp::now);
+        let daily_limit = self.limits.and_then(|l| l.max_daily_withdrawal);
+        if let Some(limits) = &self.limits {
+            let withdrawn_today = self.withdrawals.total_in_day_before(action.client_id, at);
+            limits.check_withdrawal(amount, withdrawn_today)?;
+        }
+
+        let mut account = self.accounts.get(action.client_id)?.unwrap_or_default();
+        // Check it the same way as a withdrawal, but hold the funds instead
+        let failed = |error| UpdateError::TransactionFailed {
+            transaction: id,
+            error,
+        };
+        account.clone().withdraw(amount).map_err(failed)?;
+        if let Some(category) = &action.category {
+            account.check_envelope(category, amount).map_err(failed)?;
+        }
+        account.hold(HoldId(id), amount).map_err(failed)?;
+        // Counted against the envelope straight away, like the daily limit
+        if let Some(category) = &action.category {
+            account.spend_envelope(category, amount);
+        }
+        self.put_account(action.client_id, account)?;
+
+        // Counted towards the daily limit straight away, so prepared
+        // withdrawals can't add up to more than it
+        if daily_limit.is_some() {
+            self.withdrawals.record(action.client_id, at, amount);
+        }
+        self.seen.insert(id);
+        if let Some(reference) = &action.reference {
+            self.references.insert(reference.clone(), id);
+        }
+
+        let prepared = PreparedAction {
+            transaction: id,
+            client: action.client_id,
+            amount,
+        };
+        self.prepared.insert(id, action);
+        Ok(prepared)
+    }
+
+    /// Finish a prepared withdrawal, taking its held funds out of the account
+    /// and recording the transaction.
+    ///
+    /// This can't fail for a prepared withdrawal, even if the account has
+    /// since been frozen, other than from the stores.
+    pub fn commit(&mut self, prepared: PreparedAction) -> Result<(), UpdateError> {
+        if self.open_savepoints > 0 {
+            return Err(UpdateError::SavepointOpen);
+        }
+        let action = self.take_prepared(&prepared)?;
+        let mut account = self.existing_account(prepared.client)?;
+        let hold = HoldId(prepared.transaction);
+        account.adopt_hold(hold, prepared.amount);
+        account.settle_hold(hold);
+        let after = Balances::from(&account);
+        let balances = self.running_balances(&account);
+        self.put_account(prepared.client, account)?;
+        self.transactions.put(
+            prepared.transaction,
+            Transaction {
+                id: prepared.transaction,
+                client: prepared.client,
+                state: TransactionState::Succeeded,
+                amount: -prepared.amount,
+                timestamp: action.timestamp,
+                reverses: None,
+                reference: action.reference.clone(),
+                meta: action.meta.clone(),
+                balances,
+                history: Vec::new(),
+                format: Transaction::FORMAT,
+            },
+        )?;
+
+        if self.audit_enabled {
+            let event = AuditEvent::Action {
+                kind: action.kind,
+                transaction: Some(prepared.transaction),
+                amount: action.amount,
+                outcome: Outcome::Applied,
+                meta: action.meta,
+            };
+            self.record_audit(prepared.client, Timestamp::now(), event, Some(after));
+        }
+        Ok(())
+    }
+
+    /// Call off a prepared withdrawal, returning its held funds to the
+    /// available balance. The transaction id and reference are freed again,
+    /// so the withdrawal can be retried, and it's taken back off the daily
+    /// limit.
+    pub fn abort(&mut self, prepared: PreparedAction) -> Result<(), UpdateError> {
+        if self.open_savepoints > 0 {
+            return Err(UpdateError::SavepointOpen);
+        }
+        let action = self.take_prepared(&prepared)?;
+        let mut account = self.existing_account(prepared.client)?;
+        let hold = HoldId(prepared.transaction);
+        account.adopt_hold(hold, prepared.amount);
+        account.return_hold(hold);
+        if let Some(category) = &action.category {
+            account.refund_envelope(category, prepared.amount);
+        }
+        self.put_account(prepared.client, account)?;
+
+        if let Some(at) = action.timestamp {
+            self.withdrawals
+                .cancel(prepared.client, at, prepared.amount);
+        }
+        self.seen.remove(prepared.transaction);
+        if let Some(reference) = &action.reference {
+            self.references.remove(reference);
+        }
+        Ok(())
+    }
+
+    /// Withdrawals that have been prepared but not yet committed or aborted
+    /// (e.g. to finish after a restart)
+    pub fn prepared(&self) -> impl Iterator<Item = PreparedAction> + '_ {
+        self.prepared.values().map(PreparedAction::of)
+    }
+
+    fn take_prepared(&mut self, prepared: &PreparedAction) -> Result<Action, UpdateError> {
+        match self.prepared.get(&prepared.transaction) {
+            Some(action) if PreparedAction::of(action) == *prepared => Ok(self
+                .prepared
+                .remove(&prepared.transaction)
+                .expect("just found")),
+            _ => Err(UpdateError::NotPrepared(prepared.transaction)),
+        }
+    }
+
+    /// Mark the current state, so it can be returned to with `rollback_to`.
+    ///
+    /// Actions and status changes made after this are recorded until the
+    /// savepoint is rolled back to or released, so every savepoint should end
+    /// up passed to one of them. Savepoints can be nested, and rolling back
+    /// to one also undoes everything after any savepoints taken since.
+    ///
+    /// Configuration (like the limits policy) and forgotten transactions
+    /// aren't covered.
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.open_savepoints += 1;
+        Savepoint {
+            position: self.undo_log.len(),
+            events: self.events.pending(),
+            sequence: self.sequence,
+        }
+    }
+
+    /// Undo every change made since `savepoint` was taken
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StoreError> {
+        while self.undo_log.len() > savepoint.position {
+            let undo = self.undo_log.pop().expect("checked the length");
+            undo.apply(self)?;
+        }
+        self.events.truncate(savepoint.events);
+        self.sequence = savepoint.sequence;
+        self.release(savepoint);
+        Ok(())
+    }
+
+    /// Keep every change made since `savepoint` was taken
+    pub fn release(&mut self, _savepoint: Savepoint) {
+        self.open_savepoints = self.open_savepoints.saturating_sub(1);
+        if self.open_savepoints == 0 {
+            self.undo_log.clear();
+            self.events.flush();
+        }
+    }
+
+    /// Make room for this many more accounts and transactions up front, if
+    /// the stores can (e.g. to avoid rehashing the in-memory stores as they
+    /// grow)
+    pub fn reserve(&mut self, accounts: usize, transactions: usize) {
+        self.accounts.reserve(accounts);
+        self.transactions.reserve(transactions);
+    }
+
+    /// Write out anything the account and transaction stores have buffered
+    /// (a no-op for the default in-memory stores)
+    pub fn flush_stores(&mut self) -> Result<(), StoreError> {
+        self.accounts.flush()?;
+        self.transactions.flush()
+    }
+
+    /// Totals over every account, without having to go through them
+    pub fn summary(&self) -> AccountsSummary {
+        self.summary
+    }
+
+    /// Write an account, bumping its version
+    fn put_account(&mut self, client: ClientId, mut account: Account) -> Result<(), StoreError> {
+        let before = self.accounts.get(client)?;
+        account.set_version(before.as_ref().map_or(0, Account::version) + 1);
+        self.write_account(client, before, account)
+    }
+
+    /// Write an account as it is, keeping the summary up to date
+    fn write_account(
+        &mut self,
+        client: ClientId,
+        before: Option<Account>,
+        account: Account,
+    ) -> Result<(), StoreError> {
+        self.accounts.put(client, account.clone())?;
+        if let Some(before) = before {
+            self.summary.remove(&before);
+        }
+        self.summary.add(&account);
+        if account.lock_expiry().is_some() {
+            self.expiring_locks.insert(client);
+        } else {
+            self.expiring_locks.remove(&client);
+        }
+        self.mark_changed(client);
+        if self.events.is_observed() {
+            let data = AccountData::from_with(client, &account, &self.output);
+            self.events.emit_account(data, self.open_savepoints > 0);
+        }
+        Ok(())
+    }
+
+    fn remove_account(&mut self, client: ClientId) -> Result<(), StoreError> {
+        if let Some(removed) = self.accounts.remove(client)? {
+            self.summary.remove(&removed);
+        }
+        self.expiring_locks.remove(&client);
+        self.mark_changed(client);
+        Ok(())
+    }
+
+    /// Changes made between actions (like a status change or an erasure)
+    /// are counted with the next action, so a diff taken now still has them
+    fn mark_changed(&mut self, client: ClientId) {
+        let at = if self.applying {
+            self.sequence
+        } else {
+            self.sequence + 1
+        };
+        self.changed.insert(client, at);
+    }
+
+    /// The accounts that have changed since the state's `sequence` was
+    /// `since` (e.g. when changes were last published), with the sequence to
+    /// ask from next time.
+    ///
+    /// Accounts are listed when they were written, even if their balances
+    /// ended up the same. If changes that far back aren't known (the state was
+    /// restored from a snapshot taken later, or opened over accounts that
+    /// were already stored), every account is listed and the diff is marked
+    /// `full`. Sequence numbers are reused after a rollback, so a diff
+    /// taken between a savepoint and rolling back to it can miss later
+    /// changes.
+    ///
+    /// # Panics
+    ///
+    /// If the account store fails to read (`accounts` panics the same way)
+    pub fn diff_since(&self, since: u64) -> StateDiff {
+        let full = since < self.changes_known_from;
+        let clients: Vec<ClientId> = if full {
+            let mut clients: HashSet<ClientId> = self
+                .accounts
+                .iter()
+                .map(|entry| entry.expect("account store failed").0)
+                .collect();
+            clients.extend(self.changed.keys());
+            clients.into_iter().collect()
+        } else {
+            self.changed
+                .iter()
+                .filter(|(_, at)| **at > since)
+                .map(|(client, _)| *client)
+                .collect()
+        };
+        let mut diff = StateDiff {
+            since,
+            sequence: self.sequence,
+            full,
+            accounts: Vec::new(),
+            removed: Vec::new(),
+        };
+        for client in clients {
+            match self.try_account(client).expect("account store failed") {
+                Some(account) => diff.accounts.push(account),
+                None => diff.removed.push(client),
+            }
+        }
+        diff.accounts.sort_by_key(|account| account.client);
+        diff.removed.sort();
+        diff
+    }
+
+    /// Get the data for a single client's account, if it exists
+    pub fn account(&self, client: ClientId) -> Option<AccountData> {
+        self.try_account(client).ok().flatten()
+    }
+
+    /// Get the data for a single client's account, if it exists, passing on
+    /// any storage errors
+    pub fn try_account(&self, client: ClientId) -> Result<Option<AccountData>, StoreError> {
+        let account = self.accounts.get(client)?;
+        Ok(account.map(|account| AccountData::from_with(client, &account, &self.output)))
+    }
+
+    /// The funds held in a client's account, by hold: one for each open
+    /// dispute, and one for each prepared withdrawal. Empty if the client
+    /// has no account.
+    pub fn holds(&self, client: ClientId) -> Result<BTreeMap<HoldId, Amount>, StoreError> {
+        let account = self.accounts.get(client)?;
+        Ok(account
+            .map(|account| account.holds().clone())
+            .unwrap_or_default())
+    }
+
+    /// Move a client's account to a new status with one of the transition
+    /// methods on `Account` (e.g. `|account| account.close()`)
+    pub fn change_status<F>(&mut self, client: ClientId, transition: F) -> Result<(), StatusError>
+    where
+        F: FnOnce(&mut Account) -> Result<(), StatusError>,
+    {
+        let mut account = self
+            .accounts
+            .get(client)?
+            .ok_or(StatusError::AccountMissing(client))?;
+        if self.open_savepoints > 0 {
+            let undo = Undo::account(self, client)?;
+            self.undo_log.push(undo);
+        }
+        let from = account.status().name();
+        transition(&mut account)?;
+        if self.audit_enabled {
+            let event = AuditEvent::StatusChanged {
+                from: from.into(),
+                to: account.status().name().into(),
+                quarantined: account.is_quarantined(),
+            };
+            self.record_audit(
+                client,
+                Timestamp::now(),
+                event,
+                Some(Balances::from(&account)),
+            );
+        }
+        self.put_account(client, account)?;
+        Ok(())
+    }
+
+    /// `change_status`, but only if the account is still at `version` (as
+    /// read from `AccountData::version`), for tools that change accounts
+    /// while the engine is running. If anything else wrote the account since
+    /// it was read, nothing is changed and `StatusError::VersionMismatch`
+    /// says which version it's at now.
+    pub fn change_status_at<F>(
+        &mut self,
+        client: ClientId,
+        version: u64,
+        transition: F,
+    ) -> Result<(), StatusError>
+    where
+        F: FnOnce(&mut Account) -> Result<(), StatusError>,
+    {
+        let actual = self
+            .accounts
+            .get(client)?
+            .ok_or(StatusError::AccountMissing(client))?
+            .version();
+        if actual != version {
+            return Err(StatusError::VersionMismatch {
+                client,
+                expected: version,
+                actual,
+            });
+        }
+        self.change_status(client, transition)
+    }
+
+    /// Freeze a client's account until `expiry`, after which it's unfrozen
+    /// by the next action on it (as a temporary risk hold that doesn't need
+    /// anyone to come back and lift it). It can still be unfrozen early with
+    /// `change_status`.
+    pub fn freeze_until(
+        &mut self,
+        client: ClientId,
+        reason: FreezeReason,
+        expiry: LockExpiry,
+    ) -> Result<(), StatusError> {
+        self.change_status(client, |account| {
+            account.freeze_until(reason.clone(), expiry)
+        })?;
+        self.emit(DisputeEvent::Locked {
+            client,
+            reason,
+            expiry,
+        });
+        Ok(())
+    }
+
+    /// Put a client's account in quarantine, blocking withdrawals but still
+    /// allowing deposits and disputes
+    pub fn quarantine(&mut self, client: ClientId) -> Result<(), StatusError> {
+        self.change_status(client, Account::quarantine)
+    }
+
+    /// Take a client's account out of quarantine
+    pub fn lift_quarantine(&mut self, client: ClientId) -> Result<(), StatusError> {
+        self.change_status(client, |account| {
+            account.lift_quarantine();
+            Ok(())
+        })
+    }
+
+    /// Cap a client's withdrawals in a spending category at `cap`, or remove
+    /// the cap with `None`, see `Account::set_envelope`
+    pub fn set_envelope(
+        &mut self,
+        client: ClientId,
+        category: &str,
+        cap: Option<Amount>,
+    ) -> Result<(), StatusError> {
+        self.change_envelopes(client, |account| account.set_envelope(category, cap))
+    }
+
+    /// Start all of a client's envelopes afresh, e.g. at the start of a
+    /// budgeting period
+    pub fn reset_envelopes(&mut self, client: ClientId) -> Result<(), StatusError> {
+        self.change_envelopes(client, Account::reset_envelopes)
+    }
+
+    fn change_envelopes<F: FnOnce(&mut Account)>(
+        &mut self,
+        client: ClientId,
+        change: F,
+    ) -> Result<(), StatusError> {
+        let mut account = self
+            .accounts
+            .get(client)?
+            .ok_or(StatusError::AccountMissing(client))?;
+        if self.open_savepoints > 0 {
+            let undo = Undo::account(self, client)?;
+            self.undo_log.push(undo);
+        }
+        change(&mut account);
+        self.put_account(client, account)?;
+        Ok(())
+    }
+
+    /// Everything held about one client (e.g. for a data subject access
+    /// request): their account, transactions, disputes and audit trail.
+    ///
+    /// This has to look through every transaction, so it's slow for large
+    /// states.
+    pub fn export_client(&self, client: ClientId) -> Result<ClientExport, StoreError> {
+        let mut transactions = Vec::new();
+        for entry in self.transactions.iter() {
+            let (_, transaction) = entry?;
+            if transaction.client == client {
+                transactions.push(transaction);
+            }
+        }
+        self.client_export(client, transactions)
+    }
+
+    /// Export many clients at once, as `export_client`, but with only one
+    /// pass over the transactions. Clients without an account are left out
+    /// and reported as failed.
+    pub fn export_clients(&self, clients: &[ClientId]) -> Result<BulkExport, StoreError> {
+        let mut report = BulkReport::default();
+        let mut transactions: HashMap<ClientId, Vec<Transaction>> = HashMap::new();
+        for &client in clients {
+            if transactions.contains_key(&client) {
+                continue;
+            }
+            match self.accounts.get(client)? {
+                Some(_) => {
+                    transactions.insert(client, Vec::new());
+                    report.succeeded.push(client);
+                }
+                None => report.record(client, Err(StatusError::AccountMissing(client))),
+            }
+        }
+        for entry in self.transactions.iter() {
+            let (_, transaction) = entry?;
+            if let Some(found) = transactions.get_mut(&transaction.client) {
+                found.push(transaction);
+            }
+        }
+        let exports = report
+            .succeeded
+            .iter()
+            .map(|client| {
+                let found = transactions.remove(client).unwrap_or_default();
+                self.client_export(*client, found)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BulkExport { exports, report })
+    }
+
+    /// Apply `operation` to each of `clients`' accounts, one client at a
+    /// time. Each client's change is all or nothing: one it fails for (e.g.
+    /// an account without the funds for a fee) is left exactly as it was,
+    /// and the rest carry on. Clients given more than once are only done
+    /// once.
+    pub fn bulk(&mut self, clients: &[ClientId], operation: &BulkOperation) -> BulkReport {
+        let mut report = BulkReport::default();
+        let mut done = HashSet::new();
+        for &client in clients {
+            if !done.insert(client) {
+                continue;
+            }
+            let savepoint = self.savepoint();
+            let result = match self.bulk_one(client, operation) {
+                Ok(()) => {
+                    self.release(savepoint);
+                    Ok(())
+                }
+                Err(e) => match self.rollback_to(savepoint) {
+                    Ok(()) => Err(e),
+                    Err(rollback) => Err(format!("{e} (and rolling back failed: {rollback})")),
+                },
+            };
+            report.record(client, result);
+        }
+        report
+    }
+
+    fn bulk_one(&mut self, client: ClientId, operation: &BulkOperation) -> Result<(), String> {
+        match operation {
+            BulkOperation::Freeze(reason) => self
+                .change_status(client, |account| account.freeze(reason.clone()))
+                .map_err(|e| e.to_string()),
+            BulkOperation::Fee { amount, reference } => {
+                if self
+                    .accounts
+                    .get(client)
+                    .map_err(|e| e.to_string())?
+                    .is_none()
+                {
+                    return Err(UpdateError::AccountMissing(client).to_string());
+                }
+                let reference = format!("{reference}:{client}");
+                let fee = Action {
+                    transaction_id: None,
+                    client_id: client,
+                    kind: ActionKind::Withdrawal,
+                    amount: Some(*amount),
+                    timestamp: None,
+                    reverses: None,
+                    reference: Some(reference.clone()),
+                    evidence: None,
+                    category: None,
+                    currency: None,
+                    meta: None,
+                };
+                self.update(fee)
+                    .and_then(|()| withdrawal_outcome(self, None, Some(&reference)))
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn client_export(
+        &self,
+        client: ClientId,
+        mut transactions: Vec<Transaction>,
+    ) -> Result<ClientExport, StoreError> {
+        let account = self.try_account(client)?;
+        let envelopes = self
+            .accounts
+            .get(client)?
+            .map(|account| account.envelopes().clone())
+            .unwrap_or_default();
+        transactions.sort_by_key(|t| t.id);
+        let audit_trail = self.audit.entries(client).to_vec();
+
+        Ok(ClientExport {
+            client,
+            exported_at: Timestamp::now(),
+            account,
+            envelopes,
+            disputes: DisputeHistory::collect(&transactions, &audit_trail, &self.evidence),
+            transactions,
+            audit_trail,
+        })
+    }
+
+    /// Anonymize a closed client's records (e.g. for a right to erasure
+    /// request), returning how many transactions were kept.
+    ///
+    /// The account, withdrawal history, audit trail and dispute evidence are
+    /// dropped, and the client's transactions are moved to
+    /// `ClientId::TOMBSTONE` with their timestamps, references and metadata
+    /// cleared. Closed accounts hold nothing, so the ledger still balances,
+    /// and the transaction ids stay claimed, but the references can't be
+    /// looked up (or told apart from new ones) any more. A later deposit for
+    /// the same client id opens a new account.
+    ///
+    /// This can't be rolled back, so isn't allowed while a savepoint is open.
+    /// The journal of a `StateDir` still has the client's actions, so take a
+    /// checkpoint straight after.
+    pub fn erase_client(&mut self, client: ClientId) -> Result<usize, ErasureError> {
+        if self.open_savepoints > 0 {
+            return Err(ErasureError::SavepointOpen);
+        }
+        if client == ClientId::TOMBSTONE {
+            return Err(ErasureError::Tombstone);
+        }
+        let account = self
+            .accounts
+            .get(client)?
+            .ok_or(ErasureError::AccountMissing(client))?;
+        if *account.status() != AccountStatus::Closed {
+            return Err(ErasureError::NotClosed(client));
+        }
+        match self.accounts.get(ClientId::TOMBSTONE)? {
+            Some(tombstone) if *tombstone.status() != AccountStatus::Closed => {
+                return Err(ErasureError::Tombstone)
+            }
+            Some(_) => {}
+            None => {
+                let mut tombstone = Account::default();
+                tombstone.close().expect("new accounts are empty");
+                self.put_account(ClientId::TOMBSTONE, tombstone)?;
+            }
+        }
+
+        let mut erased = Vec::new();
+        for entry in self.transactions.iter() {
+            let (_, transaction) = entry?;
+            if transaction.client == client {
+                erased.push(transaction);
+            }
+        }
+        for transaction in &mut erased {
+            transaction.client = ClientId::TOMBSTONE;
+            transaction.timestamp = None;
+            transaction.meta = None;
+            if let Some(reference) = transaction.reference.take() {
+                self.references.remove(&reference);
+            }
+        }
+        let kept = erased.len();
+        for transaction in erased {
+            self.evidence.forget(transaction.id);
+            self.transactions.put(transaction.id, transaction)?;
+        }
+
+        self.remove_account(client)?;
+        self.withdrawals.forget(client);
+        self.audit.forget(client);
+        Ok(kept)
+    }
+
+    /// Correct the recorded amount of a settled deposit or withdrawal (e.g.
+    /// one that was keyed in wrong), moving the difference into or out of
+    /// the account's available balance, rather than reversing it and making
+    /// up a new transaction. As in an action, `amount` is positive for both.
+    ///
+    /// Disputed, failed and reversed transactions (and reversals themselves)
+    /// can't be restated, and neither can transactions on a locked account,
+    /// or a smaller deposit if the difference has already been withdrawn.
+    /// The correction is added to the transaction's history, and to the
+    /// audit trail with the old and new amounts if it's enabled. Limits
+    /// aren't checked, though a withdrawal still counted towards the daily
+    /// limit is counted with its new amount.
+    pub fn restate(
+        &mut self,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<(), RestatementError> {
+        if amount.is_sign_negative() {
+            return Err(RestatementError::NegativeAmount);
+        }
+        let mut record = self
+            .transactions
+            .get(transaction)?
+            .ok_or(RestatementError::TransactionMissing(transaction))?;
+        if record.state != TransactionState::Succeeded || record.reverses.is_some() {
+            return Err(RestatementError::NotRestatable(transaction));
+        }
+        let client = record.client;
+        let mut account = self
+            .accounts
+            .get(client)?
+            .ok_or(RestatementError::AccountMissing(client))?;
+        let from = record.amount;
+        let to = if from.is_sign_negative() {
+            -amount
+        } else {
+            amount
+        };
+        account.restate(to - from)?;
+
+        if self.open_savepoints > 0 {
+            let mut undo = Undo::account(self, client)?;
+            undo.transactions.push((transaction, Some(record.clone())));
+            self.undo_log.push(undo);
+        }
+        if let (true, Some(at)) = (from.is_sign_negative(), record.timestamp) {
+            self.withdrawals.restate(client, at, -from, amount);
+        }
+        let now = Timestamp::now();
+        record.amount = to;
+        record.history.push(TransactionEvent::new(
+            self.sequence,
+            TransactionEventKind::Restated,
+            Some(now),
+            Ok(()),
+        ));
+        if self.audit_enabled {
+            let event = AuditEvent::Restated {
+                transaction,
+                from,
+                to,
+            };
+            self.record_audit(client, now, event, Some(Balances::from(&account)));
+        }
+        self.put_account(client, account)?;
+        self.transactions.put(transaction, record)?;
+        Ok(())
+    }
+
+    /// Every account.
+    ///
+    /// # Panics
+    ///
+    /// If the account store fails part way through (which the default
+    /// in-memory store can't)
+    pub fn accounts(&self) -> AccountsIter<'_> {
+        AccountsIter {
+            inner: self.accounts.iter(),
+            output: self.output,
+            remaining: self.accounts.len(),
+        }
+    }
+
+    /// Every account matching `filter`.
+    ///
+    /// This still goes through every account, but without collecting the
+    /// ones that don't match.
+    ///
+    /// # Panics
+    ///
+    /// If the account store fails part way through (which the default
+    /// in-memory store can't)
+    pub fn accounts_where(&self, filter: AccountFilter) -> impl Iterator<Item = AccountData> + '_ {
+        self.accounts()
+            .filter(move |account| filter.matches(account))
+    }
+
+    /// Every account matching `filter`, in `order`. Unlike `accounts_where`,
+    /// the matching accounts are all collected to be sorted.
+    ///
+    /// # Panics
+    ///
+    /// If the account store fails part way through (which the default
+    /// in-memory store can't)
+    pub fn accounts_sorted(&self, filter: AccountFilter, order: AccountOrder) -> Vec<AccountData> {
+        let mut accounts: Vec<_> = self.accounts_where(filter).collect();
+        order.sort(&mut accounts);
+        accounts
+    }
+
+    /// Every account with a negative available, held or total balance (see
+    /// `ChargebackPolicy::AllowNegative`).
+    ///
+    /// # Panics
+    ///
+    /// If the account store fails part way through (which the default
+    /// in-memory store can't)
+    pub fn negative_balances(&self) -> impl Iterator<Item = AccountData> + '_ {
+        self.accounts_where(AccountFilter::NegativeBalance)
+    }
+
+    /// A recorded transaction, with the history of what's been done to it
+    /// since (see `Transaction::history`). `None` if it was never recorded,
+    /// or has been forgotten.
+    pub fn transaction(&self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        self.transactions.get(id)
+    }
+
+    /// How many actions the state has been given, through `update` and
+    /// `bulk_load`. It's the sequence number of the latest action, as used
+    /// in transactions' histories, and counts actions that were rejected
+    /// too. Kept in snapshots; in the multi-threaded engine each shard counts
+    /// its own.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// A client's balances as they were once the state's `sequence` reached
+    /// `seq`, including anything done to the account before the next action
+    /// (e.g. a status change), e.g. to see what they were when a withdrawal
+    /// bounced (the `seq` of its audit entry). Read from the audit trail, so
+    /// it has to have been on since the client's first action (see
+    /// `set_audit_trail`). `None` if the client had no account by then.
+    ///
+    /// Sequence numbers are the state's own, so after `merge` or in a
+    /// `ConcurrentEngine`'s shards, a client's entries count from the state
+    /// they were recorded in.
+    pub fn balance_at(&self, client: ClientId, seq: u64) -> Option<Balances> {
+        let entries = self.audit.entries(client);
+        let end = entries.partition_point(|entry| entry.seq <= seq);
+        entries[..end].iter().rev().find_map(|entry| entry.balances)
+    }
+
+    /// Every transaction that failed, or had a dispute step (or reversal)
+    /// refused (see `Transaction::failures`).
+    ///
+    /// # Panics
+    ///
+    /// If the transaction store fails part way through (which the default
+    /// in-memory store can't)
+    pub fn failed_transactions(&self) -> impl Iterator<Item = Transaction> + '_ {
+        self.transactions
+            .iter()
+            .map(|entry| entry.expect("transaction store failed").1)
+            .filter(|t| t.failures().next().is_some())
+    }
+
+    /// Every transaction that's currently disputed.
+    ///
+    /// # Panics
+    ///
+    /// If the transaction store fails part way through (which the default
+    /// in-memory store can't)
+    pub fn disputed_transactions(&self) -> impl Iterator<Item = Transaction> + '_ {
+        self.transactions
+            .iter()
+            .map(|entry| entry.expect("transaction store failed").1)
+            .filter(|t| matches!(t.state, TransactionState::Disputed))
+    }
+
+    /// The funds held by every open dispute, by client.
+    ///
+    /// This has to look through every transaction, so it's slow for large
+    /// states.
+    pub fn open_holds(&self) -> Result<OpenHoldsReport, StoreError> {
+        let mut disputed = Vec::new();
+        for entry in self.transactions.iter() {
+            let (_, transaction) = entry?;
+            if matches!(transaction.state, TransactionState::Disputed) {
+                disputed.push(transaction);
+            }
+        }
+        Ok(OpenHoldsReport::collect(disputed))
+    }
+
+    /// Check that the state is consistent with itself, as a canary for bugs
+    /// and storage corruption:
+    ///
+    /// - no held balance is negative, and no available balance either (unless
+    ///   under `ChargebackPolicy::AllowNegative`)
+    /// - each account's total is what its transactions add up to
+    /// - every transaction belongs to a client with an account, and every
+    ///   reversal and the transaction it reversed agree on each other
+    /// - every hold is for one of the client's own transactions or prepared
+    ///   withdrawals
+    /// - the summary matches the accounts
+    ///
+    /// Totals aren't checked once any transactions have been forgotten, or
+    /// for clients with a failed transaction whose record doesn't say
+    /// whether it ever moved any funds. States built from `fixtures` only
+    /// have the transactions they were given, so their totals won't add up
+    /// unless those cover the balances.
+    ///
+    /// This has to look through every account and transaction, so it's slow
+    /// for large states.
+    ///
+    /// # Panics
+    ///
+    /// If the stores fail to read
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        self.check_invariants_within(Amount::default())
+    }
+
+    /// `check_invariants`, allowing amounts to be `tolerance` out. Without
+    /// the `decimal` feature amounts are floats, so some drift is expected.
+    ///
+    /// # Panics
+    ///
+    /// If the stores fail to read
+    pub fn check_invariants_within(&self, tolerance: Amount) -> Result<(), InvariantViolation> {
+        let near = |a: Amount, b: Amount| (a - b).abs() <= tolerance;
+        let violation = |invariant| InvariantViolation {
+            step: self.sequence,
+            action: None,
+            invariant,
+        };
+        let zero = Amount::default();
+        let negative_allowed = self.chargeback_policy == Some(ChargebackPolicy::AllowNegative);
+
+        // What each client's transactions add up to (if it can be told), and
+        // one of them, in case the client has no account
+        let mut moved: HashMap<ClientId, (TransactionId, Option<Amount>)> = HashMap::new();
+        for entry in self.transactions.iter() {
+            let (id, transaction) = entry.expect("transaction store failed");
+            let client = transaction.client;
+            let (_, sum) = moved.entry(client).or_insert((id, Some(zero)));
+            *sum = sum.zip(transaction.moved_funds()).map(|(a, b)| a + b);
+
+            // Either side of a reversal can have been forgotten
+            let agrees = |other: TransactionId, agrees: &dyn Fn(&Transaction) -> bool| {
+                self.transaction(other)
+                    .expect("transaction store failed")
+                    .is_none_or(|other| other.client == client && agrees(&other))
+            };
+            match (transaction.reverses, transaction.state) {
+                (Some(original), TransactionState::Succeeded)
+                    if !agrees(original, &|o| o.state == TransactionState::Reversed(id)) =>
+                {
+                    return Err(violation(Invariant::BrokenReversal {
+                        transaction: id,
+                        reverses: original,
+                    }));
+                }
+                (None, TransactionState::Reversed(reversal))
+                    if !agrees(reversal, &|r| r.reverses == Some(id)) =>
+                {
+                    return Err(violation(Invariant::BrokenReversal {
+                        transaction: reversal,
+                        reverses: id,
+                    }));
+                }
+                _ => {}
+            }
+        }
+
+        let mut total = zero;
+        for entry in self.accounts.iter() {
+            let (client, account) = entry.expect("account store failed");
+            total += account.total_funds();
+            if account.held_funds() < zero && !near(account.held_funds(), zero) {
+                return Err(violation(Invariant::NegativeHeld {
+                    client,
+                    held: account.held_funds(),
+                }));
+            }
+            if account.available_funds() < zero
+                && !negative_allowed
+                && !near(account.available_funds(), zero)
+            {
+                return Err(violation(Invariant::NegativeAvailable {
+                    client,
+                    available: account.available_funds(),
+                }));
+            }
+            let transactions = moved.remove(&client).map_or(Some(zero), |(_, sum)| sum);
+            if let (false, Some(transactions)) = (self.forgotten, transactions) {
+                if !near(account.total_funds(), transactions) {
+                    return Err(violation(Invariant::FundsMismatch {
+                        client,
+                        total: account.total_funds(),
+                        transactions,
+                    }));
+                }
+            }
+            for hold in account.holds().keys() {
+                let owned = match self.prepared.get(&hold.0) {
+                    Some(prepared) => prepared.client_id == client,
+                    None => self
+                        .transaction(hold.0)
+                        .expect("transaction store failed")
+                        .is_some_and(|transaction| transaction.client == client),
+                };
+                if !owned {
+                    return Err(violation(Invariant::DanglingHold {
+                        client,
+                        hold: *hold,
+                    }));
+                }
+            }
+        }
+
+        if let Some((client, (transaction, _))) = moved.into_iter().next() {
+            return Err(violation(Invariant::OrphanTransaction {
+                transaction,
+                client,
+            }));
+        }
+        if !near(self.summary.total, total) {
+            return Err(violation(Invariant::SummaryMismatch {
+                summary: self.summary.total,
+                accounts: total,
+            }));
+        }
+        Ok(())
+    }
+
+    /// A client's statement: their transactions in order, with the balance
+    /// after each.
+    ///
+    /// This has to look through every transaction, so it's slow for large
+    /// states.
+    pub fn statement(&self, client: ClientId) -> Result<Statement, StoreError> {
+        let closing_balance = self
+            .accounts
+            .get(client)?
+            .map_or(Amount::default(), |account| account.total_funds());
+        let mut transactions = Vec::new();
+        for entry in self.transactions.iter() {
+            let (_, transaction) = entry?;
+            if transaction.client == client {
+                transactions.push(transaction);
+            }
+        }
+        Ok(Statement::collect(client, closing_balance, transactions))
+    }
+
+    /// What it would take to bring the accounts in line with `corrected`, a
+    /// state rebuilt from a corrected history (with the same configuration):
+    /// a deposit or withdrawal for each account whose total balance is off,
+    /// and the differences those can't make up (like funds held by a
+    /// dispute that shouldn't be open). Adjustments are given
+    /// the external reference `{prefix}{client}`.
+    pub fn reconcile(&self, corrected: &State, prefix: &str) -> Result<Reconciliation, StoreError> {
+        let mut accounts: HashMap<ClientId, (Option<Account>, Option<Account>)> = HashMap::new();
+        for entry in self.accounts.iter() {
+            let (client, account) = entry?;
+            accounts.entry(client).or_default().0 = Some(account);
+        }
+        for entry in corrected.accounts.iter() {
+            let (client, account) = entry?;
+            accounts.entry(client).or_default().1 = Some(account);
+        }
+        let accounts = accounts
+            .into_iter()
+            .map(|(client, (current, corrected))| (client, current, corrected));
+        Ok(Reconciliation::collect(accounts, prefix))
+    }
+
+    /// The ids of every deposit or withdrawal that has been recorded, even if
+    /// the transaction itself has since been forgotten
+    pub fn seen_transactions(&self) -> &SeenTransactions {
+        &self.seen
+    }
+
+    /// The transaction created with an external reference, if there was one
+    /// (even if it has since been forgotten)
+    pub fn transaction_for_reference(&self, reference: &str) -> Option<TransactionId> {
+        self.references.get(reference).copied()
+    }
+
+    /// Drop the records of transactions matching `predicate` (e.g. ones too
+    /// old to be disputed), returning how many were removed. Transactions that
+    /// are currently disputed are always kept.
+    ///
+    /// Their ids stay claimed, so any redelivered deposits or withdrawals are
+    /// still rejected, but forgotten transactions can no longer be disputed.
+    pub fn forget_transactions<F: FnMut(&Transaction) -> bool>(
+        &mut self,
+        mut predicate: F,
+    ) -> Result<usize, StoreError> {
+        let mut forget = Vec::new();
+        for entry in self.transactions.iter() {
+            let (id, transaction) = entry?;
+            if !matches!(transaction.state, TransactionState::Disputed) && predicate(&transaction) {
+                forget.push(id);
+            }
+        }
+        for id in &forget {
+            self.transactions.remove(*id)?;
+            self.evidence.forget(*id);
+        }
+        self.forgotten |= !forget.is_empty();
+        Ok(forget.len())
+    }
+
+    /// Rebuild a state from its persisted accounts and transactions. Snapshots
+    /// written before the seen set was tracked only have the transactions
+    /// themselves to go on.
+    pub(crate) fn from_parts(
+        accounts: HashMap<ClientId, Account>,
+        transactions: HashMap<TransactionId, Transaction>,
+        seen: Option<SeenTransactions>,
+        references: HashMap<String, TransactionId>,
+        withdrawals: WithdrawalHistory,
+        audit: AuditTrail,
+        evidence: EvidenceLog,
+    ) -> Self {
+        let mut seen = seen.unwrap_or_default();
+        seen.extend(&transactions.keys().copied().collect());
+        Self {
+            seen,
+            references,
+            withdrawals,
+            audit,
+            evidence,
+            ..Self::with_stores(MemoryStore::from(accounts), MemoryStore::from(transactions))
+        }
+    }
+
+    pub(crate) fn withdrawal_history(&self) -> &WithdrawalHistory {
+        &self.withdrawals
+    }
+
+    pub(crate) fn audit(&self) -> &AuditTrail {
+        &self.audit
+    }
+
+    pub(crate) fn evidence(&self) -> &EvidenceLog {
+        &self.evidence
+    }
+
+    pub(crate) fn references(&self) -> &HashMap<String, TransactionId> {
+        &self.references
+    }
+
+    pub(crate) fn prepared_actions(&self) -> impl Iterator<Item = &Action> + '_ {
+        self.prepared.values()
+    }
+
+    /// Put back withdrawals that were prepared when a snapshot was taken
+    /// (their funds are already held in the restored accounts)
+    pub(crate) fn restore_prepared(&mut self, prepared: Vec<Action>) {
+        for action in prepared {
+            self.seen.insert(action.id());
+            self.prepared.insert(action.id(), action);
+        }
+    }
+
+    /// The funds held by a client's prepared withdrawals
+    pub(crate) fn prepared_for(&self, client: ClientId) -> Amount {
+        self.prepared
+            .values()
+            .filter(|action| action.client_id == client)
+            .filter_map(|action| action.amount)
+            .sum()
+    }
+
+    pub(crate) fn has_forgotten(&self) -> bool {
+        self.forgotten
+    }
+
+    pub(crate) fn set_forgotten(&mut self, forgotten: bool) {
+        self.forgotten = forgotten;
+    }
+
+    pub(crate) fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+        self.changes_known_from = self.changes_known_from.max(sequence);
+    }
+
+    pub(crate) fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub(crate) fn raw_account(&self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        self.accounts.get(client)
+    }
+
+    pub(crate) fn raw_accounts(&self) -> StoreIter<'_, ClientId, Account> {
+        self.accounts.iter()
+    }
+
+    pub(crate) fn raw_transactions(&self) -> StoreIter<'_, TransactionId, Transaction> {
+        self.transactions.iter()
+    }
+
+    /// Combine two states built from disjoint sets of clients, e.g. from
+    /// splitting a large input by client and processing each part on a
+    /// different machine. Everything `other` holds (accounts, transactions,
+    /// references, audit trails and the rest) is moved into `self`, which
+    /// keeps its own settings (policies, output config and observer).
+    ///
+    /// Nothing is merged if the states overlap: a client with an account in
+    /// both, a transaction id claimed in both (even if its record has since
+    /// been forgotten) or an external reference used in both. Neither can
+    /// have a savepoint open.
+    pub fn merge(mut self, other: State) -> Result<State, MergeError> {
+        if self.open_savepoints > 0 || other.open_savepoints > 0 {
+            return Err(MergeError::SavepointOpen);
+        }
+        for entry in other.accounts.iter() {
+            let (client, _) = entry?;
+            if self.accounts.get(client)?.is_some() {
+                return Err(MergeError::OverlappingClient(client));
+            }
+        }
+        if let Some(id) = self.seen.first_shared(&other.seen) {
+            return Err(MergeError::OverlappingTransaction(id));
+        }
+        for entry in other.transactions.iter() {
+            let (id, _) = entry?;
+            if self.transactions.get(id)?.is_some() {
+                return Err(MergeError::OverlappingTransaction(id));
+            }
+        }
+        if let Some(reference) = other
+            .references
+            .keys()
+            .find(|reference| self.references.contains_key(*reference))
+        {
+            return Err(MergeError::OverlappingReference(reference.clone()));
+        }
+        self.absorb(other)?;
+        Ok(self)
+    }
+
+    /// Move all accounts and transactions from another state into this one.
+    ///
+    /// This assumes the two states were built from disjoint sets of clients and
+    /// transactions (e.g. partitions of the same input), so any overlapping
+    /// entries in `other` will overwrite those in `self`.
+    pub(crate) fn absorb(&mut self, other: State) -> Result<(), StoreError> {
+        for entry in other.accounts.iter() {
+            let (client, account) = entry?;
+            // Clients are only ever in one of the states, so their versions
+            // carry over
+            self.write_account(client, None, account)?;
+        }
+        for entry in other.transactions.iter() {
+            let (id, transaction) = entry?;
+            self.transactions.put(id, transaction)?;
+        }
+        self.seen.extend(&other.seen);
+        self.forgotten |= other.forgotten;
+        self.references.extend(other.references);
+        self.withdrawals.extend(other.withdrawals);
+        self.audit.extend(other.audit);
+        self.evidence.extend(other.evidence);
+        self.prepared.extend(other.prepared);
+        self.sequence += other.sequence;
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    #[error("client {0} has an account in both states")]
+    OverlappingClient(ClientId),
+
+    #[error("transaction {0} is in both states")]
+    OverlappingTransaction(TransactionId),
+
+    #[error("the reference '{0}' is used in both states")]
+    OverlappingReference(String),
+
+    #[error("states can't be merged while a savepoint is open")]
+    SavepointOpen,
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ErasureError {
+    #[error("account {0} does not exist")]
+    AccountMissing(ClientId),
+
+    #[error("account {0} must be closed before it can be erased")]
+    NotClosed(ClientId),
+
+    #[error("the tombstone account can't be erased, or is in use by a real client")]
+    Tombstone,
+
+    #[error("clients can't be erased while a savepoint is open")]
+    SavepointOpen,
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestatementError {
+    #[error("transaction {0} does not exist")]
+    TransactionMissing(TransactionId),
+
+    #[error("account {0} does not exist")]
+    AccountMissing(ClientId),
+
+    #[error("transaction {0} isn't a settled deposit or withdrawal, so can't be restated")]
+    NotRestatable(TransactionId),
+
+    #[error("cannot restate a transaction to a negative amount")]
+    NegativeAmount,
+
+    #[error("the account can't take the correction: {0}")]
+    Account(#[from] AccountError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BulkLoadError {
+    #[error("Bulk loads can only go into an empty state")]
+    NotEmpty,
+
+    #[error("Action {index} is timestamped before an earlier action")]
+    OutOfOrder { index: u64 },
+
+    #[error("{count} actions were rejected, starting with action {index}: {source}")]
+    Rejected {
+        index: u64,
+        count: u64,
+        source: UpdateError,
+    },
+
+    #[error("{0} transactions failed")]
+    FailedTransactions(u64),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// A point a `State` can be rolled back to, from `State::savepoint`
+#[derive(Debug)]
+#[must_use = "savepoints must be rolled back to or released"]
+pub struct Savepoint {
+    /// The length of the undo log when the savepoint was taken
+    position: usize,
+    /// How many events were held back when the savepoint was taken
+    events: usize,
+    sequence: u64,
+}
+
+/// The accounts that changed over a stretch of actions, from
+/// `State::diff_since`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StateDiff {
+    /// The sequence the diff was asked for
+    pub since: u64,
+    /// The state's sequence when the diff was taken, to ask from next time
+    pub sequence: u64,
+    /// Every account is listed, since changes back to `since` aren't known
+    pub full: bool,
+    /// The changed accounts as they are now, by client
+    pub accounts: Vec<AccountData>,
+    /// Clients whose accounts have been removed (erased, or rolled back
+    /// before they were opened)
+    #[serde(serialize_with = "crate::client_format::serialize_all")]
+    pub removed: Vec<ClientId>,
+}
+
+/// A withdrawal that's been validated and had its funds reserved by
+/// `State::prepare`, waiting to be passed to `State::commit` or
+/// `State::abort`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[must_use = "prepared withdrawals must be committed or aborted"]
+pub struct PreparedAction {
+    transaction: TransactionId,
+    client: ClientId,
+    #[serde(with = "crate::persist::exact_amount")]
+    amount: Amount,
+}
+
+impl PreparedAction {
+    /// The handle of a withdrawal kept by `State::prepare`, which has had
+    /// its id filled in
+    pub(crate) fn of(action: &Action) -> Self {
+        Self {
+            transaction: action.id(),
+            client: action.client_id,
+            amount: action.amount.unwrap_or_default(),
+        }
+    }
+
+    pub fn transaction(&self) -> TransactionId {
+        self.transaction
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// The amount held for the withdrawal
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+}
+
+/// Everything a single change can touch, as it was before the change
+#[derive(Debug)]
+struct Undo {
+    client: ClientId,
+    account: Option<Account>,
+    withdrawals: Option<Vec<Withdrawal>>,
+    /// The length of the client's audit trail
+    audit_len: usize,
+    /// The action's transaction, and how much evidence it had
+    evidence_len: Option<(TransactionId, usize)>,
+    /// Every transaction the change can touch (a reversal touches two)
+    transactions: Vec<(TransactionId, Option<Transaction>)>,
+    /// A transaction id the change might claim, if it was still free
+    unclaimed: Option<TransactionId>,
+    /// An external reference the change might claim, if it was still free
+    unreferenced: Option<String>,
+}
+
+impl Undo {
+    fn action(state: &State, action: &Action) -> Result<Self, StoreError> {
+        let id = action.id();
+        let transactions = std::iter::once(id)
+            .chain(action.reverses)
+            .map(|id| Ok((id, state.transactions.get(id)?)))
+            .collect::<Result<_, StoreError>>()?;
+        Ok(Self {
+            transactions,
+            evidence_len: Some((id, state.evidence.len(id))),
+            unclaimed: Some(id).filter(|id| !state.seen.contains(*id)),
+            unreferenced: action
+                .reference
+                .clone()
+                .filter(|_| action.kind.creates_transaction())
+                .filter(|reference| !state.references.contains_key(reference)),
+            ..Self::account(state, action.client_id)?
+        })
+    }
+
+    fn account(state: &State, client: ClientId) -> Result<Self, StoreError> {
+        Ok(Self {
+            client,
+            account: state.accounts.get(client)?,
+            withdrawals: state.withdrawals.save(client),
+            audit_len: state.audit.len(client),
+            evidence_len: None,
+            transactions: Vec::new(),
+            unclaimed: None,
+            unreferenced: None,
+        })
+    }
+
+    fn apply(self, state: &mut State) -> Result<(), StoreError> {
+        match self.account {
+            Some(account) => state.put_account(self.client, account)?,
+            None => {
+                state.remove_account(self.client)?;
+            }
+        };
+        state.withdrawals.restore(self.client, self.withdrawals);
+        state.audit.truncate(self.client, self.audit_len);
+        if let Some((id, len)) = self.evidence_len {
+            state.evidence.truncate(id, len);
+        }
+        for (id, transaction) in self.transactions {
+            match transaction {
+                Some(transaction) => state.transactions.put(id, transaction)?,
+                None => {
+                    state.transactions.remove(id)?;
+                }
+            };
+        }
+        if let Some(id) = self.unclaimed {
+            state.seen.remove(id);
+        }
+        if let Some(reference) = self.unreferenced {
+            state.references.remove(&reference);
+        }
+        Ok(())
+    }
+}
+
+// Yeah, we could probably just return a vec, but where's the fun in that?
+pub struct AccountsIter<'a> {
+    inner: StoreIter<'a, ClientId, Account>,
+    output: OutputConfig,
+    remaining: usize,
+}
+
+impl<'a> Iterator for AccountsIter<'a> {
+    type Item = AccountData;
+    fn next(&mut self) -> Option<Self::Item> {
+        let (client, account) = self.inner.next()?.expect("account store failed");
+        self.remaining = self.remaining.saturating_sub(1);
+        Some(AccountData::from_with(client, &account, &self.output))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+impl<'a> ExactSizeIterator for AccountsIter<'a> {
+    fn len(&self) -> usize {
+        self.remaining
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpdateError {
+    #[error(
+        "A deposit or withdrawl as requested with the same id ({0}) as an existing transaction"
+    )]
+    TransactionUsed(TransactionId),
+
+    #[error(
+        "An action on an existing transaction was requested but transaction {0} does not exist"
+    )]
+    TransactionMissing(TransactionId),
+
+    // Technically this shouldn't happen?
+    #[error("An action on an existing account was requested but account {0} does not exist")]
+    AccountMissing(ClientId),
+
+    #[error("The action and transaction it points two reference different clients (action: {action}, transaction: {transaction})")]
+    ClientMismatch {
+        action: ClientId,
+        transaction: ClientId,
+    },
+
+    #[error("A deposit or withdrawl was requested with no amount")]
+    NoAmount,
+
+    #[error("A reversal was requested without the transaction to reverse")]
+    NoReversalTarget,
+
+    #[error("Transaction {0} is not a settled deposit or withdrawal, so it can't be reversed")]
+    NotReversible(TransactionId),
+
+    /// A resolve or chargeback of a transaction that isn't disputed. Nothing
+    /// changes, but it's recorded in the audit trail like any other rejected
+    /// action, so repeated bogus claims can be spotted.
+    #[error("Transaction {0} is not disputed, so it can't be resolved or charged back")]
+    NotDisputed(TransactionId),
+
+    /// A dispute of a transaction that failed (so never moved any funds) or
+    /// was already charged back. Nothing changes.
+    #[error("Transaction {0} failed or was charged back, so it can't be disputed")]
+    NotDisputable(TransactionId),
+
+    #[error("The {limit} limit of {max} was exceeded ({amount})")]
+    LimitExceeded {
+        limit: Limit,
+        amount: Amount,
+        max: Amount,
+    },
+
+    #[error("Transaction {transaction} failed: {error}")]
+    TransactionFailed {
+        transaction: TransactionId,
+        error: AccountError,
+    },
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error("Failed to record the action in the journal: {0}")]
+    Journal(#[from] crate::persist::PersistError),
+
+    #[error("Transaction {transaction} is too old to be disputed ({age_days} days)")]
+    DisputeWindowExpired {
+        transaction: TransactionId,
+        age_days: u64,
+    },
+
+    #[error("An action was requested with neither a transaction id nor a reference")]
+    NoTransactionId,
+
+    #[error("A new transaction was requested with the same reference ({0}) as an existing one")]
+    ReferenceUsed(String),
+
+    #[error("An action on an existing transaction was requested but reference {0} does not exist")]
+    ReferenceMissing(String),
+
+    #[error("Reference {reference} belongs to transaction {transaction}, not the one requested")]
+    ReferenceMismatch {
+        reference: String,
+        transaction: TransactionId,
+    },
+
+    #[error("Every transaction id has been used, so none could be assigned")]
+    NoFreeTransactionId,
+
+    #[error("Transaction {transaction} can't be disputed, only {available} of its {amount} is still available")]
+    DisputeExceedsAvailable {
+        transaction: TransactionId,
+        amount: Amount,
+        available: Amount,
+    },
+
+    #[error("Client {client} already has {max} open disputes")]
+    DisputeLimitReached { client: ClientId, max: usize },
+
+    #[error("The amount is in {got}, but the ledger is kept in {expected}")]
+    CurrencyMismatch { expected: String, got: String },
+
+    /// An action for `ClientId::TOMBSTONE`, which only holds the records of
+    /// erased clients
+    #[error("Client {0} is reserved for the records of erased clients")]
+    ReservedClient(ClientId),
+
+    #[error("Only withdrawals can be prepared, not {0:?} actions")]
+    NotPreparable(ActionKind),
+
+    #[error("Transaction {0} is not a prepared withdrawal")]
+    NotPrepared(TransactionId),
+
+    #[error("Withdrawals can't be prepared, committed or aborted while a savepoint is open")]
+    SavepointOpen,
+
+    #[error(
+        "The action is for client {action}, but was submitted through a view of client {view}"
+    )]
+    OutsideView { action: ClientId, view: ClientId },
+}
+
+impl UpdateError {
+    /// A short name for the error that won't change, for machine-readable
+    /// reports (the message can). A failed transaction has its account
+    /// error's code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::TransactionUsed(_) => "transaction_used",
+            Self::TransactionMissing(_) => "transaction_missing",
+            Self::AccountMissing(_) => "account_missing",
+            Self::ClientMismatch { .. } => "client_mismatch",
+            Self::NoAmount => "no_amount",
+            Self::NoReversalTarget => "no_reversal_target",
+            Self::NotReversible(_) => "not_reversible",
+            Self::NotDisputed(_) => "not_disputed",
+            Self::NotDisputable(_) => "not_disputable",
+            Self::LimitExceeded { .. } => "limit_exceeded",
+            Self::TransactionFailed { error, .. } => error.code(),
+            Self::Store(_) => "store",
+            Self::Journal(_) => "journal",
+            Self::DisputeWindowExpired { .. } => "dispute_window_expired",
+            Self::NoTransactionId => "no_transaction_id",
+            Self::ReferenceUsed(_) => "reference_used",
+            Self::ReferenceMissing(_) => "reference_missing",
+            Self::ReferenceMismatch { .. } => "reference_mismatch",
+            Self::NoFreeTransactionId => "no_free_transaction_id",
+            Self::DisputeExceedsAvailable { .. } => "dispute_exceeds_available",
+            Self::DisputeLimitReached { .. } => "dispute_limit_reached",
+            Self::CurrencyMismatch { .. } => "currency_mismatch",
+            Self::ReservedClient(_) => "reserved_client",
+            Self::NotPreparable(_) => "not_preparable",
+            Self::NotPrepared(_) => "not_prepared",
+            Self::SavepointOpen => "savepoint_open",
+            Self::OutsideView { .. } => "outside_view",
+        }
+    }
+}
+
+/// An action in an atomic group failed, so none of the group was applied
+#[derive(Debug, thiserror::Error)]
+#[error("Action {index} of the group failed, so none of it was applied: {source}")]
+pub struct GroupError {
+    /// The position of the failed action in the group
+    pub index: usize,
+    pub source: UpdateError,
+}
+
+// TODO: should this be in the engine module? Or maybe in it's own module?
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "decimal")]
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        audit::AuditEvent,
+        events::DisputeEvent,
+        io::CsvSource,
+        store::{MemoryStore, StoreError, StoreIter, TransactionStore},
+        Account, AccountError, AccountFilter, AccountOrder, AccountStatus, AccountsSummary, Action,
+        ActionKind, ActionMeta, Amount, BulkLoadError, ChargebackPolicy, ClientId, DisputeWindow,
+        ErasureError, FreezeReason, GroupError, HoldId, Limit, LimitsPolicy, LockExpiry,
+        LockedOperation, MergeError, OutputConfig, RawTransactionId, RestatementError, Rounding,
+        SingleThreadedEngine, State, StatusError, SyncEngineExt, Timestamp, Transaction,
+        TransactionEvent, TransactionEventKind, TransactionId, TransactionState, TrustedBatch,
+        TrustedBatchError, UpdateError,
+    };
+
+    /// How far apart amounts reached in different ways may be. Without the
+    /// `decimal` feature amounts are floats, so some drift is expected.
+    fn tolerance() -> Amount {
+        #[cfg(feature = "decimal")]
+        return Amount::default();
+        #[cfg(not(feature = "decimal"))]
+        return 0.000001;
+    }
+
+    // Macro for some terseness in tests
+    macro_rules! action {
+        ($kind:ident, $client:expr, $transaction:expr) => {
+            Action {
+                transaction_id: Some(TransactionId($transaction)),
+                client_id: ClientId($client),
+                kind: ActionKind::$kind,
+                amount: None,
+                timestamp: None,
+                reverses: None,
+                reference: None,
+                evidence: None,
+                category: None,
+                currency: None,
+                meta: None,
+            }
+        };
+        ($kind:ident, $client:expr, $transaction:expr, $amount:expr) => {
+            Action {
+                transaction_id: Some(TransactionId($transaction)),
+                client_id: ClientId($client),
+                kind: ActionKind::$kind,
+
+                #[cfg(feature = "decimal")]
+                amount: Some(dec!($amount)),
+
+                #[cfg(not(feature = "decimal"))]
+                amount: Some($amount),
+
+                timestamp: None,
+                reverses: None,
+                reference: None,
+                evidence: None,
+                category: None,
+                currency: None,
+                meta: None,
+            }
+        };
+    }
+
+    #[test]
+    fn test_account_is_created() {
+        let mut engine = SingleThreadedEngine::new();
+        let _ = engine.process_all(vec![
+            action!(Deposit, 1, 1, 1.5),
+            action!(Withdrawal, 1, 2, 1.0),
+        ]);
+
+        let account = engine.state().accounts().next().expect("no account!");
+        assert_eq!(account.total.to_string(), "0.5");
+    }
+
+    #[test]
+    fn test_disputes_can_be_resolved() {
+        let mut engine = SingleThreadedEngine::new();
         let _ = engine.process_all(vec![
             action!(Deposit, 1, 1, 1.5),
             action!(Dispute, 1, 1),
@@ -276,37 +2954,1827 @@ mod tests {
             action!(Withdrawal, 1, 2, 1.0),
         ]);
 
-        let account = engine.state().accounts().next().expect("no account!");
-        assert_eq!(account.total.to_string(), "0.5");
+        let account = engine.state().accounts().next().expect("no account!");
+        assert_eq!(account.total.to_string(), "0.5");
+    }
+
+    #[test]
+    fn test_transactions_can_occur_after_disputes() {
+        let mut engine = SingleThreadedEngine::new();
+        let _ = engine.process_all(vec![
+            action!(Deposit, 1, 1, 1.5),
+            action!(Dispute, 1, 1),
+            action!(Deposit, 1, 2, 3.0),
+            action!(Withdrawal, 1, 3, 1.0),
+        ]);
+
+        let account = engine.state().accounts().next().expect("no account!");
+        assert_eq!(account.available.to_string(), "2");
+        assert_eq!(account.held.to_string(), "1.5");
+    }
+
+    #[test]
+    fn test_chargebacks_lock_account() {
+        let mut engine = SingleThreadedEngine::new();
+        let _ = engine.process_all(vec![
+            action!(Deposit, 1, 1, 1.5),
+            action!(Dispute, 1, 1),
+            action!(Chargeback, 1, 1),
+            action!(Withdrawal, 1, 2, 1.0),
+        ]);
+
+        let account = engine.state().accounts().next().expect("no account!");
+        assert!(account.locked);
+        assert_eq!(account.total.to_string(), "0");
+        assert_eq!(account.freeze_reason.as_deref(), Some("chargeback"));
+    }
+
+    #[test]
+    fn test_undisputed_claims_are_rejected() {
+        use crate::audit::{AuditEvent, Outcome};
+
+        let mut state = State::new();
+        state.set_audit_trail(true);
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        for claim in [action!(Resolve, 1, 1), action!(Chargeback, 1, 1)] {
+            let error = state.update(claim).unwrap_err();
+            assert!(matches!(error, UpdateError::NotDisputed(TransactionId(1))));
+            assert_eq!(error.code(), "not_disputed");
+        }
+        // Another client's transaction is still a mismatch, disputed or not
+        assert!(matches!(
+            state.update(action!(Resolve, 2, 1)),
+            Err(UpdateError::ClientMismatch { .. })
+        ));
+
+        let account = state.accounts().next().unwrap();
+        assert_eq!(account.available, Amount::from(10u32));
+        assert!(!account.locked);
+
+        let export = state.export_client(ClientId(1)).unwrap();
+        let rejected = export
+            .audit_trail
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    &entry.event,
+                    AuditEvent::Action {
+                        outcome: Outcome::Rejected(_),
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(rejected, 2);
+    }
+
+    #[test]
+    fn test_failed_and_charged_back_transactions_cant_be_disputed() {
+        let history = vec![
+            action!(Deposit, 1, 1, 10.0),
+            action!(Dispute, 1, 1),
+            action!(Chargeback, 1, 1),
+            // The account is frozen by the chargeback, so this fails
+            action!(Deposit, 1, 2, 5.0),
+            action!(Dispute, 1, 1),
+            action!(Dispute, 1, 2),
+        ];
+
+        let mut state = State::new();
+        let results: Vec<_> = history
+            .iter()
+            .map(|action| state.update(action.clone()))
+            .collect();
+        assert!(results[..4].iter().all(Result::is_ok));
+        let error = results[4].as_ref().unwrap_err();
+        assert!(matches!(
+            error,
+            UpdateError::NotDisputable(TransactionId(1))
+        ));
+        assert_eq!(error.code(), "not_disputable");
+        assert!(matches!(
+            results[5],
+            Err(UpdateError::NotDisputable(TransactionId(2)))
+        ));
+        assert!(matches!(
+            state.transaction(TransactionId(2)).unwrap().unwrap().state,
+            TransactionState::Failed(_)
+        ));
+
+        // The fast path skips them too
+        let mut ingested = State::new();
+        ingested
+            .ingest_unchecked(TrustedBatch::new(history).unwrap())
+            .unwrap();
+        for state in [&state, &ingested] {
+            let account = state.account(ClientId(1)).unwrap();
+            assert_eq!(account.held, Amount::default());
+            assert_eq!(account.total, Amount::default());
+            assert!(state
+                .transaction(TransactionId(2))
+                .unwrap()
+                .unwrap()
+                .history
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_atomic_groups_roll_back() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 5.0)).unwrap();
+
+        // The fee can't be covered, so the withdrawal is undone too
+        let result = state.update_atomic(&[
+            action!(Withdrawal, 1, 2, 4.0),
+            action!(Withdrawal, 1, 3, 2.0),
+        ]);
+        assert!(matches!(
+            result,
+            Err(GroupError {
+                index: 1,
+                source: UpdateError::TransactionFailed { .. }
+            })
+        ));
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available.to_string(), "5");
+        assert_eq!(state.failed_transactions().count(), 0);
+
+        // The rolled back ids can be used again
+        state
+            .update_atomic(&[
+                action!(Withdrawal, 1, 2, 4.0),
+                action!(Withdrawal, 1, 3, 0.5),
+                action!(Deposit, 2, 4, 1.0),
+            ])
+            .unwrap();
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available.to_string(),
+            "0.5"
+        );
+
+        // Accounts created by a failed group are removed again
+        let result = state.update_atomic(&[action!(Deposit, 3, 5, 1.0), action!(Dispute, 3, 1)]);
+        assert!(matches!(
+            result,
+            Err(GroupError {
+                index: 1,
+                source: UpdateError::ClientMismatch { .. }
+            })
+        ));
+        assert!(state.account(ClientId(3)).is_none());
+        assert!(!state.seen_transactions().contains(TransactionId(5)));
+    }
+
+    #[test]
+    fn test_reversals() {
+        let reversal = |client, transaction, target| Action {
+            reverses: Some(TransactionId(target)),
+            ..action!(Reversal, client, transaction)
+        };
+
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 5.0)).unwrap();
+        state.update(action!(Withdrawal, 1, 2, 2.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 10.0)).unwrap();
+
+        // A fat-fingered deposit and an erroneous withdrawal
+        state.update(reversal(1, 4, 3)).unwrap();
+        state.update(reversal(1, 5, 2)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available.to_string(), "5");
+        assert_eq!(account.held.to_string(), "0");
+
+        // Reversed transactions can't be reversed again or disputed
+        assert!(matches!(
+            state.update(reversal(1, 6, 3)),
+            Err(UpdateError::NotReversible(TransactionId(3)))
+        ));
+        state.update(action!(Deposit, 1, 8, 20.0)).unwrap();
+        state.update(action!(Dispute, 1, 3)).unwrap();
+        assert_eq!(state.account(ClientId(1)).unwrap().held.to_string(), "0");
+        assert_eq!(
+            state
+                .transactions
+                .get(TransactionId(3))
+                .unwrap()
+                .unwrap()
+                .state,
+            TransactionState::Reversed(TransactionId(4))
+        );
+
+        // Disputed transactions go through the dispute flow instead
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        assert!(matches!(
+            state.update(reversal(1, 7, 1)),
+            Err(UpdateError::NotReversible(TransactionId(1)))
+        ));
+    }
+
+    #[test]
+    fn test_nested_savepoints() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 5.0)).unwrap();
+
+        let outer = state.savepoint();
+        state.update(action!(Withdrawal, 1, 2, 1.0)).unwrap();
+        let inner = state.savepoint();
+        state.update(action!(Deposit, 2, 3, 2.0)).unwrap();
+        state
+            .change_status(ClientId(1), |account| {
+                account.freeze(FreezeReason::Chargeback)
+            })
+            .unwrap();
+        state.rollback_to(inner).unwrap();
+
+        assert!(state.account(ClientId(2)).is_none());
+        let account = state.account(ClientId(1)).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.available.to_string(), "4");
+
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        state.rollback_to(outer).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available.to_string(), "5");
+        assert_eq!(account.held.to_string(), "0");
+        assert!(!state.seen_transactions().contains(TransactionId(2)));
+
+        // Released changes stay
+        let savepoint = state.savepoint();
+        state.update(action!(Withdrawal, 1, 2, 1.0)).unwrap();
+        state.release(savepoint);
+        assert!(state.undo_log.is_empty());
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available.to_string(),
+            "4"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        const HOUR: u64 = 60 * 60;
+        let withdrawal = |transaction, hour, amount: u32| Action {
+            amount: Some(Amount::from(amount)),
+            timestamp: Some(Timestamp::from_secs(hour * HOUR)),
+            ..action!(Withdrawal, 1, transaction)
+        };
+
+        let mut state = State::new();
+        state.set_limits(Some(LimitsPolicy {
+            max_transaction: Some(Amount::from(100u32)),
+            max_withdrawal: Some(Amount::from(50u32)),
+            max_daily_withdrawal: Some(Amount::from(80u32)),
+        }));
+
+        assert!(matches!(
+            state.update(action!(Deposit, 1, 1, 150.0)),
+            Err(UpdateError::LimitExceeded {
+                limit: Limit::Transaction,
+                ..
+            })
+        ));
+        state.update(action!(Deposit, 1, 2, 100.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 100.0)).unwrap();
+
+        assert!(matches!(
+            state.update(withdrawal(4, 0, 60)),
+            Err(UpdateError::LimitExceeded {
+                limit: Limit::Withdrawal,
+                ..
+            })
+        ));
+        state.update(withdrawal(5, 0, 50)).unwrap();
+        assert!(matches!(
+            state.update(withdrawal(6, 12, 40)),
+            Err(UpdateError::LimitExceeded {
+                limit: Limit::DailyWithdrawal,
+                ..
+            })
+        ));
+        state.update(withdrawal(7, 12, 30)).unwrap();
+
+        // The first withdrawal has rolled out of the window
+        state.update(withdrawal(8, 25, 40)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available.to_string(), "80");
+        // Rejected ids are still used up
+        assert!(matches!(
+            state.update(action!(Deposit, 1, 1, 1.0)),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+    }
+
+    #[test]
+    fn test_quarantine_blocks_withdrawals() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 2.0)).unwrap();
+        state.quarantine(ClientId(1)).unwrap();
+
+        state.update(action!(Withdrawal, 1, 2, 1.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 1.0)).unwrap();
+        state.update(action!(Dispute, 1, 3)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert!(account.quarantined);
+        assert!(!account.locked);
+        assert_eq!(account.available.to_string(), "2");
+        assert_eq!(account.held.to_string(), "1");
+        assert_eq!(state.failed_transactions().count(), 1);
+
+        state.lift_quarantine(ClientId(1)).unwrap();
+        state.update(action!(Withdrawal, 1, 4, 1.0)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert!(!account.quarantined);
+        assert_eq!(account.available.to_string(), "1");
+    }
+
+    #[test]
+    fn test_account_lifecycle() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 2.0)).unwrap();
+
+        // Dormant accounts can't withdraw, until a deposit wakes them up
+        state
+            .change_status(ClientId(1), |account| account.mark_dormant())
+            .unwrap();
+        state.update(action!(Withdrawal, 1, 2, 1.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 1.0)).unwrap();
+        state.update(action!(Withdrawal, 1, 4, 3.0)).unwrap();
+        assert_eq!(state.failed_transactions().count(), 1);
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().status,
+            AccountStatus::Active
+        );
+
+        // Frozen accounts refuse everything
+        let reason = FreezeReason::Manual("fraud review".into());
+        state
+            .change_status(ClientId(1), |account| account.freeze(reason))
+            .unwrap();
+        state.update(action!(Deposit, 1, 5, 1.0)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.total.to_string(), "0");
+        assert_eq!(
+            state.transaction(TransactionId(5)).unwrap().unwrap().state,
+            TransactionState::Failed(AccountError::Locked(LockedOperation::Deposit))
+        );
+        // Failures stored before the operation was recorded still load
+        assert_eq!(
+            serde_json::from_str::<AccountError>(r#""Locked""#).unwrap(),
+            AccountError::Locked(LockedOperation::Unrecorded)
+        );
+        assert_eq!(
+            account.freeze_reason.as_deref(),
+            Some("manual: fraud review")
+        );
+
+        // Only unfrozen, empty accounts can be closed
+        assert!(matches!(
+            state.change_status(ClientId(1), |account| account.close()),
+            Err(StatusError::InvalidTransition { from: "frozen", .. })
+        ));
+        state
+            .change_status(ClientId(1), |account| account.unfreeze())
+            .unwrap();
+        state
+            .change_status(ClientId(1), |account| account.close())
+            .unwrap();
+        assert!(state.update(action!(Deposit, 1, 6, 1.0)).is_ok());
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().status,
+            AccountStatus::Closed
+        );
     }
 
     #[test]
-    fn test_transactions_can_occur_after_disputes() {
-        let mut engine = SingleThreadedEngine::new();
-        let _ = engine.process_all(vec![
-            action!(Deposit, 1, 1, 1.5),
-            action!(Dispute, 1, 1),
-            action!(Deposit, 1, 2, 3.0),
-            action!(Withdrawal, 1, 3, 1.0),
-        ]);
+    fn test_account_versions() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 2.0)).unwrap();
+        state.update(action!(Deposit, 1, 2, 1.0)).unwrap();
+        let read = state.account(ClientId(1)).unwrap().version;
+        assert_eq!(read, 2);
+        // Rejected actions don't write the account
+        assert!(state.update(action!(Deposit, 1, 2, 1.0)).is_err());
+        assert_eq!(state.account(ClientId(1)).unwrap().version, read);
 
-        let account = engine.state().accounts().next().expect("no account!");
-        assert_eq!(account.available.to_string(), "2");
+        // The engine got there first, so the freeze is refused
+        state.update(action!(Withdrawal, 1, 3, 1.0)).unwrap();
+        let freeze = |account: &mut Account| account.freeze(FreezeReason::Manual("review".into()));
+        assert!(matches!(
+            state.change_status_at(ClientId(1), read, freeze),
+            Err(StatusError::VersionMismatch {
+                expected: 2,
+                actual: 3,
+                ..
+            })
+        ));
+        assert!(!state.account(ClientId(1)).unwrap().locked);
+        state.change_status_at(ClientId(1), 3, freeze).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.version, 4);
+        assert!(matches!(
+            state.change_status_at(ClientId(2), 1, freeze),
+            Err(StatusError::AccountMissing(_))
+        ));
+
+        // Rolling back is a write too, so versions never repeat
+        let savepoint = state.savepoint();
+        state
+            .change_status(ClientId(1), |account| account.unfreeze())
+            .unwrap();
+        state.rollback_to(savepoint).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.version, 6);
+    }
+
+    #[test]
+    fn test_temporary_freezes_lift_themselves() {
+        let at = |action: Action, secs| Action {
+            timestamp: Some(Timestamp::from_secs(secs)),
+            ..action
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut state = State::new();
+        state.set_observer(sender);
+        state.set_audit_trail(true);
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 2, 10.0)).unwrap();
+
+        let reason = FreezeReason::Manual("velocity check".into());
+        state
+            .freeze_until(
+                ClientId(1),
+                reason.clone(),
+                LockExpiry::At(Timestamp::from_secs(100)),
+            )
+            .unwrap();
+        state
+            .freeze_until(ClientId(2), reason, LockExpiry::AfterActions(1))
+            .unwrap();
+
+        // Refused until the freeze runs out
+        state
+            .update(at(action!(Withdrawal, 1, 3, 1.0), 99))
+            .unwrap();
+        state.update(action!(Withdrawal, 2, 4, 1.0)).unwrap();
+        assert_eq!(state.account(ClientId(1)).unwrap().total.to_string(), "10");
+        assert_eq!(state.account(ClientId(2)).unwrap().total.to_string(), "10");
+
+        state
+            .update(at(action!(Withdrawal, 1, 5, 1.0), 100))
+            .unwrap();
+        state.update(action!(Withdrawal, 2, 6, 1.0)).unwrap();
+        for client in [ClientId(1), ClientId(2)] {
+            let account = state.account(client).unwrap();
+            assert_eq!(account.status, AccountStatus::Active);
+            assert_eq!(account.total.to_string(), "9");
+        }
+        assert!(state.expiring_locks.is_empty());
+
+        let kinds: Vec<_> = receiver
+            .try_iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].clone())
+            .collect();
+        assert_eq!(kinds, ["locked", "locked", "unlocked", "unlocked"]);
+        let unfrozen = state
+            .export_client(ClientId(1))
+            .unwrap()
+            .audit_trail
+            .iter()
+            .filter(|entry| {
+                matches!(&entry.event, AuditEvent::StatusChanged { to, .. } if to == "active")
+            })
+            .count();
+        assert_eq!(unfrozen, 1);
+    }
+
+    #[test]
+    fn test_disputes_outside_window_are_rejected() {
+        const DAY: u64 = 24 * 60 * 60;
+        let at = |day: u64| Some(Timestamp::from_secs(day * DAY));
+
+        let mut state = State::new();
+        state.set_dispute_window(Some(DisputeWindow::days(60)));
+        for (tx, day) in [(1, 0), (2, 30)] {
+            state
+                .update(Action {
+                    timestamp: at(day),
+                    ..action!(Deposit, 1, tx, 1.5)
+                })
+                .unwrap();
+        }
+
+        let result = state.update(Action {
+            timestamp: at(61),
+            ..action!(Dispute, 1, 1)
+        });
+        assert!(matches!(
+            result,
+            Err(UpdateError::DisputeWindowExpired { age_days: 61, .. })
+        ));
+
+        state
+            .update(Action {
+                timestamp: at(61),
+                ..action!(Dispute, 1, 2)
+            })
+            .unwrap();
+
+        let account = state.accounts().next().expect("no account!");
         assert_eq!(account.held.to_string(), "1.5");
+        assert_eq!(account.available.to_string(), "1.5");
     }
 
     #[test]
-    fn test_chargebacks_lock_account() {
-        let mut engine = SingleThreadedEngine::new();
-        let _ = engine.process_all(vec![
-            action!(Deposit, 1, 1, 1.5),
-            action!(Dispute, 1, 1),
-            action!(Chargeback, 1, 1),
-            action!(Withdrawal, 1, 2, 1.0),
+    fn test_untimestamped_disputes_ignore_window() {
+        let mut state = State::new();
+        state.set_dispute_window(Some(DisputeWindow::days(60)));
+        state
+            .update(Action {
+                timestamp: Some(Timestamp::from_secs(0)),
+                ..action!(Deposit, 1, 1, 1.5)
+            })
+            .unwrap();
+        state.update(action!(Dispute, 1, 1)).unwrap();
+
+        let account = state.accounts().next().expect("no account!");
+        assert_eq!(account.held.to_string(), "1.5");
+    }
+
+    #[test]
+    fn test_bulk_load_matches_update() {
+        let history = vec![
+            action!(Deposit, 1, 1, 10.0),
+            action!(Deposit, 2, 2, 5.0),
+            action!(Withdrawal, 1, 3, 2.5),
+            action!(Dispute, 2, 2),
+            action!(Chargeback, 2, 2),
+            action!(Deposit, 1, 4, 1.0),
+            action!(Dispute, 1, 4),
+        ];
+
+        let mut expected = State::new();
+        for action in history.clone() {
+            expected.update(action).unwrap();
+        }
+
+        let mut state = State::new();
+        assert_eq!(state.bulk_load(history).unwrap(), 7);
+        for client in [1, 2] {
+            let (a, b) = (
+                state.account(ClientId(client)).unwrap(),
+                expected.account(ClientId(client)).unwrap(),
+            );
+            assert_eq!(
+                (a.available, a.held, a.locked),
+                (b.available, b.held, b.locked)
+            );
+        }
+
+        // Redelivered ids are still caught after loading
+        assert!(matches!(
+            state.update(action!(Deposit, 1, 1, 10.0)),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+        assert!(matches!(
+            state.bulk_load(vec![action!(Deposit, 3, 5, 1.0)]),
+            Err(BulkLoadError::NotEmpty)
+        ));
+    }
+
+    #[test]
+    fn test_ingest_unchecked_matches_update() {
+        let history: Vec<Action> = crate::testing::ActionGenerator::new(7)
+            .with_weight(ActionKind::Reversal, 0)
+            .take(2_000)
+            .collect();
+
+        let mut expected = State::new();
+        for action in history.clone() {
+            let _ = expected.update(action);
+        }
+
+        let mut state = State::new();
+        let batch = TrustedBatch::new(history).unwrap();
+        assert!(state.ingest_unchecked(batch).unwrap() > 0);
+        assert_eq!(state.sequence(), expected.sequence());
+        for account in expected.accounts() {
+            let loaded = state.account(account.client).unwrap();
+            assert_eq!(
+                (loaded.available, loaded.held, loaded.locked),
+                (account.available, account.held, account.locked)
+            );
+        }
+        for entry in expected.transactions.iter() {
+            let (id, transaction) = entry.unwrap();
+            let loaded = state.transaction(id).unwrap().unwrap();
+            assert_eq!(loaded.state, transaction.state);
+        }
+        assert!(state.check_invariants_within(tolerance()).is_ok());
+
+        // Only an empty state can be filled
+        assert!(matches!(
+            state.ingest_unchecked(TrustedBatch::default()),
+            Err(BulkLoadError::NotEmpty)
+        ));
+        assert_eq!(
+            TrustedBatch::new(vec![action!(Deposit, 1, 1, 1.0), action!(Reversal, 1, 2)])
+                .unwrap_err(),
+            TrustedBatchError::Unsupported { index: 1 }
+        );
+    }
+
+    #[test]
+    fn test_bulk_load_checks_deferred() {
+        let at = |action: Action, secs| Action {
+            timestamp: Some(Timestamp::from_secs(secs)),
+            ..action
+        };
+
+        let mut state = State::new();
+        let result = state.bulk_load(vec![
+            at(action!(Deposit, 1, 1, 1.0), 20),
+            at(action!(Deposit, 1, 2, 1.0), 10),
+            action!(Deposit, 1, 3, 1.0),
         ]);
+        assert!(matches!(
+            result,
+            Err(BulkLoadError::OutOfOrder { index: 1 })
+        ));
 
-        let account = engine.state().accounts().next().expect("no account!");
-        assert!(account.locked);
-        assert_eq!(account.total.to_string(), "0");
+        let mut state = State::new();
+        let result = state.bulk_load(vec![
+            action!(Deposit, 1, 1, 1.0),
+            action!(Deposit, 1, 1, 1.0),
+            action!(Resolve, 1, 9),
+            action!(Deposit, 1, 2, 1.0),
+        ]);
+        assert!(matches!(
+            result,
+            Err(BulkLoadError::Rejected {
+                index: 1,
+                count: 2,
+                source: UpdateError::TransactionUsed(_)
+            })
+        ));
+
+        let mut state = State::new();
+        let result = state.bulk_load(vec![action!(Withdrawal, 1, 1, 1.0)]);
+        assert!(matches!(result, Err(BulkLoadError::FailedTransactions(1))));
+    }
+
+    #[test]
+    fn test_export_client() {
+        use crate::audit::{AuditEvent, Outcome};
+
+        let mut state = State::new();
+        state.set_audit_trail(true);
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 2, 10.0)).unwrap();
+        state.update(action!(Withdrawal, 1, 3, 20.0)).unwrap();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap_err();
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        state.update(action!(Resolve, 1, 1)).unwrap();
+
+        // Rolled back actions drop out of the trail
+        let group = [
+            action!(Deposit, 1, 4, 1.0),
+            action!(Withdrawal, 1, 5, 100.0),
+        ];
+        state.update_atomic(&group).unwrap_err();
+        state.quarantine(ClientId(1)).unwrap();
+
+        let export = state.export_client(ClientId(1)).unwrap();
+        assert_eq!(
+            export.account.as_ref().unwrap().available,
+            Amount::from(10u32)
+        );
+        let ids: Vec<_> = export.transactions.iter().map(|t| t.id).collect();
+        assert_eq!(ids, [TransactionId(1), TransactionId(3)]);
+
+        let outcomes: Vec<_> = export
+            .audit_trail
+            .iter()
+            .map(|entry| match &entry.event {
+                AuditEvent::Action { outcome, .. } => outcome.clone(),
+                AuditEvent::StatusChanged { quarantined, .. } => {
+                    assert!(quarantined);
+                    Outcome::Applied
+                }
+                AuditEvent::Restated { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(outcomes.len(), 6);
+        assert!(matches!(outcomes[1], Outcome::Failed(_)));
+        assert!(matches!(outcomes[2], Outcome::Rejected(_)));
+
+        assert_eq!(export.disputes.len(), 1);
+        assert_eq!(export.disputes[0].transaction, TransactionId(1));
+        assert_eq!(
+            export.disputes[0].current_state,
+            Some(TransactionState::Succeeded)
+        );
+        assert_eq!(export.disputes[0].events.len(), 2);
+
+        let json = serde_json::to_value(&export).unwrap();
+        assert_eq!(json["audit_trail"][1]["outcome"], "failed");
+        assert_eq!(json["audit_trail"][2]["outcome"], "rejected");
+        let entries: Vec<crate::audit::AuditEntry> =
+            serde_json::from_value(json["audit_trail"].clone()).unwrap();
+        assert_eq!(entries, export.audit_trail);
+    }
+
+    #[test]
+    fn test_balance_at() {
+        use crate::audit::{AuditEvent, Outcome};
+
+        let mut state = State::new();
+        state.set_audit_trail(true);
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 2, 5.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 4.0)).unwrap();
+        state.update(action!(Dispute, 1, 3)).unwrap();
+        state.update(action!(Withdrawal, 1, 4, 11.0)).unwrap();
+        state
+            .change_status(ClientId(1), |account| {
+                account.freeze(FreezeReason::Manual("review".into()))
+            })
+            .unwrap();
+
+        assert_eq!(state.balance_at(ClientId(1), 0), None);
+        assert_eq!(state.balance_at(ClientId(2), 1), None);
+        let at = |seq| state.balance_at(ClientId(1), seq).unwrap();
+        assert_eq!(at(1).available, Amount::from(10u32));
+        assert_eq!(at(2), at(1));
+        assert_eq!(at(3).total, Amount::from(14u32));
+        assert_eq!(
+            (at(4).available, at(4).held),
+            (Amount::from(10u32), Amount::from(4u32))
+        );
+
+        // What the client saw when the withdrawal bounced
+        let trail = state.export_client(ClientId(1)).unwrap().audit_trail;
+        let bounced = trail
+            .iter()
+            .find(|entry| {
+                matches!(
+                    entry.event,
+                    AuditEvent::Action {
+                        outcome: Outcome::Failed(_),
+                        ..
+                    }
+                )
+            })
+            .unwrap();
+        assert_eq!(bounced.seq, 5);
+        assert_eq!(bounced.balances, Some(at(4)));
+
+        // The freeze came after the withdrawal, before any other action
+        assert!(at(5).locked);
+        assert_eq!(at(5).available, Amount::from(10u32));
+    }
+
+    #[test]
+    fn test_merge() {
+        let actions = || {
+            crate::testing::ActionGenerator::new(7)
+                .take(2000)
+                .collect::<Vec<_>>()
+        };
+        let mut whole = State::new();
+        let mut parts = [State::new(), State::new()];
+        for action in actions() {
+            let _ = whole.update(action.clone());
+            let _ = parts[action.client_id.0 as usize % 2].update(action);
+        }
+
+        let [even, odd] = parts;
+        let merged = even.merge(odd).unwrap();
+        let sorted =
+            |state: &State| state.accounts_sorted(AccountFilter::All(vec![]), AccountOrder::Client);
+        assert_eq!(sorted(&merged), sorted(&whole));
+        // Merging adds the partitions up in a different order
+        let (merged_summary, whole_summary) = (merged.summary(), whole.summary());
+        assert_eq!(
+            (merged_summary.clients, merged_summary.locked),
+            (whole_summary.clients, whole_summary.locked)
+        );
+        for (merged, whole) in [
+            (merged_summary.available, whole_summary.available),
+            (merged_summary.held, whole_summary.held),
+            (merged_summary.total, whole_summary.total),
+        ] {
+            assert!((merged - whole).abs() <= tolerance(), "{merged} != {whole}");
+        }
+
+        let mut first = State::new();
+        first.update(action!(Deposit, 1, 1, 1.0)).unwrap();
+        let mut second = State::new();
+        second.update(action!(Deposit, 2, 1, 1.0)).unwrap();
+        assert!(matches!(
+            first.merge(second),
+            Err(MergeError::OverlappingTransaction(TransactionId(1)))
+        ));
+        let mut first = State::new();
+        first.update(action!(Deposit, 1, 1, 1.0)).unwrap();
+        let mut second = State::new();
+        second.update(action!(Deposit, 1, 2, 1.0)).unwrap();
+        assert!(matches!(
+            first.merge(second),
+            Err(MergeError::OverlappingClient(ClientId(1)))
+        ));
+    }
+
+    #[test]
+    fn test_bulk() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 2, 1.0)).unwrap();
+        state.update(action!(Deposit, 3, 3, 10.0)).unwrap();
+
+        let clients = [ClientId(1), ClientId(2), ClientId(1), ClientId(4)];
+        let fee = crate::BulkOperation::Fee {
+            amount: Amount::from(5u32),
+            reference: "fee-2024".into(),
+        };
+        let report = state.bulk(&clients, &fee);
+        assert_eq!(report.succeeded, [ClientId(1)]);
+        let failed: Vec<_> = report.failed.iter().map(|f| f.client).collect();
+        assert_eq!(failed, [ClientId(2), ClientId(4)]);
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available,
+            Amount::from(5u32)
+        );
+        assert_eq!(
+            state.account(ClientId(2)).unwrap().available,
+            Amount::from(1u32)
+        );
+        assert!(state.try_account(ClientId(4)).unwrap().is_none());
+
+        // Running it again doesn't charge anyone twice
+        let report = state.bulk(&clients, &fee);
+        assert!(report.succeeded.is_empty());
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available,
+            Amount::from(5u32)
+        );
+
+        let freeze = crate::BulkOperation::Freeze(FreezeReason::Manual("incident".into()));
+        let report = state.bulk(&[ClientId(2), ClientId(3)], &freeze);
+        assert_eq!(report.succeeded, [ClientId(2), ClientId(3)]);
+        assert!(state.account(ClientId(3)).unwrap().locked);
+        let report = state.bulk(&[ClientId(2)], &freeze);
+        assert_eq!(report.failed.len(), 1);
+
+        let export = state
+            .export_clients(&[ClientId(3), ClientId(4), ClientId(1)])
+            .unwrap();
+        assert_eq!(export.report.succeeded, [ClientId(3), ClientId(1)]);
+        assert_eq!(export.report.failed[0].client, ClientId(4));
+        assert_eq!(export.exports.len(), 2);
+        assert_eq!(export.exports[1].transactions.len(), 2);
+        assert_eq!(
+            export.exports[0].transactions[0].id,
+            state.export_client(ClientId(3)).unwrap().transactions[0].id
+        );
+    }
+
+    #[test]
+    fn test_dispute_evidence() {
+        let with_evidence = |action: Action, evidence: &str| Action {
+            evidence: Some(evidence.into()),
+            ..action
+        };
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state
+            .update(with_evidence(action!(Dispute, 1, 1), "case-17/claim.pdf"))
+            .unwrap();
+
+        // Evidence from rolled back or ignored actions isn't kept
+        let savepoint = state.savepoint();
+        state
+            .update(with_evidence(action!(Chargeback, 1, 1), "case-17/refund"))
+            .unwrap();
+        state.rollback_to(savepoint).unwrap();
+        state
+            .update(with_evidence(action!(Resolve, 1, 1), "case-17/closed"))
+            .unwrap();
+        state
+            .update(with_evidence(action!(Resolve, 1, 1), "case-17/again"))
+            .unwrap_err();
+
+        let export = state.export_client(ClientId(1)).unwrap();
+        assert_eq!(export.disputes.len(), 1);
+        assert!(export.disputes[0].events.is_empty());
+        let evidence: Vec<_> = export.disputes[0]
+            .evidence
+            .iter()
+            .map(|e| (e.kind, e.reference.as_str()))
+            .collect();
+        assert_eq!(
+            evidence,
+            [
+                (ActionKind::Dispute, "case-17/claim.pdf"),
+                (ActionKind::Resolve, "case-17/closed")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_open_disputes() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut state = State::new();
+        state.set_observer(sender);
+        state.set_max_open_disputes(Some(2));
+        for tx in 1..=4 {
+            state.update(action!(Deposit, 1, tx, 1.0)).unwrap();
+        }
+        // Prepared withdrawals hold funds too, but aren't disputes
+        let payout = state.prepare(action!(Withdrawal, 1, 5, 1.0)).unwrap();
+
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        state.update(action!(Dispute, 1, 2)).unwrap();
+        assert!(matches!(
+            state.update(action!(Dispute, 1, 3)),
+            Err(UpdateError::DisputeLimitReached { max: 2, .. })
+        ));
+        let refused = receiver
+            .try_iter()
+            .filter(|event| matches!(event, DisputeEvent::DisputeRefused { open: 2, .. }))
+            .count();
+        assert_eq!(refused, 1);
+
+        // Settling one makes room for another
+        state.update(action!(Resolve, 1, 1)).unwrap();
+        state.update(action!(Dispute, 1, 3)).unwrap();
+        state.commit(payout).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.held, Amount::from(2u32));
+        assert_eq!(
+            state.transaction(TransactionId(3)).unwrap().unwrap().state,
+            TransactionState::Disputed
+        );
+    }
+
+    #[test]
+    fn test_currency_mismatch() {
+        let in_currency = |action: Action, currency: &str| Action {
+            currency: Some(currency.into()),
+            ..action
+        };
+        let mut state = State::new();
+        state.set_currency(Some("USD".into()));
+        state
+            .update(in_currency(action!(Deposit, 1, 1, 5.0), "USD"))
+            .unwrap();
+        // Actions that don't say are taken to be in the ledger's currency
+        state.update(action!(Deposit, 1, 2, 1.0)).unwrap();
+
+        let mismatch = |result| match result {
+            Err(UpdateError::CurrencyMismatch { expected, got }) => (expected, got),
+            other => panic!("expected a currency mismatch, got {:?}", other),
+        };
+        let (expected, got) =
+            mismatch(state.update(in_currency(action!(Withdrawal, 1, 3, 1.0), "EUR")));
+        assert_eq!((expected.as_str(), got.as_str()), ("USD", "EUR"));
+        mismatch(
+            state
+                .prepare(in_currency(action!(Withdrawal, 1, 4, 1.0), "EUR"))
+                .map(drop),
+        );
+        assert!(!state.seen_transactions().contains(TransactionId(3)));
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available,
+            Amount::from(6u32)
+        );
+    }
+
+    #[test]
+    fn test_output_rounding() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 0.125)).unwrap();
+        state.update(action!(Deposit, 2, 2, 0.135)).unwrap();
+        state.update(action!(Deposit, 3, 3, 1.23456)).unwrap();
+        let available = |state: &State| -> Vec<String> {
+            let mut accounts: Vec<_> = state.accounts().collect();
+            accounts.sort_by_key(|a| a.client);
+            accounts.iter().map(|a| a.available.to_string()).collect()
+        };
+        #[cfg(all(feature = "decimal", not(feature = "crypto")))]
+        assert_eq!(available(&state)[2], "1.2346");
+
+        state.set_output(OutputConfig::new(2, Rounding::MidpointNearestEven));
+        assert_eq!(available(&state), ["0.12", "0.14", "1.23"]);
+        state.set_output(OutputConfig::new(2, Rounding::MidpointAwayFromZero));
+        assert_eq!(available(&state), ["0.13", "0.14", "1.23"]);
+        state.set_output(OutputConfig::new(3, Rounding::ToZero));
+        assert_eq!(
+            state.account(ClientId(3)).unwrap().available.to_string(),
+            "1.234"
+        );
+    }
+
+    // Amounts are written as exact strings with the crypto feature
+    #[cfg(not(feature = "crypto"))]
+    #[test]
+    fn test_output_columns() {
+        use crate::OutputColumns;
+
+        let mut state = State::new();
+        let input = include_str!("../test_data/dense.csv");
+        for action in CsvSource::from_reader(input.as_bytes()) {
+            let _ = state.update(action.unwrap());
+        }
+        let output = |state: &State| {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for account in state.accounts_sorted(AccountFilter::All(vec![]), AccountOrder::Total) {
+                writer.serialize(account).unwrap();
+            }
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+
+        // The v1 columns, unless the others are asked for
+        assert_eq!(output(&state), include_str!("../test_data/output.csv"));
+        state.set_output(OutputConfig::default().with_columns(OutputColumns::Extended));
+        assert_eq!(
+            output(&state),
+            include_str!("../test_data/output_extended.csv")
+        );
+    }
+
+    #[test]
+    fn test_summary_is_kept_up_to_date() {
+        let fresh_sum = |state: &State| {
+            let mut summary = AccountsSummary::default();
+            for entry in state.raw_accounts() {
+                summary.add(&entry.unwrap().1);
+            }
+            summary
+        };
+
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 2, 4.0)).unwrap();
+        state.update(action!(Withdrawal, 2, 3, 1.5)).unwrap();
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        assert_eq!(state.summary(), fresh_sum(&state));
+        assert_eq!(state.summary().held, Amount::from(10u32));
+
+        let savepoint = state.savepoint();
+        state.update(action!(Deposit, 3, 4, 1.0)).unwrap();
+        state.update(action!(Chargeback, 1, 1)).unwrap();
+        assert_eq!(state.summary().locked, 1);
+        state.rollback_to(savepoint).unwrap();
+        assert_eq!(state.summary(), fresh_sum(&state));
+
+        state.update(action!(Withdrawal, 2, 5, 2.5)).unwrap();
+        state.change_status(ClientId(2), |a| a.close()).unwrap();
+        state.erase_client(ClientId(2)).unwrap();
+        let summary = state.summary();
+        assert_eq!(summary, fresh_sum(&state));
+        // Client 1, and the closed tombstone account
+        assert_eq!((summary.clients, summary.locked), (2, 1));
+        assert_eq!(summary.total, Amount::from(10u32));
+    }
+
+    #[test]
+    fn test_accounts_where() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 20.0)).unwrap();
+        state.update(action!(Deposit, 1, 2, 15.0)).unwrap();
+        state.update(action!(Dispute, 1, 2)).unwrap();
+        state.update(action!(Deposit, 2, 4, 30.0)).unwrap();
+        state.update(action!(Dispute, 2, 4)).unwrap();
+        state.update(action!(Deposit, 3, 5, 1.0)).unwrap();
+        state.update(action!(Dispute, 3, 5)).unwrap();
+        state.update(action!(Chargeback, 3, 5)).unwrap();
+
+        let clients = |filter| {
+            let mut clients: Vec<_> = state.accounts_where(filter).map(|a| a.client).collect();
+            clients.sort();
+            clients
+        };
+        let held_above = |amount: u32| AccountFilter::HeldAbove(Amount::from(amount));
+        assert_eq!(clients(AccountFilter::Locked), [ClientId(3)]);
+        assert_eq!(
+            clients(AccountFilter::AvailableBelow(Amount::from(1u32))),
+            [ClientId(2), ClientId(3)]
+        );
+        assert_eq!(clients(held_above(10)), [ClientId(1), ClientId(2)]);
+        assert_eq!(
+            clients(held_above(20).or(AccountFilter::Locked)),
+            [ClientId(2), ClientId(3)]
+        );
+        assert_eq!(
+            clients(held_above(10).and(AccountFilter::Locked.not())),
+            [ClientId(1), ClientId(2)]
+        );
+        assert!(clients(held_above(10).and(AccountFilter::Locked)).is_empty());
+        assert_eq!(clients(AccountFilter::NonZero), [ClientId(1), ClientId(2)]);
+
+        let sorted = |filter, order| -> Vec<_> {
+            let accounts = state.accounts_sorted(filter, order);
+            accounts.iter().map(|a| a.client.0).collect()
+        };
+        let everything = || AccountFilter::All(Vec::new());
+        assert_eq!(sorted(everything(), AccountOrder::Total), [1, 2, 3]);
+        assert_eq!(sorted(everything(), AccountOrder::Held), [2, 1, 3]);
+        assert_eq!(
+            sorted(AccountFilter::NonZero.not(), AccountOrder::Client),
+            [3]
+        );
+    }
+
+    #[test]
+    fn test_diff_since() {
+        use std::collections::HashMap;
+
+        use crate::StateDiff;
+
+        let clients =
+            |diff: &StateDiff| -> Vec<_> { diff.accounts.iter().map(|a| a.client.0).collect() };
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 2, 5.0)).unwrap();
+        state.update(action!(Deposit, 3, 3, 1.0)).unwrap();
+        let first = state.diff_since(0);
+        assert_eq!((first.sequence, first.full), (3, false));
+        assert_eq!(clients(&first), [1, 2, 3]);
+        assert_eq!(clients(&state.diff_since(2)), [3]);
+
+        state.update(action!(Withdrawal, 2, 4, 1.0)).unwrap();
+        // Failed, but the account is still written
+        state.update(action!(Withdrawal, 3, 5, 100.0)).unwrap();
+        let second = state.diff_since(first.sequence);
+        assert_eq!(clients(&second), [2, 3]);
+        assert_eq!(second.accounts[0].available, Amount::from(4u32));
+
+        state.update(action!(Withdrawal, 1, 6, 10.0)).unwrap();
+        let second = state.diff_since(second.sequence);
+        // Changes between actions are picked up by the next diff
+        state.change_status(ClientId(1), |a| a.close()).unwrap();
+        state.erase_client(ClientId(1)).unwrap();
+        let third = state.diff_since(second.sequence);
+        assert_eq!(clients(&third), [ClientId::TOMBSTONE.0]);
+        assert_eq!(third.removed, [ClientId(1)]);
+        // ... and counted with it
+        assert_eq!(state.diff_since(state.sequence()), third);
+        state.update(action!(Deposit, 2, 7, 1.0)).unwrap();
+        let fourth = state.diff_since(third.sequence);
+        assert_eq!(clients(&fourth), [2, ClientId::TOMBSTONE.0]);
+        assert!(state.diff_since(fourth.sequence).accounts.is_empty());
+
+        // A restored state doesn't know what changed before it was saved
+        let mut restored = State::from_parts(
+            HashMap::from([(ClientId(2), Account::default())]),
+            HashMap::new(),
+            None,
+            HashMap::new(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        restored.set_sequence(5);
+        assert!(restored.diff_since(4).full);
+        assert_eq!(clients(&restored.diff_since(4)), [2]);
+        assert!(!restored.diff_since(5).full);
+        assert!(restored.diff_since(5).accounts.is_empty());
+    }
+
+    #[test]
+    fn test_transaction_history() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 1, 2, 5.0)).unwrap();
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        state.update(action!(Resolve, 1, 1)).unwrap();
+        // Rejected, since it isn't disputed any more
+        assert!(matches!(
+            state.update(action!(Chargeback, 1, 1)),
+            Err(UpdateError::NotDisputed(TransactionId(1)))
+        ));
+        state.update(action!(Withdrawal, 1, 3, 9.0)).unwrap();
+        // Failed, since most of it has been withdrawn
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        let mut reversal = action!(Reversal, 1, 4);
+        reversal.reverses = Some(TransactionId(2));
+        state.update(reversal).unwrap();
+
+        // Rolled back actions leave nothing behind
+        let group = [action!(Dispute, 1, 2), action!(Withdrawal, 1, 5, 100.0)];
+        state.update_atomic(&group).unwrap_err();
+        assert_eq!(state.sequence(), 8);
+
+        let history = |id| {
+            let transaction = state.transaction(TransactionId(id)).unwrap().unwrap();
+            let events: Vec<_> = transaction
+                .history
+                .iter()
+                .map(|event| (event.seq, event.kind, event.failed))
+                .collect();
+            events
+        };
+        assert_eq!(
+            history(1),
+            [
+                (3, TransactionEventKind::Disputed, None),
+                (4, TransactionEventKind::Resolved, None),
+                (
+                    7,
+                    TransactionEventKind::Disputed,
+                    Some(AccountError::InsufficientFunds)
+                ),
+            ]
+        );
+        assert_eq!(
+            history(2),
+            [(8, TransactionEventKind::Reversed(TransactionId(4)), None)]
+        );
+        assert!(history(3).is_empty());
+    }
+
+    #[test]
+    fn test_refused_disputes_keep_the_transaction_settled() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Withdrawal, 1, 2, 8.0)).unwrap();
+        let error = state.update_atomic(&[action!(Dispute, 1, 1)]).unwrap_err();
+        assert!(matches!(
+            error.source,
+            UpdateError::TransactionFailed {
+                error: AccountError::InsufficientFunds,
+                ..
+            }
+        ));
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        assert_eq!(state.failed_transactions().count(), 1);
+
+        // Once the funds are back, the dispute can be tried again
+        state.update(action!(Deposit, 1, 3, 10.0)).unwrap();
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        state.update(action!(Resolve, 1, 1)).unwrap();
+        let deposit = state.transaction(TransactionId(1)).unwrap().unwrap();
+        assert_eq!(deposit.state, TransactionState::Succeeded);
+        assert_eq!(deposit.history.len(), 3);
+        assert_eq!(deposit.failures().count(), 1);
+        state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_failures_by_record_format() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state
+            .change_status(ClientId(1), |a| {
+                a.freeze(FreezeReason::Manual("review".into()))
+            })
+            .unwrap();
+        state.update(action!(Deposit, 1, 2, 5.0)).unwrap();
+        let mut failed = state.transaction(TransactionId(2)).unwrap().unwrap();
+        assert_eq!(failed.format, Transaction::FORMAT);
+        assert_eq!(
+            failed.failures().collect::<Vec<_>>(),
+            [AccountError::Locked(LockedOperation::Deposit)]
+        );
+
+        // A failed deposit with history is still a failed deposit
+        failed.history.push(TransactionEvent::new(
+            state.sequence(),
+            TransactionEventKind::Disputed,
+            None,
+            Err(AccountError::InsufficientFunds),
+        ));
+        assert_eq!(
+            failed.failures().collect::<Vec<_>>(),
+            [
+                AccountError::Locked(LockedOperation::Deposit),
+                AccountError::InsufficientFunds
+            ]
+        );
+        assert_eq!(failed.moved_funds(), Some(Amount::default()));
+
+        // Records without a format were written when a refused dispute also
+        // failed the transaction, so the state is the refusal's error
+        let mut json = serde_json::to_value(&failed).unwrap();
+        json.as_object_mut().unwrap().remove("format");
+        let legacy: Transaction = serde_json::from_value(json).unwrap();
+        assert_eq!(legacy.format, 0);
+        assert_eq!(
+            legacy.failures().collect::<Vec<_>>(),
+            [AccountError::InsufficientFunds]
+        );
+        assert_eq!(legacy.moved_funds(), None);
+    }
+
+    #[test]
+    fn test_chargeback_policies() {
+        let withdrawn = |policy| {
+            let mut state = State::new();
+            state.set_chargeback_policy(policy);
+            state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+            state.update(action!(Withdrawal, 1, 2, 8.0)).unwrap();
+            state
+        };
+
+        // Without a policy the dispute is recorded as failed, and the deposit
+        // is still settled
+        let mut state = withdrawn(None);
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        let deposit = state.transaction(TransactionId(1)).unwrap().unwrap();
+        assert_eq!(deposit.state, TransactionState::Succeeded);
+        assert_eq!(
+            deposit.failures().collect::<Vec<_>>(),
+            [AccountError::InsufficientFunds]
+        );
+        assert_eq!(state.negative_balances().count(), 0);
+
+        let mut state = withdrawn(Some(ChargebackPolicy::Strict));
+        assert!(matches!(
+            state.update(action!(Dispute, 1, 1)),
+            Err(UpdateError::DisputeExceedsAvailable { .. })
+        ));
+        assert!(matches!(
+            state.transaction(TransactionId(1)).unwrap().unwrap().state,
+            TransactionState::Succeeded
+        ));
+
+        let mut state = withdrawn(Some(ChargebackPolicy::AllowNegative));
+        state.update(action!(Dispute, 1, 1)).unwrap();
+        state.update(action!(Chargeback, 1, 1)).unwrap();
+        let negative: Vec<_> = state.negative_balances().collect();
+        assert_eq!(negative.len(), 1);
+        assert_eq!(negative[0].available, -Amount::from(8u32));
+        assert_eq!(negative[0].held, Amount::from(0u32));
+        assert_eq!(negative[0].total, -Amount::from(8u32));
+        assert!(negative[0].locked);
+    }
+
+    #[test]
+    fn test_envelopes() {
+        let tagged = |action: Action, category: &str| Action {
+            category: Some(category.into()),
+            ..action
+        };
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 100.0)).unwrap();
+        state
+            .set_envelope(ClientId(1), "groceries", Some(Amount::from(30u32)))
+            .unwrap();
+
+        state
+            .update(tagged(action!(Withdrawal, 1, 2, 20.0), "groceries"))
+            .unwrap();
+        state
+            .update(tagged(action!(Withdrawal, 1, 3, 15.0), "groceries"))
+            .unwrap();
+        assert!(matches!(
+            state.transaction(TransactionId(3)).unwrap().unwrap().state,
+            TransactionState::Failed(AccountError::EnvelopeExceeded)
+        ));
+        // Other categories, and untagged withdrawals, aren't capped
+        state
+            .update(tagged(action!(Withdrawal, 1, 4, 40.0), "rent"))
+            .unwrap();
+        state.update(action!(Withdrawal, 1, 5, 5.0)).unwrap();
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available,
+            Amount::from(35u32)
+        );
+
+        // A prepared withdrawal reserves its share until it's aborted
+        let prepared = state
+            .prepare(tagged(action!(Withdrawal, 1, 6, 10.0), "groceries"))
+            .unwrap();
+        assert!(state
+            .prepare(tagged(action!(Withdrawal, 1, 7, 1.0), "groceries"))
+            .is_err());
+        state.abort(prepared).unwrap();
+
+        let export = state.export_client(ClientId(1)).unwrap();
+        let groceries = &export.envelopes["groceries"];
+        assert_eq!(groceries.spent, Amount::from(20u32));
+        assert_eq!(groceries.remaining(), Amount::from(10u32));
+
+        state.reset_envelopes(ClientId(1)).unwrap();
+        state
+            .update(tagged(action!(Withdrawal, 1, 8, 30.0), "groceries"))
+            .unwrap();
+        assert!(matches!(
+            state.transaction(TransactionId(8)).unwrap().unwrap().state,
+            TransactionState::Succeeded
+        ));
+        assert!(matches!(
+            state.set_envelope(ClientId(2), "groceries", None),
+            Err(StatusError::AccountMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_two_phase_withdrawals() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+
+        let payout = state.prepare(action!(Withdrawal, 1, 2, 6.0)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available, Amount::from(4u32));
+        assert_eq!(account.held, Amount::from(6u32));
+
+        // The reserved funds can't be spent again, and the id is taken
+        assert!(matches!(
+            state.prepare(action!(Withdrawal, 1, 3, 5.0)),
+            Err(UpdateError::TransactionFailed { .. })
+        ));
+        assert!(matches!(
+            state.update(action!(Withdrawal, 1, 2, 1.0)),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+        assert!(matches!(
+            state.prepare(action!(Deposit, 1, 3, 5.0)),
+            Err(UpdateError::NotPreparable(ActionKind::Deposit))
+        ));
+
+        let refund = state.prepare(action!(Withdrawal, 1, 3, 4.0)).unwrap();
+        assert_eq!(state.prepared().count(), 2);
+        state.commit(payout.clone()).unwrap();
+        state.abort(refund).unwrap();
+        assert!(matches!(
+            state.commit(payout),
+            Err(UpdateError::NotPrepared(_))
+        ));
+
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available, Amount::from(4u32));
+        assert_eq!(account.held, Amount::from(0u32));
+        assert_eq!(account.total, Amount::from(4u32));
+        assert_eq!(state.prepared().count(), 0);
+        // The committed withdrawal is recorded, and the aborted id is free
+        assert_eq!(
+            state.transaction(TransactionId(2)).unwrap().unwrap().amount,
+            -Amount::from(6u32)
+        );
+        state.update(action!(Withdrawal, 1, 3, 1.0)).unwrap();
+    }
+
+    #[test]
+    fn test_repeat_disputes_are_ignored() {
+        let history = vec![
+            action!(Deposit, 1, 1, 10.0),
+            action!(Dispute, 1, 1),
+            action!(Dispute, 1, 1),
+        ];
+
+        // The second dispute is accepted, but the funds are only held once
+        let mut state = State::new();
+        for action in history.clone() {
+            state.update(action).unwrap();
+        }
+        let mut ingested = State::new();
+        ingested
+            .ingest_unchecked(TrustedBatch::new(history).unwrap())
+            .unwrap();
+        for state in [&state, &ingested] {
+            let account = state.account(ClientId(1)).unwrap();
+            assert_eq!(account.available, Amount::default());
+            assert_eq!(account.held, Amount::from(10u32));
+            assert_eq!(account.total, Amount::from(10u32));
+            let deposit = state.transaction(TransactionId(1)).unwrap().unwrap();
+            assert_eq!(deposit.state, TransactionState::Disputed);
+            assert_eq!(deposit.history.len(), 1);
+        }
+
+        // One resolve releases everything
+        state.update(action!(Resolve, 1, 1)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available, Amount::from(10u32));
+        assert_eq!(account.held, Amount::default());
+    }
+
+    #[test]
+    fn test_holds_are_released_individually() {
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 5.0)).unwrap();
+        state.update(action!(Deposit, 1, 2, 5.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 10.0)).unwrap();
+        let payout = state.prepare(action!(Withdrawal, 1, 4, 5.0)).unwrap();
+
+        for tx in [1, 2] {
+            state.update(action!(Dispute, 1, tx)).unwrap();
+        }
+        let holds = |state: &State| -> Vec<RawTransactionId> {
+            let account = state.accounts.get(ClientId(1)).unwrap().unwrap();
+            account.holds().keys().map(|hold| hold.0 .0).collect()
+        };
+        assert_eq!(holds(&state), [1, 2, 4]);
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().held,
+            Amount::from(15u32)
+        );
+
+        // The same amounts, but each comes off its own hold
+        state.update(action!(Resolve, 1, 2)).unwrap();
+        state.commit(payout).unwrap();
+        assert_eq!(holds(&state), [1]);
+        state.update(action!(Chargeback, 1, 1)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.held, Amount::from(0u32));
+        assert_eq!(account.available, Amount::from(10u32));
+
+        let mut account = Account::default();
+        account.deposit(Amount::from(1u32)).unwrap();
+        assert_eq!(
+            account.release(HoldId(TransactionId(9))),
+            Err(AccountError::HoldMissing(HoldId(TransactionId(9))))
+        );
+    }
+
+    #[test]
+    fn test_restate() {
+        let mut state = State::new();
+        state.set_audit_trail(true);
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Withdrawal, 1, 2, 3.0)).unwrap();
+        state.update(action!(Deposit, 1, 3, 1.0)).unwrap();
+        state.update(action!(Dispute, 1, 3)).unwrap();
+
+        // The deposit was meant to be 100, and the withdrawal 5
+        state
+            .restate(TransactionId(1), Amount::from(100u32))
+            .unwrap();
+        state.restate(TransactionId(2), Amount::from(5u32)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available, Amount::from(95u32));
+        let withdrawal = state.transaction(TransactionId(2)).unwrap().unwrap();
+        assert_eq!(withdrawal.amount, -Amount::from(5u32));
+        assert_eq!(withdrawal.history[0].kind, TransactionEventKind::Restated);
+
+        let trail = state.export_client(ClientId(1)).unwrap().audit_trail;
+        assert_eq!(
+            trail.last().unwrap().event,
+            AuditEvent::Restated {
+                transaction: TransactionId(2),
+                from: -Amount::from(3u32),
+                to: -Amount::from(5u32),
+            }
+        );
+
+        assert!(matches!(
+            state.restate(TransactionId(3), Amount::from(2u32)),
+            Err(RestatementError::NotRestatable(_))
+        ));
+        // Only 95 is left to take back
+        assert!(matches!(
+            state.restate(TransactionId(1), Amount::from(4u32)),
+            Err(RestatementError::Account(AccountError::InsufficientFunds))
+        ));
+
+        let savepoint = state.savepoint();
+        state
+            .restate(TransactionId(1), Amount::from(10u32))
+            .unwrap();
+        state.rollback_to(savepoint).unwrap();
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available,
+            Amount::from(95u32)
+        );
+        assert_eq!(
+            state.transaction(TransactionId(1)).unwrap().unwrap().amount,
+            Amount::from(100u32)
+        );
+    }
+
+    #[test]
+    fn test_erase_client() {
+        let mut state = State::new();
+        state.set_audit_trail(true);
+        state
+            .update(Action {
+                reference: Some("a-1".to_owned()),
+                meta: Some(Box::new(ActionMeta {
+                    source: Some("alice.csv".to_owned()),
+                    ..ActionMeta::default()
+                })),
+                ..action!(Deposit, 1, 1, 10.0)
+            })
+            .unwrap();
+        state.update(action!(Withdrawal, 1, 2, 10.0)).unwrap();
+        state.update(action!(Deposit, 2, 3, 5.0)).unwrap();
+        assert!(matches!(
+            state.erase_client(ClientId(1)),
+            Err(ErasureError::NotClosed(_))
+        ));
+
+        state.change_status(ClientId(1), |a| a.close()).unwrap();
+        assert_eq!(state.erase_client(ClientId(1)).unwrap(), 2);
+        assert!(state.account(ClientId(1)).is_none());
+        assert_eq!(
+            state.account(ClientId::TOMBSTONE).unwrap().status,
+            AccountStatus::Closed
+        );
+        assert_eq!(state.accounts().count(), 2);
+
+        let erased = state.export_client(ClientId(1)).unwrap();
+        assert!(erased.transactions.is_empty());
+        assert!(erased.audit_trail.is_empty());
+        let tombstone = state.export_client(ClientId::TOMBSTONE).unwrap();
+        assert_eq!(tombstone.transactions.len(), 2);
+        assert!(tombstone
+            .transactions
+            .iter()
+            .all(|t| t.timestamp.is_none() && t.reference.is_none() && t.meta.is_none()));
+        assert_eq!(state.transaction_for_reference("a-1"), None);
+
+        // The ids are still claimed, and the other client is untouched
+        assert!(matches!(
+            state.update(action!(Deposit, 1, 1, 10.0)),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+        assert!(matches!(
+            state.update(action!(Dispute, 1, 1)),
+            Err(UpdateError::ClientMismatch { .. })
+        ));
+        assert_eq!(
+            state.account(ClientId(2)).unwrap().available,
+            Amount::from(5u32)
+        );
+        assert!(matches!(
+            state.erase_client(ClientId::TOMBSTONE),
+            Err(ErasureError::Tombstone)
+        ));
+    }
+
+    #[test]
+    fn test_tombstone_client_is_reserved() {
+        let mut state = State::new();
+        assert!(matches!(
+            state.update(Action {
+                client_id: ClientId::TOMBSTONE,
+                ..action!(Deposit, 1, 1, 10.0)
+            }),
+            Err(UpdateError::ReservedClient(ClientId::TOMBSTONE))
+        ));
+        assert!(matches!(
+            state.prepare(Action {
+                client_id: ClientId::TOMBSTONE,
+                ..action!(Withdrawal, 1, 2, 1.0)
+            }),
+            Err(UpdateError::ReservedClient(_))
+        ));
+        assert!(state.account(ClientId::TOMBSTONE).is_none());
+        assert!(state.is_pristine());
+        assert_eq!(
+            TrustedBatch::new(vec![Action {
+                client_id: ClientId::TOMBSTONE,
+                ..action!(Deposit, 1, 1, 1.0)
+            }])
+            .unwrap_err(),
+            TrustedBatchError::ReservedClient { index: 0 }
+        );
+    }
+
+    #[test]
+    fn test_external_references() {
+        let input = "type,client,tx,amount,ref
+            deposit,1,,10,a-1
+            deposit,1,7,5,a-2
+            withdrawal,2,,1,b-1
+            deposit,1,,1,a-1
+            dispute,1,,,a-2
+            dispute,1,8,,a-1
+            dispute,1,,,missing
+            deposit,3,,1,";
+        let input = input.replace("            ", "");
+        let mut state = State::new();
+        let results: Vec<_> = CsvSource::from_reader(input.as_bytes())
+            .map(|action| state.update(action.unwrap()))
+            .collect();
+
+        assert!(results[..3].iter().all(Result::is_ok));
+        assert!(matches!(&results[3], Err(UpdateError::ReferenceUsed(r)) if r == "a-1"));
+        assert!(results[4].is_ok());
+        assert!(matches!(
+            results[5],
+            Err(UpdateError::ReferenceMismatch {
+                transaction: TransactionId(0),
+                ..
+            })
+        ));
+        assert!(matches!(results[6], Err(UpdateError::ReferenceMissing(_))));
+        assert!(matches!(results[7], Err(UpdateError::NoTransactionId)));
+
+        // Missing ids are assigned after the highest one used
+        assert_eq!(
+            state.transaction_for_reference("a-1"),
+            Some(TransactionId(0))
+        );
+        assert_eq!(
+            state.transaction_for_reference("b-1"),
+            Some(TransactionId(8))
+        );
+        assert_eq!(state.account(ClientId(1)).unwrap().held, Amount::from(5u32));
+        let export = state.export_client(ClientId(1)).unwrap();
+        assert_eq!(export.transactions[1].reference.as_deref(), Some("a-2"));
+
+        let savepoint = state.savepoint();
+        state
+            .update(Action {
+                transaction_id: None,
+                reference: Some("c-1".into()),
+                ..action!(Deposit, 3, 0, 1.0)
+            })
+            .unwrap();
+        assert_eq!(
+            state.transaction_for_reference("c-1"),
+            Some(TransactionId(9))
+        );
+        state.rollback_to(savepoint).unwrap();
+        assert_eq!(state.transaction_for_reference("c-1"), None);
+    }
+
+    /// A transaction store that's always unreachable
+    #[derive(Debug)]
+    struct Unreachable;
+
+    impl TransactionStore for Unreachable {
+        fn get(&self, _: TransactionId) -> Result<Option<Transaction>, StoreError> {
+            Err(StoreError::new(std::io::Error::other("unreachable")))
+        }
+        fn put(&mut self, _: TransactionId, _: Transaction) -> Result<(), StoreError> {
+            Err(StoreError::new(std::io::Error::other("unreachable")))
+        }
+        fn remove(&mut self, _: TransactionId) -> Result<Option<Transaction>, StoreError> {
+            Err(StoreError::new(std::io::Error::other("unreachable")))
+        }
+        fn len(&self) -> usize {
+            0
+        }
+        fn iter(&self) -> StoreIter<'_, TransactionId, Transaction> {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        use crate::{fixtures::Fixture, testing::Invariant};
+
+        let mut state = State::new();
+        state.update(action!(Deposit, 1, 1, 10.0)).unwrap();
+        state.update(action!(Deposit, 1, 2, 5.0)).unwrap();
+        state.update(action!(Withdrawal, 1, 3, 3.0)).unwrap();
+        state.update(action!(Dispute, 1, 2)).unwrap();
+        let mut reversal = action!(Reversal, 1, 4);
+        reversal.reverses = Some(TransactionId(3));
+        state.update(reversal).unwrap();
+        state.update(action!(Deposit, 2, 5, 1.0)).unwrap();
+        state.update(action!(Dispute, 2, 5)).unwrap();
+        state.update(action!(Chargeback, 2, 5)).unwrap();
+        // Failed from the start, so it didn't move anything
+        state.update(action!(Deposit, 2, 6, 1.0)).unwrap();
+        state.check_invariants().unwrap();
+
+        // The account doesn't have the funds its transaction put there
+        let broken = Fixture::new()
+            .account(1, "5", "0")
+            .deposit(1, 1, "10", TransactionState::Succeeded)
+            .build();
+        let violation = broken.check_invariants().unwrap_err();
+        assert!(matches!(
+            violation.invariant,
+            Invariant::FundsMismatch {
+                client: ClientId(1),
+                ..
+            }
+        ));
+
+        let orphaned = Fixture::new()
+            .account(1, "10", "0")
+            .deposit(1, 1, "10", TransactionState::Succeeded)
+            .deposit(2, 2, "10", TransactionState::Succeeded)
+            .build();
+        let violation = orphaned.check_invariants().unwrap_err();
+        assert!(matches!(
+            violation.invariant,
+            Invariant::OrphanTransaction {
+                transaction: TransactionId(2),
+                client: ClientId(2),
+            }
+        ));
+
+        // Totals can't be checked once transactions are gone
+        state
+            .forget_transactions(|t| t.id == TransactionId(1))
+            .unwrap();
+        state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_store_errors_are_reported() {
+        let mut state = State::with_stores(MemoryStore::new(), Unreachable);
+
+        let result = state.update(action!(Deposit, 1, 1, 1.0));
+        assert!(matches!(result, Err(UpdateError::Store(_))));
+        assert!(!state.seen_transactions().contains(TransactionId(1)));
     }
 }
@@ -0,0 +1,293 @@
+//! An in-memory read cache in front of a slower store
+//!
+//! Disputes, resolves and chargebacks read back transactions and accounts
+//! that were written recently, so with a persistent backend most of them
+//! would otherwise pay for a disk read (and decoding the record). A
+//! `CachedStore` keeps the most recently used records in memory. Writes go
+//! straight through to the inner store as well as the cache, so the inner
+//! store never falls behind and iterating it sees everything.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::Mutex,
+};
+
+use super::{AccountStore, StoreError, StoreIter, TransactionStore};
+use crate::{Account, ClientId, Transaction, TransactionId};
+
+/// Keeps up to `capacity` of the most recently read or written records of
+/// the inner store in memory, including lookups of records that don't exist
+#[derive(Debug)]
+pub struct CachedStore<S, K, V> {
+    inner: S,
+    cache: Mutex<Lru<K, V>>,
+}
+
+/// How often a `CachedStore` has found records in memory
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<S, K: Copy + Eq + Hash, V: Clone> CachedStore<S, K, V> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru<K, V>> {
+        // The cache is only a copy, so one left half updated by a panic is
+        // still fine to read from
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Read through the cache, loading from the inner store on a miss
+    fn get_with<F>(&self, key: K, load: F) -> Result<Option<V>, StoreError>
+    where
+        F: FnOnce(&S) -> Result<Option<V>, StoreError>,
+    {
+        if let Some(value) = self.lock().get(key) {
+            return Ok(value);
+        }
+        let value = load(&self.inner)?;
+        self.lock().insert(key, value.clone());
+        Ok(value)
+    }
+}
+
+impl<S: AccountStore> AccountStore for CachedStore<S, ClientId, Account> {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        self.get_with(client, |inner| inner.get(client))
+    }
+
+    fn put(&mut self, client: ClientId, account: Account) -> Result<(), StoreError> {
+        // Left uncached if the write fails, so the next read goes to the store
+        self.lock().remove(client);
+        self.inner.put(client, account.clone())?;
+        self.lock().insert(client, Some(account));
+        Ok(())
+    }
+
+    fn remove(&mut self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        self.lock().remove(client);
+        let removed = self.inner.remove(client)?;
+        self.lock().insert(client, None);
+        Ok(removed)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn iter(&self) -> StoreIter<'_, ClientId, Account> {
+        self.inner.iter()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.inner.flush()
+    }
+}
+
+impl<S: TransactionStore> TransactionStore for CachedStore<S, TransactionId, Transaction> {
+    fn get(&self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        self.get_with(id, |inner| inner.get(id))
+    }
+
+    fn contains(&self, id: TransactionId) -> Result<bool, StoreError> {
+        // Not cached on a miss, since it doesn't load the record
+        if let Some(transaction) = self.lock().get(id) {
+            return Ok(transaction.is_some());
+        }
+        self.inner.contains(id)
+    }
+
+    fn put(&mut self, id: TransactionId, transaction: Transaction) -> Result<(), StoreError> {
+        self.lock().remove(id);
+        self.inner.put(id, transaction.clone())?;
+        self.lock().insert(id, Some(transaction));
+        Ok(())
+    }
+
+    fn remove(&mut self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        self.lock().remove(id);
+        let removed = self.inner.remove(id)?;
+        self.lock().insert(id, None);
+        Ok(removed)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn iter(&self) -> StoreIter<'_, TransactionId, Transaction> {
+        self.inner.iter()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.inner.flush()
+    }
+}
+
+/// A least recently used map. Each use is stamped with a counter, and the
+/// oldest stamp is evicted first.
+#[derive(Debug)]
+struct Lru<K, V> {
+    capacity: usize,
+    /// Each record (`None` for one known not to exist) and its last use
+    entries: HashMap<K, (Option<V>, u64)>,
+    /// The key last used at each stamp
+    uses: BTreeMap<u64, K>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<K: Copy + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            uses: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// The cached record, `Some(None)` if it's known not to exist
+    fn get(&mut self, key: K) -> Option<Option<V>> {
+        let Some((value, used)) = self.entries.get_mut(&key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.uses.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.uses.insert(self.clock, key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: Option<V>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(key);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.uses.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.clock += 1;
+        self.entries.insert(key, (value, self.clock));
+        self.uses.insert(self.clock, key);
+    }
+
+    fn remove(&mut self, key: K) {
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.uses.remove(&used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{store::MemoryStore, Amount, RawTransactionId, TransactionState};
+
+    fn transaction(id: RawTransactionId) -> Transaction {
+        Transaction {
+            id: TransactionId(id),
+            client: ClientId(1),
+            state: TransactionState::Succeeded,
+            amount: Amount::from(10u32),
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            meta: None,
+            balances: None,
+            history: Vec::new(),
+            format: Transaction::FORMAT,
+        }
+    }
+
+    #[test]
+    fn test_least_recently_used_are_evicted() {
+        let mut store = CachedStore::new(MemoryStore::new(), 2);
+        for id in 1..=3 {
+            TransactionStore::put(&mut store, TransactionId(id), transaction(id)).unwrap();
+        }
+        assert_eq!(store.inner().len(), 3);
+
+        // 1 was pushed out by 3
+        TransactionStore::get(&store, TransactionId(2)).unwrap();
+        TransactionStore::get(&store, TransactionId(1)).unwrap();
+        assert_eq!(store.stats(), CacheStats { hits: 1, misses: 1 });
+        // And 3 by loading 1 back in
+        TransactionStore::get(&store, TransactionId(3)).unwrap();
+        assert_eq!(store.stats(), CacheStats { hits: 1, misses: 2 });
+
+        // Writes go through to the inner store
+        let removed = TransactionStore::remove(&mut store, TransactionId(2)).unwrap();
+        assert!(removed.is_some());
+        assert!(!TransactionStore::contains(&store, TransactionId(2)).unwrap());
+        assert!(!store.inner().contains(TransactionId(2)).unwrap());
+        assert_eq!(store.stats(), CacheStats { hits: 2, misses: 2 });
+    }
+
+    #[test]
+    fn test_state_over_cached_store() {
+        use crate::{Action, ActionKind, State};
+
+        let accounts: CachedStore<_, ClientId, Account> = CachedStore::new(MemoryStore::new(), 4);
+        let transactions: CachedStore<_, TransactionId, Transaction> =
+            CachedStore::new(MemoryStore::new(), 4);
+        let mut state = State::with_stores(accounts, transactions);
+        let action = |kind, transaction: RawTransactionId, amount: Option<u32>| Action {
+            transaction_id: Some(TransactionId(transaction)),
+            client_id: ClientId(1),
+            kind,
+            amount: amount.map(Amount::from),
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        };
+        for id in 1..=10 {
+            state
+                .update(action(ActionKind::Deposit, id, Some(5)))
+                .unwrap();
+        }
+        state.update(action(ActionKind::Dispute, 1, None)).unwrap();
+        state.update(action(ActionKind::Resolve, 1, None)).unwrap();
+        let account = state.account(ClientId(1)).unwrap();
+        assert_eq!(account.available, Amount::from(50u32));
+        assert_eq!(state.accounts().len(), 1);
+    }
+}
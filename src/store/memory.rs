@@ -0,0 +1,90 @@
+use std::{collections::HashMap, hash::Hash};
+
+use super::{AccountStore, StoreError, StoreIter, TransactionStore};
+use crate::{Account, ClientId, Transaction, TransactionId};
+
+/// The default store, keeping every record in a `HashMap`
+#[derive(Debug, Clone)]
+pub struct MemoryStore<K, V>(HashMap<K, V>);
+
+impl<K, V> MemoryStore<K, V> {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<K, V> Default for MemoryStore<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> From<HashMap<K, V>> for MemoryStore<K, V> {
+    fn from(map: HashMap<K, V>) -> Self {
+        Self(map)
+    }
+}
+
+impl<K: Eq + Hash + Copy, V: Clone> MemoryStore<K, V> {
+    fn iter_cloned(&self) -> StoreIter<'_, K, V> {
+        Box::new(self.0.iter().map(|(k, v)| Ok((*k, v.clone()))))
+    }
+}
+
+impl AccountStore for MemoryStore<ClientId, Account> {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        Ok(self.0.get(&client).cloned())
+    }
+
+    fn put(&mut self, client: ClientId, account: Account) -> Result<(), StoreError> {
+        self.0.insert(client, account);
+        Ok(())
+    }
+
+    fn remove(&mut self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        Ok(self.0.remove(&client))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> StoreIter<'_, ClientId, Account> {
+        self.iter_cloned()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+}
+
+impl TransactionStore for MemoryStore<TransactionId, Transaction> {
+    fn get(&self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        Ok(self.0.get(&id).cloned())
+    }
+
+    fn contains(&self, id: TransactionId) -> Result<bool, StoreError> {
+        Ok(self.0.contains_key(&id))
+    }
+
+    fn put(&mut self, id: TransactionId, transaction: Transaction) -> Result<(), StoreError> {
+        self.0.insert(id, transaction);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        Ok(self.0.remove(&id))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> StoreIter<'_, TransactionId, Transaction> {
+        self.iter_cloned()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+}
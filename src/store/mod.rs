@@ -0,0 +1,98 @@
+//! Storage backends for a `State`'s accounts and transactions
+//!
+//! The state keeps its records behind the `AccountStore` and
+//! `TransactionStore` traits, so they can live somewhere other than a
+//! `HashMap` (e.g. an embedded database, once there are too many transactions
+//! to keep in memory). Stores work with owned records: the state reads a
+//! record, updates it and writes it back, so a backend never has to hand out
+//! references into its own storage.
+//!
+//! A slow backend can be wrapped in a `CachedStore` to keep its most
+//! recently used records in memory.
+
+mod cache;
+mod memory;
+#[cfg(feature = "sled")]
+pub mod sled;
+
+use std::{error::Error, fmt};
+
+pub use cache::{CacheStats, CachedStore};
+pub use memory::MemoryStore;
+
+use crate::{Account, ClientId, Transaction, TransactionId};
+
+/// An iterator over every record in a store
+pub type StoreIter<'a, K, V> = Box<dyn Iterator<Item = Result<(K, V), StoreError>> + 'a>;
+
+/// Where a `State` keeps its accounts
+pub trait AccountStore: fmt::Debug + Send + Sync {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, StoreError>;
+
+    /// Insert or replace a client's account
+    fn put(&mut self, client: ClientId, account: Account) -> Result<(), StoreError>;
+
+    fn remove(&mut self, client: ClientId) -> Result<Option<Account>, StoreError>;
+
+    /// The number of accounts
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every account, in no particular order
+    fn iter(&self) -> StoreIter<'_, ClientId, Account>;
+
+    /// Make room for at least `additional` more accounts, if the backend can
+    fn reserve(&mut self, _additional: usize) {}
+
+    /// Write out anything the backend has buffered
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Where a `State` keeps its transactions
+pub trait TransactionStore: fmt::Debug + Send + Sync {
+    fn get(&self, id: TransactionId) -> Result<Option<Transaction>, StoreError>;
+
+    fn contains(&self, id: TransactionId) -> Result<bool, StoreError> {
+        self.get(id).map(|transaction| transaction.is_some())
+    }
+
+    /// Insert or replace a transaction
+    fn put(&mut self, id: TransactionId, transaction: Transaction) -> Result<(), StoreError>;
+
+    fn remove(&mut self, id: TransactionId) -> Result<Option<Transaction>, StoreError>;
+
+    /// The number of transactions
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every transaction, in no particular order
+    fn iter(&self) -> StoreIter<'_, TransactionId, Transaction>;
+
+    /// Make room for at least `additional` more transactions, if the backend
+    /// can
+    fn reserve(&mut self, _additional: usize) {}
+
+    /// Write out anything the backend has buffered
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// A storage backend failed to read or write a record
+#[derive(Debug, thiserror::Error)]
+#[error("storage backend failed: {0}")]
+pub struct StoreError(#[source] Box<dyn Error + Send + Sync>);
+
+impl StoreError {
+    pub fn new<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self(error.into())
+    }
+}
@@ -0,0 +1,355 @@
+//! A persistent store on top of [sled](https://docs.rs/sled)
+//!
+//! Accounts and transactions are kept in separate trees of the same database,
+//! so a state built with `open_state` leaves a queryable database behind after
+//! processing. Writes are buffered and applied to each tree in batches, see
+//! `SledConfig::write_buffer`, and recently used records are kept in memory,
+//! see `SledConfig::read_cache`.
+
+use std::{collections::HashMap, fmt::Debug, hash::Hash, path::Path};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{AccountStore, CachedStore, StoreError, StoreIter, TransactionStore};
+use crate::{Account, ClientId, State, Transaction, TransactionId};
+
+const ACCOUNTS_TREE: &str = "accounts";
+const TRANSACTIONS_TREE: &str = "transactions";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SledConfig {
+    /// How many changed records each store holds before writing them out in
+    /// a single batch. Larger buffers mean fewer, bigger writes, but more is
+    /// lost if the process dies before the next flush.
+    pub write_buffer: usize,
+
+    /// Bytes of the database sled keeps cached in memory
+    pub cache_capacity: u64,
+
+    /// How many of the most recently used accounts, and separately
+    /// transactions, to keep decoded in a `CachedStore` in front of the
+    /// database (0 for none). Disputes and their resolutions mostly read
+    /// back recent records, so this saves them a lookup and a decode.
+    pub read_cache: usize,
+}
+
+impl Default for SledConfig {
+    fn default() -> Self {
+        Self {
+            write_buffer: 4096,
+            cache_capacity: 256 * 1024 * 1024,
+            read_cache: 16 * 1024,
+        }
+    }
+}
+
+/// Open (or create) the database at `path` and build a state on top of it.
+///
+/// Changes are only synced to disk by `State::flush_stores`, or when the state
+/// is dropped. sled finishes closing a database on background threads, so
+/// reopening one in the same process straight after dropping its state can
+/// briefly fail to get the lock.
+///
+/// Only the accounts and transactions are kept in the database. Anything else
+/// in the state (e.g. the limits or the seen set) starts out empty, though a
+/// redelivered transaction is still caught by the store itself.
+pub fn open_state<P: AsRef<Path>>(path: P, config: &SledConfig) -> Result<State, StoreError> {
+    let db = sled::Config::new()
+        .path(path)
+        .cache_capacity(config.cache_capacity)
+        // The background flusher can hold the database lock after the state is
+        // dropped, so syncing is left to `flush`
+        .flush_every_ms(None)
+        .open()
+        .map_err(StoreError::new)?;
+
+    let accounts: SledStore<ClientId, Account> = SledStore::new(
+        db.open_tree(ACCOUNTS_TREE).map_err(StoreError::new)?,
+        config,
+    );
+    let transactions: SledStore<TransactionId, Transaction> = SledStore::new(
+        db.open_tree(TRANSACTIONS_TREE).map_err(StoreError::new)?,
+        config,
+    );
+    if config.read_cache == 0 {
+        return Ok(State::with_stores(accounts, transactions));
+    }
+    Ok(State::with_stores(
+        CachedStore::new(accounts, config.read_cache),
+        CachedStore::new(transactions, config.read_cache),
+    ))
+}
+
+/// A single tree of a sled database, with writes buffered in memory.
+///
+/// Any buffered writes are flushed when the store is dropped, but errors at
+/// that point can't be reported, so call `State::flush_stores` when done.
+#[derive(Debug)]
+pub struct SledStore<K: Key, V: Record> {
+    tree: sled::Tree,
+
+    /// Records changed since the last batch, `None` for removed ones
+    pending: HashMap<K, Option<V>>,
+
+    write_buffer: usize,
+}
+
+impl<K: Key, V: Record> SledStore<K, V> {
+    pub fn new(tree: sled::Tree, config: &SledConfig) -> Self {
+        Self {
+            tree,
+            pending: HashMap::new(),
+            write_buffer: config.write_buffer.max(1),
+        }
+    }
+
+    fn get(&self, key: K) -> Result<Option<V>, StoreError> {
+        if let Some(pending) = self.pending.get(&key) {
+            return Ok(pending.clone());
+        }
+        match self.tree.get(key.encode()).map_err(StoreError::new)? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(StoreError::new),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: K, value: Option<V>) -> Result<Option<V>, StoreError> {
+        let previous = match value {
+            // The previous value is only needed for removals
+            Some(_) => None,
+            None => self.get(key)?,
+        };
+        self.pending.insert(key, value);
+        if self.pending.len() >= self.write_buffer {
+            self.write_pending()?;
+        }
+        Ok(previous)
+    }
+
+    fn len(&self) -> usize {
+        let mut len = self.tree.len();
+        for (key, value) in &self.pending {
+            let stored = self.tree.contains_key(key.encode()).unwrap_or(false);
+            match (stored, value) {
+                (false, Some(_)) => len += 1,
+                (true, None) => len -= 1,
+                _ => {}
+            }
+        }
+        len
+    }
+
+    fn iter(&self) -> StoreIter<'_, K, V> {
+        let stored = self.tree.iter().filter_map(move |entry| {
+            let decoded = entry.map_err(StoreError::new).and_then(|(key, value)| {
+                let key = K::decode(&key)?;
+                let value = serde_json::from_slice(&value).map_err(StoreError::new)?;
+                Ok((key, value))
+            });
+            match decoded {
+                // The buffered version is newer
+                Ok((key, _)) if self.pending.contains_key(&key) => None,
+                decoded => Some(decoded),
+            }
+        });
+        let pending = self
+            .pending
+            .iter()
+            .filter_map(|(key, value)| Some(Ok((*key, value.clone()?))));
+        Box::new(stored.chain(pending))
+    }
+
+    fn write_pending(&mut self) -> Result<(), StoreError> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in self.pending.drain() {
+            match value {
+                Some(value) => {
+                    let bytes = serde_json::to_vec(&value).map_err(StoreError::new)?;
+                    batch.insert(&key.encode()[..], bytes);
+                }
+                None => batch.remove(&key.encode()[..]),
+            }
+        }
+        self.tree.apply_batch(batch).map_err(StoreError::new)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.write_pending()?;
+        self.tree.flush().map_err(StoreError::new)?;
+        Ok(())
+    }
+}
+
+impl<K: Key, V: Record> Drop for SledStore<K, V> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl AccountStore for SledStore<ClientId, Account> {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        SledStore::get(self, client)
+    }
+
+    fn put(&mut self, client: ClientId, account: Account) -> Result<(), StoreError> {
+        SledStore::put(self, client, Some(account)).map(drop)
+    }
+
+    fn remove(&mut self, client: ClientId) -> Result<Option<Account>, StoreError> {
+        SledStore::put(self, client, None)
+    }
+
+    fn len(&self) -> usize {
+        SledStore::len(self)
+    }
+
+    fn iter(&self) -> StoreIter<'_, ClientId, Account> {
+        SledStore::iter(self)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        SledStore::flush(self)
+    }
+}
+
+impl TransactionStore for SledStore<TransactionId, Transaction> {
+    fn get(&self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        SledStore::get(self, id)
+    }
+
+    fn contains(&self, id: TransactionId) -> Result<bool, StoreError> {
+        match self.pending.get(&id) {
+            Some(pending) => Ok(pending.is_some()),
+            None => self.tree.contains_key(id.encode()).map_err(StoreError::new),
+        }
+    }
+
+    fn put(&mut self, id: TransactionId, transaction: Transaction) -> Result<(), StoreError> {
+        SledStore::put(self, id, Some(transaction)).map(drop)
+    }
+
+    fn remove(&mut self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        SledStore::put(self, id, None)
+    }
+
+    fn len(&self) -> usize {
+        SledStore::len(self)
+    }
+
+    fn iter(&self) -> StoreIter<'_, TransactionId, Transaction> {
+        SledStore::iter(self)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        SledStore::flush(self)
+    }
+}
+
+/// Ids are stored as big endian `u64`s, so records iterate in id order and
+/// databases written before widening the ids can still be read
+pub trait Key: Copy + Eq + Hash + Debug + Send + Sync + 'static {
+    fn encode(self) -> [u8; 8];
+    fn decode(bytes: &[u8]) -> Result<Self, StoreError>;
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64, StoreError> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| StoreError::new(format!("invalid key of {} bytes", bytes.len())))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl Key for ClientId {
+    fn encode(self) -> [u8; 8] {
+        u64::from(self.0).to_be_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, StoreError> {
+        let id = decode_u64(bytes)?;
+        id.try_into()
+            .map(Self)
+            .map_err(|_| StoreError::new(format!("client id {} is too wide", id)))
+    }
+}
+
+// The conversions are no-ops with the `wide-transaction-ids` feature
+#[allow(clippy::useless_conversion)]
+impl Key for TransactionId {
+    fn encode(self) -> [u8; 8] {
+        u64::from(self.0).to_be_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, StoreError> {
+        let id = decode_u64(bytes)?;
+        id.try_into()
+            .map(Self)
+            .map_err(|_| StoreError::new(format!("transaction id {} is too wide", id)))
+    }
+}
+
+/// A record that can be kept in a sled tree
+pub trait Record: Clone + Serialize + DeserializeOwned + Debug + Send + Sync + 'static {}
+
+impl<T> Record for T where T: Clone + Serialize + DeserializeOwned + Debug + Send + Sync + 'static {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Action, ActionKind, Amount, RawClientId, RawTransactionId, UpdateError};
+
+    fn deposit(client: RawClientId, transaction: RawTransactionId, amount: u32) -> Action {
+        Action {
+            transaction_id: Some(TransactionId(transaction)),
+            client_id: ClientId(client),
+            kind: ActionKind::Deposit,
+            amount: Some(Amount::from(amount)),
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn test_state_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SledConfig {
+            write_buffer: 2,
+            ..SledConfig::default()
+        };
+
+        let mut state = open_state(dir.path(), &config).unwrap();
+        for transaction in 1..=5 {
+            state
+                .update(deposit((transaction % 2) as RawClientId, transaction, 10))
+                .unwrap();
+        }
+        state.flush_stores().unwrap();
+        drop(state);
+
+        // Give sled a moment to let go of the lock
+        let mut reopened = open_state(dir.path(), &config);
+        for _ in 0..50 {
+            if reopened.is_ok() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            reopened = open_state(dir.path(), &config);
+        }
+        let mut state = reopened.unwrap();
+        assert_eq!(state.accounts().len(), 2);
+        assert_eq!(
+            state.account(ClientId(1)).unwrap().available,
+            Amount::from(30u32)
+        );
+        assert!(matches!(
+            state.update(deposit(1, 3, 10)),
+            Err(UpdateError::TransactionUsed(_))
+        ));
+    }
+}
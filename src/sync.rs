@@ -0,0 +1,12 @@
+//! Synchronization primitives shared by the concurrent engines.
+//!
+//! When built with `RUSTFLAGS="--cfg transaction_engine_loom"` these are
+//! swapped for loom's instrumented versions, so the engine's critical sections
+//! can be model checked under every possible interleaving. (The usual
+//! `--cfg loom` can't be used, since it also changes how tokio is built.)
+
+#[cfg(not(transaction_engine_loom))]
+pub(crate) use std::sync::{Arc, Mutex, RwLock};
+
+#[cfg(transaction_engine_loom)]
+pub(crate) use loom::sync::{Arc, Mutex, RwLock};
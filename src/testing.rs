@@ -0,0 +1,481 @@
+//! Deterministic simulation, for testing the engine (and your own policies)
+//! against long runs of random actions
+//!
+//! An `ActionGenerator` makes an endless, seeded stream of actions over a
+//! handful of clients: deposits and withdrawals with new ids, and disputes,
+//! resolves, chargebacks and reversals of transactions it made earlier, in
+//! proportions set with `with_weight`. The same seed always gives the same
+//! actions, so a failing run can be replayed exactly.
+//!
+//! A `Simulator` applies actions to a state (configured with whatever
+//! policies are under test) and checks after each one that:
+//!
+//! - funds are conserved: an account's total only moves by the amount of the
+//!   action applied to it (nothing for disputes and resolves), and the summary
+//!   moves with it
+//! - no held balance is negative, and no available balance either (unless under
+//!   `ChargebackPolicy::AllowNegative`)
+//! - locked accounts don't move on a deposit or withdrawal
+//!
+//! stopping at the first `InvariantViolation`. Rejected actions are part of
+//! the run, and have to leave things as they were. `check_all` checks the
+//! whole state with `State::check_invariants`. `action` builds single
+//! actions for hand written tests.
+
+use crate::{
+    soak::SplitMix64, Account, Action, ActionKind, Amount, ChargebackPolicy, ClientId, HoldId,
+    RawClientId, RawTransactionId, State, TransactionId,
+};
+
+/// Builds an action, with its amount (if any) given as a string so the same
+/// test works with and without the `decimal` feature.
+///
+/// # Panics
+///
+/// If the amount isn't a number
+pub fn action(
+    kind: ActionKind,
+    client: RawClientId,
+    transaction: RawTransactionId,
+    amount: Option<&str>,
+) -> Action {
+    Action {
+        transaction_id: Some(TransactionId(transaction)),
+        client_id: ClientId(client),
+        kind,
+        amount: amount.map(|amount| amount.parse().expect("invalid amount")),
+        timestamp: None,
+        reverses: None,
+        reference: None,
+        evidence: None,
+        category: None,
+        currency: None,
+        meta: None,
+    }
+}
+
+/// A seeded, endless stream of random actions
+#[derive(Debug, Clone)]
+pub struct ActionGenerator {
+    rng: SplitMix64,
+    clients: RawClientId,
+    weights: Vec<(ActionKind, u64)>,
+    /// The largest amount, in hundredths
+    max_cents: u64,
+    next_transaction: RawTransactionId,
+    /// Every deposit and withdrawal made so far, to act on
+    made: Vec<(ClientId, TransactionId)>,
+}
+
+impl ActionGenerator {
+    /// Actions over 10 clients, of up to 1000, mostly deposits and
+    /// withdrawals
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: SplitMix64(seed),
+            clients: 10,
+            weights: vec![
+                (ActionKind::Deposit, 40),
+                (ActionKind::Withdrawal, 25),
+                (ActionKind::Dispute, 15),
+                (ActionKind::Resolve, 8),
+                (ActionKind::Chargeback, 4),
+                (ActionKind::Reversal, 3),
+            ],
+            max_cents: 100_000,
+            next_transaction: 1,
+            made: Vec::new(),
+        }
+    }
+
+    /// Spread actions over clients `1..=clients`
+    pub fn with_clients(mut self, clients: RawClientId) -> Self {
+        self.clients = clients.max(1);
+        self
+    }
+
+    /// How often actions of `kind` come up, relative to the other kinds (0
+    /// for never)
+    pub fn with_weight(mut self, kind: ActionKind, weight: u64) -> Self {
+        match self.weights.iter_mut().find(|(k, _)| *k == kind) {
+            Some(entry) => entry.1 = weight,
+            None => self.weights.push((kind, weight)),
+        }
+        self
+    }
+
+    /// Make deposits and withdrawals of up to `max` (in whole units)
+    pub fn with_max_amount(mut self, max: u32) -> Self {
+        self.max_cents = (u64::from(max) * 100).max(1);
+        self
+    }
+
+    fn kind(&mut self) -> ActionKind {
+        let total: u64 = self.weights.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return ActionKind::Deposit;
+        }
+        let mut pick = self.rng.next() % total;
+        for (kind, weight) in &self.weights {
+            if pick < *weight {
+                return *kind;
+            }
+            pick -= weight;
+        }
+        unreachable!("the pick is below the total weight")
+    }
+
+    fn amount(&mut self) -> Amount {
+        let cents = self.rng.next() % self.max_cents + 1;
+        format!("{}.{:02}", cents / 100, cents % 100)
+            .parse()
+            .expect("always a number")
+    }
+
+    fn new_transaction(&mut self) -> TransactionId {
+        let id = TransactionId(self.next_transaction);
+        self.next_transaction += 1;
+        id
+    }
+
+    /// A deposit or withdrawal made earlier
+    fn earlier(&mut self) -> (ClientId, TransactionId) {
+        self.made[self.rng.below(self.made.len())]
+    }
+}
+
+impl Iterator for ActionGenerator {
+    type Item = Action;
+
+    fn next(&mut self) -> Option<Action> {
+        let mut kind = self.kind();
+        // Nothing to act on yet
+        if self.made.is_empty() && !matches!(kind, ActionKind::Deposit | ActionKind::Withdrawal) {
+            kind = ActionKind::Deposit;
+        }
+        let generated = match kind {
+            ActionKind::Deposit | ActionKind::Withdrawal => {
+                let client = self.rng.below(self.clients as usize) as RawClientId + 1;
+                let id = self.new_transaction();
+                self.made.push((ClientId(client), id));
+                let mut generated = action(kind, client, id.0, None);
+                generated.amount = Some(self.amount());
+                generated
+            }
+            ActionKind::Dispute | ActionKind::Resolve | ActionKind::Chargeback => {
+                let (client, id) = self.earlier();
+                action(kind, client.0, id.0, None)
+            }
+            ActionKind::Reversal => {
+                let (client, reverses) = self.earlier();
+                let id = self.new_transaction();
+                let mut generated = action(kind, client.0, id.0, None);
+                generated.reverses = Some(reverses);
+                generated
+            }
+        };
+        Some(generated)
+    }
+}
+
+/// Applies actions to a state, checking its invariants after each one
+#[derive(Debug)]
+pub struct Simulator {
+    state: State,
+    tolerance: Amount,
+    stats: SimulationStats,
+}
+
+/// How a simulation has gone so far
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SimulationStats {
+    /// Actions applied, including rejected ones
+    pub actions: u64,
+    /// Actions the state rejected
+    pub rejected: u64,
+}
+
+/// An invariant that didn't hold, and the action that broke it (see also
+/// `State::check_invariants`)
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("after action {step} ({action:?}): {invariant}")]
+pub struct InvariantViolation {
+    /// How many actions had been applied, counting this one
+    pub step: u64,
+    /// The action that broke the invariant, if it was broken by one
+    pub action: Option<Box<Action>>,
+    pub invariant: Invariant,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum Invariant {
+    #[error("client {client}'s total moved by {moved}, which the action doesn't account for")]
+    FundsNotConserved { client: ClientId, moved: Amount },
+
+    #[error("the summary's total moved by {summary}, but the account's by {moved}")]
+    SummaryDrift { summary: Amount, moved: Amount },
+
+    #[error("the summary's total is {summary}, but the accounts add up to {accounts}")]
+    SummaryMismatch { summary: Amount, accounts: Amount },
+
+    #[error("client {client} has {held} held")]
+    NegativeHeld { client: ClientId, held: Amount },
+
+    #[error("client {client} has {available} available")]
+    NegativeAvailable { client: ClientId, available: Amount },
+
+    #[error("client {client}'s account is locked, but its balances moved")]
+    LockedAccountMoved { client: ClientId },
+
+    #[error("client {client} has {total} in total, but its transactions add up to {transactions}")]
+    FundsMismatch {
+        client: ClientId,
+        total: Amount,
+        transactions: Amount,
+    },
+
+    #[error("transaction {transaction} belongs to client {client}, who has no account")]
+    OrphanTransaction {
+        transaction: TransactionId,
+        client: ClientId,
+    },
+
+    #[error("transaction {transaction} and the transaction it reverses ({reverses}) don't agree")]
+    BrokenReversal {
+        transaction: TransactionId,
+        reverses: TransactionId,
+    },
+
+    #[error("client {client} has hold {hold}, which isn't for any of its transactions")]
+    DanglingHold { client: ClientId, hold: HoldId },
+}
+
+impl Simulator {
+    /// Simulate on `state`, set up with the policies under test
+    pub fn new(state: State) -> Self {
+        Self {
+            state,
+            tolerance: Amount::default(),
+            stats: SimulationStats::default(),
+        }
+    }
+
+    /// Allow amounts to be this far out before it's a violation. Without the
+    /// `decimal` feature amounts are floats, so some drift is expected.
+    pub fn with_tolerance(mut self, tolerance: Amount) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Apply `count` actions, checking the invariants after each
+    pub fn run<I>(
+        &mut self,
+        actions: I,
+        count: usize,
+    ) -> Result<SimulationStats, InvariantViolation>
+    where
+        I: IntoIterator<Item = Action>,
+    {
+        for action in actions.into_iter().take(count) {
+            self.step(action)?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Apply one action, checking the invariants it could have broken.
+    ///
+    /// # Panics
+    ///
+    /// If the state's stores fail to read
+    pub fn step(&mut self, action: Action) -> Result<(), InvariantViolation> {
+        self.stats.actions += 1;
+        let client = action.client_id;
+        let before = self.account(client);
+        let summary_before = self.state.summary().total;
+        // What a chargeback or reversal would move, from the transaction it
+        // names
+        let settled = match action.kind {
+            ActionKind::Chargeback => action.transaction_id,
+            ActionKind::Reversal => action.reverses,
+            _ => None,
+        }
+        .and_then(|id| {
+            self.state
+                .transaction(id)
+                .expect("transaction store failed")
+        })
+        .map(|transaction| -transaction.amount);
+        let allowed = match action.kind {
+            ActionKind::Deposit => action.amount,
+            ActionKind::Withdrawal => action.amount.map(|amount| -amount),
+            ActionKind::Dispute | ActionKind::Resolve => None,
+            ActionKind::Chargeback | ActionKind::Reversal => settled,
+        };
+
+        if self.state.update(action.clone()).is_err() {
+            self.stats.rejected += 1;
+        }
+
+        let after = self.account(client);
+        let moved = after.total_funds() - before.total_funds();
+        let summary = self.state.summary().total - summary_before;
+        let conserved = self.near(moved, Amount::default())
+            || allowed.is_some_and(|allowed| self.near(moved, allowed));
+        let invariant = if !conserved {
+            Some(Invariant::FundsNotConserved { client, moved })
+        } else if !self.near(summary, moved) {
+            Some(Invariant::SummaryDrift { summary, moved })
+        } else if before.is_locked()
+            && matches!(action.kind, ActionKind::Deposit | ActionKind::Withdrawal)
+            && (after.available_funds() != before.available_funds()
+                || after.held_funds() != before.held_funds())
+        {
+            Some(Invariant::LockedAccountMoved { client })
+        } else {
+            self.balance_violation(client, &after)
+        };
+        match invariant {
+            Some(invariant) => Err(InvariantViolation {
+                step: self.stats.actions,
+                action: Some(Box::new(action)),
+                invariant,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the whole state now, with `State::check_invariants`
+    ///
+    /// # Panics
+    ///
+    /// If the state's stores fail to read
+    pub fn check_all(&self) -> Result<(), InvariantViolation> {
+        self.state
+            .check_invariants_within(self.tolerance)
+            .map_err(|violation| InvariantViolation {
+                step: self.stats.actions,
+                ..violation
+            })
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        self.stats
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn into_state(self) -> State {
+        self.state
+    }
+
+    fn account(&self, client: ClientId) -> Account {
+        self.state
+            .raw_account(client)
+            .expect("account store failed")
+            .unwrap_or_default()
+    }
+
+    fn balance_violation(&self, client: ClientId, account: &Account) -> Option<Invariant> {
+        let zero = Amount::default();
+        let negative_allowed =
+            self.state.chargeback_policy() == Some(ChargebackPolicy::AllowNegative);
+        if account.held_funds() < zero && !self.near(account.held_funds(), zero) {
+            Some(Invariant::NegativeHeld {
+                client,
+                held: account.held_funds(),
+            })
+        } else if account.available_funds() < zero
+            && !negative_allowed
+            && !self.near(account.available_funds(), zero)
+        {
+            Some(Invariant::NegativeAvailable {
+                client,
+                available: account.available_funds(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn near(&self, a: Amount, b: Amount) -> bool {
+        (a - b).abs() <= self.tolerance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    fn tolerance() -> Amount {
+        "0.000001".parse().unwrap()
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let first: Vec<_> = ActionGenerator::new(42).take(200).collect();
+        let again: Vec<_> = ActionGenerator::new(42).take(200).collect();
+        let other: Vec<_> = ActionGenerator::new(43).take(200).collect();
+        let key = |actions: &[Action]| -> Vec<_> {
+            actions
+                .iter()
+                .map(|a| (a.kind, a.client_id, a.transaction_id, a.amount))
+                .collect()
+        };
+        assert_eq!(key(&first), key(&again));
+        assert_ne!(key(&first), key(&other));
+        assert!(first.iter().all(|a| (1..=10).contains(&a.client_id.0)));
+
+        let deposits = ActionGenerator::new(1)
+            .with_weight(ActionKind::Withdrawal, 0)
+            .with_weight(ActionKind::Reversal, 0)
+            .take(100)
+            .filter(|a| a.kind == ActionKind::Deposit)
+            .count();
+        assert!(deposits > 40, "{}", deposits);
+    }
+
+    #[test]
+    fn test_simulation_holds_invariants() {
+        for policy in [None, Some(ChargebackPolicy::AllowNegative)] {
+            let mut state = State::new();
+            state.set_chargeback_policy(policy);
+            let mut simulator = Simulator::new(state).with_tolerance(tolerance());
+            let generator = ActionGenerator::new(7).with_clients(5).with_max_amount(50);
+            let stats = simulator.run(generator, 5_000).unwrap();
+            assert_eq!(stats.actions, 5_000);
+            assert!(stats.rejected > 0);
+            simulator.check_all().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_violations_are_caught() {
+        // Held more than nothing, in an account that can't have had a
+        // dispute
+        let state = Fixture::new().account(1, "5", "-1").build();
+        let simulator = Simulator::new(state);
+        let violation = simulator.check_all().unwrap_err();
+        assert!(matches!(
+            violation.invariant,
+            Invariant::NegativeHeld {
+                client: ClientId(1),
+                ..
+            }
+        ));
+
+        let mut simulator = Simulator::new(State::new());
+        simulator
+            .step(action(ActionKind::Deposit, 1, 1, Some("2.5")))
+            .unwrap();
+        simulator
+            .step(action(ActionKind::Dispute, 1, 1, None))
+            .unwrap();
+        assert_eq!(
+            simulator.state().summary().held,
+            "2.5".parse::<Amount>().unwrap()
+        );
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{AccountError, Amount, ClientId, TransactionId};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::Balances, persist::exact_amount, AccountError, ActionMeta, Amount, ClientId, Timestamp,
+    TransactionId,
+};
 
 /// An individual transaction, deserialized from the input csv.
 ///
@@ -6,21 +11,158 @@ use crate::{AccountError, Amount, ClientId, TransactionId};
 /// intermediate deserializer class (particularly if we had to support multiple
 /// input formats and normalize them to a `Transaction` model), but that seems
 /// like overkill for this exercise.
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub id: TransactionId,
     pub client: ClientId,
 
     pub state: TransactionState,
 
+    #[serde(with = "exact_amount")]
     pub amount: Amount,
+
+    /// The timestamp of the deposit or withdrawal that created it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+
+    /// If this is a reversal, the transaction it reversed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<TransactionId>,
+
+    /// The external reference it was created with, if any
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+
+    /// Where the action that created it came from, if it said
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Box<ActionMeta>>,
+
+    /// The account's balances just after the transaction was applied, if
+    /// `State::set_running_balances` was on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balances: Option<Balances>,
+
+    /// The disputes, resolves, chargebacks, reversals and restatements
+    /// applied to it since it was created, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<TransactionEvent>,
+
+    /// The layout of the record (see `Transaction::FORMAT`). Records from
+    /// before it was kept read as 0.
+    #[serde(default)]
+    pub format: u8,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where a transaction stands.
+///
+/// Disputes move a settled transaction between `Succeeded`, `Disputed` and
+/// `Cancelled`. A dispute step the account refuses (e.g. a dispute of a
+/// deposit that's already been withdrawn) leaves the state as it was, and is
+/// only recorded on its event in the transaction's `history`, so a settled
+/// deposit is never mistaken for one that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TransactionState {
     Succeeded,
+    /// The deposit, withdrawal or reversal itself was refused, so it never
+    /// moved any funds
     Failed(AccountError),
 
     Disputed,
     Cancelled,
+
+    /// Undone by the given reversal transaction
+    Reversed(TransactionId),
+}
+
+/// Something that happened to a transaction after it was created, kept in
+/// its `history`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionEvent {
+    /// The action's sequence number in the state (see `State::sequence`)
+    pub seq: u64,
+
+    pub kind: TransactionEventKind,
+
+    /// The action's timestamp, if it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<Timestamp>,
+
+    /// Why the account refused it, if it did (e.g. a dispute of a deposit
+    /// that had already been withdrawn)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed: Option<AccountError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionEventKind {
+    Disputed,
+    Resolved,
+    ChargedBack,
+    /// Undone by the given reversal transaction
+    Reversed(TransactionId),
+    /// Its amount was corrected with `State::restate`
+    Restated,
+}
+
+impl Transaction {
+    /// The layout of new records. From format 1, a refused dispute step is
+    /// only recorded on its `history` event, so a `Failed` state always
+    /// means the transaction itself failed. In format 0 records, a refused
+    /// dispute step also set the transaction's state to `Failed`.
+    ///
+    /// cbindgen:ignore
+    pub const FORMAT: u8 = 1;
+
+    /// Every refusal on the transaction, oldest first: the transaction
+    /// itself, if it failed, then each dispute, resolve or chargeback of it
+    /// that the account refused. A refused reversal is the reversal
+    /// transaction's failure, not this one's.
+    pub fn failures(&self) -> impl Iterator<Item = AccountError> + '_ {
+        // Format 0 records of a refused dispute step have its error in both
+        // the state and the history, and failed transactions had no history
+        let own = match self.state {
+            TransactionState::Failed(e) if self.format > 0 || self.history.is_empty() => Some(e),
+            _ => None,
+        };
+        let steps = self.history.iter().filter_map(|event| match event.kind {
+            TransactionEventKind::Reversed(_) => None,
+            _ => event.failed,
+        });
+        own.into_iter().chain(steps)
+    }
+
+    /// How much the transaction has moved into (or out of) its account's
+    /// total, or `None` if that can't be told from its record: in format 0
+    /// records, a transaction whose dispute was refused looks the same as one
+    /// that failed from the start, unless some later step on it succeeded.
+    pub(crate) fn moved_funds(&self) -> Option<Amount> {
+        match self.state {
+            TransactionState::Cancelled => Some(Amount::default()),
+            TransactionState::Failed(_) if self.format > 0 || self.history.is_empty() => {
+                Some(Amount::default())
+            }
+            TransactionState::Failed(_) if self.history.iter().all(|e| e.failed.is_some()) => None,
+            _ => Some(self.amount),
+        }
+    }
+}
+
+impl TransactionEvent {
+    /// An event from the action with sequence number `seq`, with what the
+    /// account made of it
+    pub(crate) fn new(
+        seq: u64,
+        kind: TransactionEventKind,
+        at: Option<Timestamp>,
+        done: Result<(), AccountError>,
+    ) -> Self {
+        let failed = done.err();
+        Self {
+            seq,
+            kind,
+            at,
+            failed,
+        }
+    }
 }
@@ -0,0 +1,249 @@
+//! Checking and normalizing actions before an engine sees them
+//!
+//! An `ActionValidator` rejects actions no engine should be given (negative
+//! amounts, deposits of nothing) and brings the rest to a fixed number of
+//! decimal places, so every input source doesn't need its own checks. It can
+//! check one action at a time, or wrap a whole iterator of actions with
+//! `validate`, which passes on the good ones and sends the rest to a
+//! `RejectSink` (a closure, or a `Vec` to collect them in).
+
+use crate::{Action, ActionKind, Amount, Rounding};
+
+/// Decimal places amounts are brought to, unless set with
+/// `ActionValidator::with_decimals`
+const DEFAULT_DECIMALS: u32 = 4;
+
+/// Checks and normalizes actions
+#[derive(Debug, Clone)]
+pub struct ActionValidator {
+    /// Decimal places amounts are brought to, if any
+    decimals: Option<u32>,
+    precision: Precision,
+    zero_deposits: bool,
+}
+
+/// What to do with amounts that have more decimal places than allowed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// Round to the nearest, with midpoints away from zero (the same as
+    /// account balances are output)
+    #[default]
+    Round,
+    /// Drop the extra places
+    Truncate,
+    /// Reject the action
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ValidationError {
+    #[error("the amount {0} is negative")]
+    NegativeAmount(Amount),
+
+    #[error("deposits have to be of more than nothing")]
+    ZeroDeposit,
+
+    #[error("the amount {amount} has more than {decimals} decimal places")]
+    TooPrecise { amount: Amount, decimals: u32 },
+}
+
+/// An action that didn't pass validation, and why
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub action: Action,
+    pub error: ValidationError,
+}
+
+/// Receives rejected actions. Implemented for closures taking a `Rejected`,
+/// and for `Vec<Rejected>` (or a `&mut` one) to collect them.
+pub trait RejectSink {
+    fn reject(&mut self, rejected: Rejected);
+}
+
+impl<F: FnMut(Rejected)> RejectSink for F {
+    fn reject(&mut self, rejected: Rejected) {
+        self(rejected)
+    }
+}
+
+impl RejectSink for Vec<Rejected> {
+    fn reject(&mut self, rejected: Rejected) {
+        self.push(rejected)
+    }
+}
+
+impl RejectSink for &mut Vec<Rejected> {
+    fn reject(&mut self, rejected: Rejected) {
+        self.push(rejected)
+    }
+}
+
+impl ActionValidator {
+    /// Reject negative amounts and zero deposits, and round amounts to 4
+    /// decimal places
+    pub fn new() -> Self {
+        Self {
+            decimals: Some(DEFAULT_DECIMALS),
+            precision: Precision::Round,
+            zero_deposits: false,
+        }
+    }
+
+    /// Bring amounts to this many decimal places (e.g. 18 with the `crypto`
+    /// feature), or leave them as they are with `None`
+    pub fn with_decimals(mut self, decimals: Option<u32>) -> Self {
+        self.decimals = decimals;
+        self
+    }
+
+    /// How to deal with amounts that have more decimal places than allowed
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Let deposits of zero through (e.g. to open accounts)
+    pub fn with_zero_deposits(mut self, allowed: bool) -> Self {
+        self.zero_deposits = allowed;
+        self
+    }
+
+    /// Check an action, normalizing its amount in place
+    pub fn check(&self, action: &mut Action) -> Result<(), ValidationError> {
+        let Some(amount) = action.amount else {
+            // Left for the engine, which knows which actions need amounts
+            return Ok(());
+        };
+        if amount < Amount::default() {
+            return Err(ValidationError::NegativeAmount(amount));
+        }
+        let amount = match self.decimals {
+            Some(decimals) => self.normalize(amount, decimals)?,
+            None => amount,
+        };
+        if action.kind == ActionKind::Deposit && !self.zero_deposits && amount == Amount::default()
+        {
+            return Err(ValidationError::ZeroDeposit);
+        }
+        action.amount = Some(amount);
+        Ok(())
+    }
+
+    /// Pass on the actions that pass validation (normalized), sending the
+    /// rest to `sink`
+    pub fn validate<I, S>(self, actions: I, sink: S) -> Validated<I::IntoIter, S>
+    where
+        I: IntoIterator<Item = Action>,
+        S: RejectSink,
+    {
+        Validated {
+            validator: self,
+            actions: actions.into_iter(),
+            sink,
+        }
+    }
+
+    fn normalize(&self, amount: Amount, decimals: u32) -> Result<Amount, ValidationError> {
+        match self.precision {
+            Precision::Round => Ok(Rounding::MidpointAwayFromZero.round(amount, decimals)),
+            Precision::Truncate => Ok(Rounding::ToZero.round(amount, decimals)),
+            Precision::Reject if Rounding::ToZero.round(amount, decimals) != amount => {
+                Err(ValidationError::TooPrecise { amount, decimals })
+            }
+            Precision::Reject => Ok(amount),
+        }
+    }
+}
+
+impl Default for ActionValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The actions from an iterator that pass an `ActionValidator`, from
+/// `ActionValidator::validate`
+#[derive(Debug)]
+pub struct Validated<I, S> {
+    validator: ActionValidator,
+    actions: I,
+    sink: S,
+}
+
+impl<I: Iterator<Item = Action>, S: RejectSink> Iterator for Validated<I, S> {
+    type Item = Action;
+
+    fn next(&mut self) -> Option<Action> {
+        for mut action in self.actions.by_ref() {
+            match self.validator.check(&mut action) {
+                Ok(()) => return Some(action),
+                Err(error) => self.sink.reject(Rejected { action, error }),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::action;
+
+    /// Built directly, since reading these amounts from csv would already
+    /// reject the negative and over-precise ones
+    fn actions() -> impl Iterator<Item = Action> {
+        [
+            action(ActionKind::Deposit, 1, 1, Some("10.123456")),
+            action(ActionKind::Deposit, 1, 2, Some("-1.0")),
+            action(ActionKind::Deposit, 1, 3, Some("0.00001")),
+            action(ActionKind::Withdrawal, 1, 4, Some("2.00006")),
+            action(ActionKind::Dispute, 1, 1, None),
+        ]
+        .into_iter()
+    }
+
+    fn amounts(actions: &[Action]) -> Vec<String> {
+        actions
+            .iter()
+            .map(|action| action.amount.map_or("-".into(), |a| a.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut rejects = Vec::new();
+        let passed: Vec<_> = ActionValidator::new()
+            .validate(actions(), &mut rejects)
+            .collect();
+        assert_eq!(amounts(&passed), ["10.1235", "2.0001", "-"]);
+        let errors: Vec<_> = rejects.iter().map(|r| r.error.clone()).collect();
+        assert_eq!(
+            errors,
+            [
+                ValidationError::NegativeAmount(-Amount::from(1u32)),
+                ValidationError::ZeroDeposit
+            ]
+        );
+
+        let mut rejected = 0;
+        let passed: Vec<_> = ActionValidator::new()
+            .with_precision(Precision::Truncate)
+            .with_zero_deposits(true)
+            .validate(actions(), |_| rejected += 1)
+            .collect();
+        assert_eq!(amounts(&passed), ["10.1234", "0", "2", "-"]);
+        assert_eq!(rejected, 1);
+
+        let mut rejects = Vec::new();
+        let passed: Vec<_> = ActionValidator::new()
+            .with_decimals(Some(5))
+            .with_precision(Precision::Reject)
+            .validate(actions(), &mut rejects)
+            .collect();
+        assert_eq!(amounts(&passed), ["0.00001", "2.00006", "-"]);
+        assert!(matches!(
+            rejects[0].error,
+            ValidationError::TooPrecise { decimals: 5, .. }
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+//! A view of an engine scoped to one client, for account servicing
+
+use crate::{
+    report::Statement, store::StoreError, AccountData, Action, ActionKind, ClientId,
+    SingleThreadedEngine, Transaction, TransactionId, UpdateError,
+};
+
+/// An engine seen from one client's account, from
+/// `SingleThreadedEngine::client_view`.
+///
+/// Everything goes through the view's client: lookups only find that
+/// client's account and transactions, and actions for any other client are
+/// rejected with `UpdateError::OutsideView` before they reach the engine.
+/// Actions that point at another client's transaction are already rejected
+/// by the engine (with `UpdateError::ClientMismatch`).
+#[derive(Debug)]
+pub struct ClientView<'a> {
+    engine: &'a mut SingleThreadedEngine,
+    client: ClientId,
+}
+
+impl<'a> ClientView<'a> {
+    pub(crate) fn new(engine: &'a mut SingleThreadedEngine, client: ClientId) -> Self {
+        Self { engine, client }
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// The client's account as it stands, if it has been opened
+    pub fn account(&self) -> Option<AccountData> {
+        self.engine.state().account(self.client)
+    }
+
+    /// The client's statement, see `State::statement`
+    pub fn statement(&self) -> Result<Statement, StoreError> {
+        self.engine.state().statement(self.client)
+    }
+
+    /// One of the client's transactions. Other clients' transactions aren't
+    /// found, as if they didn't exist.
+    pub fn transaction(&self, id: TransactionId) -> Result<Option<Transaction>, StoreError> {
+        let transaction = self.engine.state().transaction(id)?;
+        Ok(transaction.filter(|transaction| transaction.client == self.client))
+    }
+
+    /// An action for the client, with no amount or anything else filled in.
+    /// The rest can be set with struct update syntax, e.g.
+    /// `Action { amount: Some(amount), ..view.action(ActionKind::Deposit, id) }`.
+    pub fn action(&self, kind: ActionKind, transaction: TransactionId) -> Action {
+        Action {
+            transaction_id: Some(transaction),
+            client_id: self.client,
+            kind,
+            amount: None,
+            timestamp: None,
+            reverses: None,
+            reference: None,
+            evidence: None,
+            category: None,
+            currency: None,
+            meta: None,
+        }
+    }
+
+    /// Apply an action for the client, returning the error if it couldn't be
+    /// applied (as `SingleThreadedEngine::try_process` does)
+    pub fn submit(&mut self, action: Action) -> Result<(), UpdateError> {
+        if action.client_id != self.client {
+            return Err(UpdateError::OutsideView {
+                action: action.client_id,
+                view: self.client,
+            });
+        }
+        self.engine.try_process(action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, TransactionState};
+
+    #[test]
+    fn test_client_view() {
+        let mut engine = SingleThreadedEngine::new();
+        let mut other = engine.client_view(ClientId(2));
+        let deposit = Action {
+            amount: Some(Amount::from(5u32)),
+            ..other.action(ActionKind::Deposit, TransactionId(1))
+        };
+        other.submit(deposit).unwrap();
+
+        let mut view = engine.client_view(ClientId(1));
+        let deposit = Action {
+            amount: Some(Amount::from(10u32)),
+            ..view.action(ActionKind::Deposit, TransactionId(2))
+        };
+        view.submit(deposit.clone()).unwrap();
+        assert_eq!(view.account().unwrap().total, Amount::from(10u32));
+        assert_eq!(
+            view.transaction(TransactionId(2)).unwrap().unwrap().state,
+            TransactionState::Succeeded
+        );
+        assert!(view.transaction(TransactionId(1)).unwrap().is_none());
+        assert_eq!(view.statement().unwrap().lines.len(), 1);
+
+        // Actions for other clients, or on their transactions, don't get in
+        let mut stray = Action {
+            client_id: ClientId(2),
+            transaction_id: Some(TransactionId(3)),
+            ..deposit
+        };
+        assert!(matches!(
+            view.submit(stray.clone()),
+            Err(UpdateError::OutsideView {
+                action: ClientId(2),
+                view: ClientId(1)
+            })
+        ));
+        stray.client_id = ClientId(1);
+        stray.kind = ActionKind::Dispute;
+        stray.transaction_id = Some(TransactionId(1));
+        assert!(matches!(
+            view.submit(stray),
+            Err(UpdateError::ClientMismatch { .. })
+        ));
+        assert_eq!(
+            engine.state().account(ClientId(2)).unwrap().held,
+            Amount::default()
+        );
+    }
+}
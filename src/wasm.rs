@@ -0,0 +1,111 @@
+//! A wasm-bindgen wrapper over `SingleThreadedEngine`, for running the
+//! engine in a browser (e.g. what-if simulations on the client)
+//!
+//! Build the library for `wasm32-unknown-unknown` with this feature, then
+//! generate the JS bindings with wasm-bindgen:
+//!
+//! ```sh
+//! cargo rustc --release --lib --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/transaction_engine.wasm
+//! ```
+//!
+//! ```js
+//! import init, { Engine } from "./pkg/transaction_engine.js";
+//!
+//! await init();
+//! const engine = new Engine();
+//! engine.process('{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}');
+//! engine.accounts(); // [{client: 1, available: 10, held: 0, ...}]
+//! ```
+//!
+//! Actions are given as JSON objects with the csv columns as keys. Amounts
+//! are best given as strings, since that's the only way the `crypto` feature
+//! takes them (so they're kept exactly). Accounts come back as they're
+//! written to JSON everywhere else. A rejected action (including a
+//! withdrawal recorded as failed) throws an `Error` with the reason, and
+//! leaves the engine as it was.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{engine::withdrawal_outcome, AccountData, Action, ActionKind, SingleThreadedEngine};
+
+/// An engine, as `Engine` in JS
+#[wasm_bindgen(js_name = Engine)]
+#[derive(Debug, Default)]
+pub struct WasmEngine {
+    engine: SingleThreadedEngine,
+}
+
+#[wasm_bindgen(js_class = Engine)]
+impl WasmEngine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply an action, given as JSON
+    pub fn process(&mut self, action: &str) -> Result<(), JsError> {
+        self.apply(action).map_err(|e| JsError::new(&e))
+    }
+
+    /// Every account, as an array of objects
+    pub fn accounts(&self) -> Result<JsValue, JsValue> {
+        js_sys::JSON::parse(&self.accounts_json())
+    }
+}
+
+impl WasmEngine {
+    fn apply(&mut self, json: &str) -> Result<(), String> {
+        let action: Action = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let withdrawal = action.kind == ActionKind::Withdrawal;
+        let id = action.transaction_id;
+        let result = self.engine.try_process(action).and_then(|()| {
+            if withdrawal {
+                withdrawal_outcome(self.engine.state(), id, None)
+            } else {
+                Ok(())
+            }
+        });
+        result.map_err(|e| e.to_string())
+    }
+
+    fn accounts_json(&self) -> String {
+        let accounts: Vec<AccountData> = self.engine.state().accounts().collect();
+        serde_json::to_string(&accounts).expect("accounts serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process() {
+        let mut engine = WasmEngine::new();
+        engine
+            .apply(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#)
+            .unwrap();
+        engine
+            .apply(r#"{"type": "deposit", "client": 2, "tx": 2, "amount": "2.5"}"#)
+            .unwrap();
+        let error = engine
+            .apply(r#"{"type": "withdrawal", "client": 1, "tx": 3, "amount": "11"}"#)
+            .unwrap_err();
+        assert!(error.contains("not enough funds"), "{error}");
+        engine
+            .apply(r#"{"type": "dispute", "client": 1, "tx": 1}"#)
+            .unwrap();
+        assert!(engine.apply(r#"{"type": "deposit"}"#).is_err());
+
+        let accounts: serde_json::Value = serde_json::from_str(&engine.accounts_json()).unwrap();
+        let accounts = accounts.as_array().unwrap();
+        assert_eq!(accounts.len(), 2);
+        let first = accounts.iter().find(|a| a["client"] == 1).unwrap();
+        // Written as a string with the `crypto` feature
+        let held = first["held"]
+            .as_str()
+            .map_or(first["held"].to_string(), String::from);
+        assert_eq!(held.parse::<f64>().unwrap(), 10.0);
+        assert_eq!(first["locked"], false);
+    }
+}
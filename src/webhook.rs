@@ -0,0 +1,331 @@
+//! Ingesting transaction webhooks in a Plaid-like JSON shape
+//!
+//! `WebhookAdapter::handle` takes the body of one delivery, turns its added
+//! transactions into actions and sends them to an `EngineHandle`, waiting for
+//! each to be applied before it answers. It doesn't run a server itself, so it
+//! can be mounted in whatever HTTP framework the service already uses, which
+//! sends back the status and JSON body in the returned `WebhookResponse`.
+//!
+//! A delivery looks like:
+//!
+//! ```json
+//! {
+//!   "webhook_type": "TRANSACTIONS",
+//!   "webhook_code": "DEFAULT_UPDATE",
+//!   "added": [{
+//!     "transaction_id": "lPNjeW1nR6CDn5okmGQ6hEpMo4lLNoSrzqDje",
+//!     "account_id": "BxBXxLj1m4HMXBm9WZZmCWVbPjX16EHwv99vp",
+//!     "amount": 12.5,
+//!     "iso_currency_code": "USD",
+//!     "date": "2024-03-01",
+//!     "pending": false
+//!   }],
+//!   "removed": []
+//! }
+//! ```
+//!
+//! As in Plaid, positive amounts are money leaving the account (withdrawals)
+//! and negative amounts are money coming in (deposits). Pending transactions
+//! are skipped, since they can still change, as are `removed` transactions
+//! (which can't be matched to a reversal yet) and other webhook types.
+//!
+//! Each transaction's id is its action's external reference, which makes it
+//! the idempotency key: a redelivered transaction is rejected by the engine
+//! with `UpdateError::ReferenceUsed`, and counted as a duplicate rather than
+//! applied twice. Deliveries can always be retried, so the adapter answers
+//! with a 5xx status (asking for a retry) if the engine couldn't be reached
+//! or its storage failed, and only answers 200 once every transaction has
+//! been applied or permanently rejected.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    io::days_from_civil, Action, ActionKind, Amount, ClientId, EngineClosed, EngineHandle,
+    Timestamp, UpdateError,
+};
+
+/// Turns webhook deliveries into actions for an engine
+#[derive(Debug, Clone)]
+pub struct WebhookAdapter {
+    handle: EngineHandle,
+    accounts: HashMap<String, ClientId>,
+    currency: Option<String>,
+}
+
+/// What to answer a delivery with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookResponse {
+    /// The HTTP status: 200 once the delivery has been dealt with, 400 if it
+    /// couldn't be read, or 5xx if it should be sent again
+    pub status: u16,
+    pub receipt: WebhookReceipt,
+}
+
+/// What came of each transaction in a delivery, sent back as the response
+/// body
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct WebhookReceipt {
+    /// Transactions the engine accepted (including withdrawals it recorded
+    /// as failed for insufficient funds, as with any other input)
+    pub applied: u64,
+    /// Transactions that had already been applied from an earlier delivery
+    pub duplicates: u64,
+    /// Pending and removed transactions
+    pub skipped: u64,
+    /// Transactions that can't be applied, and won't be on a retry either
+    pub rejected: Vec<Rejection>,
+    /// Why the delivery as a whole failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    pub transaction_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Delivery {
+    webhook_type: String,
+    #[serde(default)]
+    added: Vec<WebhookTransaction>,
+    #[serde(default)]
+    removed: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookTransaction {
+    transaction_id: String,
+    account_id: String,
+    amount: serde_json::Number,
+    #[serde(default)]
+    iso_currency_code: Option<String>,
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    pending: bool,
+}
+
+impl WebhookAdapter {
+    pub fn new(handle: EngineHandle) -> Self {
+        Self {
+            handle,
+            accounts: HashMap::new(),
+            currency: None,
+        }
+    }
+
+    /// Map an account id to a client. Unmapped accounts are taken as the
+    /// client id if they're a plain number.
+    pub fn with_account<S: Into<String>>(mut self, account: S, client: ClientId) -> Self {
+        self.accounts.insert(account.into(), client);
+        self
+    }
+
+    /// Only accept transactions in this currency (e.g. `"USD"`). Otherwise
+    /// the currency is ignored, since the engine doesn't keep one.
+    pub fn with_currency<S: Into<String>>(mut self, currency: S) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    /// Apply the transactions in one delivery's body, in order
+    pub async fn handle(&self, body: &[u8]) -> WebhookResponse {
+        let delivery: Delivery = match serde_json::from_slice(body) {
+            Ok(delivery) => delivery,
+            Err(e) => return WebhookResponse::failed(400, format!("invalid delivery: {}", e)),
+        };
+        let mut receipt = WebhookReceipt::default();
+        if delivery.webhook_type != "TRANSACTIONS" {
+            return WebhookResponse::ok(receipt);
+        }
+
+        receipt.skipped += delivery.removed.len() as u64;
+        for transaction in delivery.added {
+            if transaction.pending {
+                receipt.skipped += 1;
+                continue;
+            }
+            let action = match self.action(&transaction) {
+                Ok(action) => action,
+                Err(reason) => {
+                    receipt.reject(transaction.transaction_id, reason);
+                    continue;
+                }
+            };
+            match self.handle.process(action).await {
+                Ok(Ok(())) => receipt.applied += 1,
+                Ok(Err(UpdateError::ReferenceUsed(_))) => receipt.duplicates += 1,
+                Ok(Err(UpdateError::Store(e))) => {
+                    receipt.error = Some(e.to_string());
+                    return WebhookResponse {
+                        status: 500,
+                        receipt,
+                    };
+                }
+                Ok(Err(e)) => receipt.reject(transaction.transaction_id, e.to_string()),
+                Err(EngineClosed) => {
+                    receipt.error = Some(EngineClosed.to_string());
+                    return WebhookResponse {
+                        status: 503,
+                        receipt,
+                    };
+                }
+            }
+        }
+        WebhookResponse::ok(receipt)
+    }
+
+    fn action(&self, transaction: &WebhookTransaction) -> Result<Action, String> {
+        if let (Some(expected), Some(found)) = (&self.currency, &transaction.iso_currency_code) {
+            if expected != found {
+                return Err(format!("the amount is in {}, not {}", found, expected));
+            }
+        }
+        let account = &transaction.account_id;
+        let client = match self.accounts.get(account) {
+            Some(client) => *client,
+            None => account
+                .parse()
+                .map(ClientId)
+                .map_err(|_| format!("account {} isn't mapped to a client", account))?,
+        };
+        let amount: Amount = transaction
+            .amount
+            .to_string()
+            .parse()
+            .map_err(|_| format!("invalid amount '{}'", transaction.amount))?;
+        let timestamp = match &transaction.date {
+            Some(date) => Some(parse_date(date).ok_or_else(|| format!("invalid date '{}'", date))?),
+            None => None,
+        };
+
+        // Positive amounts leave the account
+        let kind = if amount.is_sign_negative() {
+            ActionKind::Deposit
+        } else {
+            ActionKind::Withdrawal
+        };
+        Ok(Action {
+            transaction_id: None,
+            client_id: client,
+            kind,
+            amount: Some(amount.abs()),
+            timestamp,
+            reverses: None,
+            reference: Some(transaction.transaction_id.clone()),
+            evidence: None,
+            category: None,
+            currency: transaction.iso_currency_code.clone(),
+            meta: None,
+        })
+    }
+}
+
+impl WebhookResponse {
+    fn ok(receipt: WebhookReceipt) -> Self {
+        Self {
+            status: 200,
+            receipt,
+        }
+    }
+
+    fn failed(status: u16, error: String) -> Self {
+        Self {
+            status,
+            receipt: WebhookReceipt {
+                error: Some(error),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The JSON body to send back
+    pub fn body(&self) -> String {
+        serde_json::to_string(&self.receipt).expect("receipts always serialize")
+    }
+}
+
+impl WebhookReceipt {
+    fn reject(&mut self, transaction_id: String, reason: String) {
+        self.rejected.push(Rejection {
+            transaction_id,
+            reason,
+        });
+    }
+}
+
+/// Parse a `YYYY-MM-DD` date, as midnight UTC
+fn parse_date(value: &str) -> Option<Timestamp> {
+    let mut parts = value.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    u64::try_from(days_from_civil(year, month, day) * 86_400)
+        .ok()
+        .map(Timestamp::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SingleThreadedEngine;
+
+    const DELIVERY: &str = r#"{
+        "webhook_type": "TRANSACTIONS",
+        "webhook_code": "DEFAULT_UPDATE",
+        "item_id": "item-1",
+        "added": [
+            {"transaction_id": "t-1", "account_id": "acct-a", "amount": -10.25,
+             "iso_currency_code": "USD", "date": "2024-03-01", "pending": false},
+            {"transaction_id": "t-2", "account_id": "acct-a", "amount": 4,
+             "iso_currency_code": "USD", "date": "2024-03-02"},
+            {"transaction_id": "t-3", "account_id": "acct-a", "amount": 1, "pending": true},
+            {"transaction_id": "t-4", "account_id": "acct-b", "amount": 1},
+            {"transaction_id": "t-5", "account_id": "7", "amount": 3,
+             "iso_currency_code": "EUR"}
+        ],
+        "removed": [{"transaction_id": "t-0"}]
+    }"#;
+
+    #[tokio::test]
+    async fn test_deliveries() {
+        let (handle, _thread) = EngineHandle::spawn(SingleThreadedEngine::new(), 8);
+        let adapter = WebhookAdapter::new(handle.clone())
+            .with_account("acct-a", ClientId(1))
+            .with_currency("USD");
+
+        let response = adapter.handle(DELIVERY.as_bytes()).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.receipt.applied, 2);
+        assert_eq!(response.receipt.skipped, 2);
+        let rejected: Vec<_> = response
+            .receipt
+            .rejected
+            .iter()
+            .map(|rejection| rejection.transaction_id.as_str())
+            .collect();
+        assert_eq!(rejected, ["t-4", "t-5"]);
+
+        // Redelivery doesn't apply anything twice
+        let retry = adapter.handle(DELIVERY.as_bytes()).await;
+        assert_eq!(retry.status, 200);
+        assert_eq!(retry.receipt.applied, 0);
+        assert_eq!(retry.receipt.duplicates, 2);
+
+        let account = handle.query_account(ClientId(1)).await.unwrap().unwrap();
+        assert_eq!(account.available.to_string(), "6.25");
+
+        let other = adapter
+            .handle(br#"{"webhook_type": "ITEM", "webhook_code": "ERROR"}"#)
+            .await;
+        assert_eq!(other.status, 200);
+        let garbage = adapter.handle(b"not json").await;
+        assert_eq!(garbage.status, 400);
+        assert!(garbage.body().contains("invalid delivery"));
+    }
+}